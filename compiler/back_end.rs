@@ -1,26 +1,26 @@
 use std::collections::{HashMap, VecDeque};
-use std::fs::File;
 use std::io::{self, Write};
-use std::error::Error;
-use std::fmt;
-
-// Define errors
-#[derive(Debug)]
-pub struct BackEndError(String);
-
-impl fmt::Display for BackEndError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "BackEndError: {}", self.0)
-    }
-}
-
-impl Error for BackEndError {}
+use std::path::Path;
+use crate::util::write_atomic;
+use minimisa_core::bitvec::BitVec;
+use minimisa_core::object::Object;
+use minimisa_core::{default_opcodes, encode_address, encode_const, encode_shift, lookup, size, Opcodes,
+    INSTRUCTION_COUNT};
+use crate::enums::{Line, Value, ValueType, NB_BIT_REG};
+use crate::errors::{BackEndError, Location};
+use crate::relax::align_padding;
 
 // Utility Queue (similar to Python's Queue)
 pub struct Queue<T> {
     items: VecDeque<T>,
 }
 
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Queue<T> {
     pub fn new() -> Self {
         Queue {
@@ -41,73 +41,124 @@ impl<T> Queue<T> {
     }
 }
 
-// Define Enums for Value Types and NB_BIT_REG
-#[derive(Debug, Clone, Copy)]
-pub enum ValueType {
-    Register,
-    Other,
-}
-
-pub const NB_BIT_REG: usize = 8;  // Number of bits for register (placeholder)
-
 // Trait to define common methods for BackEnd types
 pub trait BackEnd {
-    fn to_file(&mut self, filename: &str) -> io::Result<()>;
-    fn to_output(&mut self);
+    // Write the whole output to any sink: file, stdout, memory buffer...
+    fn write_to(&mut self, out: &mut dyn Write) -> io::Result<()>;
     fn handle_line(&mut self, line: &Line) -> Result<(), BackEndError>;
     fn post_packets(&mut self) -> Option<Vec<u8>>;
+
+    // Write the output to a file, replacing it only if encoding succeeds
+    fn write_file(&mut self, filename: &str) -> io::Result<()> {
+        write_atomic(Path::new(filename), |out| self.write_to(out))
+    }
 }
 
-// Placeholder struct for Line to simulate `line.funcname`, `line.typed_args`, etc.
-pub struct Line {
-    pub funcname: String,
-    pub typed_args: Vec<TypedArg>,
-    pub linenumber: usize,
+// Debug info sidecar
+//
+// Written next to the object file by the label-resolving back ends, and
+// read by the emulator's debugger. One record per line:
+//
+//     line <bit offset> <source file> <line number>
+//     label <bit offset> <name>
+//
+// Offsets are from the start of the text segment. A line record covers
+// everything up to the next line record.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugRecord {
+    Line { offset: u64, filename: String, linenumber: usize },
+    Label { offset: u64, name: String },
 }
 
-pub struct TypedArg {
-    pub typ: ValueType,
-    pub raw_value: u64,
+pub fn write_debug_info(records: &[DebugRecord], out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "; MinimISA debug info")?;
+    for record in records {
+        match record {
+            DebugRecord::Line { offset, filename, linenumber } =>
+                writeln!(out, "line {} {} {}", offset, filename, linenumber)?,
+            DebugRecord::Label { offset, name } =>
+                writeln!(out, "label {} {}", offset, name)?,
+        }
+    }
+    Ok(())
+}
+
+// Object files
+//
+// The binary back ends write the object format of minimisa_core::object:
+// the bits of every segment, the labels as symbols, and the opcode table
+// when it is not the default one, so that emu and disasm decode a program
+// assembled with --huffman without being given opcode.txt. Leaving the
+// table out (--no-embed-opcodes) makes the object smaller, and unreadable
+// without the table.
+
+/// Opcode table of the (mnemonic -> code) map of the back ends
+pub fn opcodes_of(huffman_tree: &HashMap<String, String>) -> Opcodes {
+    let mut codes = [(0, 0); INSTRUCTION_COUNT];
+    for (mnemonic, code) in huffman_tree {
+        if let (Some(op), Ok(value)) = (lookup(mnemonic), u64::from_str_radix(code, 2)) {
+            codes[op as usize] = (value, code.len() as u32);
+        }
+    }
+    codes
+}
+
+/// Opcode table to store in an object, None for the default one
+pub fn object_opcodes(huffman_tree: &HashMap<String, String>) -> Option<Opcodes> {
+    let codes = opcodes_of(huffman_tree);
+    (codes != default_opcodes()).then_some(codes)
 }
 
 // Base BackEnd Implementation
+//
+// Lines are taken one at a time from the iterator they come from, and the
+// packets of every line are written before the next one is read, so the
+// program is never held whole. The binary and label back ends are the
+// exceptions: objects start with the length of their segments, and labels
+// need the whole program to resolve jump widths.
 pub struct BaseBackEnd {
-    line_gene: Vec<Line>,
+    line_gene: Box<dyn Iterator<Item = Line>>,
     out_queue: Queue<String>,
     huffman_tree: HashMap<String, String>,
     write_mode: String,
 }
 
 impl BaseBackEnd {
-    pub fn new(huffman_tree: HashMap<String, String>, line_gene: Vec<Line>) -> Self {
+    pub fn new(huffman_tree: HashMap<String, String>, line_gene: impl IntoIterator<Item = Line> + 'static) -> Self {
         BaseBackEnd {
-            line_gene,
+            line_gene: Box::new(line_gene.into_iter()),
             out_queue: Queue::new(),
             huffman_tree,
             write_mode: "w+".to_string(),
         }
     }
 
-    fn packets(&mut self) -> impl Iterator<Item = String> + '_ {
-        self.line_gene.iter().map(move |line| {
-            self.handle_line(line).ok();
-            while !self.out_queue.is_empty() {
-                if let Some(packet) = self.out_queue.pop() {
-                    return packet;
-                }
+    // Write the packets of the lines handled so far
+    fn write_packets(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        while let Some(packet) = self.out_queue.pop() {
+            if !self.write_mode.contains("b") {
+                writeln!(out, "{}", packet)?;
+            } else {
+                out.write_all(packet.as_bytes())?;
             }
-            String::new()
-        })
+        }
+        Ok(())
     }
 }
 
+// Encoding errors as I/O errors, so that they stop write_to()
+fn invalid_data(e: BackEndError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
 // Implementation for MemonicBackEnd
 pub struct MemonicBackEnd {
     base: BaseBackEnd,
 }
 
 impl MemonicBackEnd {
-    pub fn new(huffman_tree: HashMap<String, String>, line_gene: Vec<Line>) -> Self {
+    pub fn new(huffman_tree: HashMap<String, String>, line_gene: impl IntoIterator<Item = Line> + 'static) -> Self {
         MemonicBackEnd {
             base: BaseBackEnd::new(huffman_tree, line_gene),
         }
@@ -115,30 +166,20 @@ impl MemonicBackEnd {
 }
 
 impl BackEnd for MemonicBackEnd {
-    fn to_file(&mut self, filename: &str) -> io::Result<()> {
-        let mut file = File::create(filename)?;
-        for packet in self.base.packets() {
-            if !self.base.write_mode.contains("b") {
-                writeln!(file, "{}", packet)?;
-            } else {
-                file.write_all(packet.as_bytes())?;
-            }
+    fn write_to(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        while let Some(line) = self.base.line_gene.next() {
+            self.handle_line(&line).map_err(invalid_data)?;
+            self.base.write_packets(out)?;
         }
         Ok(())
     }
 
-    fn to_output(&mut self) {
-        for packet in self.base.packets() {
-            println!("{}", packet);
-        }
-    }
-
     fn handle_line(&mut self, line: &Line) -> Result<(), BackEndError> {
         let funcname = &line.funcname;
         let typed_args = &line.typed_args;
 
         let funcname = match funcname.as_str() {
-            "jumpl" | "calll" | "jumpifl" => funcname.trim_end_matches('l').to_string(),
+            "jumpl" | "calll" | "jumpifl" | "letil" => funcname.trim_end_matches('l').to_string(),
             _ => funcname.clone(),
         };
 
@@ -147,11 +188,30 @@ impl BackEnd for MemonicBackEnd {
             return Ok(());
         }
 
+        // Data, as emitted by the data directives
+        if funcname == "const" {
+            let width = typed_args[0].raw_value as usize;
+            self.base.out_queue.push(format!("    .const  {} #{:0width$b}", width, typed_args[1].raw_value, width = width));
+            return Ok(());
+        }
+        if funcname == "org" {
+            self.base.out_queue.push(format!("    .data   {:#x}", typed_args[0].raw_value));
+            return Ok(());
+        }
+        if funcname == "align" {
+            self.base.out_queue.push(format!("    .align  {}", typed_args[0].raw_value));
+            return Ok(());
+        }
+        if funcname == "constl" {
+            self.base.out_queue.push(format!("    .const  {} {}", typed_args[0].raw_value, typed_args[1].raw_value));
+            return Ok(());
+        }
+
         let formatted_func = format!("{:<7}", funcname);
         let realize_line: Vec<String> = typed_args
             .iter()
             .map(|arg| {
-                if arg.typ == ValueType::Register {
+                if arg.typ == ValueType::REGISTER {
                     format!("r{}", arg.raw_value)
                 } else {
                     arg.raw_value.to_string()
@@ -171,112 +231,153 @@ impl BackEnd for MemonicBackEnd {
 // CleartextBitcodeBackEnd implementation (simplified)
 pub struct CleartextBitcodeBackEnd {
     base: BaseBackEnd,
-    ctr: HashMap<String, String>,
-    direction: HashMap<String, String>,
-    conditions: HashMap<String, String>,
+    // Bits encoded so far, which .align pads from
+    offset: u64,
 }
 
 impl CleartextBitcodeBackEnd {
-    pub fn new(huffman_tree: HashMap<String, String>, line_gene: Vec<Line>) -> Self {
-        let ctr = vec![("pc", "00"), ("sp", "01"), ("a0", "10"), ("a1", "11")]
-            .into_iter()
-            .map(|(k, v)| (k.to_string(), v.to_string()))
-            .collect();
-        
-        let direction = vec![("left", "0"), ("right", "1")]
-            .into_iter()
-            .map(|(k, v)| (k.to_string(), v.to_string()))
-            .collect();
-        
-        let conditions = vec![
-            ("eq", "000"), ("neq", "001"), ("sgt", "010"), ("slt", "011"),
-            ("gt", "100"), ("ge", "101"), ("lt", "110"), ("v", "111"),
-        ]
-        .into_iter()
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect();
-
+    pub fn new(huffman_tree: HashMap<String, String>, line_gene: impl IntoIterator<Item = Line> + 'static) -> Self {
         CleartextBitcodeBackEnd {
             base: BaseBackEnd::new(huffman_tree, line_gene),
-            ctr,
-            direction,
-            conditions,
+            offset: 0,
         }
     }
 
-    fn binary_repr(&self, n: i64, k: usize, signed: bool) -> Result<String, BackEndError> {
-        if signed && !(n >= -(2i64.pow((k - 1) as u32)) && n < 2i64.pow((k - 1) as u32)) {
-            return Err(BackEndError("Number not in range".to_string()));
+    pub fn huffman_tree(&self) -> &HashMap<String, String> {
+        &self.base.huffman_tree
+    }
+
+    /// The lines left to encode, for back ends that handle them otherwise
+    pub fn lines(&mut self) -> &mut dyn Iterator<Item = Line> {
+        &mut self.base.line_gene
+    }
+
+    /// Code of an instruction in the opcode table
+    pub fn opcode(&self, mnemonic: &str) -> Option<BitVec> {
+        self.base.huffman_tree.get(mnemonic).and_then(|code| code.parse().ok())
+    }
+
+    /// Bits of a single line
+    pub fn encode_line(&mut self, line: &Line) -> Result<BitVec, BackEndError> {
+        let mut bits = BitVec::new();
+        for field in self.fields(line)? {
+            bits.extend(&field);
         }
+        self.offset += bits.len();
+        Ok(bits)
+    }
 
-        let mut n = if signed { (2i64.pow(k as u32) + n) % 2i64.pow(k as u32) } else { n };
+    // Fields of a line: its opcode then its operands, or the bits of data
+    fn fields(&self, line: &Line) -> Result<Vec<BitVec>, BackEndError> {
+        let funcname = &line.funcname;
+        let typed_args = &line.typed_args;
 
-        let mut binary = format!("{:b}", n);
-        if binary.len() > k {
-            return Err(BackEndError("Too long binary".to_string()));
+        match funcname.as_str() {
+            "const" => {
+                let bits = self.bin_binary(typed_args[0].raw_value, typed_args[1].raw_value)
+                    .map_err(|e| e.at_line(line))?;
+                return Ok(vec![bits]);
+            }
+            "align" => return Ok(vec![BitVec::zeros(align_padding(self.offset, typed_args[0].raw_value))]),
+            // Padding up to an address needs to know where the code ends
+            "org" => return Err(BackEndError::Unsupported {
+                at: Location::of_line(line),
+                token: funcname.clone(),
+                msg: "data at a fixed address needs a label-resolving back end".to_string(),
+            }),
+            "letil" | "constl" => return Err(BackEndError::Unsupported {
+                at: Location::of_line(line),
+                token: funcname.clone(),
+                msg: "the address of a label needs a label-resolving back end".to_string(),
+            }),
+            _ => {}
         }
 
-        while binary.len() < k {
-            binary.insert(0, '0');
+        let mut fields = vec![self.opcode(funcname)
+            .ok_or_else(|| BackEndError::UnknownOperation { at: Location::of_line(line), token: funcname.clone() })?];
+        for arg in typed_args {
+            fields.push(self.bin_value(arg).map_err(|e| e.at_line(line))?);
         }
-        Ok(binary)
+        Ok(fields)
     }
 
-    fn bin_register(&self, val: u64) -> Result<String, BackEndError> {
-        self.binary_repr(val as i64, NB_BIT_REG, false)
+    fn bin_fields(&self, fields: &[(u64, u32)]) -> BitVec {
+        let mut bits = BitVec::new();
+        for &(value, width) in fields {
+            bits.push(value, width);
+        }
+        bits
     }
 
-    fn bin_uconstant(&self, val: u64) -> Result<String, BackEndError> {
-        match val {
-            0..=1 => Ok("0".to_string() + &self.binary_repr(val as i64, 1, false)?),
-            2..=255 => Ok("10".to_string() + &self.binary_repr(val as i64, 8, false)?),
-            256..=4294967295 => Ok("110".to_string() + &self.binary_repr(val as i64, 32, false)?),
-            _ => Err(BackEndError("Invalid constant: Not in range".to_string())),
+    fn binary_repr(&self, n: i64, k: usize, signed: bool) -> Result<BitVec, BackEndError> {
+        let fits = if signed {
+            k >= 64 || (n >= -(1 << (k - 1)) && n < 1 << (k - 1))
+        } else {
+            n >= 0 && (k >= 64 || n < 1 << k)
+        };
+        if !fits {
+            let kind = if signed { "signed bits" } else { "bits" };
+            return Err(BackEndError::out_of_range(n, &format!("number does not fit in {} {}", k, kind)));
         }
+        Ok(self.bin_fields(&[(n as u64, k as u32)]))
     }
 
-    // Helper methods like `bin_sconstant`, `bin_direction`, etc.
-}
-
-impl BackEnd for CleartextBitcodeBackEnd {
-    fn to_file(&mut self, filename: &str) -> io::Result<()> {
-        let mut file = File::create(filename)?;
-        for packet in self.base.packets() {
-            if !self.base.write_mode.contains("b") {
-                writeln!(file, "{}", packet)?;
-            } else {
-                file.write_all(packet.as_bytes())?;
-            }
+    // Bits of an operand, after the type it was parsed as
+    fn bin_value(&self, arg: &Value) -> Result<BitVec, BackEndError> {
+        let val = arg.raw_value;
+        match arg.typ {
+            ValueType::REGISTER => self.bin_register(val),
+            ValueType::DIRECTION => self.binary_repr(val as i64, 1, false),
+            ValueType::CONDITION => self.binary_repr(val as i64, 3, false),
+            ValueType::MEMCOUNTER => self.binary_repr(val as i64, 2, false),
+            ValueType::UCONSTANT => self.bin_uconstant(val),
+            ValueType::SCONSTANT => self.bin_sconstant(val),
+            ValueType::RADDRESS | ValueType::AADDRESS => Ok(self.bin_fields(&encode_address(val as i64))),
+            ValueType::SHIFTVAL => encode_shift(val).map(|fields| self.bin_fields(&fields))
+                .ok_or_else(|| BackEndError::out_of_range(val, "shift is not in 0..63")),
+            ValueType::SIZE => size(val).map(|code| self.bin_fields(&[code]))
+                .ok_or_else(|| BackEndError::out_of_range(val, "size is not 1, 4, 8, 16, 32 or 64")),
+            ValueType::LABEL | ValueType::BINARY =>
+                Err(BackEndError::out_of_range(val, &format!("{} is not an instruction operand", arg.typ))),
         }
-        Ok(())
     }
 
-    fn to_output(&mut self) {
-        for packet in self.base.packets() {
-            println!("{}", packet);
-        }
+    pub(crate) fn bin_register(&self, val: u64) -> Result<BitVec, BackEndError> {
+        self.binary_repr(val as i64, NB_BIT_REG, false)
     }
 
-    fn handle_line(&mut self, line: &Line) -> Result<(), BackEndError> {
-        let funcname = &line.funcname;
-        let typed_args = &line.typed_args;
+    fn bin_uconstant(&self, val: u64) -> Result<BitVec, BackEndError> {
+        Ok(self.bin_fields(&encode_const(val, false)))
+    }
 
-        let realize_line = vec![self
-            .base
-            .huffman_tree
-            .get(funcname)
-            .ok_or_else(|| BackEndError("Function not found".to_string()))?
-            .clone()];
+    // Signed constants are held in two's complement
+    fn bin_sconstant(&self, val: u64) -> Result<BitVec, BackEndError> {
+        Ok(self.bin_fields(&encode_const(val, true)))
+    }
 
-        for arg in typed_args {
-            let method_name = match arg.typ {
-                ValueType::Register => self.bin_register(arg.raw_value)?,
-                _ => arg.raw_value.to_string(),
-            };
-            realize_line.push(method_name);
+    // Raw bits of a .const line, the value padded to the given width
+    fn bin_binary(&self, width: u64, val: u64) -> Result<BitVec, BackEndError> {
+        if width < 64 && val >> width != 0 {
+            return Err(BackEndError::out_of_range(val, &format!("constant does not fit in {} bits", width)));
         }
+        Ok(self.bin_fields(&[(val, width as u32)]))
+    }
+}
 
-        self.base.out_queue.push(realize_line.join(" "));
+impl BackEnd for CleartextBitcodeBackEnd {
+    fn write_to(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        while let Some(line) = self.base.line_gene.next() {
+            self.handle_line(&line).map_err(invalid_data)?;
+            self.base.write_packets(out)?;
+        }
+        Ok(())
+    }
+
+    fn handle_line(&mut self, line: &Line) -> Result<(), BackEndError> {
+        let fields = self.fields(line)?;
+        self.offset += fields.iter().map(|bits| bits.len()).sum::<u64>();
+        let fields: Vec<String> = fields.iter().map(|bits| bits.to_string()).collect();
+        self.base.out_queue.push(fields.join(" "));
         Ok(())
     }
 
@@ -288,65 +389,101 @@ impl BackEnd for CleartextBitcodeBackEnd {
 // BinaryBitcodeBackEnd (inherits from CleartextBitcodeBackEnd)
 pub struct BinaryBitcodeBackEnd {
     base: CleartextBitcodeBackEnd,
-    binary: String,
+    // Bits of the last incomplete byte
+    binary: BitVec,
+    pub embed_opcodes: bool,    // Store a non-default opcode table in the object
 }
 
 impl BinaryBitcodeBackEnd {
-    pub fn new(huffman_tree: HashMap<String, String>, line_gene: Vec<Line>) -> Self {
+    pub fn new(huffman_tree: HashMap<String, String>, line_gene: impl IntoIterator<Item = Line> + 'static) -> Self {
         BinaryBitcodeBackEnd {
             base: CleartextBitcodeBackEnd::new(huffman_tree, line_gene),
-            binary: String::new(),
+            binary: BitVec::new(),
+            embed_opcodes: true,
         }
     }
 }
 
 impl BackEnd for BinaryBitcodeBackEnd {
-    fn to_file(&mut self, filename: &str) -> io::Result<()> {
-        let mut file = File::create(filename)?;
-        for packet in self.base.base.packets() {
-            file.write_all(packet.as_bytes())?;
+    // An object with the whole program as its text segment. Labels are not
+    // resolved here, see LabelsBinaryBackEnd
+    fn write_to(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let mut bits = BitVec::new();
+        while let Some(line) = self.base.lines().next() {
+            bits.extend(&self.base.encode_line(&line).map_err(invalid_data)?);
         }
+        let mut object = Object::from_text_bits(&bits);
+        object.opcodes = object_opcodes(self.base.huffman_tree()).filter(|_| self.embed_opcodes);
+        out.write_all(&object.to_bytes())
+    }
+
+    // The whole bytes of the program so far, in hexadecimal
+    fn handle_line(&mut self, line: &Line) -> Result<(), BackEndError> {
+        self.binary.extend(&self.base.encode_line(line)?);
+        let bytes = self.binary.take_bytes();
+        self.base.base.out_queue.push(bytes.iter().map(|byte| format!("{:02x}", byte)).collect());
         Ok(())
     }
 
-    fn to_output(&mut self) {
-        for packet in self.base.base.packets() {
-            println!("{}", packet);
+    fn post_packets(&mut self) -> Option<Vec<u8>> {
+        if self.binary.is_empty() {
+            return None;
         }
+        let last = self.binary.as_bytes().to_vec();
+        self.binary = BitVec::new();
+        Some(last)
     }
+}
 
-    fn handle_line(&mut self, line: &Line) -> Result<(), BackEndError> {
-        let funcname = &line.funcname;
-        let typed_args = &line.typed_args;
-
-        self.base.handle_line(line)?;
-
-        while !self.base.base.out_queue.is_empty() {
-            self.binary.push_str(&self.base.base.out_queue.pop().unwrap().replace(" ", ""));
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minimisa_core::INSTRUCTIONS;
 
-        let q = self.binary.len() / 8;
-        let bitline = usize::from_str_radix(&self.binary[..q * 8], 2)
-            .unwrap()
-            .to_be_bytes();
+    // Default opcode table, mnemonic -> code
+    fn codes() -> HashMap<String, String> {
+        INSTRUCTIONS.iter().map(|ins| (ins.mnemonic.to_string(), ins.code.to_string())).collect()
+    }
 
-        self.binary = self.binary[q * 8..].to_string();
+    // "add2 r1 r2" then "jump -13": 10 and 13 bits
+    fn program() -> Vec<Line> {
+        let line = |name: &str, args| Line::new(name.to_string(), args, 1, "test.s".to_string());
+        vec![
+            line("add2", vec![Value::new(ValueType::REGISTER, 1), Value::new(ValueType::REGISTER, 2)]),
+            line("jump", vec![Value::new(ValueType::RADDRESS, -13i64 as u64)]),
+        ]
+    }
 
-        self.base.base.out_queue.push(hex::encode(bitline));
+    #[test]
+    fn test_cleartext_write_to() {
+        let mut out = Vec::new();
+        CleartextBitcodeBackEnd::new(codes(), program()).write_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "0000 001 010\n1010 011110011\n");
+    }
 
-        Ok(())
+    #[test]
+    fn test_binary_write_to() {
+        // The object alone: its segment holds the last incomplete byte, and
+        // nothing follows it
+        let mut out = Vec::new();
+        BinaryBitcodeBackEnd::new(codes(), program()).write_to(&mut out).unwrap();
+        let bits: BitVec = "00000010101010011110011".parse().unwrap();
+        assert_eq!(out, Object::from_text_bits(&bits).to_bytes());
+        let object = Object::from_bytes(&out).unwrap();
+        assert_eq!((object.text().unwrap().length, object.opcodes), (23, None));
     }
 
-    fn post_packets(&mut self) -> Option<Vec<u8>> {
-        if !self.binary.is_empty() {
-            self.binary.push_str(&"0".repeat(8 - self.binary.len()));
-            let bitline = usize::from_str_radix(&self.binary, 2)
-                .unwrap()
-                .to_be_bytes();
-            self.binary.clear();
-            Some(bitline.to_vec())
-        } else {
-            None
+    #[test]
+    fn test_binary_post_packets() {
+        // Line by line, only whole bytes are queued; post_packets() gives
+        // the last one, padded with zeros, once
+        let mut back_end = BinaryBitcodeBackEnd::new(codes(), Vec::new());
+        for line in program() {
+            back_end.handle_line(&line).unwrap();
         }
+        let queue = &mut back_end.base.base.out_queue;
+        assert_eq!((queue.pop(), queue.pop(), queue.pop()), (Some("02".to_string()), Some("a9".to_string()), None));
+        assert_eq!(back_end.post_packets(), Some(vec![0xe6]));
+        assert_eq!(back_end.post_packets(), None);
     }
 }
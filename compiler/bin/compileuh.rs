@@ -0,0 +1,7 @@
+//---
+// compileuh - the MinimISA compiler, see the command line in compileuh.rs
+//---
+
+fn main() {
+    minimisa_compiler::compileuh::main()
+}
@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read};
+use crate::compileuh::DEFAULT_OPCODE;
+use crate::lint::{LintError, Linter};
+use crate::coder::huffman;
+
+// Code density analysis of an emitted bitstream
+//
+// The stream is decoded with the linter, which gives the instruction
+// boundaries and the opcode of every instruction. From there we report:
+//  - the order-0 entropy of the bits and of the bytes of the stream,
+//    and the runs of identical bits,
+//  - where instructions start relative to byte boundaries,
+//  - the average opcode length with the table in use, compared with the
+//    entropy of the opcode distribution and with the Huffman table built
+//    for this very program, which is the best a prefix code can do.
+
+#[derive(Debug, Default)]
+pub struct BitStats {
+    pub bits: usize,
+    pub ones: usize,
+    pub byte_counts: Vec<usize>,              // Occurrences of each byte value
+    pub runs: BTreeMap<usize, usize>,         // Run length -> number of runs
+    pub alignment: [usize; 8],                // Instruction start mod 8
+    pub opcodes: BTreeMap<String, usize>,     // Mnemonic -> occurrences
+    pub opcode_bits: usize,                   // Total bits spent on opcodes
+}
+
+// Shannon entropy in bits per symbol of a distribution given as counts
+fn entropy(counts: impl Iterator<Item = usize> + Clone) -> f64 {
+    let total: usize = counts.clone().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    counts.filter(|&c| c > 0).map(|c| {
+        let p = c as f64 / total as f64;
+        -p * p.log2()
+    }).sum()
+}
+
+impl BitStats {
+    /// Analyze an ASCII bitstring, decoding it with the given opcode table
+    /// (mnemonic -> code)
+    pub fn new(text: &str, table: &HashMap<String, String>) -> Result<BitStats, LintError> {
+        let stream: Vec<u8> = text.chars().filter_map(|c| match c {
+            '0' => Some(0),
+            '1' => Some(1),
+            _ => None,
+        }).collect();
+
+        let mut stats = BitStats {
+            bits: stream.len(),
+            ones: stream.iter().filter(|&&b| b == 1).count(),
+            byte_counts: vec![0; 256],
+            ..BitStats::default()
+        };
+
+        for byte in stream.chunks(8).filter(|c| c.len() == 8) {
+            let value = byte.iter().fold(0, |acc, &b| (acc << 1) | b as usize);
+            stats.byte_counts[value] += 1;
+        }
+
+        let mut run = 0;
+        for (i, &b) in stream.iter().enumerate() {
+            run += 1;
+            if stream.get(i + 1) != Some(&b) {
+                *stats.runs.entry(run).or_insert(0) += 1;
+                run = 0;
+            }
+        }
+
+        for (start, ins) in Linter::new(text, table).run()? {
+            stats.alignment[start % 8] += 1;
+            let mnemonic = ins.split_whitespace().next().unwrap_or("").to_string();
+            stats.opcode_bits += table.get(&mnemonic).map_or(0, |code| code.len());
+            *stats.opcodes.entry(mnemonic).or_insert(0) += 1;
+        }
+        Ok(stats)
+    }
+
+    pub fn instructions(&self) -> usize {
+        self.opcodes.values().sum()
+    }
+
+    /// Entropy of the stream seen as independent bits, in bits per bit
+    pub fn bit_entropy(&self) -> f64 {
+        entropy([self.ones, self.bits - self.ones].into_iter())
+    }
+
+    /// Entropy of the stream seen as independent bytes, in bits per byte
+    pub fn byte_entropy(&self) -> f64 {
+        entropy(self.byte_counts.iter().copied())
+    }
+
+    /// Entropy of the opcode distribution, in bits per instruction: no
+    /// opcode encoding can do better on average
+    pub fn opcode_entropy(&self) -> f64 {
+        entropy(self.opcodes.values().copied())
+    }
+
+    /// Total opcode bits with a Huffman table built for this program
+    pub fn huffman_opcode_bits(&self) -> usize {
+        let counts: HashMap<String, usize> = self.opcodes.clone().into_iter().collect();
+        huffman(&counts).iter().map(|(code, mnemonic)| code.len() * self.opcodes[mnemonic]).sum()
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let n = self.instructions().max(1) as f64;
+
+        let _ = writeln!(out, "{} bits, {} instructions, {:.2} bits per instruction",
+            self.bits, self.instructions(), self.bits as f64 / n);
+
+        let _ = writeln!(out, "entropy: {:.4} bits/bit, {:.4} bits/byte ({} ones)",
+            self.bit_entropy(), self.byte_entropy(), self.ones);
+        let longest = self.runs.keys().next_back().copied().unwrap_or(0);
+        let runs: usize = self.runs.values().sum();
+        let _ = writeln!(out, "runs: {} runs, mean length {:.2}, longest {}",
+            runs, self.bits as f64 / runs.max(1) as f64, longest);
+
+        let _ = write!(out, "instruction start mod 8:");
+        for (offset, count) in self.alignment.iter().enumerate() {
+            let _ = write!(out, " {}:{}", offset, count);
+        }
+        let _ = writeln!(out, " ({:.1}% byte-aligned)", 100.0 * self.alignment[0] as f64 / n);
+
+        let huffman_bits = self.huffman_opcode_bits();
+        let _ = writeln!(out, "opcodes: {:.3} bits/instruction with this table, {:.3} with a \
+            Huffman table for this program, {:.3} entropy bound",
+            self.opcode_bits as f64 / n, huffman_bits as f64 / n, self.opcode_entropy());
+        let _ = writeln!(out, "a program-specific table would save {} of {} opcode bits ({:.1}% of the stream)",
+            self.opcode_bits.saturating_sub(huffman_bits), self.opcode_bits,
+            100.0 * self.opcode_bits.saturating_sub(huffman_bits) as f64 / self.bits.max(1) as f64);
+        out
+    }
+}
+
+/// Analyze an ASCII bitstring file, decoded with the default opcode table,
+/// and print the report. Returns whether the stream decoded
+pub fn bitstats_file(filename: &str) -> io::Result<bool> {
+    let mut text = String::new();
+    File::open(filename)?.read_to_string(&mut text)?;
+
+    let table = DEFAULT_OPCODE.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    match BitStats::new(&text, &table) {
+        Ok(stats) => {
+            print!("{}", stats.report());
+            Ok(true)
+        }
+        Err(e) => {
+            eprintln!("{}: {}", filename, e);
+            Ok(false)
+        }
+    }
+}
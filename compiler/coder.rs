@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::cmp::Reverse;
+use std::fmt::Write as _;
+use minimisa_core::{lookup, INSTRUCTIONS};
+
+// Opcode coders (--huffman, --coder, --coder-report)
+//
+// Prefix codes for the opcodes of a program, built from the number of
+// times each operation occurs in it:
+//  - huffman: the optimal code, as the tree is built. Rare operations can
+//    end up very deep, one bit deeper for every halving of their count,
+//  - canonical: the lengths of the Huffman code, with codes given in order
+//    of length then name, so that the lengths alone describe the table,
+//  - limited:<n>: the best code whose codes are at most n bits long, from
+//    package-merge, also canonical. The expected size grows a little, the
+//    deepest codes get much shorter.
+// The report gives the size of the opcodes of the program with each one,
+// next to the default table and the entropy bound.
+
+// Longest code of the limited coder when no length is given
+const DEFAULT_LIMIT: u32 = 8;
+
+// Limits compared by the report
+const REPORT_LIMITS: [u32; 3] = [6, 7, 8];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Huffman,
+    Canonical,
+    Limited(u32),   // Longest code, in bits
+}
+
+impl Strategy {
+    /// Strategy of a name: huffman, canonical, limited or limited:<bits>
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "huffman" => Some(Strategy::Huffman),
+            "canonical" => Some(Strategy::Canonical),
+            "limited" => Some(Strategy::Limited(DEFAULT_LIMIT)),
+            _ => name.strip_prefix("limited:")?.parse().ok()
+                .filter(|bits| (1..=64).contains(bits)).map(Strategy::Limited),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Strategy::Huffman => "huffman".to_string(),
+            Strategy::Canonical => "canonical".to_string(),
+            Strategy::Limited(bits) => format!("limited:{}", bits),
+        }
+    }
+}
+
+// (code, name) pairs of an opcode table
+type Codes = Vec<(String, String)>;
+
+// Huffman tree generation
+pub fn huffman(ctr: &HashMap<String, usize>) -> Codes {
+    let mut forest: BinaryHeap<Reverse<(usize, Codes)>> = BinaryHeap::new();
+
+    for (key, &freq) in ctr {
+        forest.push(Reverse((freq, vec![("".to_string(), key.clone())])));
+    }
+
+    if forest.is_empty() {
+        return vec![];
+    }
+
+    if forest.len() == 1 {
+        let Reverse((_, mut single_tree)) = forest.pop().unwrap();
+        single_tree[0].0 = "0".to_string();
+        return single_tree;
+    }
+
+    while forest.len() > 1 {
+        let Reverse((freq_x, left_tree)) = forest.pop().unwrap();
+        let Reverse((freq_y, right_tree)) = forest.pop().unwrap();
+
+        let new_freq = freq_x + freq_y;
+        let new_tree: Vec<_> = left_tree.into_iter().map(|(pos, key)| ("0".to_string() + &pos, key))
+            .chain(right_tree.into_iter().map(|(pos, key)| ("1".to_string() + &pos, key)))
+            .collect();
+
+        forest.push(Reverse((new_freq, new_tree)));
+    }
+
+    let Reverse((_, mut tree)) = forest.pop().unwrap();
+    tree.sort_by_key(|(pos, _)| pos.len());
+    tree
+}
+
+/// Codes given in order of length, then of name, from code lengths that
+/// satisfy the Kraft inequality. Returns (code, name) pairs like huffman()
+pub fn canonical(lengths: &BTreeMap<String, u32>) -> Codes {
+    let mut order: Vec<(&String, u32)> = lengths.iter().map(|(name, &length)| (name, length)).collect();
+    order.sort_by_key(|&(name, length)| (length, name));
+
+    let mut codes = Vec::new();
+    let (mut code, mut previous) = (0u64, order.first().map_or(0, |&(_, length)| length));
+    for (name, length) in order {
+        code <<= length - previous;
+        codes.push((format!("{:0width$b}", code, width = length as usize), name.clone()));
+        code += 1;
+        previous = length;
+    }
+    codes
+}
+
+/// Lengths of the best prefix code whose codes are at most `limit` bits
+/// long (package-merge). Every operation gets a code, even those that do
+/// not occur
+pub fn limited_lengths(counts: &HashMap<String, usize>, limit: u32) -> Result<BTreeMap<String, u32>, String> {
+    let n = counts.len();
+    if limit < 64 && 1u64 << limit < n as u64 {
+        return Err(format!("{} operations do not fit in codes of {} bits", n, limit));
+    }
+    let mut leaves: Vec<(usize, &String)> = counts.iter().map(|(name, &count)| (count, name)).collect();
+    leaves.sort();
+    if n <= 1 {
+        return Ok(leaves.into_iter().map(|(_, name)| (name.clone(), 1)).collect());
+    }
+
+    // Items are (weight, leaves they hold, as indices); packages pair up
+    // the lightest items of the level below
+    let singles: Vec<(usize, Vec<usize>)> = leaves.iter().enumerate().map(|(i, &(count, _))| (count, vec![i])).collect();
+    let mut items = singles.clone();
+    for _ in 1..limit {
+        let packages = items.chunks_exact(2).map(|pair| {
+            (pair[0].0 + pair[1].0, pair[0].1.iter().chain(&pair[1].1).copied().collect())
+        });
+        let mut merged: Vec<(usize, Vec<usize>)> = singles.iter().cloned().chain(packages).collect();
+        merged.sort_by_key(|(weight, _)| *weight);
+        items = merged;
+    }
+
+    let mut lengths = vec![0; n];
+    for (_, held) in &items[..2 * n - 2] {
+        for &i in held {
+            lengths[i] += 1;
+        }
+    }
+    Ok(leaves.iter().zip(lengths).map(|(&(_, name), length)| (name.clone(), length)).collect())
+}
+
+/// Opcode table of a program, as (code, name) pairs like huffman()
+pub fn build(strategy: Strategy, counts: &HashMap<String, usize>) -> Result<Codes, String> {
+    match strategy {
+        Strategy::Huffman => Ok(huffman(counts)),
+        Strategy::Canonical => {
+            let lengths = huffman(counts).into_iter().map(|(code, name)| (name, code.len() as u32)).collect();
+            Ok(canonical(&lengths))
+        }
+        Strategy::Limited(limit) => limited_lengths(counts, limit).map(|lengths| canonical(&lengths)),
+    }
+}
+
+/// Total opcode bits of a program with a table of (code, name) pairs
+pub fn opcode_bits(codes: &[(String, String)], counts: &HashMap<String, usize>) -> usize {
+    codes.iter().map(|(code, name)| code.len() * counts.get(name).copied().unwrap_or(0)).sum()
+}
+
+/// Size of the opcodes of a program with the default table and each
+/// strategy: total bits, bits per instruction and longest code
+pub fn report(counts: &HashMap<String, usize>) -> String {
+    let total: usize = counts.values().sum();
+    let n = total.max(1) as f64;
+    let entropy: f64 = counts.values().filter(|&&c| c > 0)
+        .map(|&c| { let p = c as f64 / n; -p * p.log2() }).sum();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "opcode coding: {} instructions, entropy bound {:.3} bits/instruction", total, entropy);
+    let _ = writeln!(out, "  {:<10} {:>8} {:>9} {:>8}", "strategy", "bits", "bits/ins", "longest");
+
+    let default: Codes = counts.keys()
+        .filter_map(|name| lookup(name).map(|op| (INSTRUCTIONS[op as usize].code.to_string(), name.clone())))
+        .collect();
+    let mut rows = vec![("default".to_string(), Ok(default))];
+    let strategies = [Strategy::Huffman, Strategy::Canonical].into_iter()
+        .chain(REPORT_LIMITS.iter().map(|&limit| Strategy::Limited(limit)));
+    rows.extend(strategies.map(|strategy| (strategy.name(), build(strategy, counts))));
+
+    for (name, codes) in rows {
+        match codes {
+            Ok(codes) => {
+                let bits = opcode_bits(&codes, counts);
+                let longest = codes.iter().map(|(code, _)| code.len()).max().unwrap_or(0);
+                let _ = writeln!(out, "  {:<10} {:>8} {:>9.3} {:>8}", name, bits, bits as f64 / n, longest);
+            }
+            Err(e) => {
+                let _ = writeln!(out, "  {:<10} {}", name, e);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(pairs: &[(&str, usize)]) -> HashMap<String, usize> {
+        pairs.iter().map(|&(name, count)| (name.to_string(), count)).collect()
+    }
+
+    fn lengths(codes: &[(String, String)]) -> BTreeMap<String, usize> {
+        codes.iter().map(|(code, name)| (name.clone(), code.len())).collect()
+    }
+
+    fn prefix_free(codes: &[(String, String)]) -> bool {
+        codes.iter().all(|(a, x)| codes.iter().all(|(b, y)| x == y || !b.starts_with(a.as_str())))
+    }
+
+    #[test]
+    fn test_coders() {
+        // Counts halving from one operation to the next make the deepest
+        // possible Huffman tree
+        let skewed = counts(&[("a", 512), ("b", 256), ("c", 128), ("d", 64), ("e", 32), ("f", 16),
+            ("g", 8), ("h", 4), ("i", 2), ("j", 1), ("k", 1)]);
+        let huffman_codes = huffman(&skewed);
+        assert_eq!(lengths(&huffman_codes)["k"], 10);
+        assert_eq!(opcode_bits(&huffman_codes, &skewed), 2046);
+
+        let canonical_codes = build(Strategy::Canonical, &skewed).unwrap();
+        assert_eq!(lengths(&canonical_codes), lengths(&huffman_codes));
+        assert_eq!(canonical_codes[0], ("0".to_string(), "a".to_string()));
+        assert_eq!(canonical_codes[10], ("1111111111".to_string(), "k".to_string()));
+
+        for limit in [4, 6, 8] {
+            let codes = build(Strategy::Limited(limit), &skewed).unwrap();
+            assert!(prefix_free(&codes), "{:?}", codes);
+            assert_eq!(codes.len(), 11);
+            assert!(codes.iter().all(|(code, _)| code.len() <= limit as usize));
+            assert!(opcode_bits(&codes, &skewed) >= 2046);
+        }
+        // Loose enough a limit gives the Huffman lengths back
+        assert_eq!(opcode_bits(&build(Strategy::Limited(10), &skewed).unwrap(), &skewed), 2046);
+        assert!(build(Strategy::Limited(3), &skewed).is_err());
+        assert_eq!(lengths(&build(Strategy::Limited(4), &counts(&[("a", 0)])).unwrap())["a"], 1);
+
+        assert_eq!(Strategy::from_name("limited"), Some(Strategy::Limited(8)));
+        assert_eq!(Strategy::from_name("limited:6"), Some(Strategy::Limited(6)));
+        assert_eq!(Strategy::from_name("limited:0"), None);
+        assert_eq!(Strategy::from_name("canonical").map(|s| s.name()), Some("canonical".to_string()));
+
+        let report = report(&counts(&[("add2i", 6), ("jump", 1), ("leti", 1)]));
+        assert!(report.starts_with("opcode coding: 8 instructions, entropy bound 1.061 bits/instruction\n"), "{}", report);
+        assert!(report.contains("  default          32     4.000        4\n"), "{}", report);
+        assert!(report.contains("  huffman          10     1.250        2\n"), "{}", report);
+        assert!(report.contains("  limited:6        10     1.250        2\n"), "{}", report);
+    }
+}
@@ -1,21 +1,36 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::fs::File;
-use std::io::{self, Write};
-use regex::Regex;
-use itertools::Itertools;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use crate::enums::{ValueType, LexType};
-use crate::lexer::Lexer;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::rc::Rc;
+use minimisa_core::object::{format_symbols, Object};
+use minimisa_core::{format_opcodes, parse_opcodes, to_bits, INSTRUCTIONS};
+use lazy_static::lazy_static;
+use crate::enums::{Line, ValueType, LexType};
+use crate::errors::{render, BackEndError, Diagnostic};
+use crate::lexer::{Lexer, TokenStream};
+use crate::macros::{MacroExpander, MacroStream};
+use crate::symbols::SymbolTable;
 use crate::parser::Parser;
-use crate::util::huffman;
-use crate::back_end::MemonicBackEnd;
+use crate::coder::{self, Strategy};
+use crate::util::write_atomic;
+use crate::back_end::{opcodes_of, BackEnd, BinaryBitcodeBackEnd, CleartextBitcodeBackEnd, MemonicBackEnd};
+use crate::labels::{LabelsBinaryBackEnd, LabelsClearTextBackEnd};
+use crate::pseudo::{PseudoExpander, PseudoOptions, PseudoScan, BRANCH_ALIASES};
+use crate::sizes::SizeReport;
 
 type VT = ValueType;
 
 // Language specification
 
+// Operations of the language and the instructions they may stand for. The
+// parser picks the instruction from the types of the arguments; writing an
+// instruction name directly (add2i...) selects it among its own forms.
+
 lazy_static! {
-    static ref POSSIBLE_TRANSITION: HashMap<&'static str, Vec<&'static str>> = {
+    pub static ref POSSIBLE_TRANSITION: HashMap<&'static str, Vec<&'static str>> = {
         let mut m = HashMap::new();
         m.insert("add", vec!["add2", "add2i", "add3", "add3i"]);
         m.insert("and", vec!["and2", "and2i", "and3", "and3i"]);
@@ -23,11 +38,12 @@ lazy_static! {
         m.insert("or", vec!["or2", "or2i", "or3", "or3i"]);
         m.insert("xor", vec!["xor3", "xor3i"]);
         m.insert("cmp", vec!["cmp", "cmpi"]);
-        m.insert("let", vec!["let", "leti"]);
+        m.insert("let", vec!["let", "leti", "letil"]);
         m.insert("shift", vec!["shift"]);
         m.insert("readze", vec!["readze"]);
         m.insert("readse", vec!["readse"]);
         m.insert("jump", vec!["jump", "jumpif", "jumpl", "jumpifl"]);
+        m.insert("jumpif", vec!["jumpif", "jumpifl"]);
         m.insert("write", vec!["write"]);
         m.insert("call", vec!["call", "calll"]);
         m.insert("setctr", vec!["setctr"]);
@@ -37,9 +53,20 @@ lazy_static! {
         m.insert("asr", vec!["asr3"]);
         m.insert("pop", vec!["pop"]);
         m.insert("label", vec!["label"]);
-        m.insert("const", vec!["const"]);
+        m.insert("const", vec!["const", "constl"]);
         m.insert("sleep", vec!["sleep"]);
         m.insert("rand", vec!["rand"]);
+        m.insert("enter", vec!["enter"]);
+        m.insert("leave", vec!["leave"]);
+        m.insert("swap", vec!["swap"]);
+        m.insert("rdcycles", vec!["rdcycles"]);
+        m.insert("rdtime", vec!["rdtime"]);
+        for op in ["nop", "mov", "not", "neg", "inc", "dec"] {
+            m.insert(op, vec![op]);
+        }
+        for (alias, _) in BRANCH_ALIASES {
+            m.insert(alias, vec![alias]);
+        }
         m
     };
 }
@@ -58,24 +85,29 @@ lazy_static! {
     };
 }
 
+// Immediates of add, sub, cmp and let are signed, those of the logical
+// operations are unsigned, as in the ISA definition (AConst and LConst).
+
 lazy_static! {
-    static ref ASR_SPECS: HashMap<&'static str, Vec<ValueType>> = {
+    pub static ref ASR_SPECS: HashMap<&'static str, Vec<ValueType>> = {
         let mut m = HashMap::new();
         m.insert("add2", vec![VT::REGISTER, VT::REGISTER]);
-        m.insert("add2i", vec![VT::REGISTER, VT::UCONSTANT]);
+        m.insert("add2i", vec![VT::REGISTER, VT::SCONSTANT]);
         m.insert("add3", vec![VT::REGISTER, VT::REGISTER, VT::REGISTER]);
-        m.insert("add3i", vec![VT::REGISTER, VT::REGISTER, VT::UCONSTANT]);
+        m.insert("add3i", vec![VT::REGISTER, VT::REGISTER, VT::SCONSTANT]);
 
         m.insert("sub2", vec![VT::REGISTER, VT::REGISTER]);
-        m.insert("sub2i", vec![VT::REGISTER, VT::UCONSTANT]);
+        m.insert("sub2i", vec![VT::REGISTER, VT::SCONSTANT]);
         m.insert("sub3", vec![VT::REGISTER, VT::REGISTER, VT::REGISTER]);
-        m.insert("sub3i", vec![VT::REGISTER, VT::REGISTER, VT::UCONSTANT]);
+        m.insert("sub3i", vec![VT::REGISTER, VT::REGISTER, VT::SCONSTANT]);
 
         m.insert("cmp", vec![VT::REGISTER, VT::REGISTER]);
         m.insert("cmpi", vec![VT::REGISTER, VT::SCONSTANT]);
 
         m.insert("let", vec![VT::REGISTER, VT::REGISTER]);
         m.insert("leti", vec![VT::REGISTER, VT::SCONSTANT]);
+        // Address of a label, resolved with the jumps
+        m.insert("letil", vec![VT::REGISTER, VT::LABEL]);
 
         m.insert("shift", vec![VT::DIRECTION, VT::REGISTER, VT::SHIFTVAL]);
 
@@ -114,104 +146,563 @@ lazy_static! {
 
         m.insert("label", vec![VT::LABEL]);
         m.insert("const", vec![VT::UCONSTANT, VT::BINARY]);
+        m.insert("constl", vec![VT::UCONSTANT, VT::LABEL]);
         m.insert("sleep", vec![VT::UCONSTANT]);
         m.insert("rand", vec![VT::REGISTER]);
+
+        // Pseudo-instructions, expanded before reaching the back ends
+        m.insert("enter", vec![VT::UCONSTANT]);
+        m.insert("leave", vec![]);
+        m.insert("swap", vec![VT::REGISTER, VT::REGISTER]);
+        m.insert("rdcycles", vec![VT::REGISTER]);
+        m.insert("rdtime", vec![VT::REGISTER]);
+        m.insert("nop", vec![]);
+        m.insert("mov", vec![VT::REGISTER, VT::REGISTER]);
+        m.insert("not", vec![VT::REGISTER]);
+        m.insert("neg", vec![VT::REGISTER]);
+        m.insert("inc", vec![VT::REGISTER]);
+        m.insert("dec", vec![VT::REGISTER]);
+        for (alias, _) in BRANCH_ALIASES {
+            m.insert(alias, vec![VT::LABEL]);
+        }
         m
     };
 }
 
 lazy_static! {
-    static ref DEFAULT_OPCODE: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("add2", "0000");
-        m.insert("add2i", "0001");
-        m.insert("sub2", "0010");
-        m.insert("sub2i", "0011");
-        m.insert("cmp", "0100");
-        m.insert("cmpi", "0101");
-        m.insert("let", "0110");
-        m.insert("leti", "0111");
-        m.insert("shift", "1000");
-        m.insert("readze", "10010");
-        m.insert("pop", "1001001");
-        m.insert("readse", "10011");
-        m.insert("jump", "1010");
-        m.insert("jumpif", "1011");
-        m.insert("or2", "110000");
-        m.insert("or2i", "110001");
-        m.insert("and2", "110010");
-        m.insert("and2i", "110011");
-        m.insert("write", "110100");
-        m.insert("call", "110101");
-        m.insert("setctr", "110110");
-        m.insert("getctr", "110111");
-        m.insert("push", "1110000");
-        m.insert("return", "1110001");
-        m.insert("add3", "1110010");
-        m.insert("add3i", "1110011");
-        m.insert("sub3", "1110100");
-        m.insert("sub3i", "1110101");
-        m.insert("and3", "1110110");
-        m.insert("and3i", "1110111");
-        m.insert("or3", "1111000");
-        m.insert("or3i", "1111001");
-        m.insert("xor3", "1111010");
-        m.insert("xor3i", "1111011");
-        m.insert("asr3", "1111100");
-        m.insert("sleep", "1111101");
-        m.insert("rand", "1111110");
-        m.insert("reserved3", "1111111");
-        m
-    };
+    // Default encoding of every instruction, from the ISA definition
+    pub static ref DEFAULT_OPCODE: HashMap<&'static str, &'static str> =
+        INSTRUCTIONS.iter().map(|ins| (ins.mnemonic, ins.code)).collect();
 }
 
-fn count_operations(c: &mut HashMap<String, usize>, it: impl Iterator<Item = Line>) {
+fn count_operations<'a>(c: &mut HashMap<String, usize>, it: impl Iterator<Item = &'a Line>) {
     for line in it {
         let entry = c.entry(line.funcname.clone()).or_insert(0);
         *entry += 1;
     }
 }
 
-pub fn compile_asm(s: &str, generate_tree: bool, directory: &str, filename: &str) -> MemonicBackEnd {
-    // Replace transitions in the pre-assembly code
-    let mut s = s.to_string();
-    for (new, olds) in POSSIBLE_TRANSITION.iter() {
-        let sorted_olds: Vec<&str> = olds.iter().sorted_by_key(|s| s.len()).map(|s| *s).collect();
-        let pattern = format!("({})", sorted_olds.join("|"));
-        let re = Regex::new(&pattern).unwrap();
-        s = re.replace_all(&s, *new).into();
+// Every error with its source line
+fn rendered(errors: &[Box<dyn Diagnostic>], filename: &str, source: &str) -> String {
+    errors.iter().map(|e| {
+        let file = &e.location().filename;
+        let text = if file == filename { Some(source.to_string()) } else { fs::read_to_string(file).ok() };
+        render(e.as_ref(), text.as_deref())
+    }).collect()
+}
+
+// Print every error with its source line, and give up if there was any
+fn report(errors: &[Box<dyn Diagnostic>], filename: &str, source: &str) {
+    eprint!("{}", rendered(errors, filename, source));
+    if !errors.is_empty() {
+        eprintln!("{} error(s)", errors.len());
+        exit(1);
     }
+}
 
-    // Tokenize the pre-asm
-    let lexer = Lexer::new(&POSSIBLE_TRANSITION);
-    let gen_lex = lexer.lex(&s, filename, directory);
+/// Opcode table to encode a program with
+#[derive(Debug, Clone)]
+pub enum OpcodeTable {
+    Default,
+    Huffman(Strategy),                  // Built for the program by a coder, saved to opcode.txt
+    Given(HashMap<String, String>),     // Mnemonic -> code
+}
+
+impl OpcodeTable {
+    /// Whether the table is built from the program, which must then be
+    /// compiled whole before it is encoded
+    pub fn is_built(&self) -> bool {
+        matches!(self, OpcodeTable::Huffman(_))
+    }
 
-    // Parse to convert into assembly
-    let parser = Parser::new(&gen_lex, &POSSIBLE_TRANSITION, &ASR_SPECS, &TYPE_SPECS);
-    let mut hufftree: HashMap<String, String>;
+    /// Mnemonic -> code map to encode a program with. A table built by a
+    /// coder is built from the program given, and saved to opcode.txt
+    pub fn codes(&self, program: Option<&Program>) -> HashMap<String, String> {
+        match self {
+            OpcodeTable::Given(table) => table.clone(),
+            OpcodeTable::Huffman(strategy) => {
+                let program = program.expect("a built opcode table needs the program");
+                let codes = coder::build(*strategy, &program.operation_counts()).unwrap_or_else(|e| {
+                    eprintln!("compileuh: {}: {}", strategy.name(), e);
+                    exit(1);
+                });
+                let hufftree = codes.into_iter().map(|(code, mnemonic)| (mnemonic, code)).collect();
+                save_opcode_table(Path::new("opcode.txt"), &hufftree).unwrap();
+                hufftree
+            }
+            OpcodeTable::Default => DEFAULT_OPCODE.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+}
 
-    if generate_tree {
-        // Duplicate the iterator for huffman tree
-        let (par1, par2) = gen_lex.tee();
+/// A program compiled whole, as the lines the back ends take. Operations
+/// are counted on the very lines that are encoded
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub lines: Vec<Line>,
+}
 
+impl Program {
+    /// Number of times each instruction occurs in the program.
+    /// Instructions it does not use are counted as 0
+    pub fn operation_counts(&self) -> HashMap<String, usize> {
         let mut c = HashMap::new();
+        // Only count instructions the language can produce (not reti)
         for key in DEFAULT_OPCODE.keys() {
-            if !key.starts_with("reserved") {
+            if ASR_SPECS.contains_key(key) {
                 c.insert(key.to_string(), 0);
             }
         }
+        count_operations(&mut c, self.lines.iter());
+        c
+    }
+
+    /// Lines of the program, for a back end
+    pub fn lines(&self) -> impl Iterator<Item = Line> + 'static {
+        self.lines.clone().into_iter()
+    }
+}
+
+/// Read an opcode table written by a --huffman compilation (see
+/// minimisa_core::format_opcodes())
+pub fn load_opcode_table(filename: &str) -> io::Result<HashMap<String, String>> {
+    let codes = parse_opcodes(&fs::read_to_string(filename)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+    Ok(INSTRUCTIONS.iter().zip(codes.iter())
+        .filter(|(_, &(_, length))| length > 0)
+        .map(|(ins, &code)| (ins.mnemonic.to_string(), to_bits(&[code])))
+        .collect())
+}
+
+/// Write an opcode table in the format read by load_opcode_table() and by
+/// the disassembler and emulator
+pub fn save_opcode_table(filename: &Path, table: &HashMap<String, String>) -> io::Result<()> {
+    let codes = opcodes_of(table);
+    write_atomic(filename, |file| file.write_all(format_opcodes(&codes).as_bytes()))
+}
+
+pub fn compile_asm(source: &str, generate_tree: bool, directory: &str, filename: &str,
+    pseudo: &PseudoOptions) -> MemonicBackEnd {
+    let table = if generate_tree { OpcodeTable::Huffman(Strategy::Huffman) } else { OpcodeTable::Default };
+    let (hufftree, lines) = compile_lines(source, &table, directory, &[], filename, pseudo);
+    MemonicBackEnd::new(hufftree, lines)
+}
+
+/// Compile a program down to the lines the back ends take, with the opcode
+/// table to encode them with. Errors are all reported before exiting
+pub fn compile_lines(source: &str, table: &OpcodeTable, directory: &str, include_dirs: &[PathBuf],
+    filename: &str, pseudo: &PseudoOptions) -> (HashMap<String, String>, Vec<Line>) {
+    let source = Source::Text { name: filename.to_string(), directory: directory.to_string(), text: source.to_string() };
+    let program = Pipeline::new(source, include_dirs, pseudo).program();
+    let hufftree = table.codes(Some(&program));
+    (hufftree, program.lines)
+}
+
+/// Compile a program to an object, for the tools that embed the compiler
+/// (the tests of emu). Errors are returned as the compiler prints them
+pub fn compile_object(source: &str, table: &OpcodeTable, pseudo: &PseudoOptions) -> Result<Object, String> {
+    let source = Source::Text { name: "<source>".to_string(), directory: ".".to_string(), text: source.to_string() };
+    let pipeline = Pipeline::scan(source, &[], pseudo);
+    let program = Program { lines: pipeline.lines().collect() };
+    pipeline.errors()?;
+
+    let mut labels = LabelsClearTextBackEnd::new(CleartextBitcodeBackEnd::new(table.codes(Some(&program)), program.lines));
+    labels.label_names = pipeline.label_names();
+    labels.object().map_err(|e| rendered(&[Box::new(e)], &pipeline.source.name(), &pipeline.source.text()))
+}
+
+// Streaming compilation
+//
+// The program goes through the lexer, the macros, the constants, the
+// parser and the pseudo-instructions a line at a time, and is read again
+// for every pass that needs all of it before going on:
+//
+//   1. the constant definitions, as constants can be used before them
+//   2. the registers and functions used, for the pseudo-instructions
+//   3. the lines given to the back end
+//
+// An opcode table built for the program (--huffman, --coder) and
+// --coder-report need all of its lines before the first one is encoded:
+// the lines are then compiled once into a Program, which is both counted
+// and given to the back ends, so the counts are those of the output.
+//
+// Errors of a pass go to a shared list rather than stopping it, so that
+// they are all reported. Nothing comes out of a pass after its first error.
+
+/// Where a program is read from. Files are opened again for every pass,
+/// standard input is read once and kept
+pub enum Source {
+    File(PathBuf),
+    Text { name: String, directory: String, text: String },
+}
+
+impl Source {
+    pub fn name(&self) -> String {
+        match self {
+            Source::File(path) => path.display().to_string(),
+            Source::Text { name, .. } => name.clone(),
+        }
+    }
+
+    /// Directory of the included files given by a relative path
+    pub fn directory(&self) -> String {
+        match self {
+            Source::File(path) => path.parent().and_then(|p| p.to_str()).filter(|d| !d.is_empty())
+                .unwrap_or(".").to_string(),
+            Source::Text { directory, .. } => directory.clone(),
+        }
+    }
+
+    fn open(&self) -> io::Result<Box<dyn BufRead>> {
+        Ok(match self {
+            Source::File(path) => Box::new(BufReader::new(File::open(path)?)),
+            Source::Text { text, .. } => Box::new(io::Cursor::new(text.clone().into_bytes())),
+        })
+    }
+
+    // Text of the program, to show the lines of the errors
+    fn text(&self) -> String {
+        match self {
+            Source::File(path) => fs::read_to_string(path).unwrap_or_default(),
+            Source::Text { text, .. } => text.clone(),
+        }
+    }
+}
+
+type Errors = Rc<RefCell<Vec<Box<dyn Diagnostic>>>>;
+
+// Items of a stream, with its errors moved to the list. The stream is read
+// to its end after an error so that later ones are reported too
+fn checked<T, E: Diagnostic + 'static>(items: impl Iterator<Item = Result<T, E>> + 'static, errors: &Errors)
+    -> impl Iterator<Item = T> + 'static {
+    let errors = errors.clone();
+    items.filter_map(move |item| match item {
+        Ok(item) => errors.borrow().is_empty().then_some(item),
+        Err(e) => {
+            errors.borrow_mut().push(Box::new(e));
+            None
+        }
+    })
+}
+
+pub struct Pipeline {
+    source: Source,
+    include_dirs: Vec<PathBuf>,
+    pseudo: PseudoOptions,
+    symbols: SymbolTable,
+    scan: PseudoScan,
+    errors: Errors,
+}
 
-        count_operations(&mut c, par1);
-        hufftree = huffman(&c).into_iter().collect();
+impl Pipeline {
+    /// Run the passes over the whole program, reporting their errors
+    pub fn new(source: Source, include_dirs: &[PathBuf], pseudo: &PseudoOptions) -> Self {
+        let pipeline = Pipeline::scan(source, include_dirs, pseudo);
+        pipeline.check();
+        pipeline
+    }
+
+    // Run the passes over the whole program, keeping their errors
+    fn scan(source: Source, include_dirs: &[PathBuf], pseudo: &PseudoOptions) -> Self {
+        let mut pipeline = Pipeline {
+            source,
+            include_dirs: include_dirs.to_vec(),
+            pseudo: pseudo.clone(),
+            symbols: SymbolTable::new(),
+            scan: PseudoScan::default(),
+            errors: Rc::new(RefCell::new(Vec::new())),
+        };
 
-        let mut file = File::create("opcode.txt").unwrap();
-        for (opcode, memonic) in hufftree.iter() {
-            writeln!(file, "{} {}", memonic, opcode).unwrap();
+        let mut symbols = SymbolTable::new();
+        if let Err(e) = symbols.define_all(checked(pipeline.expanded(), &pipeline.errors)) {
+            pipeline.errors.borrow_mut().push(Box::new(e));
+        }
+        pipeline.symbols = symbols;
+        if pipeline.errors.borrow().is_empty() {
+            for line in pipeline.parsed() {
+                pipeline.scan.add(&line);
+            }
         }
-    } else {
-        hufftree = DEFAULT_OPCODE.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        pipeline
+    }
+
+    // Tokens of a new pass over the program, with the macros expanded
+    fn expanded(&self) -> MacroStream<TokenStream> {
+        let reader = self.source.open().unwrap_or_else(|e| {
+            eprintln!("{}: {}", self.source.name(), e);
+            exit(1);
+        });
+        let mut lexer = Lexer::new(&POSSIBLE_TRANSITION);
+        lexer.include_dirs = self.include_dirs.clone();
+        MacroExpander::new().stream(lexer.stream(reader, &self.source.name(), &self.source.directory()))
+    }
+
+    // Parser of a new pass, before the pseudo-instructions
+    fn parser(&self) -> Parser<'static> {
+        let tokens = checked(self.symbols.clone().substitute(self.expanded()), &self.errors);
+        Parser::new(Box::new(tokens), &POSSIBLE_TRANSITION, &ASR_SPECS, &TYPE_SPECS)
     }
 
-    let out = MemonicBackEnd::new(hufftree, parser.run());
-    out
+    // Lines of a new pass, before the pseudo-instructions
+    fn parsed(&self) -> impl Iterator<Item = Line> + 'static {
+        checked(self.parser(), &self.errors)
+    }
+
+    /// Names of the labels of the program, from a pass over it
+    pub fn label_names(&self) -> HashMap<u64, String> {
+        let mut parser = self.parser();
+        parser.by_ref().for_each(drop);
+        parser.label_names()
+    }
+
+    /// Lines of a new pass, as the back ends take them
+    pub fn lines(&self) -> impl Iterator<Item = Line> + 'static {
+        let mut expander = PseudoExpander::new(&self.scan, &self.pseudo);
+        // None marks the end of the program
+        let mut parsed = self.parsed().map(Some).chain(std::iter::once(None));
+        let expanded = std::iter::from_fn(move || Some(match parsed.next()? {
+            Some(line) => {
+                let mut out = Vec::new();
+                expander.expand_line(line, &mut out).map(|()| out)
+            }
+            None => expander.finish().map(|()| Vec::new()),
+        }));
+        checked(expanded, &self.errors).flatten()
+    }
+
+    /// The program compiled whole, from a pass over it
+    pub fn program(&self) -> Program {
+        let lines = self.lines().collect();
+        self.check();
+        Program { lines }
+    }
+
+    /// Error if a pass found any, to stop an output before it is committed
+    pub fn status(&self) -> io::Result<()> {
+        match self.errors.borrow().len() {
+            0 => Ok(()),
+            n => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} error(s) in the program", n))),
+        }
+    }
+
+    /// Report an error writing an output, at its source line for encoding
+    /// errors, and exit
+    pub fn fail(&self, output: &str, e: io::Error) -> ! {
+        let message = format!("{}: {}", output, e);
+        if e.get_ref().is_some_and(|inner| inner.is::<BackEndError>()) {
+            let e = e.into_inner().unwrap().downcast::<BackEndError>().unwrap();
+            report(&[e as Box<dyn Diagnostic>], &self.source.name(), &self.source.text());
+        }
+        eprintln!("{}", message);
+        exit(1);
+    }
+
+    /// The errors found so far, as they are reported
+    pub fn errors(&self) -> Result<(), String> {
+        let errors = self.errors.borrow();
+        match errors.len() {
+            0 => Ok(()),
+            n => Err(format!("{}{} error(s)", rendered(&errors, &self.source.name(), &self.source.text()), n)),
+        }
+    }
+
+    /// Report the errors found so far, and exit if there was any
+    pub fn check(&self) {
+        let errors = self.errors.borrow();
+        if !errors.is_empty() {
+            report(&errors, &self.source.name(), &self.source.text());
+        }
+    }
+}
+
+// Command line
+//
+//     compileuh [options] [<source file>|-]
+//
+// Reads the program from standard input without a source file, and writes
+// the output to standard output unless -o is given.
+
+// Writer of the output of the chosen back end
+type Output = dyn FnMut(&mut dyn Write) -> io::Result<()>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Mnemonic,
+    Cleartext,
+    Binary,
+    LabelsBinary,
+}
+
+impl Backend {
+    fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "mnemonic" => Some(Backend::Mnemonic),
+            "cleartext" => Some(Backend::Cleartext),
+            "binary" => Some(Backend::Binary),
+            "labels-binary" => Some(Backend::LabelsBinary),
+            _ => None,
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: compileuh [options] [<source file>|-]");
+    eprintln!("  -o <file>               output file (default: standard output)");
+    eprintln!("  -I <dir>                look for included files in dir (repeatable)");
+    eprintln!("  --backend <name>        mnemonic (default), cleartext, binary or labels-binary");
+    eprintln!("  --huffman               build an opcode table for the program, saved to opcode.txt");
+    eprintln!("  --coder <strategy>      build an opcode table with huffman, canonical or limited[:<bits>] codes");
+    eprintln!("  --no-huffman            use the default opcode table (default)");
+    eprintln!("  --opcode-table <file>   use the opcode table of a file (mnemonic code lines)");
+    eprintln!("  --size-report           print the size of the program per mnemonic to stderr");
+    eprintln!("  --coder-report          print the size of the opcodes with each coder to stderr");
+    eprintln!("  --no-embed-opcodes      leave the opcode table out of binary objects");
+    eprintln!("  --symbols <file>        write the address of every label (\"<address> <label>\" lines)");
+    exit(1);
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut output = None;
+    let mut backend = Backend::Mnemonic;
+    let mut table = OpcodeTable::Default;
+    let mut include_dirs = Vec::new();
+    let mut size_report = false;
+    let mut coder_report = false;
+    let mut embed_opcodes = true;
+    let mut symbols = None;
+    let mut input = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-o" => {
+                i += 1;
+                output = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "-I" => {
+                i += 1;
+                include_dirs.push(PathBuf::from(args.get(i).unwrap_or_else(|| usage())));
+            }
+            arg if arg.starts_with("-I") => include_dirs.push(PathBuf::from(&arg[2..])),
+            "--backend" => {
+                i += 1;
+                backend = args.get(i).and_then(|name| Backend::from_name(name)).unwrap_or_else(|| usage());
+            }
+            "--huffman" => table = OpcodeTable::Huffman(Strategy::Huffman),
+            "--coder" => {
+                i += 1;
+                let strategy = args.get(i).and_then(|name| Strategy::from_name(name)).unwrap_or_else(|| usage());
+                table = OpcodeTable::Huffman(strategy);
+            }
+            "--no-huffman" => table = OpcodeTable::Default,
+            "--opcode-table" => {
+                i += 1;
+                let file = args.get(i).unwrap_or_else(|| usage());
+                table = OpcodeTable::Given(load_opcode_table(file).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", file, e);
+                    exit(1);
+                }));
+            }
+            "--size-report" => size_report = true,
+            "--no-embed-opcodes" => embed_opcodes = false,
+            "--coder-report" => coder_report = true,
+            "--symbols" => {
+                i += 1;
+                symbols = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            arg if (arg == "-" || !arg.starts_with('-')) && input.is_none() => input = Some(arg.to_string()),
+            _ => usage(),
+        }
+        i += 1;
+    }
+
+    let source = match input.as_deref() {
+        None | Some("-") => {
+            let mut text = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut text) {
+                eprintln!("compileuh: cannot read standard input: {}", e);
+                exit(1);
+            }
+            Source::Text { name: "<stdin>".to_string(), directory: ".".to_string(), text }
+        }
+        Some(file) => Source::File(PathBuf::from(file)),
+    };
+
+    let pipeline = Pipeline::new(source, &include_dirs, &PseudoOptions::default());
+    let program = (table.is_built() || coder_report).then(|| pipeline.program());
+    if let Some(program) = program.as_ref().filter(|_| coder_report) {
+        eprint!("{}", coder::report(&program.operation_counts()));
+    }
+    let hufftree = table.codes(program.as_ref());
+    // Lines of the program kept whole if it was compiled, of a new pass
+    // otherwise
+    let lines = || -> Box<dyn Iterator<Item = Line>> {
+        match &program {
+            Some(program) => Box::new(program.lines()),
+            None => Box::new(pipeline.lines()),
+        }
+    };
+
+    // Sizes and label addresses are known once jump widths are resolved,
+    // whatever the output
+    if size_report || symbols.is_some() {
+        let mut labels = LabelsClearTextBackEnd::new(CleartextBitcodeBackEnd::new(hufftree.clone(), lines()));
+        labels.label_names = pipeline.label_names();
+        pipeline.check();
+        let resolved = match labels.packets() {
+            Ok(_) => true,
+            Err(e) => {
+                report(&[Box::new(e)], &pipeline.source.name(), &pipeline.source.text());
+                false
+            }
+        };
+        if size_report {
+            eprint!("{}", SizeReport::new(labels.line_sizes(), &hufftree));
+        }
+        if let Some(file) = symbols.as_ref().filter(|_| resolved) {
+            let text = format_symbols(&labels.symbols());
+            if let Err(e) = write_atomic(Path::new(file), |out| out.write_all(text.as_bytes())) {
+                eprintln!("{}: {}", file, e);
+                exit(1);
+            }
+        }
+    }
+
+    // The lines are compiled as the back end writes them: errors of this
+    // last pass are checked before the output file is replaced
+    let lines = lines();
+    let mut out: Box<Output> = match backend {
+        Backend::LabelsBinary => {
+            let mut labels = LabelsBinaryBackEnd::new(LabelsClearTextBackEnd::new(
+                CleartextBitcodeBackEnd::new(hufftree, lines)));
+            labels.embed_opcodes = embed_opcodes;
+            Box::new(move |out| labels.write_to(out))
+        }
+        _ => {
+            let mut back_end: Box<dyn BackEnd> = match backend {
+                Backend::Mnemonic => Box::new(MemonicBackEnd::new(hufftree, lines)),
+                Backend::Cleartext => Box::new(CleartextBitcodeBackEnd::new(hufftree, lines)),
+                _ => {
+                    let mut binary = BinaryBitcodeBackEnd::new(hufftree, lines);
+                    binary.embed_opcodes = embed_opcodes;
+                    Box::new(binary)
+                }
+            };
+            Box::new(move |out| back_end.write_to(out))
+        }
+    };
+    let result = match &output {
+        Some(file) => write_atomic(Path::new(file), |file| {
+            out(file)?;
+            pipeline.status()
+        }),
+        None => out(&mut io::stdout().lock()).and_then(|()| pipeline.status()),
+    };
+    pipeline.check();
+
+    if let Err(e) = result {
+        pipeline.fail(output.as_deref().unwrap_or("<stdout>"), e);
+    }
 }
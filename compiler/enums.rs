@@ -1,8 +1,5 @@
-use std::collections::HashMap;
 use std::fmt;
-use std::cmp::Ordering;
 use std::str::FromStr;
-use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct Token {
@@ -64,6 +61,11 @@ pub enum LexType {
     SKIP,
     BINARY,
     CONS,
+    DIRECTIVE,
+    STRING,
+    MACRO,
+    ENDM,
+    EQU,
     MISMATCH,
 }
 
@@ -84,11 +86,47 @@ impl fmt::Display for LexType {
             LexType::SKIP => write!(f, "SKIP"),
             LexType::BINARY => write!(f, "BINARY"),
             LexType::CONS => write!(f, "CONS"),
+            LexType::DIRECTIVE => write!(f, "DIRECTIVE"),
+            LexType::STRING => write!(f, "STRING"),
+            LexType::MACRO => write!(f, "MACRO"),
+            LexType::ENDM => write!(f, "ENDM"),
+            LexType::EQU => write!(f, "EQU"),
             LexType::MISMATCH => write!(f, "MISMATCH"),
         }
     }
 }
 
+// Kinds by name, as in the group names of the lexer's regular expression
+impl FromStr for LexType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "MEMCOUNTER" => LexType::MEMCOUNTER,
+            "OPERATION" => LexType::OPERATION,
+            "DIRECTION" => LexType::DIRECTION,
+            "CONDITION" => LexType::CONDITION,
+            "REGISTER" => LexType::REGISTER,
+            "COMMENT" => LexType::COMMENT,
+            "NEWLINE" => LexType::NEWLINE,
+            "ENDFILE" => LexType::ENDFILE,
+            "INCLUDE" => LexType::INCLUDE,
+            "NUMBER" => LexType::NUMBER,
+            "LABEL" => LexType::LABEL,
+            "SKIP" => LexType::SKIP,
+            "BINARY" => LexType::BINARY,
+            "CONS" => LexType::CONS,
+            "DIRECTIVE" => LexType::DIRECTIVE,
+            "STRING" => LexType::STRING,
+            "MACRO" => LexType::MACRO,
+            "ENDM" => LexType::ENDM,
+            "EQU" => LexType::EQU,
+            "MISMATCH" => LexType::MISMATCH,
+            _ => return Err(()),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ValueType {
     MEMCOUNTER,
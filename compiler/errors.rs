@@ -1,31 +1,232 @@
+use std::fmt;
+use crate::enums::{Line, Token};
+
+// Errors of the compiler stages
+//
+// Every error knows where it happened and which token is to blame, so that
+// it can be reported as
+//
+//     prog.s:3:5: error: unknown operation 'ad'
+//         3 |     ad r0 r1
+//           |     ^^
+//
+// Lines and columns are counted from 1. Errors of the back ends only know
+// the line, their column is 0.
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub filename: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn of(token: &Token) -> Self {
+        Location { filename: token.filename.clone(), line: token.line, column: token.column + 1 }
+    }
+
+    pub fn of_line(line: &Line) -> Self {
+        Location { filename: line.filename.clone(), line: line.linenumber, column: 0 }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.column {
+            0 => write!(f, "{}:{}", self.filename, self.line),
+            c => write!(f, "{}:{}:{}", self.filename, self.line, c),
+        }
+    }
+}
+
+/// Common view of the errors of all stages
+pub trait Diagnostic: fmt::Display {
+    fn location(&self) -> &Location;
+    /// Offending token, empty if there is none
+    fn token(&self) -> &str;
+}
+
+/// Caret-style report of an error, with its line of `source` if given
+pub fn render(diag: &dyn Diagnostic, source: Option<&str>) -> String {
+    let at = diag.location();
+    let mut out = format!("{}: error: {}\n", at, diag);
+
+    if let Some(text) = source.and_then(|s| s.lines().nth(at.line.checked_sub(1)?)) {
+        let number = at.line.to_string();
+        let gutter = " ".repeat(number.len());
+        out.push_str(&format!("    {} | {}\n", number, text));
+        if at.column > 0 {
+            // Keep tabs so that the caret lines up with the source
+            let pad: String = text.chars().take(at.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let width = diag.token().chars().count().max(1);
+            out.push_str(&format!("    {} | {}{}\n", gutter, pad, "^".repeat(width)));
+        }
+    }
+    out
+}
+
 #[derive(Debug)]
-pub struct TokenError;
+pub enum TokenError {
+    /// Text that is not a token
+    InvalidSyntax { at: Location, token: String },
+    /// Included file that cannot be read
+    Include { at: Location, token: String, msg: String },
+    /// Malformed directive (.macro, .equ...)
+    Directive { at: Location, token: String, msg: String },
+}
 
-impl std::fmt::Display for TokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "TokenError")
+impl TokenError {
+    pub fn directive(token: &Token, msg: String) -> Self {
+        TokenError::Directive { at: Location::of(token), token: token.value.clone(), msg }
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::InvalidSyntax { token, .. } => write!(f, "invalid syntax '{}'", token),
+            TokenError::Include { token, msg, .. } => write!(f, "cannot include {}: {}", token, msg),
+            TokenError::Directive { msg, .. } => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Diagnostic for TokenError {
+    fn location(&self) -> &Location {
+        match self {
+            TokenError::InvalidSyntax { at, .. }
+            | TokenError::Include { at, .. }
+            | TokenError::Directive { at, .. } => at,
+        }
+    }
+
+    fn token(&self) -> &str {
+        match self {
+            TokenError::InvalidSyntax { token, .. }
+            | TokenError::Include { token, .. }
+            | TokenError::Directive { token, .. } => token,
+        }
     }
 }
 
 impl std::error::Error for TokenError {}
 
 #[derive(Debug)]
-pub struct ParserError;
+pub enum ParserError {
+    /// Line with arguments but no operation
+    MissingOperation { at: Location, token: String },
+    UnknownOperation { at: Location, token: String },
+    /// No variant of the operation takes these argument types
+    ArgumentTypes { at: Location, token: String },
+    ArgumentCount { at: Location, token: String, expected: usize, got: usize },
+    /// Argument that cannot be read as its type, or out of range
+    InvalidValue { at: Location, token: String, msg: String },
+}
 
-impl std::fmt::Display for ParserError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ParserError")
+impl ParserError {
+    pub fn invalid(token: &Token, msg: String) -> Self {
+        ParserError::InvalidValue { at: Location::of(token), token: token.value.clone(), msg }
+    }
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParserError::MissingOperation { token, .. } => write!(f, "expected an operation before '{}'", token),
+            ParserError::UnknownOperation { token, .. } => write!(f, "unknown operation '{}'", token),
+            ParserError::ArgumentTypes { token, .. } => write!(f, "arguments do not match any form of '{}'", token),
+            ParserError::ArgumentCount { token, expected, got, .. } =>
+                write!(f, "'{}' takes {} argument(s), got {}", token, expected, got),
+            ParserError::InvalidValue { msg, .. } => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Diagnostic for ParserError {
+    fn location(&self) -> &Location {
+        match self {
+            ParserError::MissingOperation { at, .. }
+            | ParserError::UnknownOperation { at, .. }
+            | ParserError::ArgumentTypes { at, .. }
+            | ParserError::ArgumentCount { at, .. }
+            | ParserError::InvalidValue { at, .. } => at,
+        }
+    }
+
+    fn token(&self) -> &str {
+        match self {
+            ParserError::MissingOperation { token, .. }
+            | ParserError::UnknownOperation { token, .. }
+            | ParserError::ArgumentTypes { token, .. }
+            | ParserError::ArgumentCount { token, .. }
+            | ParserError::InvalidValue { token, .. } => token,
+        }
     }
 }
 
 impl std::error::Error for ParserError {}
 
 #[derive(Debug)]
-pub struct BackEndError;
+pub enum BackEndError {
+    /// Operation missing from the opcode table
+    UnknownOperation { at: Location, token: String },
+    UndefinedLabel { at: Location, token: String },
+    /// Value that does not fit its encoding
+    OutOfRange { at: Location, token: String, msg: String },
+    /// Feature this back end cannot encode
+    Unsupported { at: Location, token: String, msg: String },
+}
 
-impl std::fmt::Display for BackEndError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "BackEndError")
+impl BackEndError {
+    pub fn out_of_range(token: impl ToString, msg: &str) -> Self {
+        BackEndError::OutOfRange { at: Location::default(), token: token.to_string(), msg: msg.to_string() }
+    }
+
+    /// Set the location of an error raised by a helper that did not know it
+    pub fn at_line(mut self, line: &Line) -> Self {
+        let at = match &mut self {
+            BackEndError::UnknownOperation { at, .. }
+            | BackEndError::UndefinedLabel { at, .. }
+            | BackEndError::OutOfRange { at, .. }
+            | BackEndError::Unsupported { at, .. } => at,
+        };
+        if at.line == 0 {
+            *at = Location::of_line(line);
+        }
+        self
+    }
+}
+
+impl fmt::Display for BackEndError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackEndError::UnknownOperation { token, .. } => write!(f, "no opcode for '{}'", token),
+            BackEndError::UndefinedLabel { token, .. } => write!(f, "undefined label '{}'", token),
+            BackEndError::OutOfRange { token, msg, .. } => write!(f, "{}: {}", msg, token),
+            BackEndError::Unsupported { msg, .. } => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Diagnostic for BackEndError {
+    fn location(&self) -> &Location {
+        match self {
+            BackEndError::UnknownOperation { at, .. }
+            | BackEndError::UndefinedLabel { at, .. }
+            | BackEndError::OutOfRange { at, .. }
+            | BackEndError::Unsupported { at, .. } => at,
+        }
+    }
+
+    fn token(&self) -> &str {
+        match self {
+            BackEndError::UnknownOperation { token, .. }
+            | BackEndError::UndefinedLabel { token, .. }
+            | BackEndError::OutOfRange { token, .. }
+            | BackEndError::Unsupported { token, .. } => token,
+        }
     }
 }
 
@@ -41,3 +242,25 @@ impl std::fmt::Display for ImpossibleError {
 }
 
 impl std::error::Error for ImpossibleError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enums::LexType;
+
+    #[test]
+    fn test_render() {
+        let token = Token::new(LexType::LABEL, "ad".to_string(), "prog.s".to_string(), 2, 2);
+        let err = ParserError::UnknownOperation { at: Location::of(&token), token: token.value.clone() };
+        let source = "main:\n\t\tad r0 r1\n";
+        assert_eq!(render(&err, Some(source)),
+            "prog.s:2:3: error: unknown operation 'ad'\n    2 | \t\tad r0 r1\n      | \t\t^^\n");
+
+        // Without the source, or for errors without a column
+        assert_eq!(render(&err, None), "prog.s:2:3: error: unknown operation 'ad'\n");
+        let err = BackEndError::out_of_range(300, "register out of range")
+            .at_line(&Line::new("let".to_string(), vec![], 1, "prog.s".to_string()));
+        assert_eq!(render(&err, Some(source)),
+            "prog.s:1: error: register out of range: 300\n    1 | main:\n");
+    }
+}
@@ -1,234 +1,247 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
-use std::error::Error;
-use crate::back_end::{CleartextBitcodeBackEnd, BinaryBitcodeBackEnd};
+use std::io::{self, Write};
+use std::path::Path;
+use minimisa_core::bitvec::BitVec;
+use minimisa_core::object::{Object, Segment};
+use crate::back_end::{object_opcodes, write_debug_info, CleartextBitcodeBackEnd, DebugRecord};
 use crate::enums::Line;
-use crate::errors::{BackEndError, ImpossibleError};
-use crate::util::Queue;
+use crate::errors::{BackEndError, Location};
+use crate::relax::{relax, Fragment, RefKind, RelaxError};
+use crate::util::write_atomic;
+
+// Label-resolving back end
+//
+// Every line becomes fragments for the relaxation (see relax.rs): label
+// definitions, origins, alignments, label references, and the bits of everything else
+// as the cleartext back end encodes them. Instructions on a label are split
+// into their fixed bits (opcode, condition or register) and the reference.
 
 pub struct LabelsClearTextBackEnd {
     base: CleartextBitcodeBackEnd,
-    bit_cost: HashMap<u64, u64>,
-    bit_prefix: HashMap<u64, String>,
+    pub label_names: HashMap<u64, String>,
+    // The whole program: jump widths depend on the code between a jump and
+    // its label, wherever it is
+    lines: Vec<Line>,
+
+    // Bit offset of every line of the last call to packets(), with its
+    // index in the lines, for the debug info
+    line_offsets: Vec<(u64, usize)>,
+    // Segments (start, end) of the last call to packets(), in bits: the
+    // code at 0, then one per origin
+    segments: Vec<(u64, u64)>,
+    // Size in bits of every instruction and data line, once jumps are
+    // resolved, with its mnemonic
+    sizes: Vec<(String, u64)>,
 }
 
 impl LabelsClearTextBackEnd {
-    pub fn new(base: CleartextBitcodeBackEnd) -> Self {
-        let mut bit_cost = HashMap::new();
-        bit_cost.insert(8, 9);
-        bit_cost.insert(16, 18);
-        bit_cost.insert(32, 35);
-        bit_cost.insert(64, 67);
-
-        let mut bit_prefix = HashMap::new();
-        bit_prefix.insert(8, "0".to_string());
-        bit_prefix.insert(16, "10".to_string());
-        bit_prefix.insert(32, "110".to_string());
-        bit_prefix.insert(64, "111".to_string());
-
-        LabelsClearTextBackEnd { base, bit_cost, bit_prefix }
-    }
-
-    pub fn get_fullcode(&mut self) -> Vec<(usize, String)> {
-        let mut fullcode = vec![(0, "".to_string())];
-        let mut acc = String::new();
-
-        for line in &self.base.line_gene {
-            if !["jumpl", "jumpifl", "calll", "label"].contains(&line.funcname.as_str()) {
-                self.base.handle_line(line.clone()).unwrap();
-
-                while !self.base.out_queue.is_empty() {
-                    acc.push_str(&(self.base.out_queue.pop().unwrap() + "\n"));
-                }
-            } else {
-                fullcode.push((acc.split_whitespace().collect::<String>().len(), acc.clone()));
-
-                let bitcode = if line.funcname == "label" {
-                    "".to_string()
-                } else {
-                    self.base.huffman_tree[&line.funcname[..line.funcname.len()-1]].clone()
-                };
-
-                if line.funcname == "jumpl" || line.funcname == "calll" {
-                    fullcode.push((bitcode.len(), line.clone()));
-                } else if line.funcname == "jumpifl" {
-                    fullcode.push((bitcode.len() + 3, line.clone()));
-                }
-
-                acc.clear();
-            }
+    pub fn new(mut base: CleartextBitcodeBackEnd) -> Self {
+        let lines = base.lines().collect();
+        LabelsClearTextBackEnd {
+            base,
+            label_names: HashMap::new(),
+            lines,
+            line_offsets: Vec::new(),
+            segments: Vec::new(),
+            sizes: Vec::new(),
         }
-
-        fullcode.push((acc.len(), acc));
-        fullcode
     }
 
-    pub fn get_label_pos(&self, fullcode: &[(usize, String)]) -> HashMap<u64, usize> {
-        let mut label_dict = HashMap::new();
-
-        for (i, (_, x)) in fullcode.iter().enumerate() {
-            if let Some(line) = self.base.line_gene.iter().find(|line| line.funcname == "label") {
-                let label = line.typed_args[0].raw_value;
-                label_dict.insert(label, i);
-            }
-        }
+    fn label_name(&self, label: u64) -> String {
+        self.label_names.get(&label).cloned().unwrap_or_else(|| format!("L{}", label))
+    }
 
-        label_dict
+    fn opcode(&self, name: &str, line: &Line) -> Result<BitVec, BackEndError> {
+        self.base.opcode(name)
+            .ok_or_else(|| BackEndError::UnknownOperation { at: Location::of_line(line), token: name.to_string() })
     }
 
-    pub fn count_bytes(&self, fullcode: &[(usize, String)], addr_values: &HashMap<usize, (u64, i64)>, i: usize, j: usize) -> i64 {
-        let mut s = 0;
-        if j < i {
-            for k in (j + 1)..i {
-                s += fullcode[k].0 as i64;
-                if let Some(&(nb_bit, _)) = addr_values.get(&k) {
-                    s += *self.bit_cost.get(&nb_bit).unwrap() as i64;
-                }
+    // Fragments of the line at the given index
+    fn fragments(&mut self, line: &Line, index: usize) -> Result<Vec<Fragment>, BackEndError> {
+        let args = &line.typed_args;
+        let reference = |kind, label| Fragment::Ref { kind, label, line: index };
+
+        Ok(match line.funcname.as_str() {
+            "label" => vec![Fragment::Label(args[0].raw_value)],
+            "org" => vec![Fragment::Org { address: args[0].raw_value, line: index }],
+            "align" => vec![Fragment::Align(args[0].raw_value)],
+            "jumpl" => vec![
+                Fragment::Bits(self.opcode("jump", line)?),
+                reference(RefKind::Relative, args[0].raw_value),
+            ],
+            "jumpifl" => {
+                let mut bits = self.opcode("jumpif", line)?;
+                bits.push(args[0].raw_value, 3);
+                vec![Fragment::Bits(bits), reference(RefKind::Relative, args[1].raw_value)]
             }
-            s
-        } else {
-            for k in i..=j {
-                s += fullcode[k].0 as i64;
-                if let Some(&(nb_bit, _)) = addr_values.get(&k) {
-                    s += *self.bit_cost.get(&nb_bit).unwrap() as i64;
-                }
+            "calll" => vec![
+                Fragment::Bits(self.opcode("call", line)?),
+                reference(RefKind::Relative, args[0].raw_value),
+            ],
+            "letil" => {
+                let mut bits = self.opcode("leti", line)?;
+                bits.extend(&self.base.bin_register(args[0].raw_value).map_err(|e| e.at_line(line))?);
+                vec![Fragment::Bits(bits), reference(RefKind::Constant, args[1].raw_value)]
             }
-            -s
-        }
+            "constl" => vec![reference(RefKind::Raw(args[0].raw_value as u32), args[1].raw_value)],
+            _ => vec![Fragment::Bits(self.base.encode_line(line)?)],
+        })
     }
 
-    pub fn packets(&mut self) -> Vec<String> {
-        let fullcode = self.get_fullcode();
-        let label_dict = self.get_label_pos(&fullcode);
+    // Error of the relaxation, at its source line
+    fn relax_error(&self, e: RelaxError, lines: &[Line]) -> BackEndError {
+        let at = Location::of_line(&lines[e.line()]);
+        match e {
+            RelaxError::UndefinedLabel { label, .. } =>
+                BackEndError::UndefinedLabel { at, token: self.label_name(label) },
+            RelaxError::OutOfRange { label, value, .. } => BackEndError::OutOfRange {
+                at,
+                token: self.label_name(label),
+                msg: format!("address {} does not fit in the instruction", value),
+            },
+            RelaxError::Overlap { address, end, .. } => BackEndError::Unsupported {
+                at,
+                token: format!("{:#x}", address),
+                msg: format!("data at {:#x} overlaps the code before it, which ends at {:#x}", address, end),
+            },
+        }
+    }
 
-        let mut addr_values: HashMap<usize, (u64, i64)> = HashMap::new();
+    // Bits of every line, one BitVec per fragment, with jump widths resolved
+    fn emit(&mut self) -> Result<Vec<Vec<BitVec>>, BackEndError> {
+        let lines = self.lines.clone();
 
-        for (j, (_, x)) in fullcode.iter().enumerate() {
-            if let Some(line) = self.base.line_gene.get(j) {
-                if ["jumpl", "jumpifl", "calll"].contains(&line.funcname.as_str()) {
-                    addr_values.insert(j, (8, 0));
-                }
-            }
+        // Fragments of line i are starts[i]..starts[i + 1]
+        let mut fragments = Vec::new();
+        let mut starts = Vec::with_capacity(lines.len() + 1);
+        for (index, line) in lines.iter().enumerate() {
+            starts.push(fragments.len());
+            fragments.extend(self.fragments(line, index)?);
         }
-
-        loop {
-            let mut change = false;
-
-            for (j, (_, x)) in fullcode.iter().enumerate() {
-                if let Some(line) = self.base.line_gene.get(j) {
-                    if line.funcname == "jumpl" || line.funcname == "jumpifl" {
-                        let label = if line.funcname == "jumpl" {
-                            line.typed_args[0].raw_value
-                        } else {
-                            line.typed_args[1].raw_value
-                        };
-
-                        if !label_dict.contains_key(&label) {
-                            panic!("Undefined label '{}'", label);
-                        }
-
-                        let i = label_dict[&label];
-                        let (nb_bit, old_s) = addr_values[&j];
-                        let s = self.count_bytes(&fullcode, &addr_values, i, j);
-
-                        if s < -(1 << (nb_bit - 1)) || s >= (1 << (nb_bit - 1)) {
-                            if nb_bit == 64 {
-                                panic!("Jump too long");
-                            }
-                            addr_values.insert(j, (nb_bit * 2, s));
-                            change = true;
-                            break;
-                        } else {
-                            addr_values.insert(j, (nb_bit, s));
-                        }
-                    } else if line.funcname == "calll" {
-                        let label = line.typed_args[0].raw_value;
-
-                        if !label_dict.contains_key(&label) {
-                            panic!("Undefined label '{}'", label);
-                        }
-
-                        let i = label_dict[&label];
-                        let (nb_bit, old_s) = addr_values[&j];
-                        let s = self.count_bytes(&fullcode, &addr_values, i, 0);
-
-                        if s < -(1 << (nb_bit - 1)) || s >= (1 << (nb_bit - 1)) {
-                            if nb_bit == 64 {
-                                panic!("Address too big");
-                            }
-                            addr_values.insert(j, (nb_bit * 2, s));
-                            change = true;
-                            break;
-                        } else {
-                            addr_values.insert(j, (nb_bit, s));
-                        }
-                    }
-                }
+        starts.push(fragments.len());
+
+        let layout = relax(&fragments).map_err(|e| self.relax_error(e, &lines))?;
+
+        self.line_offsets.clear();
+        self.sizes.clear();
+        let mut packets = Vec::with_capacity(lines.len());
+        for (index, line) in lines.iter().enumerate() {
+            let (start, end) = (layout.offsets[starts[index]], layout.offsets[starts[index + 1]]);
+            // What follows an origin is at the origin, not before the padding
+            self.line_offsets.push((if line.funcname == "org" { end } else { start }, index));
+            if line.funcname != "label" && line.funcname != "org" {
+                let mnemonic = match line.funcname.as_str() {
+                    "jumpl" | "jumpifl" | "calll" | "letil" | "constl" => &line.funcname[..line.funcname.len() - 1],
+                    name => name,
+                };
+                self.sizes.push((mnemonic.to_string(), end - start));
             }
+            packets.push((starts[index]..starts[index + 1])
+                .map(|i| layout.bits(&fragments, i))
+                .filter(|bits| !bits.is_empty())
+                .collect());
+        }
 
-            if !change {
-                break;
+        self.segments.clear();
+        let mut segment_start = 0;
+        for (i, fragment) in fragments.iter().enumerate() {
+            if let Fragment::Org { .. } = fragment {
+                self.segments.push((segment_start, layout.offsets[i]));
+                segment_start = layout.offsets[i + 1];
             }
         }
+        self.segments.push((segment_start, *layout.offsets.last().unwrap()));
 
-        let mut endcode = vec![];
+        Ok(packets)
+    }
 
-        for (i, (_, x)) in fullcode.iter().enumerate() {
-            if x.is_empty() {
-                continue;
-            }
+    /// Bits of every line as '0' and '1' characters, with jump widths
+    /// resolved. Fragments are separated by blanks
+    pub fn packets(&mut self) -> Result<Vec<String>, BackEndError> {
+        Ok(self.emit()?.iter()
+            .map(|line| line.iter().map(|bits| bits.to_string()).collect::<Vec<_>>().join(" "))
+            .collect())
+    }
 
-            let line = self.base.line_gene.get(i).unwrap();
+    /// Size in bits of every instruction and data line of the last call to
+    /// packets(), with its mnemonic ("const" for data)
+    pub fn line_sizes(&self) -> &[(String, u64)] {
+        &self.sizes
+    }
 
-            if ["jumpl", "jumpifl", "calll"].contains(&line.funcname.as_str()) {
-                let mut bitcode = " ".to_string() + &self.base.huffman_tree[&line.funcname[..line.funcname.len() - 1]];
+    /// Object of the program: its segments, labels and opcode table
+    pub fn object(&mut self) -> Result<Object, BackEndError> {
+        let mut bits = BitVec::new();
+        for fragment in self.emit()?.iter().flatten() {
+            bits.extend(fragment);
+        }
+        let segments = self.segments.iter()
+            .filter(|&&(start, end)| start == 0 || end > start)
+            .map(|&(start, end)| Segment::from_bitvec(start, &bits.slice(start, end)))
+            .collect();
+        Ok(Object { entry: 0, segments, symbols: self.symbols(), opcodes: object_opcodes(self.base.huffman_tree()) })
+    }
 
-                if line.funcname == "jumpifl" {
-                    let cond = line.typed_args[0].raw_value;
-                    bitcode.push_str(&format!(" {}", self.base.bin_condition(cond)));
-                }
+    /// Labels of the last call to packets(), with their bit address
+    pub fn symbols(&self) -> Vec<(String, u64)> {
+        self.debug_info().into_iter()
+            .filter_map(|record| match record {
+                DebugRecord::Label { offset, name } => Some((name, offset)),
+                DebugRecord::Line { .. } => None,
+            })
+            .collect()
+    }
 
-                let (k, n) = addr_values[&i];
-                bitcode.push_str(&format!(" {}{}", self.bit_prefix[&k], self.base.binary_repr(n, k, true)));
-                endcode.push(bitcode);
+    /// Debug info of the last call to packets(): the bit offset of every
+    /// source line and label. Labels without a known name are named after
+    /// their number
+    pub fn debug_info(&self) -> Vec<DebugRecord> {
+        self.line_offsets.iter().map(|&(offset, index)| {
+            let line = &self.lines[index];
+            if line.funcname == "label" {
+                DebugRecord::Label { offset, name: self.label_name(line.typed_args[0].raw_value) }
             } else {
-                endcode.push(x.clone());
+                DebugRecord::Line { offset, filename: line.filename.clone(), linenumber: line.linenumber }
             }
-        }
-
-        endcode
+        }).collect()
     }
 }
 
 pub struct LabelsBinaryBackEnd {
     base: LabelsClearTextBackEnd,
-    write_mode: String,
+    pub embed_opcodes: bool,    // Store a non-default opcode table in the object
 }
 
 impl LabelsBinaryBackEnd {
     pub fn new(base: LabelsClearTextBackEnd) -> Self {
         LabelsBinaryBackEnd {
             base,
-            write_mode: "wb".to_string(),
+            embed_opcodes: true,
         }
     }
 
-    pub fn to_file(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
-        let bitcode = self.base.packets().join("");
-        let text_size = bitcode.len();
-        let padded_bitcode = bitcode + &"0".repeat((8 - (bitcode.len() % 8)) % 8);
-        let q = padded_bitcode.len() / 8;
-
-        let mut file = File::create(filename)?;
+    /// Write the object. Encoding errors are InvalidData errors wrapping
+    /// the BackEndError
+    pub fn write_to(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let mut object = self.base.object().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if !self.embed_opcodes {
+            object.opcodes = None;
+        }
+        out.write_all(&object.to_bytes())
+    }
 
-        file.write_all(&text_size.to_be_bytes())?;
+    /// Write the object to a file, replacing it only if encoding succeeds
+    pub fn write_file(&mut self, filename: &str) -> io::Result<()> {
+        write_atomic(Path::new(filename), |out| self.write_to(out))
+    }
 
-        for k in 0..q {
-            let byte = u8::from_str_radix(&padded_bitcode[8 * k..8 * (k + 1)], 2)?;
-            file.write_all(&[byte])?;
-        }
+    /// Labels of the last write_to(), with their bit address
+    pub fn symbols(&self) -> Vec<(String, u64)> {
+        self.base.symbols()
+    }
 
-        Ok(())
+    /// Write the debug info sidecar of the last write_to()
+    pub fn write_debug_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_debug_info(&self.base.debug_info(), out)
     }
 }
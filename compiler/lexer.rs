@@ -1,47 +1,62 @@
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
-use std::process::exit;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use crate::enums::{Token, LexType};
-use crate::errors::TokenError;
-use crate::util::{Stack, huffman, sub};
+use crate::errors::{Location, TokenError};
 
+#[derive(Clone)]
 pub struct Lexer {
     rexp: Regex,
     aliases: HashMap<LexType, HashMap<String, String>>,
-    possible_transitions: HashMap<String, Vec<String>>,
-    includes: HashSet<String>,
+    pub include_dirs: Vec<PathBuf>,     // Searched in order, after the including file's directory
+    chain: Vec<PathBuf>,                // Files being lexed, outermost first
 }
 
 impl Lexer {
-    pub fn new(possible_transitions: HashMap<String, Vec<String>>) -> Self {
-        let mut token_specification = HashMap::new();
-
-        token_specification.insert(LexType::OPERATION, 
-            r"\b(?:add|sub|cmp|let|shift|readze|readse|jump|or|and|write|call|setctr|getctr|push|return|xor|asr|pop|sleep|rand)\b");
-        
-        token_specification.insert(LexType::COMMENT, r";(?:.|[ \t])*");
-        token_specification.insert(LexType::REGISTER, r"\b(?:r|R)[0-9]+\b");
-        token_specification.insert(LexType::DIRECTION, r"\b(?:left|right)\b");
-        token_specification.insert(LexType::NUMBER, r"[+-]?(?:0x[0-9A-Fa-f]+|[0-9]+)\b");
-        token_specification.insert(LexType::CONDITION, 
-            r"\b(?:eq|z|neq|nz|sgt|slt|gt|ge|nc|lt|c|v|le)\b");
-        token_specification.insert(LexType::MEMCOUNTER, r"\b(?:pc|sp|a0|a1)\b");
-
-        token_specification.insert(LexType::LABEL, r"\b[a-zA-Z_][a-z_A-Z0-9]*:?");
-        token_specification.insert(LexType::INCLUDE, r"\.include\s+[a-zA-Z_][a-z_A-Z0-9\.]*\b");
-        token_specification.insert(LexType::CONS, r"\.const");
-        token_specification.insert(LexType::BINARY, r"#[01]+");
-
-        token_specification.insert(LexType::NEWLINE, r"\n");
-        token_specification.insert(LexType::SKIP, r"[ \t]+");
-        token_specification.insert(LexType::ENDFILE, r"$");
-        token_specification.insert(LexType::MISMATCH, r".+");
+    pub fn new(possible_transitions: &HashMap<&'static str, Vec<&'static str>>) -> Self {
+        // Kinds are tried in this order, the first that matches wins
+        let mut token_specification = Vec::new();
+
+        // Operations and the names of the instructions they stand for, both
+        // kept as written: the parser resolves them. Labels and constants
+        // are not written as operations
+        let mut operations: Vec<&str> = possible_transitions.iter()
+            .flat_map(|(op, variants)| std::iter::once(*op).chain(variants.iter().copied()))
+            .filter(|op| !["label", "const", "constl"].contains(op))
+            .collect();
+        operations.sort_unstable();
+        operations.dedup();
+        operations.sort_by_key(|op| std::cmp::Reverse(op.len()));
+        let operation_re = format!(r"\b(?:{})\b", operations.join("|"));
+        token_specification.push((LexType::OPERATION, operation_re.as_str()));
+
+        token_specification.push((LexType::COMMENT, r";(?:.|[ \t])*"));
+        token_specification.push((LexType::REGISTER, r"\b(?:r|R)[0-9]+\b"));
+        token_specification.push((LexType::DIRECTION, r"\b(?:left|right)\b"));
+        token_specification.push((LexType::NUMBER, r"[+-]?(?:0x[0-9A-Fa-f]+|[0-9]+)\b"));
+        token_specification.push((LexType::CONDITION,
+            r"\b(?:eq|z|neq|nz|sgt|slt|gt|ge|nc|lt|c|v|le)\b"));
+        token_specification.push((LexType::MEMCOUNTER, r"\b(?:pc|sp|a0|a1)\b"));
+
+        token_specification.push((LexType::LABEL, r"\b[a-zA-Z_][a-z_A-Z0-9]*:?"));
+        token_specification.push((LexType::INCLUDE, r"\.include\s+[a-zA-Z_\./][a-z_A-Z0-9\./-]*"));
+        token_specification.push((LexType::CONS, r"\.const"));
+        token_specification.push((LexType::BINARY, r"#[01]+"));
+        token_specification.push((LexType::DIRECTIVE, r"\.(?:byte|word|ascii|space|align|data|text|func|endfunc)\b"));
+        token_specification.push((LexType::MACRO, r"\.macro\b"));
+        token_specification.push((LexType::ENDM, r"\.endm\b"));
+        token_specification.push((LexType::EQU, r"\.(?:equ|define)\b"));
+        token_specification.push((LexType::STRING, r#""(?:[^"\\\n]|\\.)*""#));
+
+        token_specification.push((LexType::NEWLINE, r"\n"));
+        token_specification.push((LexType::SKIP, r"[ \t]+"));
+        token_specification.push((LexType::ENDFILE, r"$"));
+        token_specification.push((LexType::MISMATCH, r".+"));
 
         let tok_regex = token_specification.iter()
-            .map(|(name, re)| format!("(?P<{}>{})", format!("{:?}", name), re))
+            .map(|(name, re)| format!("(?P<{:?}>{})", name, re))
             .collect::<Vec<String>>()
             .join("|");
 
@@ -61,58 +76,113 @@ impl Lexer {
         Lexer {
             rexp,
             aliases,
-            possible_transitions,
-            includes: HashSet::new(),
+            include_dirs: Vec::new(),
+            chain: Vec::new(),
         }
     }
 
+    /// Lex a file. The tokens of included files are spliced in place of the
+    /// .include line, and keep their own file name and line numbers
     pub fn lex(&mut self, code: &str, name: &str, directory: &str) -> impl Iterator<Item = Result<Token, TokenError>> {
-        if self.includes.contains(name) {
-            return vec![].into_iter(); // Return empty iterator if file already included
+        let reader = Box::new(io::Cursor::new(code.to_string()));
+        let tokens: Vec<_> = self.clone().stream(reader, name, directory).collect();
+        tokens.into_iter()
+    }
+
+    /// Lex a file as its tokens are asked for, a line at a time, so that a
+    /// large program is never held in memory whole
+    pub fn stream(mut self, reader: Box<dyn BufRead>, name: &str, directory: &str) -> TokenStream {
+        self.chain = vec![fs::canonicalize(name).unwrap_or_else(|_| PathBuf::from(name))];
+        TokenStream { frames: vec![Frame::new(reader, name, directory)], lexer: self }
+    }
+
+    // Included files are looked up next to the including file, then in the
+    // include directories in order
+    fn resolve_include(&self, target: &str, directory: &str) -> Option<PathBuf> {
+        std::iter::once(Path::new(directory))
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(target))
+            .find(|path| path.is_file())
+    }
+
+    // Open an included file, which is then in the chain until it is lexed
+    fn include(&mut self, target: &str, at: Location, directory: &str) -> Result<Frame, TokenError> {
+        let error = |msg: String| TokenError::Include { at: at.clone(), token: target.to_string(), msg };
+
+        let Some(path) = self.resolve_include(target, directory) else {
+            let dirs: Vec<String> = std::iter::once(directory.to_string())
+                .chain(self.include_dirs.iter().map(|d| d.display().to_string()))
+                .collect();
+            return Err(error(format!("not found in {}", dirs.join(", "))));
+        };
+
+        let key = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if let Some(start) = self.chain.iter().position(|f| *f == key) {
+            let cycle: Vec<String> = self.chain[start..].iter().chain(std::iter::once(&key))
+                .map(|f| f.display().to_string())
+                .collect();
+            return Err(error(format!("include cycle: {}", cycle.join(" -> "))));
         }
 
-        self.includes.insert(name.to_string());
-        let mut line_num = 1;
-        let mut line_start = 0;
+        let file = File::open(&path).map_err(|e| error(e.to_string()))?;
+        let name = path.display().to_string();
+        let dir = path.parent().and_then(Path::to_str).filter(|d| !d.is_empty()).unwrap_or(".").to_string();
+        self.chain.push(key);
+        Ok(Frame::new(Box::new(BufReader::new(file)), &name, &dir))
+    }
 
-        let tokens = self.rexp.find_iter(code).map(move |mat| {
-            let kindname = mat.as_str();
-            let value = mat.as_str().to_string();
-            let kind = LexType::from_str(kindname).unwrap_or(LexType::MISMATCH);
-            let column = mat.start() - line_start;
+    // Tokens of one line of a file, with its newline if it has one. The end
+    // of the file is left to the stream
+    fn lex_line(&self, text: &str, frame: &mut Frame) {
+        let name = frame.name.as_str();
 
-            let value = self.lex_alias(kind, value.clone());
+        for caps in self.rexp.captures_iter(text) {
+            let mat = caps.get(0).unwrap();
+            let kindname = self.rexp.capture_names().flatten().find(|n| caps.name(n).is_some()).unwrap();
+            let kind = kindname.parse().unwrap_or(LexType::MISMATCH);
+            if kind == LexType::ENDFILE {
+                continue;
+            }
+            let column = mat.start();
+            let line_num = frame.line_num;
+
+            let value = self.lex_alias(kind, mat.as_str().to_string());
             let value = self.lex_value(kindname, value.clone());
 
-            match kind {
-                LexType::NEWLINE | LexType::ENDFILE => {
-                    line_start = mat.end();
-                    line_num += 1;
-                    Ok(Token::new(LexType::NEWLINE, None, name.to_string(), line_num - 1, column))
+            let token = match kind {
+                LexType::NEWLINE => {
+                    frame.line_num += 1;
+                    Ok(Token::new(LexType::NEWLINE, String::new(), name.to_string(), line_num, column))
+                }
+                LexType::SKIP => Ok(Token::new(LexType::SKIP, String::new(), name.to_string(), line_num, column)),
+                LexType::MISMATCH => Err(TokenError::InvalidSyntax {
+                    at: Location { filename: name.to_string(), line: line_num, column: column + 1 },
+                    token: value,
+                }),
+                LexType::LABEL if mat.as_str().ends_with(':') => {
+                    // A label followed by a colon is defined there, on a
+                    // line of its own for the parser: an instruction may
+                    // follow it
+                    let token = |typ, value: &str| Lexed::Token(Ok(Token::new(typ, value.to_string(), name.to_string(), line_num, column)));
+                    frame.queue.push_back(token(LexType::OPERATION, "label"));
+                    frame.queue.push_back(token(LexType::LABEL, &value));
+                    frame.queue.push_back(token(LexType::NEWLINE, ""));
+                    continue;
                 }
-                LexType::SKIP => Ok(Token::new(LexType::SKIP, None, name.to_string(), line_num, column)),
-                LexType::MISMATCH => Err(TokenError::new(format!("Invalid syntax at line {} : {}", line_num, value))),
-                LexType::LABEL => Ok(Token::new(LexType::LABEL, Some(value), name.to_string(), line_num, column)),
-                LexType::CONS => Ok(Token::new(LexType::OPERATION, Some("const".to_string()), name.to_string(), line_num, column)),
+                LexType::LABEL => Ok(Token::new(LexType::LABEL, value, name.to_string(), line_num, column)),
+                LexType::CONS => Ok(Token::new(LexType::OPERATION, "const".to_string(), name.to_string(), line_num, column)),
+                LexType::DIRECTIVE => Ok(Token::new(LexType::OPERATION, value[1..].to_string(), name.to_string(), line_num, column)),
+                LexType::STRING => Ok(Token::new(LexType::STRING, value, name.to_string(), line_num, column)),
                 LexType::INCLUDE => {
-                    let filename = format!("{}/{}", directory, value[9..].to_string());
-                    let mut file = File::open(&filename).map_err(|e| {
-                        println!("Lexer Error in file \"{}\" line {}: {}", filename, line_num, e);
-                        exit(1);
-                    })?;
-
-                    let mut contents = String::new();
-                    file.read_to_string(&mut contents)?;
-
-                    // Recursively lex the included file
-                    self.lex(&contents, &filename, directory).for_each(|t| {});
-                    Ok(Token::new(LexType::INCLUDE, Some(value), name.to_string(), line_num, column))
+                    let at = Location { filename: name.to_string(), line: line_num, column: column + 1 };
+                    frame.queue.push_back(Lexed::Include(value[".include".len()..].trim().to_string(), at));
+                    continue;
                 }
-                _ => Ok(Token::new(kind, Some(value), name.to_string(), line_num, column)),
-            }
-        });
-
-        tokens
+                _ => Ok(Token::new(kind, value, name.to_string(), line_num, column)),
+            };
+            frame.queue.push_back(Lexed::Token(token));
+        }
+        frame.end_column = if text.ends_with('\n') { 0 } else { text.len() };
     }
 
     fn lex_alias(&self, kind: LexType, value: String) -> String {
@@ -126,25 +196,53 @@ impl Lexer {
 
     fn lex_value(&self, kindname: &str, value: String) -> String {
         match kindname {
-            "NUMBER" => self.lex_value_NUMBER(value),
-            "REGISTER" => self.lex_value_REGISTER(value),
-            "LABEL" => self.lex_value_LABEL(value),
+            "NUMBER" => self.lex_value_number(value),
+            "REGISTER" => self.lex_value_register(value),
+            "LABEL" => self.lex_value_label(value),
+            "STRING" => self.lex_value_string(value),
             _ => value,
         }
     }
 
-    fn lex_value_NUMBER(&self, value: String) -> String {
-        if value.to_lowercase().starts_with("0x") {
-            return format!("{}", i64::from_str_radix(&value[2..], 16).unwrap());
+    fn lex_value_number(&self, value: String) -> String {
+        let (sign, digits) = match value.strip_prefix(['+', '-']) {
+            Some(digits) => (&value[..1], digits),
+            None => ("", value.as_str()),
+        };
+        match digits.strip_prefix("0x") {
+            Some(hex) => match u64::from_str_radix(hex, 16) {
+                Ok(n) => format!("{}{}", if sign == "-" { "-" } else { "" }, n),
+                Err(_) => value,
+            },
+            None => value,
         }
-        value
     }
 
-    fn lex_value_REGISTER(&self, value: String) -> String {
+    fn lex_value_register(&self, value: String) -> String {
         value[1..].to_string()  // Remove 'r' or 'R' prefix
     }
 
-    fn lex_value_LABEL(&self, value: String) -> String {
+    // Contents of a string literal, with the escapes \n \t \0 \" and \\
+    fn lex_value_string(&self, value: String) -> String {
+        let mut out = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('0') => out.push('\0'),
+                Some(c) => out.push(c),
+                None => {}
+            }
+        }
+        out
+    }
+
+    fn lex_value_label(&self, value: String) -> String {
         if value.ends_with(':') {
             value[..value.len() - 1].to_string()  // Remove trailing ':'
         } else {
@@ -152,3 +250,110 @@ impl Lexer {
         }
     }
 }
+
+// A token of a line, or a file to splice in its place
+enum Lexed {
+    Token(Result<Token, TokenError>),
+    Include(String, Location),
+}
+
+// A file being lexed
+struct Frame {
+    reader: Box<dyn BufRead>,
+    name: String,
+    directory: String,
+    line_num: usize,
+    end_column: usize,          // Column after the last line read
+    queue: VecDeque<Lexed>,     // What is left of the current line
+    done: bool,                 // End of file reached
+}
+
+impl Frame {
+    fn new(reader: Box<dyn BufRead>, name: &str, directory: &str) -> Self {
+        Frame {
+            reader,
+            name: name.to_string(),
+            directory: directory.to_string(),
+            line_num: 1,
+            end_column: 0,
+            queue: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+/// Tokens of a file and the files it includes, see Lexer::stream(). Only
+/// the current line of every open file is held in memory
+pub struct TokenStream {
+    lexer: Lexer,
+    frames: Vec<Frame>,         // Open files, the innermost last
+}
+
+impl Iterator for TokenStream {
+    type Item = Result<Token, TokenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.frames.last_mut()?;
+            match frame.queue.pop_front() {
+                Some(Lexed::Token(token)) => return Some(token),
+                Some(Lexed::Include(target, at)) => {
+                    let directory = frame.directory.clone();
+                    match self.lexer.include(&target, at, &directory) {
+                        Ok(included) => self.frames.push(included),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                None if frame.done => {
+                    self.frames.pop();
+                    self.lexer.chain.pop();
+                }
+                None => {
+                    let mut text = String::new();
+                    match frame.reader.read_line(&mut text) {
+                        Ok(0) => {
+                            // The end of a file ends its last line
+                            frame.done = true;
+                            let token = Token::new(LexType::NEWLINE, String::new(), frame.name.clone(), frame.line_num, frame.end_column);
+                            return Some(Ok(token));
+                        }
+                        Ok(_) => self.lexer.lex_line(&text, frame),
+                        Err(e) => {
+                            frame.done = true;
+                            let at = Location { filename: frame.name.clone(), line: frame.line_num, column: 1 };
+                            return Some(Err(TokenError::Include { at, token: frame.name.clone(), msg: e.to_string() }));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compileuh::POSSIBLE_TRANSITION;
+
+    fn lex(code: &str) -> Vec<(LexType, String)> {
+        Lexer::new(&POSSIBLE_TRANSITION).lex(code, "test.s", ".")
+            .map(|t| t.unwrap())
+            .filter(|t| !matches!(t.typ, LexType::SKIP | LexType::NEWLINE))
+            .map(|t| (t.typ, t.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        use LexType::*;
+        let tokens = lex("main:\tjump main ; forever\n\tleti r0 -0x10\n\tjumpif nz main\n");
+        let expected = [
+            (OPERATION, "label"), (LABEL, "main"),
+            (OPERATION, "jump"), (LABEL, "main"), (COMMENT, "; forever"),
+            (OPERATION, "leti"), (REGISTER, "0"), (NUMBER, "-16"),
+            (OPERATION, "jumpif"), (CONDITION, "neq"), (LABEL, "main"),
+        ];
+        let tokens: Vec<(LexType, &str)> = tokens.iter().map(|(t, v)| (*t, v.as_str())).collect();
+        assert_eq!(tokens, expected);
+    }
+}
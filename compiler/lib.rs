@@ -0,0 +1,26 @@
+//---
+// minimisa-compiler - library interface of the MinimISA compiler
+//
+// The passes of compileuh (lexer, macros, constants, parser,
+// pseudo-instructions, back ends) and the tools built on them, for the
+// programs of this directory and for the emulator's tests, which assemble
+// their programs with the compiler.
+//---
+
+pub mod util;
+pub mod enums;
+pub mod errors;
+pub mod lexer;
+pub mod macros;
+pub mod symbols;
+pub mod parser;
+pub mod pseudo;
+pub mod relax;
+pub mod coder;
+pub mod back_end;
+pub mod labels;
+pub mod sizes;
+pub mod compileuh;
+pub mod lint;
+pub mod bitstats;
+pub mod xref;
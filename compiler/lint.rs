@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use minimisa_core::codec::Insn;
+use minimisa_core::{decode, default_opcodes, lookup, Opcodes, Operand, ADDRESS_WIDTHS, CONST_WIDTHS,
+    INSTRUCTIONS, SIZES};
+
+#[derive(Debug)]
+pub struct LintError {
+    pub bit: usize,
+    pub line: usize,
+    pub column: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for LintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bit {} (line {}, column {}): {}", self.bit, self.line, self.column, self.msg)
+    }
+}
+
+impl std::error::Error for LintError {}
+
+// A bit of the stream, remembering where it was written in the source
+#[derive(Debug, Clone, Copy)]
+struct SourceBit {
+    bit: u8,
+    line: usize,
+    column: usize,
+}
+
+// Why an instruction could not be read
+enum Stop {
+    End,                // The stream ended in the middle of it
+    Invalid(LintError), // Its bits are not an instruction
+}
+
+/// Decoder of an ASCII bitstream. Instructions are read with the opcode
+/// table and the operand kinds of minimisa-core, as the emulator does
+pub struct Linter {
+    bits: Vec<SourceBit>,
+    pos: usize,
+    codes: Opcodes,
+    default: bool,      // codes is the default table, which decode() reads
+}
+
+impl Linter {
+    // Keep only '0' and '1' characters, as the simulator loader does.
+    // The opcode table is given as mnemonic -> code
+    pub fn new(text: &str, opcodes: &HashMap<String, String>) -> Self {
+        let mut bits = Vec::new();
+        for (l, line) in text.lines().enumerate() {
+            for (c, ch) in line.chars().enumerate() {
+                if ch == '0' || ch == '1' {
+                    bits.push(SourceBit { bit: (ch == '1') as u8, line: l + 1, column: c + 1 });
+                }
+            }
+        }
+
+        let mut codes = [(0, 0); INSTRUCTIONS.len()];
+        for (mnemonic, code) in opcodes {
+            if let (Some(op), Ok(value)) = (lookup(mnemonic), u64::from_str_radix(code, 2)) {
+                codes[op as usize] = (value, code.len() as u32);
+            }
+        }
+
+        Linter { bits, pos: 0, codes, default: codes == default_opcodes() }
+    }
+
+    pub fn with_default_opcodes(text: &str) -> Self {
+        let codes = default_opcodes();
+        Linter { codes, default: true, ..Linter::new(text, &HashMap::new()) }
+    }
+
+    fn error_at(&self, bit: usize, msg: String) -> LintError {
+        let (line, column) = match self.bits.get(bit).or(self.bits.last()) {
+            Some(b) => (b.line, b.column),
+            None => (0, 0),
+        };
+        LintError { bit, line, column, msg }
+    }
+
+    fn read(&mut self, n: u32) -> Result<u64, Stop> {
+        let end = self.pos + n as usize;
+        if end > self.bits.len() {
+            return Err(Stop::End);
+        }
+        let value = self.bits[self.pos..end].iter().fold(0, |acc, b| (acc << 1) | b.bit as u64);
+        self.pos = end;
+        Ok(value)
+    }
+
+    // Opcode number of the code at the current position
+    fn opcode(&self, code: u64, length: u32) -> Option<u32> {
+        if self.default {
+            return decode(code, length);
+        }
+        self.codes.iter().position(|&c| c == (code, length)).map(|op| op as u32)
+    }
+
+    fn read_opcode(&mut self) -> Result<u32, Stop> {
+        let start = self.pos;
+        let longest = self.codes.iter().map(|&(_, length)| length).max().unwrap_or(0);
+        let (mut code, mut length) = (0, 0);
+        while length < longest {
+            code = (code << 1) | self.read(1)?;
+            length += 1;
+            if let Some(op) = self.opcode(code, length) {
+                return Ok(op);
+            }
+        }
+        let text = format!("{:0width$b}", code, width = length as usize);
+        Err(Stop::Invalid(self.error_at(start, format!("no opcode starts with '{}'", text))))
+    }
+
+    // Width selected by a 0 / 10 / 110 / 111 prefix
+    fn read_prefix(&mut self, widths: [u32; 4]) -> Result<u32, Stop> {
+        let mut index = 0;
+        while index < 3 && self.read(1)? == 1 {
+            index += 1;
+        }
+        Ok(widths[index])
+    }
+
+    fn read_operand(&mut self, kind: Operand) -> Result<u64, Stop> {
+        Ok(match kind {
+            Operand::None => 0,
+            Operand::Register | Operand::Condition => self.read(3)?,
+            Operand::Direction => self.read(1)?,
+            Operand::Pointer => self.read(2)?,
+            Operand::Address => {
+                let width = self.read_prefix(ADDRESS_WIDTHS)?;
+                sign_extend(self.read(width)?, width)
+            }
+            Operand::AConst => {
+                let width = self.read_prefix(CONST_WIDTHS)?;
+                sign_extend(self.read(width)?, width)
+            }
+            Operand::LConst => {
+                let width = self.read_prefix(CONST_WIDTHS)?;
+                self.read(width)?
+            }
+            Operand::Shift => if self.read(1)? == 1 { 1 } else { self.read(6)? },
+            Operand::Size => {
+                let (mut code, mut length) = (self.read(2)?, 2);
+                if code >= 2 {
+                    code = (code << 1) | self.read(1)?;
+                    length = 3;
+                }
+                SIZES.iter().find(|&&(_, c)| c == (code, length)).unwrap().0
+            }
+        })
+    }
+
+    // Decode one instruction
+    fn next_instruction(&mut self) -> Result<Insn, LintError> {
+        let start = self.pos;
+        let opcode = match self.read_opcode() {
+            Ok(opcode) => opcode,
+            Err(Stop::Invalid(e)) => return Err(e),
+            Err(Stop::End) => return Err(self.error_at(start, format!(
+                "{} trailing bits do not form an opcode", self.bits.len() - start))),
+        };
+
+        let ins = &INSTRUCTIONS[opcode as usize];
+        let mut args = [0; 3];
+        for (i, (arg, &kind)) in args.iter_mut().zip(&ins.operands).enumerate() {
+            let at = self.pos;
+            *arg = match self.read_operand(kind) {
+                Ok(value) => value,
+                Err(Stop::Invalid(e)) => return Err(e),
+                Err(Stop::End) => return Err(self.error_at(at, format!(
+                    "operand {} of {} at bit {} is truncated, the stream ends at bit {}",
+                    i + 1, ins.mnemonic, start, self.bits.len()))),
+            };
+        }
+        Ok(Insn { opcode, args })
+    }
+
+    /// Decode the whole stream. On success, return the listing of the
+    /// instructions with the bit where each starts, otherwise the first
+    /// bit where decoding fails
+    pub fn run(&mut self) -> Result<Vec<(usize, String)>, LintError> {
+        let mut listing = Vec::new();
+        while self.pos < self.bits.len() {
+            let start = self.pos;
+            let ins = self.next_instruction()?;
+            listing.push((start, ins.to_string()));
+        }
+        Ok(listing)
+    }
+}
+
+// Two's complement value of the low `width` bits
+fn sign_extend(value: u64, width: u32) -> u64 {
+    if width >= 64 { value } else { (((value << (64 - width)) as i64) >> (64 - width)) as u64 }
+}
+
+/// Lint an ASCII bitstring file and print a report. Returns whether the
+/// whole stream decoded cleanly
+pub fn lint_file(filename: &str) -> io::Result<bool> {
+    let mut text = String::new();
+    File::open(filename)?.read_to_string(&mut text)?;
+
+    let mut linter = Linter::with_default_opcodes(&text);
+    match linter.run() {
+        Ok(listing) => {
+            for (bit, ins) in &listing {
+                println!("{:>8}  {}", bit, ins);
+            }
+            println!("{}: {} instructions decoded, no error", filename, listing.len());
+            Ok(true)
+        }
+        Err(e) => {
+            eprintln!("{}: {}", filename, e);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(text: &str) -> Result<Vec<(usize, String)>, LintError> {
+        Linter::with_default_opcodes(text).run()
+    }
+
+    #[test]
+    fn test_valid_stream() {
+        // add2 r1 r2, leti r3 -1, jumpif slt -2 on 8 bits
+        let listing = lint("0000 001 010\n0111 011 0 1\n1011 011 0 11111110\n").unwrap();
+        assert_eq!(listing, [
+            (0, "add2 r1 r2".to_string()),
+            (10, "leti r3 -1".to_string()),
+            (19, "jumpif slt -2".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_bad_opcode() {
+        let table = [("add2", "0000"), ("leti", "0111")].iter()
+            .map(|(m, c)| (m.to_string(), c.to_string())).collect();
+        let e = Linter::new("0000 001 010\n1111 001 010\n", &table).run().unwrap_err();
+        assert_eq!((e.bit, e.line, e.column), (10, 2, 1));
+        assert!(e.msg.contains("'1111'"), "{}", e.msg);
+    }
+
+    #[test]
+    fn test_truncated_operand() {
+        let e = lint("0000 001 01").unwrap_err();
+        assert_eq!((e.bit, e.line, e.column), (7, 1, 10));
+        assert!(e.msg.contains("operand 2 of add2"), "{}", e.msg);
+    }
+
+    #[test]
+    fn test_trailing_bits() {
+        let e = lint("0000 001 010\n  11\n").unwrap_err();
+        assert_eq!((e.bit, e.line, e.column), (10, 2, 3));
+        assert!(e.msg.contains("2 trailing bits"), "{}", e.msg);
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::{HashMap, VecDeque};
+use crate::enums::{LexType, Token};
+use crate::errors::TokenError;
+
+// Macros
+//
+//     .macro name arg1 arg2 ...
+//         body, where the arguments are used like labels
+//     .endm
+//
+// A line starting with `name a b ...` is replaced by the body, with every
+// argument substituted by the token given for it. Labels defined in the
+// body are local to each expansion: they are renamed name__label__N, where N
+// counts expansions, so a macro with a loop can be used several times.
+// Expansions may use other macros, up to MAX_DEPTH levels deep. Macro
+// names cannot be used as labels at the start of a line.
+//
+// Expanded tokens keep the position of the macro call, so that errors and
+// the line map point at the call site.
+
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+#[derive(Debug, Default)]
+pub struct MacroExpander {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+}
+
+// Tokens that carry meaning, as opposed to blanks and comments
+fn words(line: &[Token]) -> Vec<&Token> {
+    line.iter()
+        .filter(|t| !matches!(t.typ, LexType::SKIP | LexType::COMMENT | LexType::NEWLINE))
+        .collect()
+}
+
+// Split a token stream into lines, each ending with its NEWLINE token
+fn split_lines(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut lines = vec![Vec::new()];
+    for token in tokens {
+        let newline = token.typ == LexType::NEWLINE;
+        lines.last_mut().unwrap().push(token);
+        if newline {
+            lines.push(Vec::new());
+        }
+    }
+    lines.retain(|l| !l.is_empty());
+    lines
+}
+
+impl MacroExpander {
+    pub fn new() -> Self {
+        MacroExpander::default()
+    }
+
+    /// Expand all macros of a token stream, dropping their definitions
+    pub fn expand(&mut self, tokens: impl Iterator<Item = Result<Token, TokenError>>) -> Result<Vec<Token>, TokenError> {
+        let mut stream = std::mem::take(self).stream(tokens);
+        let out = stream.by_ref().collect();
+        *self = stream.expander;
+        out
+    }
+
+    /// Expand the macros of a token stream as it is read. Only a macro
+    /// definition or the expansion of one call is held at a time
+    pub fn stream<I: Iterator<Item = Result<Token, TokenError>>>(self, tokens: I) -> MacroStream<I> {
+        MacroStream { expander: self, lines: TokenLines { tokens }, pending: VecDeque::new(), failed: false }
+    }
+
+    fn expand_lines(&mut self, lines: &mut dyn Iterator<Item = Result<Vec<Token>, TokenError>>, depth: usize,
+        out: &mut VecDeque<Token>) -> Result<(), TokenError> {
+        while let Some(line) = lines.next() {
+            self.expand_line(line?, lines, depth, out)?;
+        }
+        Ok(())
+    }
+
+    // Expand a line, reading the body from the next lines if it starts a
+    // macro definition
+    fn expand_line(&mut self, line: Vec<Token>, lines: &mut dyn Iterator<Item = Result<Vec<Token>, TokenError>>,
+        depth: usize, out: &mut VecDeque<Token>) -> Result<(), TokenError> {
+        let w = words(&line);
+        match w.first() {
+            Some(t) if t.typ == LexType::MACRO => {
+                let (name, params) = match w.get(1) {
+                    Some(n) if n.typ == LexType::LABEL => (n.value.clone(), &w[2..]),
+                    _ => return Err(TokenError::directive(t, ".macro needs a name".to_string())),
+                };
+                if let Some(p) = params.iter().find(|p| p.typ != LexType::LABEL) {
+                    return Err(TokenError::directive(p, format!("invalid macro argument name '{}'", p.value)));
+                }
+                let params = params.iter().map(|p| p.value.clone()).collect();
+
+                let mut body = Vec::new();
+                loop {
+                    let Some(body_line) = lines.next() else {
+                        return Err(TokenError::directive(t, format!("macro '{}' has no .endm", name)));
+                    };
+                    let body_line = body_line?;
+                    match words(&body_line).first().map(|b| b.typ) {
+                        Some(LexType::ENDM) => break,
+                        Some(LexType::MACRO) =>
+                            return Err(TokenError::directive(&body_line[0], "macros cannot be defined inside macros".to_string())),
+                        _ => body.extend(body_line),
+                    }
+                }
+                self.macros.insert(name, Macro { params, body });
+            }
+            Some(t) if t.typ == LexType::ENDM => return Err(TokenError::directive(t, ".endm without .macro".to_string())),
+            Some(t) if t.typ == LexType::LABEL && self.macros.contains_key(&t.value) => {
+                if depth >= MAX_DEPTH {
+                    return Err(TokenError::directive(t, format!("macro '{}' expands too deeply (recursive macro?)", t.value)));
+                }
+                let body = self.instantiate(t, &w[1..])?;
+                self.expand_lines(&mut split_lines(body).into_iter().map(Ok), depth + 1, out)?;
+            }
+            _ => out.extend(line),
+        }
+        Ok(())
+    }
+
+    // Body of the macro called at `call` with the given arguments
+    fn instantiate(&mut self, call: &Token, args: &[&Token]) -> Result<Vec<Token>, TokenError> {
+        let m = self.macros[&call.value].clone();
+        if args.len() != m.params.len() {
+            return Err(TokenError::directive(call, format!("macro '{}' takes {} argument(s), got {}",
+                call.value, m.params.len(), args.len())));
+        }
+        self.expansions += 1;
+        let n = self.expansions;
+
+        // Labels opening a body line are the local ones
+        let mut locals = Vec::new();
+        for line in split_lines(m.body.clone()) {
+            if let Some(t) = words(&line).first() {
+                if t.typ == LexType::LABEL && !m.params.contains(&t.value) && !self.macros.contains_key(&t.value) {
+                    locals.push(t.value.clone());
+                }
+            }
+        }
+
+        let body = m.body.iter().map(|t| {
+            let mut token = match m.params.iter().position(|p| t.typ == LexType::LABEL && *p == t.value) {
+                Some(i) => args[i].clone(),
+                None => t.clone(),
+            };
+            if token.typ == LexType::LABEL && locals.contains(&t.value) {
+                token.value = format!("{}__{}__{}", call.value, t.value, n);
+            }
+            token.filename = call.filename.clone();
+            token.line = call.line;
+            token
+        }).collect();
+        Ok(body)
+    }
+}
+
+// Lines of a token stream, each ending with its NEWLINE token
+struct TokenLines<I> {
+    tokens: I,
+}
+
+impl<I: Iterator<Item = Result<Token, TokenError>>> Iterator for TokenLines<I> {
+    type Item = Result<Vec<Token>, TokenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        for token in self.tokens.by_ref() {
+            let token = match token {
+                Ok(token) => token,
+                Err(e) => return Some(Err(e)),
+            };
+            let newline = token.typ == LexType::NEWLINE;
+            line.push(token);
+            if newline {
+                break;
+            }
+        }
+        (!line.is_empty()).then_some(Ok(line))
+    }
+}
+
+/// Tokens of a stream with its macros expanded, see MacroExpander::stream().
+/// The stream ends after the first error
+pub struct MacroStream<I> {
+    expander: MacroExpander,
+    lines: TokenLines<I>,
+    pending: VecDeque<Token>,   // Rest of the last line or expansion
+    failed: bool,
+}
+
+impl<I: Iterator<Item = Result<Token, TokenError>>> Iterator for MacroStream<I> {
+    type Item = Result<Token, TokenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Some(Ok(token));
+            }
+            if self.failed {
+                return None;
+            }
+            let result = match self.lines.next()? {
+                Ok(line) => self.expander.expand_line(line, &mut self.lines, 0, &mut self.pending),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tok(typ: LexType, value: &str) -> Token {
+        Token::new(typ, value.to_string(), "test.s".to_string(), 1, 0)
+    }
+
+    fn lines(code: &[&[(LexType, &str)]]) -> Vec<Result<Token, TokenError>> {
+        let mut tokens = Vec::new();
+        for line in code {
+            tokens.extend(line.iter().map(|&(typ, value)| Ok(tok(typ, value))));
+            tokens.push(Ok(tok(LexType::NEWLINE, "")));
+        }
+        tokens
+    }
+
+    fn values(tokens: &[Token]) -> Vec<String> {
+        tokens.iter().filter(|t| t.typ != LexType::NEWLINE).map(|t| t.value.clone()).collect()
+    }
+
+    #[test]
+    fn test_macros() {
+        use LexType::*;
+        let code = lines(&[
+            &[(MACRO, ".macro"), (LABEL, "countdown"), (LABEL, "reg")],
+            &[(LABEL, "loop")],
+            &[(OPERATION, "sub"), (LABEL, "reg"), (NUMBER, "1")],
+            &[(OPERATION, "jump"), (CONDITION, "nz"), (LABEL, "loop")],
+            &[(ENDM, ".endm")],
+            &[(LABEL, "countdown"), (REGISTER, "3")],
+            &[(LABEL, "countdown"), (REGISTER, "4")],
+        ]);
+        let out = MacroExpander::new().expand(code.into_iter()).unwrap();
+        assert_eq!(values(&out), [
+            "countdown__loop__1", "sub", "3", "1", "jump", "nz", "countdown__loop__1",
+            "countdown__loop__2", "sub", "4", "1", "jump", "nz", "countdown__loop__2",
+        ]);
+
+        let bad = lines(&[
+            &[(MACRO, ".macro"), (LABEL, "m")],
+            &[(OPERATION, "return")],
+            &[(ENDM, ".endm")],
+            &[(LABEL, "m"), (NUMBER, "1")],
+        ]);
+        assert!(MacroExpander::new().expand(bad.into_iter()).is_err());
+
+        let unterminated = lines(&[&[(MACRO, ".macro"), (LABEL, "m")], &[(OPERATION, "return")]]);
+        assert!(MacroExpander::new().expand(unterminated.into_iter()).is_err());
+
+        let recursive = lines(&[
+            &[(MACRO, ".macro"), (LABEL, "m")],
+            &[(LABEL, "m")],
+            &[(ENDM, ".endm")],
+            &[(LABEL, "m")],
+        ]);
+        assert!(MacroExpander::new().expand(recursive.into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        use LexType::*;
+        // Expansions come out as the calls are read, and reading stops at
+        // the first error
+        let code = lines(&[
+            &[(MACRO, ".macro"), (LABEL, "twice"), (LABEL, "reg")],
+            &[(OPERATION, "add2"), (LABEL, "reg"), (LABEL, "reg")],
+            &[(ENDM, ".endm")],
+            &[(LABEL, "twice"), (REGISTER, "1")],
+            &[(ENDM, ".endm")],
+            &[(OPERATION, "return")],
+        ]);
+        let mut read = 0;
+        let tokens = code.into_iter().inspect(|_| read += 1);
+        let mut stream = MacroExpander::new().stream(tokens);
+        let first: Vec<Token> = stream.by_ref().take(3).map(Result::unwrap).collect();
+        assert_eq!(values(&first), ["add2", "1", "1"]);
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+        drop(stream);
+        assert_eq!(read, 15);
+    }
+}
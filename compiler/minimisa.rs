@@ -0,0 +1,224 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use minimisa_core::object::format_symbols;
+use minimisa_compiler::back_end::CleartextBitcodeBackEnd;
+use minimisa_compiler::bitstats::bitstats_file;
+use minimisa_compiler::coder::Strategy;
+use minimisa_compiler::compileuh::{load_opcode_table, OpcodeTable, Pipeline, Source};
+use minimisa_compiler::enums::Line;
+use minimisa_compiler::labels::{LabelsBinaryBackEnd, LabelsClearTextBackEnd};
+use minimisa_compiler::lint::lint_file;
+use minimisa_compiler::pseudo::PseudoOptions;
+use minimisa_compiler::util::write_atomic;
+use minimisa_compiler::xref::xref_file;
+
+// Build driver
+//
+//     minimisa build <source> [-o <object>] [options] [--run|--debug|--simu] [-- <emulator options>]
+//
+// Compiles a program, resolves its labels and writes it as an object, then
+// optionally runs it: the steps of compileuh --backend labels-binary
+// followed by emu, with one set of flags. Paths are taken as given, and
+// the object defaults to the source with the extension .obj (prog.s gives
+// prog.obj). The emulator is the emu found next to this program, else the
+// one on the PATH; its exit status becomes that of minimisa.
+//
+//     minimisa xref <source>
+//
+// Prints where every label of a program is defined and used (see xref.rs).
+//
+//     minimisa bitstats <bitstring>
+//
+// Analyzes the code density of an ASCII bitstring, as the cleartext back
+// end writes it (see bitstats.rs).
+//
+//     minimisa lint <bitstring>
+//
+// Decodes an ASCII bitstring instruction by instruction and reports where
+// it stops making sense (see lint.rs).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    No,
+    Emu,        // --run: run to the end and print the final state
+    Debugger,   // --debug: run in the debugger
+    Simu,       // --simu: run like subject/simu
+}
+
+fn usage() -> ! {
+    eprintln!("usage: minimisa build <source> [options] [-- <emulator options>]");
+    eprintln!("  -o <file>               object file (default: the source with the extension .obj)");
+    eprintln!("  -I <dir>                look for included files in dir (repeatable)");
+    eprintln!("  --huffman               build an opcode table for the program, saved to opcode.txt");
+    eprintln!("  --coder <strategy>      build an opcode table with huffman, canonical or limited[:<bits>] codes");
+    eprintln!("  --opcode-table <file>   use the opcode table of a file (mnemonic code lines)");
+    eprintln!("  --no-embed-opcodes      leave the opcode table out of the object");
+    eprintln!("  --symbols <file>        write the address of every label (\"<address> <label>\" lines)");
+    eprintln!("  --run                   run the object in the emulator once built");
+    eprintln!("  --debug                 run the object in the debugger once built");
+    eprintln!("  --simu                  run the object like subject/simu once built");
+    eprintln!("       minimisa xref <source>");
+    eprintln!("       minimisa bitstats <bitstring>");
+    eprintln!("       minimisa lint <bitstring>");
+    exit(1);
+}
+
+// The emulator next to this program, or the one on the PATH
+fn emulator() -> PathBuf {
+    env::current_exe().ok()
+        .and_then(|exe| Some(exe.parent()?.join("emu")))
+        .filter(|emu| emu.is_file())
+        .unwrap_or_else(|| PathBuf::from("emu"))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("build") => build(&args),
+        Some("xref") => xref(&args),
+        Some("bitstats") => bitstats(&args),
+        Some("lint") => lint(&args),
+        _ => usage(),
+    }
+}
+
+// Label cross-reference of a source file
+fn xref(args: &[String]) {
+    let [_, input] = args else { usage() };
+    match xref_file(input) {
+        Ok(report) => print!("{}", report),
+        Err(e) => {
+            eprintln!("{}: {}", input, e);
+            exit(1);
+        }
+    }
+}
+
+// Code density report of a bitstring; fails if it does not decode
+fn bitstats(args: &[String]) {
+    let [_, input] = args else { usage() };
+    match bitstats_file(input) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}: {}", input, e);
+            exit(1);
+        }
+    }
+}
+
+// Listing of a bitstring; fails on the first instruction that does not decode
+fn lint(args: &[String]) {
+    let [_, input] = args else { usage() };
+    match lint_file(input) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}: {}", input, e);
+            exit(1);
+        }
+    }
+}
+
+fn build(args: &[String]) {
+    let mut output = None;
+    let mut table = OpcodeTable::Default;
+    let mut include_dirs = Vec::new();
+    let mut embed_opcodes = true;
+    let mut symbols = None;
+    let mut run = Run::No;
+    let mut emu_args = Vec::new();
+    let mut input = None;
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-o" => {
+                i += 1;
+                output = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "-I" => {
+                i += 1;
+                include_dirs.push(PathBuf::from(args.get(i).unwrap_or_else(|| usage())));
+            }
+            arg if arg.starts_with("-I") => include_dirs.push(PathBuf::from(&arg[2..])),
+            "--huffman" => table = OpcodeTable::Huffman(Strategy::Huffman),
+            "--coder" => {
+                i += 1;
+                let strategy = args.get(i).and_then(|name| Strategy::from_name(name)).unwrap_or_else(|| usage());
+                table = OpcodeTable::Huffman(strategy);
+            }
+            "--opcode-table" => {
+                i += 1;
+                let file = args.get(i).unwrap_or_else(|| usage());
+                table = OpcodeTable::Given(load_opcode_table(file).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", file, e);
+                    exit(1);
+                }));
+            }
+            "--no-embed-opcodes" => embed_opcodes = false,
+            "--symbols" => {
+                i += 1;
+                symbols = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "--run" => run = Run::Emu,
+            "--debug" => run = Run::Debugger,
+            "--simu" => run = Run::Simu,
+            "--" => {
+                emu_args.extend(args[i + 1..].iter().cloned());
+                break;
+            }
+            arg if !arg.starts_with('-') && input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => usage(),
+        }
+        i += 1;
+    }
+    let input = input.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| input.with_extension("obj").display().to_string());
+    if !emu_args.is_empty() && run == Run::No {
+        eprintln!("minimisa: emulator options need --run, --debug or --simu");
+        exit(1);
+    }
+
+    // Compile, then resolve the labels and write the object
+    let pipeline = Pipeline::new(Source::File(input), &include_dirs, &PseudoOptions::default());
+    let program = table.is_built().then(|| pipeline.program());
+    let hufftree = table.codes(program.as_ref());
+    let lines: Box<dyn Iterator<Item = Line>> = match &program {
+        Some(program) => Box::new(program.lines()),
+        None => Box::new(pipeline.lines()),
+    };
+    let mut resolved = LabelsClearTextBackEnd::new(CleartextBitcodeBackEnd::new(hufftree, lines));
+    resolved.label_names = pipeline.label_names();
+    let mut labels = LabelsBinaryBackEnd::new(resolved);
+    labels.embed_opcodes = embed_opcodes;
+    let result = write_atomic(Path::new(&output), |out| {
+        labels.write_to(out)?;
+        pipeline.status()
+    });
+    pipeline.check();
+    if let Err(e) = result {
+        pipeline.fail(&output, e);
+    }
+    if let Some(file) = &symbols {
+        let text = format_symbols(&labels.symbols());
+        if let Err(e) = write_atomic(Path::new(file), |out| out.write_all(text.as_bytes())) {
+            eprintln!("{}: {}", file, e);
+            exit(1);
+        }
+    }
+
+    let mode: &[&str] = match run {
+        Run::No => return,
+        Run::Emu => &[],
+        Run::Debugger => &["--debugger"],
+        Run::Simu => &["--compat", "simu"],
+    };
+    let emu = emulator();
+    let status = Command::new(&emu).args(mode).args(&emu_args).arg(&output).status().unwrap_or_else(|e| {
+        eprintln!("minimisa: cannot run {}: {}", emu.display(), e);
+        exit(1);
+    });
+    exit(status.code().unwrap_or(1));
+}
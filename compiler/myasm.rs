@@ -1,8 +1,13 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, Read, Write};
 use std::num::ParseIntError;
+use std::path::Path;
+use lazy_static::lazy_static;
 use regex::Regex;
+use minimisa_core::bitvec::BitVec;
+use minimisa_core::{encode_const, to_bits, Operand, CONDITIONS, CONDITION_ALIASES, INSTRUCTIONS};
+use minimisa_compiler::util::write_atomic;
 
 // Structs equivalent to namedtuples
 #[derive(Debug, Clone)]
@@ -16,66 +21,37 @@ struct Condition {
     opcode: String,
 }
 
-// Commands equivalent to the Python named tuples
+// Operand kinds as named by asm_line, from the kinds of the ISA definition
+fn operand_kind(operand: Operand) -> Option<&'static str> {
+    match operand {
+        Operand::None => None,
+        Operand::Register => Some("reg"),
+        Operand::Direction => Some("dir"),
+        Operand::Condition => Some("cond"),
+        Operand::Address => Some("addr_signed"),
+        Operand::LConst => Some("const"),
+        Operand::AConst => Some("sconst"),
+        Operand::Shift => Some("shiftval"),
+        Operand::Size => Some("size"),
+        Operand::Pointer => Some("ctr"),
+    }
+}
+
+// Commands of the whole instruction set
 fn init_commands() -> HashMap<&'static str, Command> {
-    let mut commands = HashMap::new();
-    commands.insert("add2", Command { opcode: "0000".to_string(), operands: vec!["reg", "reg"] });
-    commands.insert("add2i", Command { opcode: "0001".to_string(), operands: vec!["reg", "const"] });
-    commands.insert("sub2", Command { opcode: "0010".to_string(), operands: vec!["reg", "reg"] });
-    commands.insert("sub2i", Command { opcode: "0011".to_string(), operands: vec!["reg", "const"] });
-    commands.insert("cmp", Command { opcode: "0100".to_string(), operands: vec!["reg", "reg"] });
-    commands.insert("cmpi", Command { opcode: "0101".to_string(), operands: vec!["reg", "sconst"] });
-    commands.insert("let", Command { opcode: "0110".to_string(), operands: vec!["reg", "reg"] });
-    commands.insert("leti", Command { opcode: "0111".to_string(), operands: vec!["reg", "sconst"] });
-    commands.insert("shift", Command { opcode: "1000".to_string(), operands: vec!["dir", "reg", "shiftval"] });
-    commands.insert("readze", Command { opcode: "10010".to_string(), operands: vec!["ctr", "size", "reg"] });
-    commands.insert("pop", Command { opcode: "1001001".to_string(), operands: vec!["size", "reg"] });
-    commands.insert("readse", Command { opcode: "10011".to_string(), operands: vec!["ctr", "size", "reg"] });
-    commands.insert("jump", Command { opcode: "1010".to_string(), operands: vec!["addr_signed"] });
-    commands.insert("jumpif", Command { opcode: "1011".to_string(), operands: vec!["cond", "addr_signed"] });
-    commands.insert("or2", Command { opcode: "110000".to_string(), operands: vec!["reg", "reg"] });
-    commands.insert("or2i", Command { opcode: "110001".to_string(), operands: vec!["reg", "const"] });
-    commands.insert("and2", Command { opcode: "110010".to_string(), operands: vec!["reg", "reg"] });
-    commands.insert("and2i", Command { opcode: "110011".to_string(), operands: vec!["reg", "const"] });
-    commands.insert("write", Command { opcode: "110100".to_string(), operands: vec!["ctr", "size", "reg"] });
-    commands.insert("call", Command { opcode: "110101".to_string(), operands: vec!["addr_signed"] });
-    commands.insert("setctr", Command { opcode: "110110".to_string(), operands: vec!["ctr", "reg"] });
-    commands.insert("getctr", Command { opcode: "110111".to_string(), operands: vec!["ctr", "reg"] });
-    commands.insert("push", Command { opcode: "1110000".to_string(), operands: vec!["size", "reg"] });
-    commands.insert("return", Command { opcode: "1110001".to_string(), operands: vec![] });
-    commands.insert("add3", Command { opcode: "1110010".to_string(), operands: vec!["reg", "reg", "reg"] });
-    commands.insert("add3i", Command { opcode: "1110011".to_string(), operands: vec!["reg", "reg", "const"] });
-    commands.insert("sub3", Command { opcode: "1110100".to_string(), operands: vec!["reg", "reg", "reg"] });
-    commands.insert("sub3i", Command { opcode: "1110101".to_string(), operands: vec!["reg", "reg", "const"] });
-    commands.insert("and3", Command { opcode: "1110110".to_string(), operands: vec!["reg", "reg", "reg"] });
-    commands.insert("and3i", Command { opcode: "1110111".to_string(), operands: vec!["reg", "reg", "const"] });
-    commands.insert("or3", Command { opcode: "1111000".to_string(), operands: vec!["reg", "reg", "reg"] });
-    commands.insert("or3i", Command { opcode: "1111001".to_string(), operands: vec!["reg", "reg", "const"] });
-    commands.insert("xor3", Command { opcode: "1111010".to_string(), operands: vec!["reg", "reg", "reg"] });
-    commands.insert("xor3i", Command { opcode: "1111011".to_string(), operands: vec!["reg", "reg", "const"] });
-    commands.insert("asr3", Command { opcode: "1111100".to_string(), operands: vec!["reg", "reg", "shiftval"] });
-    commands.insert("rese1", Command { opcode: "1111101".to_string(), operands: vec![] });
-    commands.insert("rese2", Command { opcode: "1111110".to_string(), operands: vec![] });
-    commands.insert("rese3", Command { opcode: "1111111".to_string(), operands: vec![] });
-    commands
-}
-
-// Conditions equivalent to the Python named tuples
+    INSTRUCTIONS.iter().map(|ins| {
+        let operands = ins.operands.iter().filter_map(|&op| operand_kind(op)).collect();
+        (ins.mnemonic, Command { opcode: ins.code.to_string(), operands })
+    }).collect()
+}
+
+// Conditions, with their aliases
 fn init_conditions() -> HashMap<&'static str, Condition> {
-    let mut conditions = HashMap::new();
-    conditions.insert("eq", Condition { opcode: "000".to_string() });
-    conditions.insert("z", Condition { opcode: "000".to_string() });
-    conditions.insert("neq", Condition { opcode: "001".to_string() });
-    conditions.insert("nz", Condition { opcode: "001".to_string() });
-    conditions.insert("sgt", Condition { opcode: "010".to_string() });
-    conditions.insert("slt", Condition { opcode: "011".to_string() });
-    conditions.insert("gt", Condition { opcode: "100".to_string() });
-    conditions.insert("ge", Condition { opcode: "101".to_string() });
-    conditions.insert("nc", Condition { opcode: "101".to_string() });
-    conditions.insert("lt", Condition { opcode: "110".to_string() });
-    conditions.insert("c", Condition { opcode: "110".to_string() });
-    conditions.insert("v", Condition { opcode: "111".to_string() });
-    conditions
+    let names = CONDITIONS.iter().copied().chain(CONDITION_ALIASES.iter().map(|&(alias, _)| alias));
+    names.map(|name| {
+        let code = minimisa_core::condition(name).unwrap();
+        (name, Condition { opcode: format!("{:03b}", code) })
+    }).collect()
 }
 
 #[derive(Debug)]
@@ -89,15 +65,21 @@ impl std::fmt::Display for TokenError {
 
 impl std::error::Error for TokenError {}
 
+impl From<ParseIntError> for TokenError {
+    fn from(e: ParseIntError) -> Self {
+        TokenError(e.to_string())
+    }
+}
+
 const NB_REG: u32 = 8;
-const NB_BIT_REG: u32 = (NB_REG as f64).log2().ceil() as u32;
+const NB_BIT_REG: u32 = NB_REG.next_power_of_two().ilog2();
 
 fn binary_repr(n: i64, k: u32, signed: bool) -> Result<String, TokenError> {
     if signed && (n < -(1 << (k - 1)) || n >= (1 << (k - 1))) {
         return Err(TokenError("Number not in range".to_string()));
     }
 
-    let mut n = if signed { (1 << k) + n } else { n } as u64;
+    let n = if signed { (1 << k) + n } else { n } as u64;
     let unfilled = format!("{:b}", n);
     if unfilled.len() > k as usize {
         return Err(TokenError("Too long binary".to_string()));
@@ -109,7 +91,7 @@ fn binary_repr(n: i64, k: u32, signed: bool) -> Result<String, TokenError> {
 // Regular expressions
 lazy_static! {
     static ref RE_REG: Regex = Regex::new(r"^r([0-9]+)$").unwrap();
-    static ref RE_CONST: Regex = Regex::new(r"^([+-]?0x[0-9A-Fa-f]+)|([+-]?[0-9]+)$").unwrap();
+    static ref RE_CONST: Regex = Regex::new(r"^([+-]?)(?:0x([0-9A-Fa-f]+)|([0-9]+))$").unwrap();
     static ref RE_DIR: Regex = Regex::new(r"(left)|(right)").unwrap();
     static ref RE_SHIFTVAL: Regex = Regex::new(r"^(0x[0-9A-Fa-f]+)|([0-9]+)$").unwrap();
     static ref RE_CTR: Regex = Regex::new(r"(pc|sp|a0|a1)").unwrap();
@@ -127,24 +109,29 @@ fn asm_reg(s: &str) -> Result<String, TokenError> {
     binary_repr(val as i64, NB_BIT_REG, false)
 }
 
-fn asm_const(s: &str) -> Result<String, TokenError> {
+// Constants are unsigned (logical operations) or signed (add, sub, cmp and
+// let), on the smallest of 1, 8, 32 and 64 bits that holds them
+fn asm_const(s: &str, signed: bool) -> Result<String, TokenError> {
     let res = RE_CONST.captures(s).ok_or(TokenError("Invalid constant syntax".to_string()))?;
-    let val: i64 = if let Some(hex_val) = res.get(1) {
-        i64::from_str_radix(hex_val.as_str().trim_start_matches("0x"), 16)?
-    } else {
-        res.get(2).unwrap().as_str().parse().map_err(|_| TokenError("Invalid decimal constant".to_string()))?
+    let magnitude = match res.get(2) {
+        Some(hex_val) => u64::from_str_radix(hex_val.as_str(), 16).ok(),
+        None => res[3].parse::<u64>().ok(),
     };
-
-    if val < (1 << 1) {
-        Ok(format!("0{}", binary_repr(val, 1, false)?))
-    } else if val < (1 << 8) {
-        Ok(format!("10{}", binary_repr(val, 8, false)?))
-    } else if val < (1 << 32) {
-        Ok(format!("110{}", binary_repr(val, 32, false)?))
-    } else if val < (1 << 64) {
-        Ok(format!("111{}", binary_repr(val, 64, false)?))
-    } else {
-        Err(TokenError("Invalid constant: not in range".to_string()))
+    let negative = &res[1] == "-";
+
+    let val = match (magnitude, negative, signed) {
+        (Some(m), false, false) => Some(m),
+        (Some(m), false, true) => i64::try_from(m).ok().map(|v| v as u64),
+        (Some(m), true, true) => 0i64.checked_sub_unsigned(m).map(|v| v as u64),
+        (Some(_), true, false) => {
+            return Err(TokenError(format!("Invalid constant: {} is negative, but this constant is unsigned", s)));
+        }
+        (None, _, _) => None,
+    };
+    match val {
+        Some(val) => Ok(to_bits(&encode_const(val, signed))),
+        None if signed => Err(TokenError(format!("Invalid constant: not in range {}..{}", i64::MIN, i64::MAX))),
+        None => Err(TokenError(format!("Invalid constant: not in range 0..{}", u64::MAX))),
     }
 }
 
@@ -165,6 +152,11 @@ fn asm_shiftval(s: &str) -> Result<String, TokenError> {
     }
 }
 
+fn asm_cond(s: &str) -> Result<String, TokenError> {
+    init_conditions().get(s).map(|cond| cond.opcode.clone())
+        .ok_or_else(|| TokenError(format!("Invalid condition: {}", s)))
+}
+
 fn asm_line(s: &str, commands: &HashMap<&str, Command>) -> Result<String, TokenError> {
     let cmds: Vec<&str> = s.split_whitespace().collect();
     if cmds.is_empty() {
@@ -183,8 +175,10 @@ fn asm_line(s: &str, commands: &HashMap<&str, Command>) -> Result<String, TokenE
     for (&operand, &arg) in cmd.operands.iter().zip(args.iter()) {
         let code = match operand {
             "reg" => asm_reg(arg)?,
-            "const" => asm_const(arg)?,
+            "const" => asm_const(arg, false)?,
+            "sconst" => asm_const(arg, true)?,
             "shiftval" => asm_shiftval(arg)?,
+            "cond" => asm_cond(arg)?,
             _ => return Err(TokenError(format!("Unknown operand type: {}", operand))),
         };
         linecode.push(code);
@@ -210,12 +204,121 @@ fn asm_doc(s: &str, commands: &HashMap<&str, Command>) -> Result<String, TokenEr
     Ok(bitcode.join("\n"))
 }
 
+// One line entered in the REPL: a label definition or an instruction
+#[derive(Debug, Clone)]
+enum Entry {
+    Label(String, usize),
+    Instruction { source: String, bitcode: String, address: usize },
+}
+
+// Incremental assembly state of the REPL. Everything is derived from the
+// list of entries, so undoing is just popping from it
+#[derive(Debug, Default)]
+struct Repl {
+    entries: Vec<Entry>,
+}
+
+impl Repl {
+    // Address of the next instruction, i.e. the cumulative size in bits
+    fn size(&self) -> usize {
+        self.entries.iter().rev().find_map(|e| match e {
+            Entry::Instruction { bitcode, address, .. } => Some(address + bitcode.replace(" ", "").len()),
+            Entry::Label(..) => None,
+        }).unwrap_or(0)
+    }
+
+    fn symbols(&self) -> Vec<(&str, usize)> {
+        self.entries.iter().filter_map(|e| match e {
+            Entry::Label(name, address) => Some((name.as_str(), *address)),
+            _ => None,
+        }).collect()
+    }
+
+    fn define(&mut self, name: &str) -> Result<usize, TokenError> {
+        if self.symbols().iter().any(|(n, _)| *n == name) {
+            return Err(TokenError(format!("Label {} already defined", name)));
+        }
+        let address = self.size();
+        self.entries.push(Entry::Label(name.to_string(), address));
+        Ok(address)
+    }
+
+    fn assemble(&mut self, line: &str, commands: &HashMap<&str, Command>) -> Result<(usize, String), TokenError> {
+        let bitcode = asm_line(line, commands)?;
+        let address = self.size();
+        self.entries.push(Entry::Instruction { source: line.to_string(), bitcode: bitcode.clone(), address });
+        Ok((address, bitcode))
+    }
+
+    // Remove the last instruction, along with the labels defined after it
+    // since they pointed past it. Returns the removed source line
+    fn undo(&mut self) -> Option<String> {
+        while let Some(entry) = self.entries.pop() {
+            if let Entry::Instruction { source, .. } = entry {
+                return Some(source);
+            }
+        }
+        None
+    }
+}
+
+// Interactive mode: assemble lines as they are typed
+fn repl(commands: &HashMap<&str, Command>) -> io::Result<()> {
+    let mut state = Repl::default();
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    print!("> ");
+    stdout.flush()?;
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let line = line.trim();
+
+        match line {
+            "" => {}
+            ":symbols" => {
+                for (name, address) in state.symbols() {
+                    println!("{:>8}  {}", address, name);
+                }
+            }
+            ":size" => {
+                let size = state.size();
+                println!("{} bits ({} bytes)", size, size.div_ceil(8));
+            }
+            ":undo" => match state.undo() {
+                Some(source) => println!("removed: {}", source),
+                None => println!("nothing to undo"),
+            },
+            ":quit" | ":q" => break,
+            _ if line.starts_with(':') => eprintln!("unknown command {} (:symbols, :size, :undo, :quit)", line),
+            _ if line.ends_with(':') => match state.define(&line[..line.len() - 1]) {
+                Ok(address) => println!("{:>8}  {}", address, line),
+                Err(e) => eprintln!("/!\\ {}", e),
+            },
+            _ => match state.assemble(line, commands) {
+                Ok((address, bitcode)) => println!("{:>8}  {}", address, bitcode),
+                Err(e) => eprintln!("/!\\ {}", e),
+            },
+        }
+
+        print!("> ");
+        stdout.flush()?;
+    }
+    println!();
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
         eprintln!("Usage: {} <source file>", args[0]);
+        eprintln!("       {} -i", args[0]);
         return Err(Box::new(TokenError("No source file provided".to_string())));
     }
+    if args[1] == "-i" {
+        repl(&init_commands())?;
+        return Ok(());
+    }
 
     let filename = &args[1];
     let mut file = File::open(format!("{}.s", filename))?;
@@ -225,15 +328,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let commands = init_commands();
     let bitcode = asm_doc(&contents, &commands)?;
 
-    let mut debug_file = File::create(format!("{}.debug", filename))?;
-    debug_file.write_all(bitcode.as_bytes())?;
-
-    let res = bitcode.replace(" ", "");
-    let padded_res = format!("{:0<8}", res);
-    let bin = u64::from_str_radix(&padded_res, 2)?.to_be_bytes();
+    let bin: BitVec = bitcode.parse().map_err(TokenError)?;
 
-    let mut bin_file = File::create(format!("{}.bin", filename))?;
-    bin_file.write_all(&bin)?;
+    write_atomic(Path::new(&format!("{}.debug", filename)), |out| out.write_all(bitcode.as_bytes()))?;
+    write_atomic(Path::new(&format!("{}.bin", filename)), |out| out.write_all(bin.as_bytes()))?;
 
     Ok(())
 }
@@ -1,69 +1,16 @@
 use std::collections::{HashMap, VecDeque};
-use std::fs::File;
-use std::io::{self, BufReader, Read};
-use std::process;
-use std::fmt;
-
-// Define Token and Value structs
-#[derive(Debug, Clone)]
-struct Token {
-    typ: LexType,
-    value: String,
-    filename: String,
-    line: usize,
-    column: usize,
-}
-
-#[derive(Debug, Clone)]
-struct Value {
-    typ: ValueType,
-    raw_value: String,
-}
-
-#[derive(Debug, Clone)]
-struct Line {
-    funcname: String,
-    typed_args: Vec<Value>,
-    linenumber: usize,
-    filename: String,
-}
+use minimisa_core::{condition, pointer, DIRECTIONS};
+use crate::enums::{LexType, Line, Token, Value, ValueType, NB_REG};
+use crate::errors::{Location, ParserError};
 
-// LexType and ValueType enums
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-enum LexType {
-    Operation,
-    Comment,
-    EndFile,
-    NewLine,
-    Label,
+fn at(token: &Token) -> Location {
+    Location { filename: token.filename.clone(), line: token.line, column: token.column + 1 }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-enum ValueType {
-    MemCounter,
-    Direction,
-    Condition,
-    UConstant,
-    SConstant,
-    RAddress,
-    ShiftVal,
-    Size,
-    Register,
-    Label,
-    Binary,
+fn invalid(token: &Token, msg: String) -> ParserError {
+    ParserError::InvalidValue { at: at(token), token: token.value.clone(), msg }
 }
 
-#[derive(Debug)]
-struct ParserError(String);
-
-impl fmt::Display for ParserError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ParserError: {}", self.0)
-    }
-}
-
-impl std::error::Error for ParserError {}
-
 // Utility functions for stack and queue management
 struct Stack<T> {
     inner: Vec<T>,
@@ -81,14 +28,6 @@ impl<T> Stack<T> {
     fn pop(&mut self) -> Option<T> {
         self.inner.pop()
     }
-
-    fn is_empty(&self) -> bool {
-        self.inner.is_empty()
-    }
-
-    fn peek(&self) -> Option<&T> {
-        self.inner.last()
-    }
 }
 
 struct Queue<T> {
@@ -107,235 +46,406 @@ impl<T> Queue<T> {
     fn pop(&mut self) -> Option<T> {
         self.inner.pop_front()
     }
-
-    fn is_empty(&self) -> bool {
-        self.inner.is_empty()
-    }
 }
 
 // The parser structure
-struct Parser<'a> {
-    lexer_gen: &'a mut dyn Iterator<Item = Token>,
+//
+// Lines are parsed as their tokens are read and come out as an iterator,
+// except the data sections, which are held until the end of the code.
+// Instructions an operation or instruction name stands for, by the kinds of
+// the tokens of their arguments: (instruction, argument types)
+type Functions = HashMap<String, HashMap<Vec<LexType>, (String, Vec<ValueType>)>>;
+
+pub struct Parser<'a> {
+    lexer_gen: Box<dyn Iterator<Item = Token> + 'a>,
     stack: Stack<Token>,
-    out_stack: Stack<Line>,
-    functions: HashMap<String, HashMap<Vec<LexType>, (String, Vec<ValueType>)>>,
-    labels: HashMap<String, usize>,
+    out_queue: Queue<Line>,
+    functions: Functions,
+    // Labels are numbered in the order they first appear
+    labels: HashMap<String, u64>,
+
+    // Lines of the data sections, emitted after the code
+    in_data: bool,
+    data: Vec<Line>,
+    ended: bool,
 }
 
 impl<'a> Parser<'a> {
-    fn new(
-        lexer_gen: &'a mut dyn Iterator<Item = Token>,
-        possible_transitions: &HashMap<String, Vec<String>>,
-        asr_specs: &HashMap<String, Vec<ValueType>>,
+    pub fn new(
+        lexer_gen: Box<dyn Iterator<Item = Token> + 'a>,
+        possible_transitions: &HashMap<&'static str, Vec<&'static str>>,
+        asr_specs: &HashMap<&'static str, Vec<ValueType>>,
         types_specs: &HashMap<LexType, Vec<ValueType>>,
     ) -> Self {
         let mut functions = HashMap::new();
         let rev_types_specs = inv_dict_list(types_specs);
 
+        // An operation selects among its instructions by argument types; an
+        // instruction name only has its own forms
         for (funcname, list_asr_funcname) in possible_transitions {
-            let mut func_map = HashMap::new();
             for asr_funcname in list_asr_funcname {
                 let asr_args = asr_specs.get(asr_funcname).unwrap();
                 let preasr_args = asr_args
                     .iter()
-                    .map(|x| rev_types_specs.get(x).unwrap().clone())
+                    .map(|x| *rev_types_specs.get(x).unwrap())
                     .collect::<Vec<LexType>>();
-                func_map.insert(preasr_args, (asr_funcname.clone(), asr_args.clone()));
+                let variant = (asr_funcname.to_string(), asr_args.clone());
+                functions.entry(funcname.to_string()).or_insert_with(HashMap::new)
+                    .insert(preasr_args.clone(), variant.clone());
+                functions.entry(asr_funcname.to_string()).or_insert_with(HashMap::new)
+                    .insert(preasr_args, variant);
             }
-            functions.insert(funcname.clone(), func_map);
         }
 
         Parser {
             lexer_gen,
             stack: Stack::new(),
-            out_stack: Stack::new(),
+            out_queue: Queue::new(),
             functions,
             labels: HashMap::new(),
+            in_data: false,
+            data: Vec::new(),
+            ended: false,
+        }
+    }
+
+    /// Parse every line, going on after errors so that they are all
+    /// reported at once
+    pub fn run(&mut self) -> Result<Vec<Line>, Vec<ParserError>> {
+        let mut lines = Vec::new();
+        let mut errors = Vec::new();
+        for line in self {
+            match line {
+                Ok(line) => lines.push(line),
+                Err(e) => errors.push(e),
+            }
         }
+        if errors.is_empty() { Ok(lines) } else { Err(errors) }
     }
 
-    fn run(&mut self) -> Result<(), ParserError> {
-        for token in self.lexer_gen {
+    /// Names of the labels seen so far, by their number
+    pub fn label_names(&self) -> HashMap<u64, String> {
+        self.labels.iter().map(|(name, &label)| (label, name.clone())).collect()
+    }
+
+    fn label(&mut self, name: &str) -> u64 {
+        let next = self.labels.len() as u64;
+        *self.labels.entry(name.to_string()).or_insert(next)
+    }
+
+    // Parse the tokens up to the end of the next line
+    fn next_line(&mut self) -> Option<Result<(), ParserError>> {
+        for token in self.lexer_gen.by_ref() {
             match token.typ {
-                LexType::Comment => continue,
-                LexType::EndFile => continue,
-                LexType::NewLine => {
-                    self.handle_one()?;
-                    while let Some(out_line) = self.out_stack.pop() {
-                        println!("{:?}", out_line);
-                    }
-                }
+                LexType::COMMENT | LexType::SKIP => continue,
+                LexType::ENDFILE => continue,
+                LexType::NEWLINE => return Some(self.handle_one()),
                 _ => self.stack.push(token),
             }
         }
-        Ok(())
+        None
     }
 
+    // Tokens of the current line, operation first. The stack is left empty
     fn unstack_until_operation(&mut self) -> Result<Vec<Token>, ParserError> {
-        let mut res = Queue::new();
+        let mut res = Vec::new();
 
         while let Some(token) = self.stack.pop() {
-            if token.typ != LexType::Operation {
-                res.push(token);
-            } else {
-                return Ok(res.inner.into_iter().collect());
+            let operation = token.typ == LexType::OPERATION;
+            res.push(token);
+            if operation {
+                while self.stack.pop().is_some() {}
+                res.reverse();
+                return Ok(res);
             }
         }
 
-        Err(ParserError("Couldn't find operation on the stack".to_string()))
+        match res.last() {
+            None => Ok(res),
+            Some(first) => Err(ParserError::MissingOperation { at: at(first), token: first.value.clone() }),
+        }
     }
 
     fn handle_one(&mut self) -> Result<(), ParserError> {
         let res = self.unstack_until_operation()?;
+        if res.is_empty() {
+            return Ok(());
+        }
 
         let fun_name = &res[0].value;
-        let args_types = res.iter().skip(1).map(|x| x.typ).collect::<Vec<LexType>>();
-
-        if let Some(func_map) = self.functions.get(fun_name) {
-            if let Some((funcname, goal_args_type)) = func_map.get(&args_types) {
-                let args_values = res.iter().skip(1).map(|x| x.value.clone()).collect::<Vec<_>>();
-                let mut typed_args = Vec::new();
-
-                if args_values.len() != goal_args_type.len() {
-                    return Err(ParserError(format!(
-                        "Incorrect number of arguments for function {}",
-                        funcname
-                    )));
-                }
+        if let Some(lines) = self.handle_directive(&res)? {
+            for line in lines {
+                self.emit(line);
+            }
+            return Ok(());
+        }
 
-                for (value, goal_type) in args_values.iter().zip(goal_args_type) {
-                    let method_name = format!("read_{}", goal_type.to_string().to_lowercase());
-                    if let Some(typed_value) = self.read_value(goal_type, value)? {
-                        typed_args.push(typed_value);
-                    } else {
-                        return Err(ParserError(format!(
-                            "Couldn't read {}",
-                            goal_type.to_string()
-                        )));
-                    }
-                }
+        let args_types = res.iter().skip(1).map(|x| x.typ).collect::<Vec<LexType>>();
 
-                self.out_stack.push(Line {
-                    funcname: funcname.clone(),
-                    typed_args,
-                    linenumber: res[0].line,
-                    filename: res[0].filename.clone(),
+        let func_map = self.functions.get(fun_name).ok_or_else(|| ParserError::UnknownOperation {
+            at: at(&res[0]),
+            token: fun_name.clone(),
+        })?;
+        let (funcname, goal_args_type) = match func_map.get(&args_types) {
+            Some(variant) => variant,
+            None if func_map.keys().all(|k| k.len() != args_types.len()) => {
+                // Report the closest arity
+                let expected = func_map.keys().map(|k| k.len())
+                    .min_by_key(|n| n.abs_diff(args_types.len())).unwrap_or(0);
+                return Err(ParserError::ArgumentCount {
+                    at: at(&res[0]), token: fun_name.clone(), expected, got: args_types.len(),
                 });
-
-                Ok(())
-            } else {
-                Err(ParserError(format!(
-                    "Arguments types don't match function: {}",
-                    fun_name
-                )))
             }
+            None => return Err(ParserError::ArgumentTypes { at: at(&res[0]), token: fun_name.clone() }),
+        }.clone();
+
+        let mut typed_args = Vec::new();
+        for (token, goal_type) in res.iter().skip(1).zip(&goal_args_type) {
+            match self.read_value(goal_type, token)? {
+                Some(typed_value) => typed_args.push(typed_value),
+                None => return Err(invalid(token, format!("Couldn't read {:?}", goal_type))),
+            }
+        }
+
+        let line = Line {
+            funcname,
+            typed_args,
+            linenumber: res[0].line,
+            filename: res[0].filename.clone(),
+        };
+        self.emit(line);
+        Ok(())
+    }
+
+    // Lines go to the code or, inside a .data section, after it
+    fn emit(&mut self, line: Line) {
+        if self.in_data {
+            self.data.push(line);
         } else {
-            Err(ParserError(format!("Function not found: {}", fun_name)))
+            self.out_queue.push(line);
         }
     }
 
-    fn read_value(&self, goal_type: &ValueType, value: &str) -> Result<Option<Value>, ParserError> {
-        match goal_type {
-            ValueType::MemCounter => Ok(Some(Value {
-                typ: *goal_type,
-                raw_value: value.to_string(),
-            })),
-            ValueType::Direction => Ok(Some(Value {
-                typ: *goal_type,
-                raw_value: value.to_string(),
-            })),
-            ValueType::Condition => Ok(Some(Value {
-                typ: *goal_type,
-                raw_value: value.to_string(),
-            })),
-            ValueType::UConstant => {
-                let parsed_value = value.parse::<u64>().map_err(|_| {
-                    ParserError("Couldn't parse unsigned constant".to_string())
-                })?;
-                if parsed_value < (1 << 64) {
-                    Ok(Some(Value {
-                        typ: *goal_type,
-                        raw_value: parsed_value.to_string(),
-                    }))
-                } else {
-                    Err(ParserError("UConstant out of range".to_string()))
+    // Data directives
+    //
+    //     .byte n...      one byte per value, from -128 to 255
+    //     .word n...      one 64-bit word per value, or the address of a label
+    //     .ascii "s"...   the bytes of the strings, without terminator
+    //     .space n        n zero bytes
+    //     .align n        zero bits up to the next multiple of n bits
+    //                     (.align 8 for a byte, .align 64 for a word)
+    //     .data [addr]    start a data section, at bit address addr if given
+    //     .text           back to the code
+    //
+    // The data is lowered to .const lines, and .align to an align line
+    // which the back ends pad from where it ends up. Data sections are moved after
+    // the code, and an address becomes an org line which the label back
+    // end pads up to.
+    //
+    //     .func f r... [n]    start function f, which saves r... and has n
+    //                         bits of locals (0 if not given)
+    //     .endfunc            end of the function
+    //
+    // .func becomes the label f and a func line with the registers and the
+    // locals size, which the pseudo-instruction pass turns into the
+    // prologue (see pseudo.rs).
+    fn handle_directive(&mut self, res: &[Token]) -> Result<Option<Vec<Line>>, ParserError> {
+        let directive = res[0].value.as_str();
+        let args = &res[1..];
+        let line = |funcname: &str, typed_args: Vec<Value>| Line {
+            funcname: funcname.to_string(),
+            typed_args,
+            linenumber: res[0].line,
+            filename: res[0].filename.clone(),
+        };
+        let constant = |width: u64, value: u64| line("const", vec![
+            Value::new(ValueType::UCONSTANT, width),
+            Value::new(ValueType::BINARY, value),
+        ]);
+        let numbers = || -> Result<Vec<(i128, &Token)>, ParserError> {
+            args.iter().map(|t| match t.typ {
+                LexType::NUMBER => t.value.parse::<i128>().map(|n| (n, t))
+                    .map_err(|_| invalid(t, format!("Couldn't parse number {}", t.value))),
+                _ => Err(invalid(t, format!(".{} expects numbers, got {}", directive, t.value))),
+            }).collect()
+        };
+
+        let lines = match directive {
+            "byte" => numbers()?.into_iter().map(|(n, t)| {
+                if !(-128..=255).contains(&n) {
+                    return Err(invalid(t, format!("Byte out of range: {}", n)));
+                }
+                Ok(constant(8, n as u8 as u64))
+            }).collect::<Result<Vec<_>, _>>()?,
+            "word" => args.iter().map(|t| match t.typ {
+                // Address of a label, filled in by the label back end
+                LexType::LABEL => Ok(line("constl", vec![
+                    Value::new(ValueType::UCONSTANT, 64),
+                    Value::new(ValueType::LABEL, self.label(&t.value)),
+                ])),
+                LexType::NUMBER => {
+                    let n = t.value.parse::<i128>()
+                        .map_err(|_| invalid(t, format!("Couldn't parse number {}", t.value)))?;
+                    if !(i64::MIN as i128..=u64::MAX as i128).contains(&n) {
+                        return Err(invalid(t, format!("Word out of range: {}", n)));
+                    }
+                    Ok(constant(64, n as u64))
                 }
+                _ => Err(invalid(t, format!(".word expects numbers or labels, got {}", t.value))),
+            }).collect::<Result<Vec<_>, _>>()?,
+            "ascii" => {
+                let mut lines = Vec::new();
+                for t in args {
+                    if t.typ != LexType::STRING {
+                        return Err(invalid(t, format!(".ascii expects strings, got {}", t.value)));
+                    }
+                    lines.extend(t.value.bytes().map(|b| constant(8, b as u64)));
+                }
+                lines
             }
-            ValueType::SConstant => {
-                let parsed_value = value.parse::<i64>().map_err(|_| {
-                    ParserError("Couldn't parse signed constant".to_string())
-                })?;
-                if parsed_value >= -(1 << 63) && parsed_value < (1 << 63) {
-                    Ok(Some(Value {
-                        typ: *goal_type,
-                        raw_value: parsed_value.to_string(),
-                    }))
-                } else {
-                    Err(ParserError("SConstant out of range".to_string()))
+            "space" => match numbers()?.as_slice() {
+                [(n, _)] if (0..=u32::MAX as i128).contains(n) => vec![constant(8 * *n as u64, 0)],
+                _ => return Err(invalid(&res[0], ".space expects a byte count".to_string())),
+            },
+            "align" => match numbers()?.as_slice() {
+                [(n, _)] if (1..=u32::MAX as i128).contains(n) => vec![line("align", vec![
+                    Value::new(ValueType::UCONSTANT, *n as u64),
+                ])],
+                _ => return Err(invalid(&res[0], ".align expects a positive number of bits".to_string())),
+            },
+            "data" => {
+                let address = numbers()?;
+                if address.len() > 1 || address.iter().any(|&(a, _)| a < 0) {
+                    return Err(invalid(&res[0], ".data expects at most one address".to_string()));
                 }
+                self.in_data = true;
+                address.iter().map(|&(a, _)| line("org", vec![
+                    Value::new(ValueType::AADDRESS, a as u64),
+                ])).collect()
             }
-            ValueType::RAddress => {
-                let parsed_value = value.parse::<i64>().map_err(|_| {
-                    ParserError("Couldn't parse relative address".to_string())
-                })?;
-                if parsed_value >= -(1 << 63) && parsed_value < (1 << 63) {
-                    Ok(Some(Value {
-                        typ: *goal_type,
-                        raw_value: parsed_value.to_string(),
-                    }))
-                } else {
-                    Err(ParserError("RAddress out of range".to_string()))
+            "func" => {
+                if self.in_data {
+                    return Err(invalid(&res[0], "functions cannot be in a data section".to_string()));
+                }
+                let Some((name, rest)) = args.split_first().filter(|(n, _)| n.typ == LexType::LABEL) else {
+                    return Err(invalid(&res[0], ".func expects a name".to_string()));
+                };
+                let (saved, locals) = match rest.split_last() {
+                    Some((n, saved)) if n.typ == LexType::NUMBER => (saved, self.read_value(&ValueType::UCONSTANT, n)?),
+                    _ => (rest, Some(Value::new(ValueType::UCONSTANT, 0))),
+                };
+                let mut typed_args = Vec::new();
+                for t in saved {
+                    if t.typ != LexType::REGISTER {
+                        return Err(invalid(t, format!(".func expects registers then a size, got {}", t.value)));
+                    }
+                    typed_args.extend(self.read_value(&ValueType::REGISTER, t)?);
                 }
+                typed_args.extend(locals);
+                vec![
+                    line("label", vec![Value::new(ValueType::LABEL, self.label(&name.value))]),
+                    line("func", typed_args),
+                ]
             }
-            ValueType::ShiftVal => {
+            "endfunc" => {
+                if !args.is_empty() {
+                    return Err(invalid(&args[0], ".endfunc takes no argument".to_string()));
+                }
+                vec![line("endfunc", vec![])]
+            }
+            "text" => {
+                if !args.is_empty() {
+                    return Err(invalid(&args[0], ".text takes no argument".to_string()));
+                }
+                self.in_data = false;
+                Vec::new()
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(lines))
+    }
+
+    // Value of a token as the back ends take it: the code of a pointer,
+    // direction or condition, the number of a label, the bits of a binary
+    // constant, and numbers in two's complement
+    fn read_value(&mut self, goal_type: &ValueType, token: &Token) -> Result<Option<Value>, ParserError> {
+        let value = token.value.as_str();
+        let raw_value = match goal_type {
+            ValueType::MEMCOUNTER => match pointer(value) {
+                Some(code) => code,
+                None => return Ok(None),
+            },
+            ValueType::DIRECTION => match DIRECTIONS.iter().position(|&d| d == value) {
+                Some(code) => code as u64,
+                None => return Ok(None),
+            },
+            ValueType::CONDITION => match condition(value) {
+                Some(code) => code,
+                None => return Ok(None),
+            },
+            // Immediates of the logical operations are unsigned, those of
+            // add, sub, cmp and let are signed
+            ValueType::UCONSTANT => {
+                if value.starts_with('-') {
+                    return Err(invalid(token, format!("{} is negative, but this constant is unsigned", value)));
+                }
+                value.parse::<u64>().map_err(|_| {
+                    invalid(token, format!("unsigned constant out of range 0..{}", u64::MAX))
+                })?
+            }
+            ValueType::SCONSTANT => {
+                value.parse::<i64>().map_err(|_| {
+                    invalid(token, format!("signed constant out of range {}..{}", i64::MIN, i64::MAX))
+                })? as u64
+            }
+            ValueType::RADDRESS => {
+                value.parse::<i64>().map_err(|_| {
+                    invalid(token, "Couldn't parse relative address".to_string())
+                })? as u64
+            }
+            ValueType::AADDRESS => {
+                value.parse::<u64>().map_err(|_| {
+                    invalid(token, "Couldn't parse absolute address".to_string())
+                })?
+            }
+            ValueType::SHIFTVAL => {
                 let parsed_value = value.parse::<u64>().map_err(|_| {
-                    ParserError("Couldn't parse shift value".to_string())
+                    invalid(token, "Couldn't parse shift value".to_string())
                 })?;
-                if parsed_value < (1 << 6) {
-                    Ok(Some(Value {
-                        typ: *goal_type,
-                        raw_value: parsed_value.to_string(),
-                    }))
-                } else {
-                    Err(ParserError("ShiftVal out of range".to_string()))
+                if parsed_value >= (1 << 6) {
+                    return Err(invalid(token, "ShiftVal out of range".to_string()));
                 }
+                parsed_value
             }
-            ValueType::Size => {
+            ValueType::SIZE => {
                 let parsed_value = value.parse::<u64>().map_err(|_| {
-                    ParserError("Couldn't parse size value".to_string())
+                    invalid(token, "Couldn't parse size value".to_string())
                 })?;
                 let valid_sizes = [1, 4, 8, 16, 32, 64];
-                if valid_sizes.contains(&parsed_value) {
-                    Ok(Some(Value {
-                        typ: *goal_type,
-                        raw_value: parsed_value.to_string(),
-                    }))
-                } else {
-                    Err(ParserError("Size out of range".to_string()))
+                if !valid_sizes.contains(&parsed_value) {
+                    return Err(invalid(token, "Size out of range".to_string()));
                 }
+                parsed_value
             }
-            ValueType::Register => {
+            ValueType::REGISTER => {
                 let parsed_value = value.parse::<u64>().map_err(|_| {
-                    ParserError("Couldn't parse register value".to_string())
+                    invalid(token, "Couldn't parse register value".to_string())
                 })?;
-                if parsed_value < NB_REG as u64 {
-                    Ok(Some(Value {
-                        typ: *goal_type,
-                        raw_value: parsed_value.to_string(),
-                    }))
-                } else {
-                    Err(ParserError("Register out of range".to_string()))
+                if parsed_value >= NB_REG as u64 {
+                    return Err(invalid(token, "Register out of range".to_string()));
                 }
+                parsed_value
             }
-            ValueType::Label => Ok(Some(Value {
-                typ: *goal_type,
-                raw_value: value.to_string(),
-            })),
-            ValueType::Binary => Ok(Some(Value {
-                typ: *goal_type,
-                raw_value: value[1..].to_string(),
-            })),
-        }
+            ValueType::LABEL => self.label(value),
+            ValueType::BINARY => {
+                let bits = value[1..].trim_start_matches('0');
+                if bits.len() > 64 {
+                    return Err(invalid(token, "Binary constant wider than 64 bits".to_string()));
+                }
+                u64::from_str_radix(bits, 2).unwrap_or(0)
+            }
+        };
+        Ok(Some(Value::new(*goal_type, raw_value)))
     }
 }
 
@@ -351,14 +461,28 @@ fn inv_dict_list(
     inv_map
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut lexer_gen: Box<dyn Iterator<Item = Token>> = Box::new(vec![].into_iter());
-    let possible_transitions = HashMap::new(); 
-    let asr_specs = HashMap::new();
-    let types_specs = HashMap::new(); 
-
-    let mut parser = Parser::new(&mut lexer_gen, &possible_transitions, &asr_specs, &types_specs);
+impl<'a> Iterator for Parser<'a> {
+    type Item = Result<Line, ParserError>;
 
-    parser.run()?;
-    Ok(())
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(line) = self.out_queue.pop() {
+                return Some(Ok(line));
+            }
+            if self.ended {
+                return None;
+            }
+            match self.next_line() {
+                Some(Ok(())) => {}
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    // The data sections follow the code
+                    self.ended = true;
+                    for line in self.data.drain(..) {
+                        self.out_queue.push(line);
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,475 @@
+use std::fmt;
+use minimisa_core::condition;
+use crate::enums::{Line, Value, ValueType, NB_REG};
+use crate::errors::{Diagnostic, Location};
+
+type VT = ValueType;
+
+// Stack frames
+//
+// The stack grows towards lower addresses and r7 is the frame pointer.
+// After `call f` and `enter n` at the start of f, the frame looks like:
+//
+//     fp + 64   return address (pushed by call)
+//     fp        saved frame pointer of the caller
+//     fp - n    n bits of locals, sp points here
+//
+// `leave` restores sp and the caller's frame pointer, so `return` finds
+// the return address on top of the stack.
+//
+// Functions
+//
+//     .func f r1 r2 64
+//         body
+//     .endfunc
+//
+// declares f with the callee-saved registers r1 and r2 and 64 bits of
+// locals. The start of f pushes the registers then does `enter 64`, and
+// every `return` in the body does `leave`, pops the registers back and
+// returns. The saved registers lie between the return address and fp.
+// Once a program declares functions, `return` outside of them is an error,
+// and so are `enter` and `leave` inside them.
+
+pub const FRAME_POINTER: u64 = 7;
+const CTR_SP: u64 = 1;
+const CTR_A0: u64 = 2;
+
+// Clock
+//
+// `rdcycles r` loads the simulated time of the emulator in cycles and
+// `rdtime r` the host time in milliseconds, from the clock registers of its
+// I/O window (emu/include/devices.rs). They read through a0, which is saved
+// in a temporary and restored. The addresses assume the default segment
+// sizes of the emulator.
+
+/// Address of the cycle counter, then of the millisecond clock
+pub const CLOCK_CYCLES_ADDRESS: u64 = 0xfd50;
+pub const CLOCK_MILLIS_ADDRESS: u64 = 0xfd90;
+
+// Scratch registers
+//
+// Some expansions need temporaries. Each pseudo-instruction declares how
+// many registers it clobbers and they are picked among the candidates:
+// the scratch registers given with PseudoOptions, or else every register
+// the program never mentions (r7 excepted). Registers used as operands of
+// the pseudo-instruction itself are never picked. With TempPolicy::Random
+// the choice is shuffled from a seed, to shake out code that silently
+// relies on a particular temporary.
+
+/// How temporaries are chosen among the candidates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TempPolicy {
+    #[default]
+    Fixed,          // Highest free registers first
+    Random(u64),    // Seeded pseudo-random choice, reproducible
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PseudoOptions {
+    pub temps: TempPolicy,
+    pub scratch: Option<Vec<u64>>,  // Registers the program gives away
+}
+
+#[derive(Debug)]
+pub struct PseudoError {
+    pub at: Location,
+    pub token: String,      // The pseudo-instruction
+    pub msg: String,
+}
+
+impl fmt::Display for PseudoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Diagnostic for PseudoError {
+    fn location(&self) -> &Location {
+        &self.at
+    }
+
+    fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl std::error::Error for PseudoError {}
+
+// Conditional branches
+//
+// `beq label` and friends stand for `jump <condition> label`, with the
+// condition in the name. The signed comparisons are bsgt and bslt; bz, bnz,
+// bc and bnc test the flags under their usual names.
+
+/// Branch pseudo-instructions and the condition they jump on
+pub const BRANCH_ALIASES: [(&str, &str); 12] = [
+    ("beq", "eq"), ("bne", "neq"), ("bsgt", "sgt"), ("bslt", "slt"),
+    ("bgt", "gt"), ("bge", "ge"), ("blt", "lt"), ("bv", "v"),
+    ("bz", "eq"), ("bnz", "neq"), ("bc", "lt"), ("bnc", "ge"),
+];
+
+/// Number of temporaries clobbered by each pseudo-instruction
+pub fn pseudo_temps(funcname: &str) -> Option<usize> {
+    match funcname {
+        "enter" | "leave" | "func" | "endfunc" => Some(0),
+        "nop" | "mov" | "not" | "neg" | "inc" | "dec" => Some(0),
+        "swap" | "rdcycles" | "rdtime" => Some(1),
+        _ if BRANCH_ALIASES.iter().any(|(alias, _)| *alias == funcname) => Some(0),
+        _ => None,
+    }
+}
+
+struct TempAllocator {
+    candidates: Vec<u64>,
+    policy: TempPolicy,
+    state: u64,
+}
+
+impl TempAllocator {
+    fn new(scan: &PseudoScan, options: &PseudoOptions) -> Self {
+        let candidates = match &options.scratch {
+            Some(regs) => regs.clone(),
+            None => (0..NB_REG as u64).rev().filter(|&r| r != FRAME_POINTER && !scan.used[r as usize]).collect(),
+        };
+        let state = match options.temps {
+            TempPolicy::Random(seed) => seed | 1,
+            TempPolicy::Fixed => 0,
+        };
+        TempAllocator { candidates, policy: options.temps, state }
+    }
+
+    // xorshift64, enough to spread the choices
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn allocate(&mut self, l: &Line, count: usize) -> Result<Vec<u64>, PseudoError> {
+        let mut free: Vec<u64> = self.candidates.iter().copied()
+            .filter(|&r| !l.typed_args.iter().any(|a| a.typ == VT::REGISTER && a.raw_value == r))
+            .collect();
+
+        if free.len() < count {
+            return Err(PseudoError {
+                at: Location::of_line(l),
+                token: l.funcname.clone(),
+                msg: format!("'{}' needs {} scratch register(s) but {} are free; \
+                    declare some with the scratch registers option", l.funcname, count, free.len()),
+            });
+        }
+
+        if let TempPolicy::Random(_) = self.policy {
+            for i in (1..free.len()).rev() {
+                let j = (self.next_random() % (i as u64 + 1)) as usize;
+                free.swap(i, j);
+            }
+        }
+        free.truncate(count);
+        Ok(free)
+    }
+}
+
+fn line(funcname: &str, args: Vec<Value>, from: &Line) -> Line {
+    Line::new(funcname.to_string(), args, from.linenumber, from.filename.clone())
+}
+
+fn reg(n: u64) -> Value {
+    Value::new(VT::REGISTER, n)
+}
+
+fn error(l: &Line, msg: String) -> PseudoError {
+    PseudoError { at: Location::of_line(l), token: l.funcname.clone(), msg }
+}
+
+// enter n: push 64 r7; getctr sp r7; sub2i r7 n; setctr sp r7; add2i r7 n
+fn expand_enter(locals: u64, l: &Line, out: &mut Vec<Line>) {
+    let fp = FRAME_POINTER;
+
+    out.push(line("push", vec![Value::new(VT::SIZE, 64), reg(fp)], l));
+    out.push(line("getctr", vec![Value::new(VT::MEMCOUNTER, CTR_SP), reg(fp)], l));
+    if locals != 0 {
+        out.push(line("sub2i", vec![reg(fp), Value::new(VT::SCONSTANT, locals)], l));
+        out.push(line("setctr", vec![Value::new(VT::MEMCOUNTER, CTR_SP), reg(fp)], l));
+        out.push(line("add2i", vec![reg(fp), Value::new(VT::SCONSTANT, locals)], l));
+    }
+}
+
+// leave: setctr sp r7; pop 64 r7
+fn expand_leave(l: &Line, out: &mut Vec<Line>) {
+    let fp = FRAME_POINTER;
+    out.push(line("setctr", vec![Value::new(VT::MEMCOUNTER, CTR_SP), reg(fp)], l));
+    out.push(line("pop", vec![Value::new(VT::SIZE, 64), reg(fp)], l));
+}
+
+// swap ra rb: let t ra; let ra rb; let rb t
+fn expand_swap(l: &Line, temps: &[u64], out: &mut Vec<Line>) {
+    let (a, b, t) = (l.typed_args[0].raw_value, l.typed_args[1].raw_value, temps[0]);
+    out.push(line("let", vec![reg(t), reg(a)], l));
+    out.push(line("let", vec![reg(a), reg(b)], l));
+    out.push(line("let", vec![reg(b), reg(t)], l));
+}
+
+// rdcycles r: getctr a0 t; leti r address; setctr a0 r; readze a0 64 r; setctr a0 t
+fn expand_clock(l: &Line, address: u64, temps: &[u64], out: &mut Vec<Line>) {
+    let (r, t) = (l.typed_args[0].raw_value, temps[0]);
+    let a0 = || Value::new(VT::MEMCOUNTER, CTR_A0);
+    out.push(line("getctr", vec![a0(), reg(t)], l));
+    out.push(line("leti", vec![reg(r), Value::new(VT::SCONSTANT, address)], l));
+    out.push(line("setctr", vec![a0(), reg(r)], l));
+    out.push(line("readze", vec![a0(), Value::new(VT::SIZE, 64), reg(r)], l));
+    out.push(line("setctr", vec![a0(), reg(t)], l));
+}
+
+// All ones, to flip every bit with xor3i
+const ONES: u64 = u64::MAX;
+
+// nop: let r0 r0, which leaves the flags alone
+// mov rd rs: let rd rs
+// not r: xor3i r r -1
+// neg r: xor3i r r -1; add2i r 1
+// inc r: add2i r 1
+// dec r: sub2i r 1
+fn expand_simple(l: &Line, out: &mut Vec<Line>) {
+    let args = &l.typed_args;
+    let one = || Value::new(VT::SCONSTANT, 1);
+    let ones = |r| line("xor3i", vec![reg(r), reg(r), Value::new(VT::UCONSTANT, ONES)], l);
+
+    match l.funcname.as_str() {
+        "nop" => out.push(line("let", vec![reg(0), reg(0)], l)),
+        "mov" => out.push(line("let", args.clone(), l)),
+        "not" => out.push(ones(args[0].raw_value)),
+        "neg" => {
+            out.push(ones(args[0].raw_value));
+            out.push(line("add2i", vec![args[0].clone(), one()], l));
+        }
+        "inc" => out.push(line("add2i", vec![args[0].clone(), one()], l)),
+        "dec" => out.push(line("sub2i", vec![args[0].clone(), one()], l)),
+        _ => unreachable!(),
+    }
+}
+
+// beq label: jump eq label, and so on
+fn expand_branch(l: &Line, cond: &str, out: &mut Vec<Line>) {
+    let code = condition(cond).unwrap();
+    out.push(line("jumpifl", vec![Value::new(VT::CONDITION, code), l.typed_args[0].clone()], l));
+}
+
+// func r... n: push 64 r...; enter n
+fn expand_func(l: &Line, out: &mut Vec<Line>) -> Result<(), PseudoError> {
+    let (locals, saved) = l.typed_args.split_last().unwrap();
+    for (i, r) in saved.iter().enumerate() {
+        if r.raw_value == FRAME_POINTER {
+            return Err(error(l, format!("r{} is the frame pointer, every function saves it", FRAME_POINTER)));
+        }
+        if saved[..i].iter().any(|s| s.raw_value == r.raw_value) {
+            return Err(error(l, format!("r{} is saved twice", r.raw_value)));
+        }
+        out.push(line("push", vec![Value::new(VT::SIZE, 64), r.clone()], l));
+    }
+    expand_enter(locals.raw_value, l, out);
+    Ok(())
+}
+
+// return in a function: leave; pop 64 r... in reverse; return
+fn expand_return(func: &Line, l: &Line, out: &mut Vec<Line>) {
+    let (_, saved) = func.typed_args.split_last().unwrap();
+    expand_leave(l, out);
+    for r in saved.iter().rev() {
+        out.push(line("pop", vec![Value::new(VT::SIZE, 64), r.clone()], l));
+    }
+    out.push(line("return", vec![], l));
+}
+
+/// What the expansion needs to know of the whole program, gathered by a
+/// first pass over its lines
+#[derive(Debug, Clone, Default)]
+pub struct PseudoScan {
+    used: [bool; NB_REG],       // Registers of real instructions
+    has_functions: bool,
+}
+
+impl PseudoScan {
+    pub fn add(&mut self, l: &Line) {
+        self.has_functions |= l.funcname == "func";
+        if pseudo_temps(&l.funcname).is_none() {
+            for arg in l.typed_args.iter().filter(|a| a.typ == VT::REGISTER) {
+                self.used[arg.raw_value as usize % NB_REG] = true;
+            }
+        }
+    }
+}
+
+/// Expansion of pseudo-instructions a line at a time, for programs that
+/// are not held in memory whole. The scan must cover the whole program
+pub struct PseudoExpander {
+    temps: TempAllocator,
+    has_functions: bool,
+    function: Option<Line>,     // .func line of the function being expanded
+}
+
+impl PseudoExpander {
+    pub fn new(scan: &PseudoScan, options: &PseudoOptions) -> Self {
+        PseudoExpander { temps: TempAllocator::new(scan, options), has_functions: scan.has_functions, function: None }
+    }
+
+    /// Append the expansion of a line
+    pub fn expand_line(&mut self, l: Line, out: &mut Vec<Line>) -> Result<(), PseudoError> {
+        match (l.funcname.as_str(), &self.function) {
+            ("func", Some(_)) => return Err(error(&l, "functions cannot be nested".to_string())),
+            ("func", None) => {
+                expand_func(&l, out)?;
+                self.function = Some(l);
+            }
+            ("endfunc", None) => return Err(error(&l, ".endfunc without .func".to_string())),
+            ("endfunc", Some(_)) => self.function = None,
+            ("return", Some(func)) => expand_return(func, &l, out),
+            ("return", None) if self.has_functions =>
+                return Err(error(&l, "return outside of a function".to_string())),
+            ("enter" | "leave", Some(_)) =>
+                return Err(error(&l, format!("'{}' in a function, which sets up its frame already", l.funcname))),
+            ("enter", None) => expand_enter(l.typed_args[0].raw_value, &l, out),
+            ("leave", None) => expand_leave(&l, out),
+            ("swap", _) => {
+                let t = self.temps.allocate(&l, 1)?;
+                expand_swap(&l, &t, out);
+            }
+            ("rdcycles" | "rdtime", _) => {
+                let t = self.temps.allocate(&l, 1)?;
+                let address = if l.funcname == "rdcycles" { CLOCK_CYCLES_ADDRESS } else { CLOCK_MILLIS_ADDRESS };
+                expand_clock(&l, address, &t, out);
+            }
+            ("nop" | "mov" | "not" | "neg" | "inc" | "dec", _) => expand_simple(&l, out),
+            (name, _) => match BRANCH_ALIASES.iter().find(|(alias, _)| *alias == name) {
+                Some((_, cond)) => expand_branch(&l, cond, out),
+                None => out.push(l),
+            },
+        }
+        Ok(())
+    }
+
+    /// Check the end of the program
+    pub fn finish(&mut self) -> Result<(), PseudoError> {
+        match self.function.take() {
+            Some(func) => Err(error(&func, "function has no .endfunc".to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Replace pseudo-instructions with the real instructions they stand for.
+/// Expanded lines keep the line number of the pseudo-instruction.
+pub fn expand_pseudo(lines: Vec<Line>, options: &PseudoOptions) -> Result<Vec<Line>, PseudoError> {
+    let mut scan = PseudoScan::default();
+    for l in &lines {
+        scan.add(l);
+    }
+    let mut expander = PseudoExpander::new(&scan, options);
+    let mut out = Vec::with_capacity(lines.len());
+    for l in lines {
+        expander.expand_line(l, &mut out)?;
+    }
+    expander.finish()?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn src(funcname: &str, args: Vec<Value>) -> Line {
+        Line::new(funcname.to_string(), args, 1, "test.s".to_string())
+    }
+
+    // Expanded lines as (funcname, raw values)
+    fn expand(lines: Vec<Line>) -> Vec<(String, Vec<u64>)> {
+        expand_pseudo(lines, &PseudoOptions::default()).unwrap().into_iter()
+            .map(|l| (l.funcname, l.typed_args.iter().map(|a| a.raw_value).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_simple_pseudo() {
+        let out = expand(vec![
+            src("nop", vec![]),
+            src("mov", vec![reg(1), reg(2)]),
+            src("not", vec![reg(3)]),
+            src("neg", vec![reg(4)]),
+            src("inc", vec![reg(5)]),
+            src("dec", vec![reg(6)]),
+        ]);
+        let expected: Vec<(String, Vec<u64>)> = [
+            ("let", vec![0, 0]),
+            ("let", vec![1, 2]),
+            ("xor3i", vec![3, 3, ONES]),
+            ("xor3i", vec![4, 4, ONES]),
+            ("add2i", vec![4, 1]),
+            ("add2i", vec![5, 1]),
+            ("sub2i", vec![6, 1]),
+        ].into_iter().map(|(f, a)| (f.to_string(), a)).collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_branch_aliases() {
+        let label = Value::new(VT::LABEL, 12);
+        let out = expand(vec![
+            src("bge", vec![label.clone()]),
+            src("bnz", vec![label.clone()]),
+            src("bslt", vec![label]),
+        ]);
+        let conditions: Vec<u64> = out.iter().map(|(f, a)| {
+            assert_eq!((f.as_str(), a[1]), ("jumpifl", 12));
+            a[0]
+        }).collect();
+        assert_eq!(conditions, [condition("ge").unwrap(), condition("neq").unwrap(), condition("slt").unwrap()]);
+
+        // Every alias names a real condition
+        assert!(BRANCH_ALIASES.iter().all(|(_, cond)| condition(cond).is_some()));
+    }
+
+    #[test]
+    fn test_functions() {
+        let locals = |n| Value::new(VT::UCONSTANT, n);
+        let out = expand(vec![
+            src("func", vec![reg(1), reg(2), locals(0)]),
+            src("return", vec![]),
+            src("endfunc", vec![]),
+        ]);
+        let names: Vec<&str> = out.iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(names, ["push", "push", "push", "getctr", "setctr", "pop", "pop", "pop", "return"]);
+        // Saved registers are popped in reverse, after the frame pointer
+        assert_eq!(out[1].1, [64, 2]);
+        assert_eq!(out[6].1, [64, 2]);
+        assert_eq!(out[7].1, [64, 1]);
+
+        let bad = |lines: Vec<Line>| expand_pseudo(lines, &PseudoOptions::default()).unwrap_err().msg;
+        assert_eq!(bad(vec![src("func", vec![locals(8)])]), "function has no .endfunc");
+        assert_eq!(bad(vec![src("endfunc", vec![])]), ".endfunc without .func");
+        assert_eq!(bad(vec![src("func", vec![reg(7), locals(0)]), src("endfunc", vec![])]),
+            "r7 is the frame pointer, every function saves it");
+        assert_eq!(bad(vec![
+            src("func", vec![locals(0)]), src("endfunc", vec![]), src("return", vec![]),
+        ]), "return outside of a function");
+
+        // Without functions, return is a plain instruction
+        assert_eq!(expand(vec![src("return", vec![])]), [("return".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn test_clock() {
+        let out = expand(vec![src("rdcycles", vec![reg(1)]), src("rdtime", vec![reg(2)])]);
+        // r7 and the registers in use are never temporaries
+        let expected: Vec<(String, Vec<u64>)> = [
+            ("getctr", vec![CTR_A0, 6]),
+            ("leti", vec![1, CLOCK_CYCLES_ADDRESS]),
+            ("setctr", vec![CTR_A0, 1]),
+            ("readze", vec![CTR_A0, 64, 1]),
+            ("setctr", vec![CTR_A0, 6]),
+        ].into_iter().map(|(f, a)| (f.to_string(), a)).collect();
+        assert_eq!(out[..5], expected);
+        assert_eq!(out[6].1, [2, CLOCK_MILLIS_ADDRESS]);
+        assert_eq!(CLOCK_MILLIS_ADDRESS, CLOCK_CYCLES_ADDRESS + 64);
+    }
+}
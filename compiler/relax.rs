@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::fmt;
+use minimisa_core::bitvec::BitVec;
+use minimisa_core::{ADDRESS_WIDTHS, CONST_WIDTHS, PREFIXES};
+
+// Label relaxation
+//
+// The label back end turns a program into fragments: fixed bits, label
+// definitions, origins (.data addr), alignments (.align n) and references
+// to labels. The width of
+// a reference depends on the distance to its label, which depends on the
+// width of the references in between. relax() finds widths that fit:
+//
+//  - every reference starts on its smallest width, and references that do
+//    not fit grow one width at a time until none has to. Widths only grow
+//    in this phase, so it ends;
+//  - then every reference is shrunk back one width at a time as long as
+//    the whole program still fits, since growing a reference may have made
+//    others larger than needed.
+//
+// Code that runs into an origin while references are growing is let
+// through: shrinking may make room again. Only the final layout must not
+// overlap.
+//
+// Offsets are in bits from address 0.
+
+/// How the value of a label reference is computed and encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefKind {
+    /// Offset from the end of the reference to the label, as an address
+    /// (jumpl, jumpifl, calll)
+    Relative,
+    /// Address of the label, as an address
+    Absolute,
+    /// Address of the label, as a signed constant (letil)
+    Constant,
+    /// Address of the label on a fixed number of bits, without prefix
+    /// (constl)
+    Raw(u32),
+}
+
+impl RefKind {
+    /// Widths the value can be encoded on, smallest first
+    fn widths(self) -> &'static [u32] {
+        match self {
+            RefKind::Relative | RefKind::Absolute => &ADDRESS_WIDTHS,
+            RefKind::Constant => &CONST_WIDTHS,
+            RefKind::Raw(_) => &[0],
+        }
+    }
+
+    fn width(self, index: usize) -> u32 {
+        match self {
+            RefKind::Raw(width) => width,
+            _ => self.widths()[index],
+        }
+    }
+
+    /// Size in bits, prefix included, on the width of the given index
+    fn size(self, index: usize) -> u64 {
+        match self {
+            RefKind::Raw(width) => width as u64,
+            _ => (PREFIXES[index].1 + self.widths()[index]) as u64,
+        }
+    }
+
+    fn fits(self, value: i64, index: usize) -> bool {
+        let width = self.width(index);
+        match self {
+            RefKind::Raw(_) => value >= 0 && (width >= 64 || value < 1 << width),
+            _ => width >= 64 || (value >= -(1 << (width - 1)) && value < 1 << (width - 1)),
+        }
+    }
+
+    fn encode(self, value: i64, index: usize) -> BitVec {
+        let mut bits = BitVec::new();
+        if let RefKind::Raw(width) = self {
+            bits.push(value as u64, width);
+        } else {
+            bits.push(PREFIXES[index].0, PREFIXES[index].1);
+            bits.push(value as u64, self.widths()[index]);
+        }
+        bits
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fragment {
+    /// Bits that do not depend on labels
+    Bits(BitVec),
+    Label(u64),
+    /// The next fragment starts at this address, after zero padding.
+    /// `line` is the index of the source line, for errors
+    Org { address: u64, line: usize },
+    /// The next fragment starts at the next multiple of this many bits,
+    /// after zero padding
+    Align(u64),
+    Ref { kind: RefKind, label: u64, line: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelaxError {
+    UndefinedLabel { line: usize, label: u64 },
+    /// The value does not fit on the largest width of the reference
+    OutOfRange { line: usize, label: u64, value: i64 },
+    /// An origin lies before the end of the fragments preceding it
+    Overlap { line: usize, address: u64, end: u64 },
+}
+
+impl RelaxError {
+    pub fn line(&self) -> usize {
+        match self {
+            RelaxError::UndefinedLabel { line, .. }
+            | RelaxError::OutOfRange { line, .. }
+            | RelaxError::Overlap { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for RelaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelaxError::UndefinedLabel { label, .. } => write!(f, "undefined label {}", label),
+            RelaxError::OutOfRange { label, value, .. } =>
+                write!(f, "address {} of label {} does not fit in the instruction", value, label),
+            RelaxError::Overlap { address, end, .. } =>
+                write!(f, "data at {:#x} overlaps the code before it, which ends at {:#x}", address, end),
+        }
+    }
+}
+
+/// Placement of the fragments once widths are resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// Start of every fragment, then the end of the program. The padding
+    /// before an origin or an alignment is the size of its fragment
+    pub offsets: Vec<u64>,
+    /// Width index of every reference, 0 for other fragments
+    widths: Vec<usize>,
+    labels: HashMap<u64, u64>,
+    /// First origin that the code before it runs into
+    overlap: Option<RelaxError>,
+}
+
+impl Layout {
+    /// Size of fragment i in bits
+    pub fn size(&self, i: usize) -> u64 {
+        self.offsets[i + 1] - self.offsets[i]
+    }
+
+    pub fn label(&self, label: u64) -> Option<u64> {
+        self.labels.get(&label).copied()
+    }
+
+    /// Bits of fragment i
+    pub fn bits(&self, fragments: &[Fragment], i: usize) -> BitVec {
+        match &fragments[i] {
+            Fragment::Bits(bits) => bits.clone(),
+            Fragment::Label(_) => BitVec::new(),
+            Fragment::Org { .. } | Fragment::Align(_) => BitVec::zeros(self.size(i)),
+            Fragment::Ref { kind, label, .. } => {
+                let value = ref_value(*kind, self.labels[label], self.offsets[i + 1]);
+                kind.encode(value, self.widths[i])
+            }
+        }
+    }
+}
+
+/// Zero bits from an offset up to the next multiple of `align` bits
+pub fn align_padding(offset: u64, align: u64) -> u64 {
+    offset.next_multiple_of(align) - offset
+}
+
+fn ref_value(kind: RefKind, label: u64, end: u64) -> i64 {
+    match kind {
+        RefKind::Relative => label as i64 - end as i64,
+        _ => label as i64,
+    }
+}
+
+// Offsets and label addresses for the given widths
+fn layout(fragments: &[Fragment], widths: &[usize]) -> Layout {
+    let mut offsets = Vec::with_capacity(fragments.len() + 1);
+    let mut labels = HashMap::new();
+    let mut overlap = None;
+    let mut offset = 0;
+
+    for (i, fragment) in fragments.iter().enumerate() {
+        match fragment {
+            Fragment::Bits(bits) => {
+                offsets.push(offset);
+                offset += bits.len();
+            }
+            Fragment::Label(label) => {
+                offsets.push(offset);
+                labels.insert(*label, offset);
+            }
+            Fragment::Org { address, line } => {
+                if offset > *address && overlap.is_none() {
+                    overlap = Some(RelaxError::Overlap { line: *line, address: *address, end: offset });
+                }
+                offsets.push(offset);
+                offset = offset.max(*address);
+            }
+            Fragment::Align(align) => {
+                offsets.push(offset);
+                offset += align_padding(offset, *align);
+            }
+            Fragment::Ref { kind, .. } => {
+                offsets.push(offset);
+                offset += kind.size(widths[i]);
+            }
+        }
+    }
+    offsets.push(offset);
+    Layout { offsets, widths: widths.to_vec(), labels, overlap }
+}
+
+// References that do not fit in a layout, as (fragment, value)
+fn misfits(fragments: &[Fragment], layout: &Layout) -> Result<Vec<(usize, i64)>, RelaxError> {
+    let mut out = Vec::new();
+    for (i, fragment) in fragments.iter().enumerate() {
+        if let Fragment::Ref { kind, label, line } = fragment {
+            let address = layout.label(*label)
+                .ok_or(RelaxError::UndefinedLabel { line: *line, label: *label })?;
+            let value = ref_value(*kind, address, layout.offsets[i + 1]);
+            if !kind.fits(value, layout.widths[i]) {
+                out.push((i, value));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Resolve the widths of all references
+pub fn relax(fragments: &[Fragment]) -> Result<Layout, RelaxError> {
+    let mut widths = vec![0; fragments.len()];
+
+    // Grow the references that do not fit
+    loop {
+        let misfits = misfits(fragments, &layout(fragments, &widths))?;
+        if misfits.is_empty() {
+            break;
+        }
+        for (i, value) in misfits {
+            let Fragment::Ref { kind, label, line } = &fragments[i] else { unreachable!() };
+            if widths[i] + 1 >= kind.widths().len() {
+                return Err(RelaxError::OutOfRange { line: *line, label: *label, value });
+            }
+            widths[i] += 1;
+        }
+    }
+
+    // Shrink them back while everything fits
+    let mut shrunk = true;
+    while shrunk {
+        shrunk = false;
+        for i in 0..fragments.len() {
+            if widths[i] == 0 {
+                continue;
+            }
+            widths[i] -= 1;
+            let candidate = layout(fragments, &widths);
+            let fits = candidate.overlap.is_none() && misfits(fragments, &candidate)?.is_empty();
+            if fits {
+                shrunk = true;
+            } else {
+                widths[i] += 1;
+            }
+        }
+    }
+
+    let layout = layout(fragments, &widths);
+    match layout.overlap {
+        Some(overlap) => Err(overlap),
+        None => Ok(layout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minimisa_core::to_bits;
+
+    fn bits(n: u64) -> Fragment {
+        Fragment::Bits(BitVec::zeros(n))
+    }
+
+    fn jump(label: u64) -> Fragment {
+        Fragment::Ref { kind: RefKind::Relative, label, line: 0 }
+    }
+
+    fn encoded(fragments: &[Fragment]) -> Vec<String> {
+        let layout = relax(fragments).unwrap();
+        (0..fragments.len()).map(|i| layout.bits(fragments, i).to_string()).collect()
+    }
+
+    #[test]
+    fn test_forward_backward() {
+        // Backward jump to itself, as in "jump -13": 4 bits of opcode and
+        // 9 of address
+        let code = [Fragment::Label(1), Fragment::Bits("1010".parse().unwrap()), jump(1)];
+        assert_eq!(encoded(&code)[2], "0".to_string() + &to_bits(&[(-13i64 as u64, 8)]));
+
+        // Forward jumps: 127 bits still fit on 8 bits, 128 do not
+        let code = [jump(1), bits(127), Fragment::Label(1)];
+        assert_eq!(relax(&code).unwrap().size(0), 9);
+        let code = [jump(1), bits(128), Fragment::Label(1)];
+        let layout = relax(&code).unwrap();
+        assert_eq!(layout.size(0), 18);
+        assert_eq!(layout.bits(&code, 0).to_string(), "10".to_string() + &to_bits(&[(128, 16)]));
+        assert_eq!(layout.label(1), Some(146));
+    }
+
+    #[test]
+    fn test_overlapping() {
+        // A jumps forward over B, which jumps backward over A: both fit on
+        // 8 bits, just
+        let crossed = |tail: u64| [
+            Fragment::Label(1), bits(10), jump(2), bits(100), jump(1), bits(tail), Fragment::Label(2),
+        ];
+        let code = crossed(18);
+        let layout = relax(&code).unwrap();
+        assert_eq!((layout.size(2), layout.size(4)), (9, 9));
+        assert_eq!(layout.bits(&code, 4).to_string(), "0".to_string() + &to_bits(&[(-128i64 as u64, 8)]));
+
+        // One more bit and A grows, which takes B out of range too
+        let code = crossed(19);
+        let layout = relax(&code).unwrap();
+        assert_eq!((layout.size(2), layout.size(4)), (18, 18));
+        assert_eq!(layout.bits(&code, 2).to_string(), "10".to_string() + &to_bits(&[(137, 16)]));
+        assert_eq!(layout.bits(&code, 4).to_string(), "10".to_string() + &to_bits(&[(-146i64 as u64, 16)]));
+    }
+
+    #[test]
+    fn test_shrink() {
+        // A jumps to data at a fixed address: the more code before it, the
+        // shorter the jump. Both jumps grow in the first pass, running into
+        // the data, then A fits again on 8 bits once B has grown
+        let code = [
+            jump(3), jump(2), bits(119), Fragment::Label(3),
+            Fragment::Org { address: 150, line: 0 }, Fragment::Label(2),
+        ];
+        let layout = relax(&code).unwrap();
+        assert_eq!((layout.size(0), layout.size(1)), (18, 9));
+        assert_eq!(layout.bits(&code, 1).to_string(), "0".to_string() + &to_bits(&[(123, 8)]));
+        assert_eq!(layout.size(4), 4);
+        assert!(misfits(&code, &layout).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_align() {
+        // The padding follows the jump as it grows
+        let code = [bits(3), jump(1), Fragment::Align(8), bits(8), Fragment::Label(1)];
+        let layout = relax(&code).unwrap();
+        assert_eq!((layout.offsets[2], layout.size(2)), (12, 4));
+        let code = [bits(3), jump(1), Fragment::Align(8), bits(200), Fragment::Label(1)];
+        let layout = relax(&code).unwrap();
+        assert_eq!((layout.offsets[2], layout.size(2)), (21, 3));
+        assert_eq!(layout.bits(&code, 2).to_string(), "000");
+        assert_eq!(layout.label(1), Some(224));
+
+        // Aligned already: no padding
+        let code = [bits(64), Fragment::Align(64), Fragment::Label(1)];
+        assert_eq!(relax(&code).unwrap().label(1), Some(64));
+        assert_eq!(align_padding(65, 64), 63);
+    }
+
+    #[test]
+    fn test_absolute() {
+        let code = [
+            Fragment::Ref { kind: RefKind::Constant, label: 1, line: 0 },
+            Fragment::Ref { kind: RefKind::Raw(16), label: 1, line: 1 },
+            Fragment::Org { address: 0xc000, line: 2 },
+            Fragment::Label(1),
+        ];
+        let layout = relax(&code).unwrap();
+        assert_eq!(layout.bits(&code, 0).to_string(), "110".to_string() + &to_bits(&[(0xc000, 32)]));
+        assert_eq!(layout.bits(&code, 1).to_string(), to_bits(&[(0xc000, 16)]));
+        assert_eq!(layout.size(2), 0xc000 - 35 - 16);
+
+        let code = [Fragment::Ref { kind: RefKind::Raw(8), label: 1, line: 4 }, bits(300), Fragment::Label(1)];
+        assert_eq!(relax(&code), Err(RelaxError::OutOfRange { line: 4, label: 1, value: 308 }));
+    }
+
+    #[test]
+    fn test_errors() {
+        let code = [bits(4), Fragment::Ref { kind: RefKind::Absolute, label: 9, line: 3 }];
+        assert_eq!(relax(&code).unwrap_err().line(), 3);
+        assert!(matches!(relax(&code), Err(RelaxError::UndefinedLabel { label: 9, .. })));
+
+        let code = [bits(64), Fragment::Org { address: 32, line: 7 }, bits(1)];
+        assert_eq!(relax(&code), Err(RelaxError::Overlap { line: 7, address: 32, end: 64 }));
+    }
+}
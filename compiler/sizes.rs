@@ -0,0 +1,97 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use minimisa_core::{lookup, INSTRUCTIONS};
+
+// Static size accounting (--size-report)
+//
+// Built from the size of every line once jump widths are resolved: the
+// total size of the program, a histogram per mnemonic, and what the opcode
+// table saves over the default encoding. Data lines are counted under
+// ".const" and alignment padding under ".align", they have no opcode.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MnemonicSize {
+    pub count: usize,
+    pub bits: u64,          // Whole instructions, operands included
+    pub opcode_bits: u64,   // Opcodes only, with the table in use
+    pub default_bits: u64,  // Opcodes only, with the default table
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SizeReport {
+    pub total: u64,
+    pub mnemonics: BTreeMap<String, MnemonicSize>,
+}
+
+impl SizeReport {
+    /// Report of (mnemonic, size in bits) lines encoded with the given
+    /// (mnemonic -> code) table
+    pub fn new(sizes: &[(String, u64)], huffman_tree: &HashMap<String, String>) -> Self {
+        let mut report = SizeReport::default();
+        for (mnemonic, bits) in sizes {
+            let name = match mnemonic.as_str() {
+                "const" => ".const",
+                "align" => ".align",
+                mnemonic => mnemonic,
+            };
+            let entry = report.mnemonics.entry(name.to_string()).or_default();
+            entry.count += 1;
+            entry.bits += bits;
+            entry.opcode_bits += huffman_tree.get(mnemonic).map_or(0, |code| code.len() as u64);
+            entry.default_bits += lookup(mnemonic).map_or(0, |op| INSTRUCTIONS[op as usize].code.len() as u64);
+            report.total += bits;
+        }
+        report
+    }
+
+    /// Bits saved by the opcode table over the default one, negative if it
+    /// is worse
+    pub fn savings(&self) -> i64 {
+        self.mnemonics.values().map(|m| m.default_bits as i64 - m.opcode_bits as i64).sum()
+    }
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "size report: {} bits ({} bytes)", self.total, self.total.div_ceil(8))?;
+        writeln!(f, "  {:<8} {:>6} {:>8} {:>6}  {:>7} {:>7}", "mnemonic", "count", "bits", "%", "opcode", "default")?;
+
+        // Largest first, then by name
+        let mut rows: Vec<_> = self.mnemonics.iter().collect();
+        rows.sort_by(|a, b| b.1.bits.cmp(&a.1.bits).then(a.0.cmp(b.0)));
+        for (name, m) in rows {
+            let share = if self.total == 0 { 0.0 } else { 100.0 * m.bits as f64 / self.total as f64 };
+            writeln!(f, "  {:<8} {:>6} {:>8} {:>5.1}%  {:>7} {:>7}", name, m.count, m.bits, share, m.opcode_bits, m.default_bits)?;
+        }
+
+        let savings = self.savings();
+        let default_total = self.total as i64 + savings;
+        let percent = if default_total == 0 { 0.0 } else { 100.0 * savings as f64 / default_total as f64 };
+        writeln!(f, "opcode table saves {} bits over the default one ({:.1}%)", savings, percent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_report() {
+        let tree: HashMap<String, String> = [("leti", "0"), ("jump", "10"), ("add2", "11")]
+            .iter().map(|(m, c)| (m.to_string(), c.to_string())).collect();
+        let sizes: Vec<(String, u64)> = [("leti", 14), ("leti", 21), ("jump", 11), ("const", 64), ("add2", 8)]
+            .iter().map(|(m, b)| (m.to_string(), *b)).collect();
+
+        let report = SizeReport::new(&sizes, &tree);
+        assert_eq!(report.total, 118);
+        assert_eq!(report.mnemonics["leti"], MnemonicSize { count: 2, bits: 35, opcode_bits: 2, default_bits: 8 });
+        assert_eq!(report.mnemonics[".const"].opcode_bits, 0);
+        // leti 4 -> 1 twice, jump 4 -> 2, add2 4 -> 2
+        assert_eq!(report.savings(), 10);
+
+        let text = report.to_string();
+        assert!(text.starts_with("size report: 118 bits (15 bytes)\n"));
+        assert!(text.lines().nth(2).unwrap().trim_start().starts_with(".const"));
+        assert!(text.ends_with("saves 10 bits over the default one (7.8%)\n"));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use crate::enums::{LexType, Token};
+use crate::errors::TokenError;
+
+// Symbolic constants
+//
+//     .equ SCREEN_BASE 0x10000
+//     .define WIDTH 160
+//
+// bind a name to a number, which may be given by a constant defined
+// before. The name can then be used wherever a number is allowed, before
+// or after its definition: it is replaced by a NUMBER token before parsing.
+// Constants and labels share one namespace, so defining a label with the
+// name of a constant is an error.
+
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    constants: HashMap<String, i64>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<i64> {
+        self.constants.get(name).copied()
+    }
+
+    pub fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if self.constants.insert(name.to_string(), value).is_some() {
+            return Err(format!("constant '{}' is defined twice", name));
+        }
+        Ok(())
+    }
+
+    /// Collect the constant definitions of a token stream, then replace
+    /// every use of a constant with its value. Definitions are dropped
+    pub fn resolve(&mut self, tokens: Vec<Token>) -> Result<Vec<Token>, TokenError> {
+        // Definitions first, so that constants can be used before them
+        self.define_all(tokens.iter().cloned())?;
+        self.clone().substitute(tokens.into_iter().map(Ok)).collect()
+    }
+
+    /// Collect the constant definitions of a token stream
+    pub fn define_all(&mut self, tokens: impl Iterator<Item = Token>) -> Result<(), TokenError> {
+        let mut definition: Option<Vec<Token>> = None;
+        for token in tokens {
+            match (&mut definition, token.typ) {
+                (None, LexType::EQU) => definition = Some(vec![token]),
+                (Some(d), LexType::NEWLINE) => {
+                    self.define_from(d)?;
+                    definition = None;
+                }
+                (Some(_), LexType::SKIP | LexType::COMMENT) => {}
+                (Some(d), _) => d.push(token),
+                (None, _) => {}
+            }
+        }
+        if let Some(d) = definition {
+            self.define_from(&d)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the uses of the constants collected by define_all() in a
+    /// token stream, as it is read. Definitions are dropped
+    pub fn substitute<I>(self, tokens: I) -> impl Iterator<Item = Result<Token, TokenError>>
+    where I: Iterator<Item = Result<Token, TokenError>> {
+        let mut skip_line = false;
+        let mut line_start = true;
+        tokens.filter_map(move |token| {
+            let mut token = match token {
+                Ok(token) => token,
+                Err(e) => return Some(Err(e)),
+            };
+            match token.typ {
+                LexType::NEWLINE => {
+                    skip_line = false;
+                    line_start = true;
+                    return Some(Ok(token));
+                }
+                LexType::EQU => skip_line = true,
+                _ => {}
+            }
+            if skip_line {
+                return None;
+            }
+            if matches!(token.typ, LexType::SKIP | LexType::COMMENT) {
+                return Some(Ok(token));
+            }
+
+            if token.typ == LexType::LABEL {
+                if let Some(value) = self.get(&token.value) {
+                    if line_start {
+                        return Some(Err(TokenError::directive(&token,
+                            format!("'{}' is a constant and cannot be a label", token.value))));
+                    }
+                    token.typ = LexType::NUMBER;
+                    token.value = value.to_string();
+                }
+            }
+            line_start = false;
+            Some(Ok(token))
+        })
+    }
+
+    // Define a constant from the tokens of a `.equ name value` line
+    fn define_from(&mut self, line: &[Token]) -> Result<(), TokenError> {
+        let value = match line {
+            [_, name, value] if name.typ == LexType::LABEL => match value.typ {
+                LexType::NUMBER => value.value.parse::<i64>().ok(),
+                LexType::LABEL => self.get(&value.value),
+                _ => None,
+            },
+            _ => return Err(TokenError::directive(&line[0], "expected .equ <name> <number>".to_string())),
+        };
+        let value = value.ok_or_else(|| TokenError::directive(&line[2],
+            format!("'{}' is not a number or a constant defined before", line[2].value)))?;
+        self.define(&line[1].value, value).map_err(|e| TokenError::directive(&line[1], e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tok(typ: LexType, value: &str) -> Token {
+        Token::new(typ, value.to_string(), "test.s".to_string(), 1, 0)
+    }
+
+    #[test]
+    fn test_constants() {
+        use LexType::*;
+        let tokens = vec![
+            tok(OPERATION, "let"), tok(SKIP, " "), tok(REGISTER, "0"), tok(SKIP, " "), tok(LABEL, "HEIGHT"), tok(NEWLINE, ""),
+            tok(EQU, ".equ"), tok(SKIP, " "), tok(LABEL, "WIDTH"), tok(SKIP, " "), tok(NUMBER, "160"), tok(NEWLINE, ""),
+            tok(EQU, ".define"), tok(LABEL, "HEIGHT"), tok(LABEL, "WIDTH"), tok(COMMENT, "; same"), tok(NEWLINE, ""),
+            tok(OPERATION, "jump"), tok(LABEL, "loop"), tok(NEWLINE, ""),
+        ];
+        let mut table = SymbolTable::new();
+        let out = table.resolve(tokens).unwrap();
+        let values: Vec<&str> = out.iter().filter(|t| !matches!(t.typ, NEWLINE | SKIP)).map(|t| t.value.as_str()).collect();
+        assert_eq!(values, ["let", "0", "160", "jump", "loop"]);
+        assert_eq!(out[4].typ, NUMBER);
+        assert_eq!(table.get("HEIGHT"), Some(160));
+
+        // Labels and constants share their names
+        let clash = vec![
+            tok(EQU, ".equ"), tok(LABEL, "X"), tok(NUMBER, "1"), tok(NEWLINE, ""),
+            tok(LABEL, "X"), tok(NEWLINE, ""),
+        ];
+        assert!(SymbolTable::new().resolve(clash).is_err());
+
+        let twice = vec![
+            tok(EQU, ".equ"), tok(LABEL, "X"), tok(NUMBER, "1"), tok(NEWLINE, ""),
+            tok(EQU, ".equ"), tok(LABEL, "X"), tok(NUMBER, "2"), tok(NEWLINE, ""),
+        ];
+        assert!(SymbolTable::new().resolve(twice).is_err());
+    }
+}
@@ -1,22 +1,19 @@
 use std::collections::{HashMap, VecDeque};
-use std::collections::BinaryHeap;
-use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use regex::Regex;
 
-fn inv_dict_list(dictionnary: &HashMap<String, Vec<String>>) -> HashMap<String, String> {
-    let mut inv_d = HashMap::new();
-    for (key1, value_list) in dictionnary {
-        for key2 in value_list {
-            inv_d.insert(key2.clone(), key1.clone());
-        }
-    }
-    inv_d
-}
-
 pub struct Queue<T> {
     inner: VecDeque<T>,
 }
 
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Queue<T> {
     pub fn new() -> Self {
         Queue {
@@ -41,6 +38,12 @@ pub struct Stack<T> {
     inner: VecDeque<T>,
 }
 
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Stack<T> {
     pub fn new() -> Self {
         Stack {
@@ -64,41 +67,94 @@ impl<T> Stack<T> {
 pub fn sub(chaine: &str, dico: &HashMap<String, String>) -> String {
     let pattern = Regex::new(&format!("({})", dico.keys().cloned().collect::<Vec<_>>().join("|"))).unwrap();
     pattern.replace_all(chaine, |caps: &regex::Captures| {
-        dico.get(&caps[0]).unwrap_or(&caps[0]).to_string()
+        dico.get(&caps[0]).map_or(&caps[0], String::as_str).to_string()
     }).to_string()
 }
 
-// Huffman tree generation
-pub fn huffman(ctr: &HashMap<String, usize>) -> Vec<(String, String)> {
-    let mut forest: BinaryHeap<Reverse<(usize, Vec<(String, String)>)>> = BinaryHeap::new();
-
-    for (key, &freq) in ctr {
-        forest.push(Reverse((freq, vec![("".to_string(), key.clone())])));
-    }
+// Crash-safe output
+//
+// Objects are written to a temporary file next to the destination, which
+// is renamed over it only once everything was written and synced. A failed
+// assembly leaves the previous object (or no object) behind, never a
+// truncated one.
 
-    if forest.is_empty() {
-        return vec![];
-    }
+fn temporary_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or_else(|| "out".into(), |n| n.to_string_lossy().into_owned());
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
 
-    if forest.len() == 1 {
-        let Reverse((_, mut single_tree)) = forest.pop().unwrap();
-        single_tree[0].0 = "0".to_string();
-        return single_tree;
+/// Write a file atomically: `write` fills a buffered temporary file, which
+/// replaces `path` if and only if `write` and the final flush succeed
+pub fn write_atomic<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let tmp = temporary_path(path);
+    let result = (|| {
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        write(&mut out)?;
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
     }
+    result
+}
 
-    while forest.len() > 1 {
-        let Reverse((freq_x, left_tree)) = forest.pop().unwrap();
-        let Reverse((freq_y, right_tree)) = forest.pop().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let new_freq = freq_x + freq_y;
-        let new_tree: Vec<_> = left_tree.into_iter().map(|(pos, key)| ("0".to_string() + &pos, key))
-            .chain(right_tree.into_iter().map(|(pos, key)| ("1".to_string() + &pos, key)))
-            .collect();
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minimisa-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-        forest.push(Reverse((new_freq, new_tree)));
+    #[test]
+    fn test_write_atomic() {
+        let dir = scratch_dir("atomic");
+        let path = dir.join("prog.obj");
+
+        write_atomic(&path, |out| out.write_all(b"0101")).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"0101");
+
+        // Failing halfway through leaves the old object untouched
+        let err = write_atomic(&path, |out| {
+            out.write_all(b"1111")?;
+            Err(io::Error::other("encoding failed"))
+        });
+        assert!(err.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"0101");
+
+        // ... and does not create one either, nor leave temporary files
+        let fresh = dir.join("fresh.obj");
+        assert!(write_atomic(&fresh, |_| Err(io::Error::other("no"))).is_err());
+        assert!(!fresh.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    let Reverse((_, tree)) = forest.pop().unwrap();
-    tree = tree.into_iter().sorted_by_key(|(pos, _)| pos.len()).collect();
-    tree
-}
\ No newline at end of file
+    #[test]
+    fn test_write_atomic_large() {
+        // Bigger than the BufWriter buffer, so the failure happens after
+        // data has already reached the temporary file
+        let dir = scratch_dir("atomic-large");
+        let path = dir.join("big.obj");
+        fs::write(&path, b"old").unwrap();
+
+        let err = write_atomic(&path, |out| {
+            out.write_all(&vec![b'1'; 1 << 16])?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(err.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use crate::compileuh::POSSIBLE_TRANSITION;
+use crate::enums::{LexType, Token};
+use crate::errors::TokenError;
+use crate::lexer::Lexer;
+
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub filename: String,
+    pub line: usize,
+    pub column: usize,
+    pub operation: String,
+}
+
+#[derive(Debug, Default)]
+pub struct LabelXref {
+    pub definitions: Vec<Site>,
+    pub references: Vec<Site>,
+}
+
+// Cross-reference table, sorted by label name
+pub type Xref = BTreeMap<String, LabelXref>;
+
+/// Build the cross-reference table from a token stream. A label that opens
+/// a line is a definition; a label used as an operand is a reference.
+pub fn xref_tokens(tokens: impl Iterator<Item = Result<Token, TokenError>>) -> Result<Xref, TokenError> {
+    let mut table = Xref::new();
+    let mut operation: Option<String> = None;
+
+    for token in tokens {
+        let token = token?;
+        match token.typ {
+            LexType::NEWLINE => operation = None,
+            LexType::OPERATION => operation = Some(token.value.clone()),
+            LexType::LABEL => {
+                let entry = table.entry(token.value.clone()).or_default();
+                let site = Site {
+                    filename: token.filename.clone(),
+                    line: token.line,
+                    column: token.column,
+                    operation: operation.clone().unwrap_or_default(),
+                };
+                match operation {
+                    None => entry.definitions.push(site),
+                    Some(_) => entry.references.push(site),
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    Ok(table)
+}
+
+/// Print the report: definition site, every reference, and warnings for
+/// dead, undefined and multiply-defined labels
+pub fn xref_report(table: &Xref) -> String {
+    let mut out = String::new();
+
+    for (label, x) in table {
+        out.push_str(&format!("{}\n", label));
+        for d in &x.definitions {
+            out.push_str(&format!("    defined    {}:{}:{}\n", d.filename, d.line, d.column));
+        }
+        for r in &x.references {
+            out.push_str(&format!("    used by    {}:{}:{}  {}\n", r.filename, r.line, r.column, r.operation));
+        }
+
+        if x.definitions.is_empty() {
+            out.push_str("    warning: label is never defined\n");
+        } else if x.definitions.len() > 1 {
+            out.push_str("    warning: label is defined several times\n");
+        }
+        if x.references.is_empty() {
+            out.push_str("    warning: dead label, never referenced\n");
+        }
+    }
+
+    out
+}
+
+pub fn xref_file(filename: &str) -> io::Result<String> {
+    let mut code = String::new();
+    File::open(filename)?.read_to_string(&mut code)?;
+
+    let directory = Path::new(filename).parent().and_then(|p| p.to_str()).unwrap_or(".");
+    let mut lexer = Lexer::new(&POSSIBLE_TRANSITION);
+    let table = xref_tokens(lexer.lex(&code, filename, directory))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    Ok(xref_report(&table))
+}
@@ -0,0 +1,263 @@
+//---
+// minimisa-core:bitvec - sequences of bits
+//
+// Programs are bit streams: instructions are not aligned on bytes. BitVec
+// stores bits most significant first in bytes, the last byte padded with
+// zeros, which is also how objects store segments and how the emulator
+// loads them. BitWriter packs a stream of fields into bytes the same way
+// without holding it whole.
+//---
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct BitVec {
+    bytes: Vec<u8>,
+    len: u64,
+}
+
+impl BitVec {
+    pub fn new() -> BitVec {
+        BitVec::default()
+    }
+
+    /// `len` zero bits
+    pub fn zeros(len: u64) -> BitVec {
+        BitVec { bytes: vec![0; len.div_ceil(8) as usize], len }
+    }
+
+    /// The first `len` bits of some bytes
+    pub fn from_bytes(bytes: &[u8], len: u64) -> BitVec {
+        assert!(len <= 8 * bytes.len() as u64, "{} bits do not fit in {} bytes", len, bytes.len());
+        let mut bits = BitVec { bytes: bytes[..len.div_ceil(8) as usize].to_vec(), len };
+        if !len.is_multiple_of(8) {
+            *bits.bytes.last_mut().unwrap() &= 0xff << (8 - len % 8);
+        }
+        bits
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes of the bits, the last one padded with zeros
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn push_bit(&mut self, bit: bool) {
+        self.push(bit as u64, 1);
+    }
+
+    /// Append the low `width` bits of a value, most significant first.
+    /// This is where all fields get packed into bytes
+    pub fn push(&mut self, value: u64, width: u32) {
+        assert!(width <= 64, "fields are at most 64 bits wide");
+        let mut left = width;
+        while left > 0 {
+            // Fill the free bits of the last byte, or a new one
+            let used = (self.len % 8) as u32;
+            if used == 0 {
+                self.bytes.push(0);
+            }
+            let n = left.min(8 - used);
+            let bits = (value >> (left - n)) & ((1 << n) - 1);
+            *self.bytes.last_mut().unwrap() |= (bits << (8 - used - n)) as u8;
+            self.len += n as u64;
+            left -= n;
+        }
+    }
+
+    pub fn extend(&mut self, other: &BitVec) {
+        let mut pos = 0;
+        while pos < other.len {
+            let n = (other.len - pos).min(64) as u32;
+            self.push(other.read(pos, n).unwrap(), n);
+            pos += n as u64;
+        }
+    }
+
+    /// Bits start..end
+    pub fn slice(&self, start: u64, end: u64) -> BitVec {
+        assert!(start <= end && end <= self.len, "bits {}..{} out of {}", start, end, self.len);
+        let mut bits = BitVec::new();
+        let mut pos = start;
+        while pos < end {
+            let n = (end - pos).min(64) as u32;
+            bits.push(self.read(pos, n).unwrap(), n);
+            pos += n as u64;
+        }
+        bits
+    }
+
+    /// Remove the whole bytes at the start and return them, keeping the
+    /// bits of a last incomplete byte
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        let whole = (self.len / 8) as usize;
+        let rest = self.bytes.split_off(whole);
+        self.len %= 8;
+        std::mem::replace(&mut self.bytes, rest)
+    }
+
+    /// Bit i, counted from the start
+    pub fn get(&self, i: u64) -> bool {
+        assert!(i < self.len, "bit {} out of {}", i, self.len);
+        (self.bytes[(i / 8) as usize] >> (7 - i % 8)) & 1 == 1
+    }
+
+    /// The `width` bits from `pos` as a number, None past the end
+    pub fn read(&self, pos: u64, width: u32) -> Option<u64> {
+        if width > 64 || pos.checked_add(width as u64)? > self.len {
+            return None;
+        }
+        Some((pos..pos + width as u64).fold(0, |acc, i| (acc << 1) | self.get(i) as u64))
+    }
+}
+
+/// Packs fields into bytes and writes every complete byte to a sink. The
+/// last byte is padded with zeros by finish()
+pub struct BitWriter<W: Write> {
+    out: W,
+    pending: BitVec,
+    written: u64,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(out: W) -> BitWriter<W> {
+        BitWriter { out, pending: BitVec::new(), written: 0 }
+    }
+
+    /// Number of bits written so far
+    pub fn len(&self) -> u64 {
+        self.written + self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the low `width` bits of a value
+    pub fn write(&mut self, value: u64, width: u32) -> io::Result<()> {
+        self.pending.push(value, width);
+        self.flush_bytes()
+    }
+
+    pub fn write_bits(&mut self, bits: &BitVec) -> io::Result<()> {
+        self.pending.extend(bits);
+        self.flush_bytes()
+    }
+
+    fn flush_bytes(&mut self) -> io::Result<()> {
+        if self.pending.len() >= 8 {
+            let bytes = self.pending.take_bytes();
+            self.written += 8 * bytes.len() as u64;
+            self.out.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Write the last incomplete byte and give the sink back
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            self.out.write_all(self.pending.as_bytes())?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl fmt::Display for BitVec {
+    /// The bits as '0' and '1' characters
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in 0..self.len {
+            f.write_str(if self.get(i) { "1" } else { "0" })?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BitVec {
+    type Err = String;
+
+    /// Bits of a string of '0' and '1' characters. Whitespace is ignored
+    fn from_str(s: &str) -> Result<BitVec, String> {
+        let mut bits = BitVec::new();
+        for c in s.chars().filter(|c| !c.is_whitespace()) {
+            match c {
+                '0' | '1' => bits.push_bit(c == '1'),
+                _ => return Err(format!("invalid bit '{}'", c)),
+            }
+        }
+        Ok(bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitvec() {
+        let mut bits = BitVec::new();
+        bits.push(0b0111, 4);
+        bits.push(1, 3);
+        bits.push(u64::MAX, 64);
+        assert_eq!(bits.len(), 71);
+        assert_eq!(bits.as_bytes()[0], 0b0111_0011);
+        assert_eq!(bits.as_bytes().len(), 9);
+        assert_eq!(bits.read(4, 3), Some(1));
+        assert_eq!(bits.read(7, 64), Some(u64::MAX));
+        assert_eq!(bits.read(8, 64), None);
+
+        let text = bits.to_string();
+        assert_eq!(&text[..8], "01110011");
+        assert_eq!(text.parse::<BitVec>(), Ok(bits.clone()));
+        assert_eq!("0111 001".parse::<BitVec>().unwrap().read(0, 7), Some(0b0111001));
+        assert!("012".parse::<BitVec>().is_err());
+
+        // Padding bits past the length are dropped
+        assert_eq!(BitVec::from_bytes(&[0xff, 0xff], 9).as_bytes(), [0xff, 0x80]);
+        let mut joined = BitVec::from_bytes(&[0b1010_0000], 3);
+        joined.extend(&bits);
+        assert_eq!(joined.len(), 74);
+        assert_eq!(joined.read(0, 7), Some(0b101_0111));
+        assert_eq!(joined.slice(3, 74), bits);
+        assert_eq!(joined.slice(70, 70), BitVec::new());
+        assert_eq!(BitVec::zeros(10).to_string(), "0000000000");
+    }
+
+    #[test]
+    fn test_packing() {
+        // Fields of every width at every alignment pack like bit by bit
+        let mut packed = BitVec::new();
+        let mut text = String::new();
+        for width in 0..=64 {
+            let value = 0x9e37_79b9_7f4a_7c15u64.rotate_left(width);
+            packed.push(value, width);
+            for i in (0..width).rev() {
+                text.push(if (value >> i) & 1 == 1 { '1' } else { '0' });
+            }
+        }
+        assert_eq!(packed.len(), 64 * 65 / 2);
+        assert_eq!(packed.to_string(), text);
+
+        let mut writer = BitWriter::new(Vec::new());
+        for i in 0..packed.len() / 13 {
+            writer.write(packed.read(13 * i, 13).unwrap(), 13).unwrap();
+        }
+        writer.write_bits(&packed.slice(packed.len() / 13 * 13, packed.len())).unwrap();
+        assert_eq!(writer.len(), packed.len());
+        assert_eq!(writer.finish().unwrap(), packed.as_bytes());
+
+        let mut rest = packed.clone();
+        let bytes = rest.take_bytes();
+        assert_eq!(bytes, packed.as_bytes()[..bytes.len()]);
+        assert_eq!(rest, packed.slice(8 * bytes.len() as u64, packed.len()));
+    }
+}
@@ -0,0 +1,342 @@
+//---
+// minimisa-core:codec - whole instructions to bits and back
+//
+// encode_instruction() and decode_instruction() are the reference encoding
+// of the ISA, built on the operand encoders of the crate root. The compiler
+// back ends and the emulator decoders keep their own code paths; tests
+// check that they agree with these functions on random instructions from
+// arbitrary_instruction(), which is also the entry point for fuzzers.
+//
+// Insn prints as the disassembler does and parses back from that text,
+// so that tools can assemble a single instruction (the debugger patches
+// code this way). Operands are raw numbers in Insn::args, in the order of
+// the operands of the instruction (unused ones are 0):
+//
+//     Register, Condition, Direction, Pointer     their code (r3 is 3)
+//     Address, AConst                             two's complement i64
+//     LConst, Shift                               the value
+//     Size                                        the size in bits (1..64)
+//---
+
+use std::fmt;
+use std::str::FromStr;
+use crate::bitvec::BitVec;
+use crate::{condition, default_opcodes, encode_address, encode_const, encode_shift, lookup, pointer, size,
+    Opcodes, Operand, ADDRESS_WIDTHS, CONDITIONS, CONST_WIDTHS, DIRECTIONS, INSTRUCTIONS,
+    INSTRUCTION_COUNT, POINTERS, SIZES};
+
+/// An instruction with the values of its operands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Insn {
+    pub opcode: u32,
+    pub args: [u64; 3],
+}
+
+impl fmt::Display for Insn {
+    /// The instruction as the disassembler prints it, e.g. "jumpif slt -2"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ins = &INSTRUCTIONS[self.opcode as usize];
+        f.write_str(ins.mnemonic)?;
+        for (&kind, &arg) in ins.operands.iter().zip(&self.args) {
+            match kind {
+                Operand::None => break,
+                Operand::Register => write!(f, " r{}", arg)?,
+                Operand::Direction => write!(f, " {}", DIRECTIONS[arg as usize & 1])?,
+                Operand::Condition => write!(f, " {}", CONDITIONS[arg as usize & 7])?,
+                Operand::Pointer => write!(f, " {}", POINTERS[arg as usize & 3])?,
+                Operand::Address | Operand::AConst => write!(f, " {}", arg as i64)?,
+                Operand::LConst | Operand::Shift | Operand::Size => write!(f, " {}", arg)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Decimal or 0x-prefixed hexadecimal number, possibly negative, in two's
+// complement
+fn parse_int(text: &str) -> Option<u64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+impl FromStr for Insn {
+    type Err = String;
+
+    /// An instruction in the syntax of the disassembler, e.g. "jumpif slt
+    /// -2". Condition aliases (nz, c...) and hexadecimal numbers are
+    /// accepted; values are checked by the encoder
+    fn from_str(text: &str) -> Result<Insn, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let (&mnemonic, operands) = words.split_first().ok_or("empty instruction")?;
+        let opcode = lookup(mnemonic).ok_or_else(|| format!("unknown instruction '{}'", mnemonic))?;
+        let desc = &INSTRUCTIONS[opcode as usize];
+        if operands.len() != desc.arity() {
+            return Err(format!("'{}' takes {} operand(s), got {}", mnemonic, desc.arity(), operands.len()));
+        }
+
+        let mut args = [0; 3];
+        for ((arg, &kind), &word) in args.iter_mut().zip(&desc.operands).zip(operands) {
+            let value = match kind {
+                Operand::Register => word.strip_prefix('r').and_then(|r| r.parse().ok()).filter(|&r| r < 8),
+                Operand::Condition => condition(word),
+                Operand::Direction => DIRECTIONS.iter().position(|&d| d == word).map(|d| d as u64),
+                Operand::Pointer => pointer(word),
+                Operand::LConst | Operand::Shift | Operand::Size if !word.starts_with('-') => parse_int(word),
+                Operand::Address | Operand::AConst => parse_int(word),
+                _ => None,
+            };
+            *arg = value.ok_or_else(|| format!("invalid operand '{}' for {}", word, mnemonic))?;
+        }
+        Ok(Insn { opcode, args })
+    }
+}
+
+/// Bits of an instruction with the default opcode table
+pub fn encode_instruction(ins: &Insn) -> Result<BitVec, String> {
+    encode_instruction_with(ins, &default_opcodes())
+}
+
+/// Bits of an instruction with the given opcode table. Constants and
+/// addresses get their smallest width
+pub fn encode_instruction_with(ins: &Insn, codes: &Opcodes) -> Result<BitVec, String> {
+    let desc = INSTRUCTIONS.get(ins.opcode as usize).ok_or_else(|| format!("invalid opcode {}", ins.opcode))?;
+    let (code, length) = codes[ins.opcode as usize];
+    if length == 0 {
+        return Err(format!("{} has no code in the opcode table", desc.mnemonic));
+    }
+
+    let mut bits = BitVec::new();
+    bits.push(code, length);
+    for (&kind, &arg) in desc.operands.iter().zip(&ins.args) {
+        let invalid = || format!("invalid {:?} operand {} for {}", kind, arg, desc.mnemonic);
+        let fields: Vec<(u64, u32)> = match kind {
+            Operand::None => break,
+            Operand::Register | Operand::Condition if arg < 8 => vec![(arg, 3)],
+            Operand::Direction if arg < 2 => vec![(arg, 1)],
+            Operand::Pointer if arg < 4 => vec![(arg, 2)],
+            Operand::Address => encode_address(arg as i64).to_vec(),
+            Operand::LConst => encode_const(arg, false).to_vec(),
+            Operand::AConst => encode_const(arg, true).to_vec(),
+            Operand::Shift => encode_shift(arg).ok_or_else(invalid)?,
+            Operand::Size => vec![size(arg).ok_or_else(invalid)?],
+            _ => return Err(invalid()),
+        };
+        for (value, width) in fields {
+            bits.push(value, width);
+        }
+    }
+    Ok(bits)
+}
+
+/// Decode the instruction at `pos` with the default opcode table. Returns
+/// it with the position of the next instruction
+pub fn decode_instruction(bits: &BitVec, pos: u64) -> Result<(Insn, u64), String> {
+    decode_instruction_with(bits, pos, &default_opcodes())
+}
+
+// Two's complement value of the low `width` bits
+fn sign_extend(value: u64, width: u32) -> u64 {
+    if width >= 64 { value } else { (((value << (64 - width)) as i64) >> (64 - width)) as u64 }
+}
+
+/// Decode the instruction at `pos` with the given opcode table
+pub fn decode_instruction_with(bits: &BitVec, pos: u64, codes: &Opcodes) -> Result<(Insn, u64), String> {
+    let mut ptr = pos;
+    let mut read = |width: u32| -> Result<u64, String> {
+        let value = bits.read(ptr, width).ok_or_else(|| format!("instruction at {} is truncated", pos))?;
+        ptr += width as u64;
+        Ok(value)
+    };
+
+    // Opcodes are prefix codes: read bits until one matches
+    let (mut code, mut length) = (0, 0);
+    let opcode = loop {
+        code = (code << 1) | read(1)?;
+        length += 1;
+        if let Some(op) = codes.iter().position(|&c| c == (code, length)) {
+            break op;
+        }
+        if length == 64 {
+            return Err(format!("no opcode at {}", pos));
+        }
+    };
+
+    // Index of a 0 / 10 / 110 / 111 prefix
+    let prefix = |read: &mut dyn FnMut(u32) -> Result<u64, String>| -> Result<usize, String> {
+        let mut index = 0;
+        while index < 3 && read(1)? == 1 {
+            index += 1;
+        }
+        Ok(index)
+    };
+
+    let mut args = [0; 3];
+    for (arg, &kind) in args.iter_mut().zip(&INSTRUCTIONS[opcode].operands) {
+        *arg = match kind {
+            Operand::None => break,
+            Operand::Register | Operand::Condition => read(3)?,
+            Operand::Direction => read(1)?,
+            Operand::Pointer => read(2)?,
+            Operand::Address => {
+                let width = ADDRESS_WIDTHS[prefix(&mut read)?];
+                sign_extend(read(width)?, width)
+            }
+            Operand::LConst => {
+                let width = CONST_WIDTHS[prefix(&mut read)?];
+                read(width)?
+            }
+            Operand::AConst => {
+                let width = CONST_WIDTHS[prefix(&mut read)?];
+                sign_extend(read(width)?, width)
+            }
+            Operand::Shift => if read(1)? == 1 { 1 } else { read(6)? },
+            Operand::Size => {
+                let mut code = read(2)?;
+                let mut length = 2;
+                if code >= 2 {
+                    code = (code << 1) | read(1)?;
+                    length = 3;
+                }
+                SIZES.iter().find(|&&(_, c)| c == (code, length)).unwrap().0
+            }
+        };
+    }
+    Ok((Insn { opcode: opcode as u32, args }, ptr))
+}
+
+// One step of splitmix64
+fn next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A valid instruction chosen from a seed, the same on every run.
+/// Constants and addresses are spread over all their widths
+pub fn arbitrary_instruction(seed: u64) -> Insn {
+    let mut state = seed;
+    let opcode = (next(&mut state) % INSTRUCTION_COUNT as u64) as u32;
+    let mut args = [0; 3];
+
+    for (arg, &kind) in args.iter_mut().zip(&INSTRUCTIONS[opcode as usize].operands) {
+        let r = next(&mut state);
+        // Random value on one of four widths, sign-extended if needed
+        let mut sized = |widths: [u32; 4], signed: bool| {
+            let width = widths[(r % 4) as usize];
+            let value = next(&mut state) & if width == 64 { u64::MAX } else { (1 << width) - 1 };
+            if signed { sign_extend(value, width) } else { value }
+        };
+        *arg = match kind {
+            Operand::None => break,
+            Operand::Register | Operand::Condition => r % 8,
+            Operand::Direction => r % 2,
+            Operand::Pointer => r % 4,
+            Operand::Address => sized(ADDRESS_WIDTHS, true),
+            Operand::LConst => sized(CONST_WIDTHS, false),
+            Operand::AConst => sized(CONST_WIDTHS, true),
+            Operand::Shift => r % 64,
+            Operand::Size => SIZES[(r % SIZES.len() as u64) as usize].0,
+        };
+    }
+    Insn { opcode, args }
+}
+
+/// Check that an instruction decodes back to itself, with all its bits
+pub fn check_round_trip(ins: &Insn) -> Result<(), String> {
+    let bits = encode_instruction(ins)?;
+    let (decoded, end) = decode_instruction(&bits, 0)?;
+    if decoded != *ins || end != bits.len() {
+        return Err(format!("'{}' encodes to {} which decodes to '{}' on {} bits", ins, bits, decoded, end));
+    }
+    if ins.to_string().parse::<Insn>().as_ref() != Ok(ins) {
+        return Err(format!("'{}' does not parse back to itself", ins));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::op;
+
+    #[test]
+    fn test_encode() {
+        let leti = Insn { opcode: op::OP_LETI, args: [1, 37, 0] };
+        assert_eq!(encode_instruction(&leti).unwrap().to_string(), "0111001".to_string() + "10" + "00100101");
+        assert_eq!(leti.to_string(), "leti r1 37");
+
+        let jumpif = Insn { opcode: op::OP_JUMPIF, args: [3, -2i64 as u64, 0] };
+        let bits = encode_instruction(&jumpif).unwrap();
+        assert_eq!(bits.to_string(), "1011011".to_string() + "0" + "11111110");
+        assert_eq!(jumpif.to_string(), "jumpif slt -2");
+        assert_eq!(decode_instruction(&bits, 0), Ok((jumpif, 16)));
+
+        let readse = Insn { opcode: op::OP_READSE, args: [2, 16, 4] };
+        assert_eq!(readse.to_string(), "readse a0 16 r4");
+        assert!(encode_instruction(&Insn { opcode: op::OP_ADD2, args: [8, 0, 0] }).is_err());
+        assert!(encode_instruction(&Insn { opcode: op::OP_PUSH, args: [12, 0, 0] }).is_err());
+        assert!(encode_instruction(&Insn { opcode: op::OP_SHIFT, args: [0, 0, 64] }).is_err());
+        assert!(encode_instruction(&Insn { opcode: 38, args: [0; 3] }).is_err());
+
+        // Truncated after the opcode of leti
+        assert!(decode_instruction(&"0111".parse().unwrap(), 0).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("jumpif slt -2".parse(), Ok(Insn { opcode: op::OP_JUMPIF, args: [3, -2i64 as u64, 0] }));
+        assert_eq!("jumpif  nz 0x10".parse(), Ok(Insn { opcode: op::OP_JUMPIF, args: [1, 16, 0] }));
+        assert_eq!("readse a0 16 r4".parse(), Ok(Insn { opcode: op::OP_READSE, args: [2, 16, 4] }));
+        assert_eq!("return".parse(), Ok(Insn { opcode: op::OP_RETURN, args: [0; 3] }));
+        assert!("leti r8 1".parse::<Insn>().is_err());
+        assert!("or2i r0 -1".parse::<Insn>().is_err());
+        assert!("add2 r0".parse::<Insn>().is_err());
+        assert!("frob r0".parse::<Insn>().is_err());
+        assert!("".parse::<Insn>().is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for seed in 0..20000 {
+            let ins = arbitrary_instruction(seed);
+            if let Err(e) = check_round_trip(&ins) {
+                panic!("seed {}: {}", seed, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        // Instructions back to back decode one after the other, and any
+        // bits that decode encode back to the same instruction
+        let mut bits = BitVec::new();
+        let program: Vec<Insn> = (0..500).map(|seed| arbitrary_instruction(seed * 7919)).collect();
+        for ins in &program {
+            bits.extend(&encode_instruction(ins).unwrap());
+        }
+        let mut pos = 0;
+        for ins in &program {
+            let (decoded, next) = decode_instruction(&bits, pos).unwrap();
+            assert_eq!(decoded, *ins);
+            pos = next;
+        }
+        assert_eq!(pos, bits.len());
+
+        for seed in 0..2000 {
+            let mut state = seed;
+            let bytes: Vec<u8> = (0..4).flat_map(|_| next(&mut state).to_be_bytes()).collect();
+            if let Ok((ins, _)) = decode_instruction(&BitVec::from_bytes(&bytes, 256), 0) {
+                check_round_trip(&ins).unwrap();
+            }
+        }
+    }
+}
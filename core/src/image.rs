@@ -0,0 +1,136 @@
+//---
+// minimisa-core:image - screen captures
+//
+// RGB images with 8-bit channels, written as binary PPM or PNG depending
+// on the file extension. The emulator and the simulator both save their
+// screens with these. PNG data is stored without compression, so that no
+// compression library is needed; a 160x128 capture is about 60 kB.
+//---
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Save an image as PNG if the file name ends in .png, PPM otherwise
+pub fn save(filename: &str, width: usize, height: usize, pixels: &[[u8; 3]]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    if Path::new(filename).extension().is_some_and(|e| e.eq_ignore_ascii_case("png")) {
+        write_png(&mut out, width, height, pixels)?;
+    } else {
+        write_ppm(&mut out, width, height, pixels)?;
+    }
+    out.flush()
+}
+
+/// Name of frame n of a sequence: frame.png becomes frame-0003.png
+pub fn numbered(filename: &str, n: usize) -> String {
+    match filename.rfind('.').filter(|&dot| !filename[dot..].contains('/')) {
+        Some(dot) => format!("{}-{:04}{}", &filename[..dot], n, &filename[dot..]),
+        None => format!("{}-{:04}", filename, n),
+    }
+}
+
+/// Binary PPM (P6)
+pub fn write_ppm(out: &mut impl Write, width: usize, height: usize, pixels: &[[u8; 3]]) -> io::Result<()> {
+    assert_eq!(pixels.len(), width * height);
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+    out.write_all(&pixels.concat())
+}
+
+/// PNG, truecolor, with the image data in stored (uncompressed) deflate
+/// blocks
+pub fn write_png(out: &mut impl Write, width: usize, height: usize, pixels: &[[u8; 3]]) -> io::Result<()> {
+    assert_eq!(pixels.len(), width * height);
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::new();
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    header.extend([8, 2, 0, 0, 0]);  // 8-bit RGB, no interlacing
+    write_chunk(out, b"IHDR", &header)?;
+
+    // Rows start with their filter type, 0 for none
+    let mut raw = Vec::with_capacity(height * (1 + 3 * width));
+    for row in pixels.chunks(width.max(1)) {
+        raw.push(0);
+        raw.extend(row.concat());
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend(block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+    write_chunk(out, b"IDAT", &zlib)?;
+
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32(&[kind, data]).to_be_bytes())
+}
+
+// CRC-32 of the concatenation of some byte strings, as in PNG and zlib
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().flat_map(|p| p.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png() {
+        assert_eq!(crc32(&[b"IEND"]), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+
+        let pixels = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [1, 2, 3]];
+        let mut png = Vec::new();
+        write_png(&mut png, 2, 2, &pixels).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x02\x08\x02"));
+        assert!(png.ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+
+        // One stored block holding both rows
+        let idat = png.windows(4).position(|w| w == b"IDAT").unwrap() + 4;
+        assert_eq!(&png[idat..idat + 7], &[0x78, 0x01, 1, 14, 0, !14, 0xff]);
+        assert_eq!(&png[idat + 7..idat + 14], &[0, 255, 0, 0, 0, 255, 0]);
+
+        let mut ppm = Vec::new();
+        write_ppm(&mut ppm, 2, 2, &pixels).unwrap();
+        assert_eq!(ppm.len(), 11 + 12);
+    }
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!(numbered("frame.png", 3), "frame-0003.png");
+        assert_eq!(numbered("out/shot", 12), "out/shot-0012");
+        assert_eq!(numbered("./v1.0/shot", 1), "./v1.0/shot-0001");
+    }
+}
@@ -0,0 +1,465 @@
+//---
+// minimisa-core - the MinimISA instruction set, defined in one place
+//
+// Mnemonics, operand kinds and default opcodes of every instruction, and
+// the encodings of the operands. The assembler (compiler/), disassembler
+// and emulator (emu/) and simulator (subject/simu.src) build their tables
+// from here instead of keeping copies that drift apart.
+//
+// Opcodes are prefix codes: frequent instructions get short codes. An
+// instruction is identified by its opcode number, its index in
+// INSTRUCTIONS, which does not depend on the binary encoding; programs may
+// be assembled with another (e.g. Huffman) table.
+//---
+
+pub mod bitvec;
+pub mod codec;
+pub mod image;
+pub mod object;
+pub mod pages;
+
+/// Kinds of operands, in their order of appearance in an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    None,       // No argument
+    Register,   // Register: r0..r7 on 3 bits
+    Direction,  // Direction: left/right on 1 bit
+    Condition,  // Condition: various on 3 bits
+    Address,    // Address: on 9, 18, 35 or 67 bits
+    LConst,     // Unsigned constants: on 2, 10, 35, or 67 bits
+    AConst,     // Arithmetic (signed) constants: add, sub, cmp and let
+    Shift,      // Shifts: 1 bit or 7 bits
+    Size,       // Size: 2 or 3 bits
+    Pointer,    // Pointer: PC, SP, A0, or A1 on 2 bits
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Arithmetic,
+    Test,
+    Let,
+    Jump,
+    Memory,
+    Control,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+    pub category: Category,
+    pub operands: [Operand; 3],
+    pub code: &'static str,  // Default opcode, as '0' and '1' characters
+}
+
+impl Instruction {
+    /// Number of operands
+    pub fn arity(&self) -> usize {
+        self.operands.iter().take_while(|&&op| op != Operand::None).count()
+    }
+
+    /// Default opcode as (code, length)
+    pub const fn bits(&self) -> (u64, u32) {
+        let text = self.code.as_bytes();
+        let mut code = 0;
+        let mut i = 0;
+        while i < text.len() {
+            code = (code << 1) | (text[i] == b'1') as u64;
+            i += 1;
+        }
+        (code, text.len() as u32)
+    }
+}
+
+/// Opcode numbers, i.e. indices in INSTRUCTIONS
+pub mod op {
+    pub const OP_ADD2: u32 = 0;
+    pub const OP_ADD2I: u32 = 1;
+    pub const OP_SUB2: u32 = 2;
+    pub const OP_SUB2I: u32 = 3;
+    pub const OP_CMP: u32 = 4;
+    pub const OP_CMPI: u32 = 5;
+    pub const OP_LET: u32 = 6;
+    pub const OP_LETI: u32 = 7;
+    pub const OP_SHIFT: u32 = 8;
+    pub const OP_READZE: u32 = 9;
+    pub const OP_READSE: u32 = 10;
+    pub const OP_JUMP: u32 = 11;
+    pub const OP_JUMPIF: u32 = 12;
+    pub const OP_OR2: u32 = 13;
+    pub const OP_OR2I: u32 = 14;
+    pub const OP_AND2: u32 = 15;
+    pub const OP_AND2I: u32 = 16;
+    pub const OP_WRITE: u32 = 17;
+    pub const OP_CALL: u32 = 18;
+    pub const OP_SETCTR: u32 = 19;
+    pub const OP_GETCTR: u32 = 20;
+    pub const OP_PUSH: u32 = 21;
+    pub const OP_RETURN: u32 = 22;
+    pub const OP_ADD3: u32 = 23;
+    pub const OP_ADD3I: u32 = 24;
+    pub const OP_SUB3: u32 = 25;
+    pub const OP_SUB3I: u32 = 26;
+    pub const OP_AND3: u32 = 27;
+    pub const OP_AND3I: u32 = 28;
+    pub const OP_OR3: u32 = 29;
+    pub const OP_OR3I: u32 = 30;
+    pub const OP_XOR3: u32 = 31;
+    pub const OP_XOR3I: u32 = 32;
+    pub const OP_ASR3: u32 = 33;
+    pub const OP_SLEEP: u32 = 34;
+    pub const OP_RAND: u32 = 35;
+    pub const OP_POP: u32 = 36;
+    pub const OP_RETI: u32 = 37;
+}
+
+/// Number of different instructions (the 37 MinimISA opcodes plus reti)
+pub const INSTRUCTION_COUNT: usize = 38;
+
+const fn ins(mnemonic: &'static str, category: Category, operands: [Operand; 3],
+    code: &'static str) -> Instruction {
+    Instruction { mnemonic, category, operands, code }
+}
+
+use Operand::{AConst, Address, Condition, Direction, LConst, Pointer, Register, Shift, Size};
+use Operand::None as No;
+
+/// The instruction set, indexed by opcode number. reti has the last code,
+/// which the assembler keeps reserved
+pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
+    ins("add2",   Category::Arithmetic, [Register,  Register, No],       "0000"),
+    ins("add2i",  Category::Arithmetic, [Register,  AConst,   No],       "0001"),
+    ins("sub2",   Category::Arithmetic, [Register,  Register, No],       "0010"),
+    ins("sub2i",  Category::Arithmetic, [Register,  AConst,   No],       "0011"),
+    ins("cmp",    Category::Test,       [Register,  Register, No],       "0100"),
+    ins("cmpi",   Category::Test,       [Register,  AConst,   No],       "0101"),
+    ins("let",    Category::Let,        [Register,  Register, No],       "0110"),
+    ins("leti",   Category::Let,        [Register,  AConst,   No],       "0111"),
+    ins("shift",  Category::Arithmetic, [Direction, Register, Shift],    "1000"),
+    ins("readze", Category::Memory,     [Pointer,   Size,     Register], "10010"),
+    ins("readse", Category::Memory,     [Pointer,   Size,     Register], "10011"),
+    ins("jump",   Category::Jump,       [Address,   No,       No],       "1010"),
+    ins("jumpif", Category::Jump,       [Condition, Address,  No],       "1011"),
+    ins("or2",    Category::Arithmetic, [Register,  Register, No],       "110000"),
+    ins("or2i",   Category::Arithmetic, [Register,  LConst,   No],       "110001"),
+    ins("and2",   Category::Arithmetic, [Register,  Register, No],       "110010"),
+    ins("and2i",  Category::Arithmetic, [Register,  LConst,   No],       "110011"),
+    ins("write",  Category::Memory,     [Pointer,   Size,     Register], "110100"),
+    ins("call",   Category::Jump,       [Address,   No,       No],       "110101"),
+    ins("setctr", Category::Let,        [Pointer,   Register, No],       "110110"),
+    ins("getctr", Category::Let,        [Pointer,   Register, No],       "110111"),
+    ins("push",   Category::Memory,     [Size,      Register, No],       "1110000"),
+    ins("return", Category::Jump,       [No,        No,       No],       "1110001"),
+    ins("add3",   Category::Arithmetic, [Register,  Register, Register], "1110010"),
+    ins("add3i",  Category::Arithmetic, [Register,  Register, AConst],   "1110011"),
+    ins("sub3",   Category::Arithmetic, [Register,  Register, Register], "1110100"),
+    ins("sub3i",  Category::Arithmetic, [Register,  Register, AConst],   "1110101"),
+    ins("and3",   Category::Arithmetic, [Register,  Register, Register], "1110110"),
+    ins("and3i",  Category::Arithmetic, [Register,  Register, LConst],   "1110111"),
+    ins("or3",    Category::Arithmetic, [Register,  Register, Register], "1111000"),
+    ins("or3i",   Category::Arithmetic, [Register,  Register, LConst],   "1111001"),
+    ins("xor3",   Category::Arithmetic, [Register,  Register, Register], "1111010"),
+    ins("xor3i",  Category::Arithmetic, [Register,  Register, LConst],   "1111011"),
+    ins("asr3",   Category::Arithmetic, [Register,  Register, Shift],    "1111100"),
+    ins("sleep",  Category::Control,    [LConst,    No,       No],       "1111101"),
+    ins("rand",   Category::Control,    [Register,  No,       No],       "1111110"),
+    // pop used to be 1001001, which readze (10010) is a prefix of
+    ins("pop",    Category::Memory,     [Size,      Register, No],       "11111110"),
+    ins("reti",   Category::Control,    [No,        No,       No],       "11111111"),
+];
+
+/// Instruction of an opcode number
+pub fn instruction(opcode: u32) -> Option<&'static Instruction> {
+    INSTRUCTIONS.get(opcode as usize)
+}
+
+/// Opcode number of a mnemonic
+pub fn lookup(mnemonic: &str) -> Option<u32> {
+    INSTRUCTIONS.iter().position(|i| i.mnemonic == mnemonic).map(|op| op as u32)
+}
+
+/// Opcode number of a (code, length) pair in the default encoding
+pub fn decode(code: u64, length: u32) -> Option<u32> {
+    INSTRUCTIONS.iter().position(|i| i.bits() == (code, length)).map(|op| op as u32)
+}
+
+//---
+// Operand encodings
+//---
+
+/// Condition names, indexed by their 3-bit code
+pub const CONDITIONS: [&str; 8] = ["eq", "neq", "sgt", "slt", "gt", "ge", "lt", "v"];
+
+/// Other names accepted for conditions, with the name they stand for
+pub const CONDITION_ALIASES: [(&str, &str); 5] =
+    [("z", "eq"), ("nz", "neq"), ("nc", "ge"), ("c", "lt"), ("le", "v")];
+
+/// Pointer names, indexed by their 2-bit code
+pub const POINTERS: [&str; 4] = ["pc", "sp", "a0", "a1"];
+
+/// Direction names, indexed by their 1-bit code
+pub const DIRECTIONS: [&str; 2] = ["left", "right"];
+
+/// Access sizes in bits, with their (code, length)
+pub const SIZES: [(u64, (u64, u32)); 6] = [
+    (1, (0b00, 2)), (4, (0b01, 2)), (8, (0b100, 3)),
+    (16, (0b101, 3)), (32, (0b110, 3)), (64, (0b111, 3)),
+];
+
+/// Prefixes 0/10/110/111 select one of four widths for constants and
+/// addresses
+pub const PREFIXES: [(u64, u32); 4] = [(0b0, 1), (0b10, 2), (0b110, 3), (0b111, 3)];
+pub const CONST_WIDTHS: [u32; 4] = [1, 8, 32, 64];
+pub const ADDRESS_WIDTHS: [u32; 4] = [8, 16, 32, 64];
+
+/// Code of a condition name, aliases included
+pub fn condition(name: &str) -> Option<u64> {
+    let name = CONDITION_ALIASES.iter().find(|(alias, _)| *alias == name).map_or(name, |(_, n)| n);
+    CONDITIONS.iter().position(|&c| c == name).map(|c| c as u64)
+}
+
+/// Code of a pointer name
+pub fn pointer(name: &str) -> Option<u64> {
+    POINTERS.iter().position(|&p| p == name).map(|p| p as u64)
+}
+
+/// Encoding (code, length) of an access size in bits
+pub fn size(bits: u64) -> Option<(u64, u32)> {
+    SIZES.iter().find(|(s, _)| *s == bits).map(|&(_, code)| code)
+}
+
+fn mask(width: u32) -> u64 {
+    if width >= 64 { u64::MAX } else { (1 << width) - 1 }
+}
+
+// Smallest of `widths` that holds the value, as an index
+fn width_index(value: u64, signed: bool, widths: [u32; 4]) -> usize {
+    widths.iter().position(|&w| if w == 64 {
+        true
+    } else if signed {
+        let v = value as i64;
+        v >= -(1 << (w - 1)) && v < (1 << (w - 1))
+    } else {
+        value < (1 << w)
+    }).unwrap()
+}
+
+/// Fields (value, width) of a constant, on the smallest width that holds
+/// it. Signed constants are given in two's complement
+pub fn encode_const(value: u64, signed: bool) -> [(u64, u32); 2] {
+    let index = width_index(value, signed, CONST_WIDTHS);
+    let width = CONST_WIDTHS[index];
+    [PREFIXES[index], (value & mask(width), width)]
+}
+
+/// Fields (value, width) of a relative address
+pub fn encode_address(offset: i64) -> [(u64, u32); 2] {
+    let index = width_index(offset as u64, true, ADDRESS_WIDTHS);
+    let width = ADDRESS_WIDTHS[index];
+    [PREFIXES[index], (offset as u64 & mask(width), width)]
+}
+
+/// Fields (value, width) of a shift amount: "1" for one, "0" and 6 bits
+/// otherwise
+pub fn encode_shift(amount: u64) -> Option<Vec<(u64, u32)>> {
+    match amount {
+        1 => Some(vec![(1, 1)]),
+        0..=63 => Some(vec![(0, 1), (amount, 6)]),
+        _ => None,
+    }
+}
+
+/// Fields as a string of '0' and '1'
+pub fn to_bits(fields: &[(u64, u32)]) -> String {
+    fields.iter().map(|&(value, width)| format!("{:0width$b}", value & mask(width), width = width as usize)).collect()
+}
+
+//---
+// Machine
+//---
+
+/// Width of the registers and of the arithmetic on them. Programs do not
+/// depend on it: constants are encoded on up to 64 bits and cut to the
+/// word when used, and pointers are bit addresses of 64 bits either way.
+/// emu runs 64-bit words by default and subject/simu 32-bit words
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WordSize {
+    W32,
+    #[default]
+    W64,
+}
+
+impl WordSize {
+    /// The word size of a number of bits, 32 or 64
+    pub fn from_bits(bits: u64) -> Option<WordSize> {
+        match bits {
+            32 => Some(WordSize::W32),
+            64 => Some(WordSize::W64),
+            _ => None,
+        }
+    }
+
+    pub const fn bits(self) -> u32 {
+        match self {
+            WordSize::W32 => 32,
+            WordSize::W64 => 64,
+        }
+    }
+
+    /// The low `bits()` bits of a value
+    pub const fn truncate(self, value: u64) -> u64 {
+        match self {
+            WordSize::W32 => value & 0xffff_ffff,
+            WordSize::W64 => value,
+        }
+    }
+
+    /// A word as a signed number
+    pub const fn signed(self, value: u64) -> i64 {
+        match self {
+            WordSize::W32 => value as u32 as i32 as i64,
+            WordSize::W64 => value as i64,
+        }
+    }
+}
+
+//---
+// Opcode tables
+//---
+
+/// First line of an opcode table file, with the version of the format
+pub const OPCODE_TABLE_HEADER: &str = "minimisa-opcodes 1";
+
+/// Code of every instruction as (code, length), indexed by opcode number.
+/// A length of 0 means the instruction has no code in the table
+pub type Opcodes = [(u64, u32); INSTRUCTION_COUNT];
+
+/// The default opcode table
+pub fn default_opcodes() -> Opcodes {
+    let mut codes = [(0, 0); INSTRUCTION_COUNT];
+    for (code, ins) in codes.iter_mut().zip(INSTRUCTIONS.iter()) {
+        *code = ins.bits();
+    }
+    codes
+}
+
+/// Text of an opcode table: the header, then one "<mnemonic> <code>" line
+/// per instruction that has a code, in opcode order
+pub fn format_opcodes(codes: &Opcodes) -> String {
+    let mut text = format!("{}\n", OPCODE_TABLE_HEADER);
+    for (ins, &(code, length)) in INSTRUCTIONS.iter().zip(codes.iter()) {
+        if length > 0 {
+            text.push_str(&format!("{} {}\n", ins.mnemonic, to_bits(&[(code, length)])));
+        }
+    }
+    text
+}
+
+/// Read an opcode table written by format_opcodes(). Files without the
+/// header (written before it existed) are accepted, other versions are
+/// not. Blank lines and lines starting with ';' are ignored. Errors are
+/// given with their line number
+pub fn parse_opcodes(text: &str) -> Result<Opcodes, String> {
+    let mut codes = [(0, 0); INSTRUCTION_COUNT];
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (mnemonic, code) = match fields.as_slice() {
+            ["minimisa-opcodes", version] if number == 0 => match *version {
+                "1" => continue,
+                v => return Err(format!("line 1: unsupported opcode table version {}", v)),
+            },
+            [mnemonic, code] => (*mnemonic, *code),
+            _ => return Err(format!("line {}: expected '<mnemonic> <code>'", number + 1)),
+        };
+
+        let op = lookup(mnemonic)
+            .ok_or_else(|| format!("line {}: unknown instruction '{}'", number + 1, mnemonic))?;
+        if code.is_empty() || code.len() > 64 || !code.chars().all(|c| c == '0' || c == '1') {
+            return Err(format!("line {}: invalid code '{}'", number + 1, code));
+        }
+        if codes[op as usize].1 > 0 {
+            return Err(format!("line {}: '{}' is given twice", number + 1, mnemonic));
+        }
+        codes[op as usize] = (u64::from_str_radix(code, 2).unwrap(), code.len() as u32);
+    }
+
+    // A prefix code is required to decode the stream unambiguously
+    for (i, &(c1, l1)) in codes.iter().enumerate() {
+        for (j, &(c2, l2)) in codes.iter().enumerate() {
+            if i != j && l1 > 0 && l2 > 0 && l1 <= l2 && c2 >> (l2 - l1) == c1 {
+                return Err(format!("code of {} is a prefix of the code of {}",
+                    INSTRUCTIONS[i].mnemonic, INSTRUCTIONS[j].mnemonic));
+            }
+        }
+    }
+    Ok(codes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_free() {
+        for (i, a) in INSTRUCTIONS.iter().enumerate() {
+            for b in &INSTRUCTIONS[i + 1..] {
+                assert!(!a.code.starts_with(b.code) && !b.code.starts_with(a.code),
+                    "{} and {} are not prefix-free", a.mnemonic, b.mnemonic);
+            }
+            assert_eq!(decode(a.bits().0, a.bits().1), Some(i as u32));
+            assert_eq!(lookup(a.mnemonic), Some(i as u32));
+        }
+        assert_eq!(instruction(op::OP_RETI).unwrap().mnemonic, "reti");
+        assert_eq!(instruction(op::OP_ASR3).unwrap().arity(), 3);
+    }
+
+    #[test]
+    fn test_operands() {
+        assert_eq!(condition("nc"), Some(5));
+        assert_eq!(condition("le"), condition("v"));
+        assert_eq!(condition("x"), None);
+        assert_eq!(size(16), Some((0b101, 3)));
+        assert_eq!(to_bits(&encode_const(1, false)), "01");
+        assert_eq!(to_bits(&encode_const(-1i64 as u64, true)), "01");
+        assert_eq!(to_bits(&encode_const(-2i64 as u64, true)), "10".to_string() + "11111110");
+        assert_eq!(to_bits(&encode_address(-13)), "0".to_string() + "11110011");
+        assert_eq!(encode_address(300)[1], (300, 16));
+        assert_eq!(encode_shift(1), Some(vec![(1, 1)]));
+        assert_eq!(encode_shift(64), None);
+    }
+
+    #[test]
+    fn test_word_size() {
+        assert_eq!(WordSize::default(), WordSize::W64);
+        assert_eq!(WordSize::from_bits(32).map(WordSize::bits), Some(32));
+        assert_eq!(WordSize::from_bits(16), None);
+        assert_eq!(WordSize::W32.truncate(0x1_2345_6789), 0x2345_6789);
+        assert_eq!(WordSize::W64.truncate(u64::MAX), u64::MAX);
+        assert_eq!(WordSize::W32.signed(0xffff_fffe), -2);
+        assert_eq!(WordSize::W64.signed(0xffff_fffe), 0xffff_fffe);
+    }
+
+    #[test]
+    fn test_opcode_table() {
+        let codes = default_opcodes();
+        let text = format_opcodes(&codes);
+        assert!(text.starts_with("minimisa-opcodes 1\nadd2 0000\n"));
+        assert_eq!(parse_opcodes(&text), Ok(codes));
+
+        // Tables written before the header, partial tables
+        let codes = parse_opcodes("; built for prog.s\nlet 0\njump 10\n\nreti 11\n").unwrap();
+        assert_eq!(codes[op::OP_JUMP as usize], (0b10, 2));
+        assert_eq!(codes[op::OP_ADD2 as usize], (0, 0));
+        assert_eq!(parse_opcodes(&format_opcodes(&codes)), Ok(codes));
+
+        assert!(parse_opcodes("minimisa-opcodes 2\nlet 0\n").is_err());
+        assert!(parse_opcodes("let 0\njump 01\n").is_err());
+        assert!(parse_opcodes("let 0\nlet 1\n").is_err());
+        assert!(parse_opcodes("lett 0\n").is_err());
+        assert!(parse_opcodes("let 02\n").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//---
+// minimisa-core:object - the MinimISA object file format
+//
+// Written by the compiler, read by the emulator and the simulator. All
+// numbers are big-endian, addresses and lengths are in bits:
+//
+//     magic       "MISA"
+//     version     u16, OBJECT_VERSION
+//     flags       u16, FLAG_OPCODES if an opcode table follows the symbols
+//     entry       u64, address of the first instruction
+//     segments    u32 count, then for each:
+//                     address u64, length u64, ceil(length / 8) bytes
+//     symbols     u32 count, then for each:
+//                     address u64, name length u16, name (UTF-8)
+//     opcodes     u32 length, then the text of format_opcodes()
+//
+// Segment bytes hold the bits most significant first, the last byte is
+// padded with zeros. The text segment is the one at address 0; a program
+// with data at a fixed address (.data) has one more segment per block.
+//
+// The symbols can also be kept in a text file of their own (compileuh
+// --symbols), for programs that are not objects: one "<bit address>
+// <label>" line per symbol, the label map format of disasm -m.
+//---
+
+use crate::bitvec::BitVec;
+use crate::{format_opcodes, parse_opcodes, Opcodes};
+
+pub const OBJECT_MAGIC: &[u8; 4] = b"MISA";
+pub const OBJECT_VERSION: u16 = 1;
+
+/// The object carries the opcode table the program was assembled with
+pub const FLAG_OPCODES: u16 = 1;
+
+/// Bits loaded at a fixed address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u64,
+    pub length: u64,    // In bits
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    /// Segment of a string of '0' and '1' characters; other characters
+    /// are ignored
+    pub fn from_bits(address: u64, bits: &str) -> Segment {
+        let bits: String = bits.chars().filter(|c| *c == '0' || *c == '1').collect();
+        Segment::from_bitvec(address, &bits.parse().unwrap())
+    }
+
+    pub fn from_bitvec(address: u64, bits: &BitVec) -> Segment {
+        Segment { address, length: bits.len(), bytes: bits.as_bytes().to_vec() }
+    }
+
+    /// Bit `i` of the segment, counted from its start
+    pub fn bit(&self, i: u64) -> bool {
+        (self.bytes[(i / 8) as usize] >> (7 - i % 8)) & 1 == 1
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<(String, u64)>,
+    /// Opcode table of the program, None for the default one
+    pub opcodes: Option<Opcodes>,
+}
+
+// Reader of the big-endian fields of an object
+struct Fields<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize, what: &str) -> Result<&'a [u8], String> {
+        let slice = self.bytes.get(self.pos..self.pos + n)
+            .ok_or_else(|| format!("truncated object (in {} at byte {})", what, self.pos))?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.take(2, what)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self, what: &str) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8, what)?.try_into().unwrap()))
+    }
+}
+
+impl Object {
+    /// Whether a file starts like an object. Other files are bare bit
+    /// streams (binary or '0'/'1' text) loaded at address 0
+    pub fn is_object(bytes: &[u8]) -> bool {
+        bytes.starts_with(OBJECT_MAGIC)
+    }
+
+    /// Object with a single text segment
+    pub fn from_text(bits: &str) -> Object {
+        Object { segments: vec![Segment::from_bits(0, bits)], ..Object::default() }
+    }
+
+    pub fn from_text_bits(bits: &BitVec) -> Object {
+        Object { segments: vec![Segment::from_bitvec(0, bits)], ..Object::default() }
+    }
+
+    /// The segment at address 0, if any
+    pub fn text(&self) -> Option<&Segment> {
+        self.segments.iter().find(|s| s.address == 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = OBJECT_MAGIC.to_vec();
+        let flags = if self.opcodes.is_some() { FLAG_OPCODES } else { 0 };
+        out.extend(OBJECT_VERSION.to_be_bytes());
+        out.extend(flags.to_be_bytes());
+        out.extend(self.entry.to_be_bytes());
+
+        out.extend((self.segments.len() as u32).to_be_bytes());
+        for segment in &self.segments {
+            out.extend(segment.address.to_be_bytes());
+            out.extend(segment.length.to_be_bytes());
+            out.extend(&segment.bytes);
+        }
+
+        out.extend((self.symbols.len() as u32).to_be_bytes());
+        for (name, address) in &self.symbols {
+            out.extend(address.to_be_bytes());
+            out.extend((name.len() as u16).to_be_bytes());
+            out.extend(name.as_bytes());
+        }
+
+        if let Some(codes) = &self.opcodes {
+            let text = format_opcodes(codes);
+            out.extend((text.len() as u32).to_be_bytes());
+            out.extend(text.as_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, String> {
+        if !Object::is_object(bytes) {
+            return Err("not a MinimISA object (bad magic)".to_string());
+        }
+        let mut f = Fields { bytes, pos: OBJECT_MAGIC.len() };
+
+        let version = f.u16("header")?;
+        if version != OBJECT_VERSION {
+            return Err(format!("unsupported object version {}", version));
+        }
+        let flags = f.u16("header")?;
+        let entry = f.u64("header")?;
+
+        let mut segments = Vec::new();
+        for _ in 0..f.u32("segment table")? {
+            let address = f.u64("segment")?;
+            let length = f.u64("segment")?;
+            let size = usize::try_from(length.div_ceil(8)).map_err(|_| "segment too large".to_string())?;
+            let bytes = f.take(size, "segment")?.to_vec();
+            segments.push(Segment { address, length, bytes });
+        }
+
+        let mut symbols = Vec::new();
+        for _ in 0..f.u32("symbol table")? {
+            let address = f.u64("symbol")?;
+            let length = f.u16("symbol")? as usize;
+            let name = std::str::from_utf8(f.take(length, "symbol")?)
+                .map_err(|_| "symbol name is not UTF-8".to_string())?;
+            symbols.push((name.to_string(), address));
+        }
+
+        let opcodes = if flags & FLAG_OPCODES != 0 {
+            let length = f.u32("opcode table")? as usize;
+            let text = std::str::from_utf8(f.take(length, "opcode table")?)
+                .map_err(|_| "opcode table is not UTF-8".to_string())?;
+            Some(parse_opcodes(text).map_err(|e| format!("opcode table: {}", e))?)
+        } else {
+            None
+        };
+
+        if f.pos != bytes.len() {
+            return Err(format!("{} trailing bytes after the object", bytes.len() - f.pos));
+        }
+        Ok(Object { entry, segments, symbols, opcodes })
+    }
+}
+
+/// Text of a symbol file, addresses in hexadecimal, in address order
+pub fn format_symbols(symbols: &[(String, u64)]) -> String {
+    let mut sorted: Vec<&(String, u64)> = symbols.iter().collect();
+    sorted.sort_by_key(|(name, address)| (*address, name.as_str()));
+    sorted.iter().map(|(name, address)| format!("{:#x} {}\n", address, name)).collect()
+}
+
+/// Read a symbol file. Addresses are decimal or 0x-prefixed hexadecimal;
+/// blank lines and lines starting with ';' are ignored. Errors are given
+/// with their line number
+pub fn parse_symbols(text: &str) -> Result<Vec<(String, u64)>, String> {
+    let mut symbols = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [address, name] = fields.as_slice() else {
+            return Err(format!("line {}: expected '<address> <label>'", number + 1));
+        };
+        let address = match address.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => address.parse(),
+        };
+        let address = address.map_err(|_| format!("line {}: invalid address in '{}'", number + 1, line))?;
+        symbols.push((name.to_string(), address));
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_opcodes;
+
+    #[test]
+    fn test_object_round_trip() {
+        let mut object = Object::from_text("0111 001 10 00100101\n1010 0 11110011");
+        assert_eq!(object.text().unwrap().length, 30);
+        assert_eq!(object.text().unwrap().bytes, [0b01110011, 0b00010010, 0b11010011, 0b11001100]);
+        assert!(object.text().unwrap().bit(1) && !object.text().unwrap().bit(4));
+        let bits: BitVec = "0111 001 10 00100101 1010 0 11110011".parse().unwrap();
+        assert_eq!(Object::from_text_bits(&bits), object);
+
+        object.segments.push(Segment::from_bits(0xc000, "1"));
+        object.symbols.push(("main".to_string(), 0));
+        object.symbols.push(("table".to_string(), 0xc000));
+        let bytes = object.to_bytes();
+        assert!(Object::is_object(&bytes));
+        assert_eq!(Object::from_bytes(&bytes), Ok(object.clone()));
+
+        object.entry = 17;
+        object.opcodes = Some(default_opcodes());
+        assert_eq!(Object::from_bytes(&object.to_bytes()), Ok(object.clone()));
+
+        // Damaged objects are rejected, not misread
+        let bytes = object.to_bytes();
+        assert!(Object::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Object::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(Object::from_bytes(b"MISA\x00\x02").is_err());
+        assert!(Object::from_bytes(b"0101").is_err());
+    }
+
+    #[test]
+    fn test_symbol_file() {
+        let symbols = vec![("end".to_string(), 0x33), ("main".to_string(), 0)];
+        let text = format_symbols(&symbols);
+        assert_eq!(text, "0x0 main\n0x33 end\n");
+        assert_eq!(parse_symbols(&text).unwrap(), [symbols[1].clone(), symbols[0].clone()]);
+        assert_eq!(parse_symbols("; map\n\n17 loop\n").unwrap(), [("loop".to_string(), 17)]);
+        assert!(parse_symbols("0x1g loop\n").is_err());
+        assert!(parse_symbols("17\n").is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//---
+// minimisa-core:pages - sparse memory backing
+//
+// Memory of the emulator and the simulator is an array of 64-bit words.
+// Pages stores it in pages of 64 KiB allocated on the first write of a
+// non-zero word, so that a large address space only costs the pages a
+// program touches; everything else reads as zero. How bits are laid out in
+// a word is up to the memory using it.
+//---
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Words in a page (64 KiB)
+pub const PAGE_WORDS: u64 = 8192;
+
+#[derive(Clone, Default)]
+pub struct Pages {
+    pages: HashMap<u64, Box<[u64]>>,
+}
+
+impl Pages {
+    pub fn new() -> Pages {
+        Pages::default()
+    }
+
+    /// Word at an index, 0 if its page was never written
+    pub fn word(&self, index: u64) -> u64 {
+        self.pages.get(&(index / PAGE_WORDS)).map_or(0, |page| page[(index % PAGE_WORDS) as usize])
+    }
+
+    /// Set a word, allocating its page unless the word stays 0
+    pub fn set_word(&mut self, index: u64, value: u64) {
+        let page = match self.pages.get_mut(&(index / PAGE_WORDS)) {
+            Some(page) => page,
+            None if value == 0 => return,
+            None => self.pages.entry(index / PAGE_WORDS)
+                .or_insert_with(|| vec![0; PAGE_WORDS as usize].into_boxed_slice()),
+        };
+        page[(index % PAGE_WORDS) as usize] = value;
+    }
+
+    /// Number of allocated pages
+    pub fn allocated(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Indices of the words that differ from those of other pages, in
+    /// order. Pages allocated on one side only are compared with zeros
+    pub fn changed_words(&self, other: &Pages) -> Vec<u64> {
+        let zeros = [0u64; PAGE_WORDS as usize];
+        let mut pages: Vec<u64> = self.pages.keys().chain(other.pages.keys().filter(|p| !self.pages.contains_key(p)))
+            .copied().collect();
+        pages.sort_unstable();
+
+        let mut changed = Vec::new();
+        for page in pages {
+            let a = self.pages.get(&page).map_or(&zeros[..], |p| &p[..]);
+            let b = other.pages.get(&page).map_or(&zeros[..], |p| &p[..]);
+            changed.extend((0..PAGE_WORDS).filter(|&i| a[i as usize] != b[i as usize]).map(|i| page * PAGE_WORDS + i));
+        }
+        changed
+    }
+
+    /// Free every page, making all words 0
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
+
+impl fmt::Debug for Pages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pages({} allocated)", self.pages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages() {
+        let mut pages = Pages::new();
+        let far = (1u64 << 32) / 64 - 1;
+        assert_eq!(pages.word(far), 0);
+
+        pages.set_word(3, 0);
+        assert_eq!(pages.allocated(), 0);
+        pages.set_word(far, 0xdead);
+        pages.set_word(far - 1, 1);
+        pages.set_word(PAGE_WORDS, 2);
+        assert_eq!((pages.word(far), pages.word(far - 1), pages.word(PAGE_WORDS)), (0xdead, 1, 2));
+        assert_eq!(pages.word(PAGE_WORDS - 1), 0);
+        assert_eq!(pages.allocated(), 2);
+
+        let mut other = pages.clone();
+        other.set_word(far, 0xbeef);
+        other.set_word(5, 7);
+        other.set_word(PAGE_WORDS, 0);
+        assert_eq!(pages.changed_words(&other), [5, PAGE_WORDS, far]);
+        assert_eq!(other.changed_words(&pages), [5, PAGE_WORDS, far]);
+        assert!(pages.changed_words(&pages.clone()).is_empty());
+
+        pages.clear();
+        assert_eq!((pages.word(far), pages.allocated()), (0, 0));
+    }
+}
@@ -0,0 +1,198 @@
+//---
+// emu:branch - branch statistics and prediction experiments
+//
+// Conditional branches are collected from a trace (either a simu trace
+// file or a run of the emulator) and replayed through a few textbook
+// predictors, which is what the branch-prediction exercises ask for.
+//---
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use crate::disasm::{disasm_addr, disasm_cond, disasm_opcode, OP_JUMPIF};
+use crate::memory::Memory;
+
+/// One executed conditional branch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BranchEvent {
+    pub pc: u64,
+    pub taken: bool,
+}
+
+/// A branch predictor: guess the outcome, then learn the real one
+pub trait Predictor {
+    fn name(&self) -> &'static str;
+    fn predict(&mut self, pc: u64) -> bool;
+    fn update(&mut self, pc: u64, taken: bool);
+}
+
+/// Static prediction: every branch is taken
+pub struct AlwaysTaken;
+
+impl Predictor for AlwaysTaken {
+    fn name(&self) -> &'static str { "always-taken" }
+    fn predict(&mut self, _pc: u64) -> bool { true }
+    fn update(&mut self, _pc: u64, _taken: bool) {}
+}
+
+/// One bit per branch: predict the last outcome (initially not taken)
+#[derive(Default)]
+pub struct OneBit {
+    last: HashMap<u64, bool>,
+}
+
+impl Predictor for OneBit {
+    fn name(&self) -> &'static str { "1-bit" }
+
+    fn predict(&mut self, pc: u64) -> bool {
+        self.last.get(&pc).copied().unwrap_or(false)
+    }
+
+    fn update(&mut self, pc: u64, taken: bool) {
+        self.last.insert(pc, taken);
+    }
+}
+
+/// Two-bit saturating counter per branch: 0-1 predict not taken, 2-3
+/// predict taken. Counters start at 1 (weakly not taken)
+#[derive(Default)]
+pub struct TwoBit {
+    counters: HashMap<u64, u8>,
+}
+
+impl Predictor for TwoBit {
+    fn name(&self) -> &'static str { "2-bit" }
+
+    fn predict(&mut self, pc: u64) -> bool {
+        self.counters.get(&pc).copied().unwrap_or(1) >= 2
+    }
+
+    fn update(&mut self, pc: u64, taken: bool) {
+        let counter = self.counters.entry(pc).or_insert(1);
+        *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+    }
+}
+
+/// Per-branch counts and predictor accuracies
+pub struct BranchStats {
+    pub branches: BTreeMap<u64, (usize, usize)>,  // pc -> (taken, not taken)
+    pub predictors: Vec<(Box<dyn Predictor>, usize)>,  // predictor, hits
+    pub total: usize,
+}
+
+impl Default for BranchStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BranchStats {
+    /// Statistics with the always-taken, 1-bit and 2-bit predictors
+    pub fn new() -> Self {
+        BranchStats {
+            branches: BTreeMap::new(),
+            predictors: vec![
+                (Box::new(AlwaysTaken), 0),
+                (Box::new(OneBit::default()), 0),
+                (Box::new(TwoBit::default()), 0),
+            ],
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, event: BranchEvent) {
+        let counts = self.branches.entry(event.pc).or_insert((0, 0));
+        if event.taken { counts.0 += 1 } else { counts.1 += 1 }
+
+        for (predictor, hits) in &mut self.predictors {
+            if predictor.predict(event.pc) == event.taken {
+                *hits += 1;
+            }
+            predictor.update(event.pc, event.taken);
+        }
+        self.total += 1;
+    }
+
+    /// Accuracy of each predictor, in the order they were registered
+    pub fn accuracies(&self) -> Vec<(&'static str, f64)> {
+        self.predictors.iter().map(|(p, hits)| {
+            let rate = if self.total == 0 { 0.0 } else { *hits as f64 / self.total as f64 };
+            (p.name(), rate)
+        }).collect()
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{} conditional branches executed, {} distinct",
+            self.total, self.branches.len());
+        let _ = writeln!(out, "{:>10} {:>10} {:>10} {:>8}", "pc", "taken", "not taken", "rate");
+        for (pc, (taken, not_taken)) in &self.branches {
+            let rate = 100.0 * *taken as f64 / (*taken + *not_taken) as f64;
+            let _ = writeln!(out, "{:>10x} {:>10} {:>10} {:>7.1}%", pc, taken, not_taken, rate);
+        }
+        let _ = writeln!(out, "predictors:");
+        for (name, rate) in self.accuracies() {
+            let _ = writeln!(out, "  {:<14} {:>6.2}%", name, 100.0 * rate);
+        }
+        out
+    }
+}
+
+/// Read the conditional branches of a simu trace (simu -t). Only jumpif
+/// lines carry a taken=0/1 field
+pub fn read_simu_trace(filename: &str) -> io::Result<Vec<BranchEvent>> {
+    let reader = BufReader::new(File::open(filename)?);
+    let mut events = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let taken = match line.split_whitespace().find_map(|f| f.strip_prefix("taken=")) {
+            Some(t) => t == "1",
+            None => continue,
+        };
+        let pc = line.split_whitespace().next().and_then(|pc| u64::from_str_radix(pc, 16).ok());
+        match pc {
+            Some(pc) => events.push(BranchEvent { pc, taken }),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("invalid trace line: {}", line))),
+        }
+    }
+    Ok(events)
+}
+
+/// Turn an executed instruction of the emulator into a branch event:
+/// returns None unless the instruction at `pc` is a jumpif, whose outcome
+/// is found by comparing `next_pc` with the fall-through address
+pub fn branch_event(memory: &Memory, pc: u64, next_pc: u64) -> Option<BranchEvent> {
+    let mut ptr = pc;
+    let (opcode, _) = disasm_opcode(memory, &mut ptr);
+    if opcode != OP_JUMPIF {
+        return None;
+    }
+    disasm_cond(memory, &mut ptr);
+    disasm_addr(memory, &mut ptr, None);
+    Some(BranchEvent { pc, taken: next_pc != ptr })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predictors() {
+        // A loop branch taken 3 times then falling through, run twice
+        let mut stats = BranchStats::new();
+        for _ in 0..2 {
+            for taken in [true, true, true, false] {
+                stats.record(BranchEvent { pc: 0x40, taken });
+            }
+        }
+
+        assert_eq!(stats.branches[&0x40], (6, 2));
+        let hits: Vec<usize> = stats.predictors.iter().map(|(_, h)| *h).collect();
+        // always-taken: 6/8, 1-bit: misses the first and every transition,
+        // 2-bit: only misses warm-up and the loop exits
+        assert_eq!(hits, vec![6, 4, 5]);
+    }
+}
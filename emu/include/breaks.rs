@@ -1,15 +1,21 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io;
 use std::sync::{Arc, Mutex};
 
-/// Breakpoint manager structure to manage breakpoints
+/// Breakpoint manager structure to manage breakpoints. Clones share the
+/// breakpoints and watches
+#[derive(Clone)]
 pub struct BreakpointManager {
     breakpoints: Arc<Mutex<HashSet<u64>>>,  
+    watches: Arc<Mutex<BTreeSet<(u64, usize)>>>,  // (address, size in bits)
 }
 
 impl BreakpointManager {
     pub fn new() -> Self {
         BreakpointManager {
             breakpoints: Arc::new(Mutex::new(HashSet::new())),
+            watches: Arc::new(Mutex::new(BTreeSet::new())),
         }
     }
 
@@ -32,6 +38,106 @@ impl BreakpointManager {
         breaks.contains(&address)
     }
 
+    pub fn add_watch(&self, address: u64, size: usize) {
+        self.watches.lock().unwrap().insert((address, size));
+    }
+
+    pub fn remove_watch(&self, address: u64) -> Result<(), String> {
+        let mut watches = self.watches.lock().unwrap();
+        let before = watches.len();
+        watches.retain(|&(a, _)| a != address);
+        if watches.len() < before {
+            Ok(())
+        } else {
+            Err(format!("Watch not found at address: 0x{:x}", address))
+        }
+    }
+
+    pub fn watches(&self) -> Vec<(u64, usize)> {
+        self.watches.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Text form of the breakpoint and watch lists, one entry per line:
+    ///   break <address> [<symbol>]
+    ///   watch <address> <size> [<symbol>]
+    /// Symbols are taken from `labels` (address -> name) when known.
+    pub fn to_text(&self, labels: &BTreeMap<u64, String>) -> String {
+        let mut out = String::from("# MinimISA debugger breakpoints and watches\n");
+        let symbol = |a: &u64| labels.get(a).map(|l| format!(" {}", l)).unwrap_or_default();
+
+        let mut breaks: Vec<u64> = self.breakpoints.lock().unwrap().iter().copied().collect();
+        breaks.sort();
+        for a in &breaks {
+            out.push_str(&format!("break 0x{:x}{}\n", a, symbol(a)));
+        }
+        for (a, size) in self.watches() {
+            out.push_str(&format!("watch 0x{:x} {}{}\n", a, size, symbol(&a)));
+        }
+        out
+    }
+
+    /// Add the entries of a list in to_text() format. An entry may give a
+    /// symbol instead of an address, or both: the symbol is then resolved
+    /// with `labels` and wins, so that lists survive program changes.
+    /// Returns the number of entries added.
+    pub fn from_text(&self, text: &str, labels: &BTreeMap<u64, String>) -> Result<usize, String> {
+        let resolve = |name: &str| labels.iter().find(|(_, l)| *l == name).map(|(a, _)| *a);
+        let parse_address = |s: &str| match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+        let mut count = 0;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let err = |msg: &str| Err(format!("line {}: {}", number + 1, msg));
+
+            let (kind, rest) = match fields.split_first() {
+                Some((kind, rest)) => (*kind, rest),
+                None => continue,
+            };
+            // The location is an address, a symbol, or an address and a symbol
+            let (location, size, symbol) = match (kind, rest) {
+                ("break", [loc]) => (*loc, None, None),
+                ("break", [loc, sym]) => (*loc, None, Some(*sym)),
+                ("watch", [loc, size]) => (*loc, Some(*size), None),
+                ("watch", [loc, size, sym]) => (*loc, Some(*size), Some(*sym)),
+                ("break", _) | ("watch", _) => return err("wrong number of fields"),
+                _ => return err(&format!("unknown entry '{}'", kind)),
+            };
+
+            let address = match symbol.and_then(resolve) {
+                Some(a) => a,
+                None => match parse_address(location).or_else(|| resolve(location)) {
+                    Some(a) => a,
+                    None => return err(&format!("unknown symbol '{}'", symbol.unwrap_or(location))),
+                },
+            };
+
+            match size {
+                None => self.add(address),
+                Some(size) => match size.parse() {
+                    Ok(size) if size > 0 && size <= 64 => self.add_watch(address, size),
+                    _ => return err(&format!("invalid watch size '{}'", size)),
+                },
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Save the lists to a file (breaks export)
+    pub fn export(&self, filename: &str, labels: &BTreeMap<u64, String>) -> io::Result<()> {
+        fs::write(filename, self.to_text(labels))
+    }
+
+    /// Load lists from a file (breaks import)
+    pub fn import(&self, filename: &str, labels: &BTreeMap<u64, String>) -> Result<usize, String> {
+        let text = fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        self.from_text(&text, labels).map_err(|e| format!("{}: {}", filename, e))
+    }
+
     pub fn show(&self) {
         let breaks = self.breakpoints.lock().unwrap();
         if breaks.is_empty() {
@@ -67,4 +173,29 @@ mod tests {
 
         manager.show();
     }
+
+    #[test]
+    fn test_export_import() {
+        let mut labels = BTreeMap::new();
+        labels.insert(0x40, "loop".to_string());
+
+        let manager = BreakpointManager::new();
+        manager.add(0x40);
+        manager.add(0x1000);
+        manager.add_watch(0x10000, 64);
+        let text = manager.to_text(&labels);
+        assert_eq!(text.lines().skip(1).collect::<Vec<_>>(),
+            vec!["break 0x40 loop", "break 0x1000", "watch 0x10000 64"]);
+
+        // The symbol wins over the recorded address once the program moved
+        let mut moved = BTreeMap::new();
+        moved.insert(0x48, "loop".to_string());
+        let imported = BreakpointManager::new();
+        assert_eq!(imported.from_text(&text, &moved), Ok(3));
+        assert!(imported.has(0x48) && !imported.has(0x40) && imported.has(0x1000));
+        assert_eq!(imported.watches(), vec![(0x10000, 64)]);
+
+        assert_eq!(imported.from_text("break main", &labels), Err("line 1: unknown symbol 'main'".to_string()));
+        assert!(imported.from_text("watch 0x10 100", &labels).is_err());
+    }
 }
@@ -0,0 +1,150 @@
+//---
+// emu:compat - behave like subject/simu
+//
+// The course scripts drive subject/simu, which duplicates the emulator
+// engine with a 32-bit word. This profile reproduces what the scripts
+// rely on: 32-bit registers, ASCII objects and the -d debug output, so
+// that simu can be retired in favor of emu --compat simu.
+//---
+
+use std::fs;
+use std::io;
+use minimisa_core::object::Object;
+use crate::cpu::{CPU, A0, A1, PC, SP};
+use crate::disasm::{disasm_code, disasm_one, OP_JUMPIF};
+use crate::icache::decode;
+use crate::memory::Memory;
+use minimisa_core::WordSize;
+
+/// Word size of subject/simu
+pub const SIMU_WORD_SIZE: WordSize = WordSize::W32;
+
+/// Object formats accepted by simu --format, shared with subject/simu
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjFormat {
+    Text,    // ASCII '0' and '1' characters
+    Binary,  // Packed bytes, most significant bit first
+    Object,  // Object file of the compiler (minimisa_core::object)
+}
+
+impl ObjFormat {
+    /// Object files start with their magic; a file made only of '0', '1'
+    /// and whitespace is an ASCII object
+    pub fn detect(bytes: &[u8]) -> ObjFormat {
+        if Object::is_object(bytes) {
+            ObjFormat::Object
+        } else if Memory::is_text_object(bytes) {
+            ObjFormat::Text
+        } else {
+            ObjFormat::Binary
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ObjFormat> {
+        match name {
+            "txt" => Some(ObjFormat::Text),
+            "bin" => Some(ObjFormat::Binary),
+            "obj" => Some(ObjFormat::Object),
+            _ => None,
+        }
+    }
+}
+
+/// Switch the CPU to the simu profile
+pub fn simu_profile(cpu: &mut CPU) {
+    cpu.word_size = SIMU_WORD_SIZE;
+}
+
+/// Load an object the way simu does: the format is detected from the
+/// contents unless it is given explicitly. Returns the object, if the file
+/// is one
+pub fn simu_load(memory: &mut Memory, filename: &str, format: Option<ObjFormat>) -> io::Result<Option<Object>> {
+    let bytes = fs::read(filename)?;
+    match format.unwrap_or_else(|| ObjFormat::detect(&bytes)) {
+        ObjFormat::Text => memory.load_text(filename).map(|_| None),
+        ObjFormat::Object if !Object::is_object(&bytes) =>
+            Err(io::Error::new(io::ErrorKind::InvalidData, "not an object file")),
+        ObjFormat::Binary | ObjFormat::Object => memory.load_bytes(&bytes),
+    }
+}
+
+/// What simu prints about an instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimuLines {
+    pub debug: String,  // With -d
+    pub trace: String,  // In the -t file
+}
+
+/// Execute one instruction and return the lines simu prints for it. The
+/// -d line has the opcode bits, old and new pc, counters, flags and
+/// registers. The -t line has the address and the instruction (as the
+/// disassembler shows it), the registers that changed, the flags and
+/// taken=0/1 for conditional branches
+pub fn simu_step(cpu: &mut CPU) -> SimuLines {
+    let instr_pc = cpu.ptr[PC];
+    let (decoded, text, opcode) = {
+        let memory = cpu.mem.lock().unwrap();
+        let decoded = decode(&memory, instr_pc);
+        let text = disasm_one(&memory, &mut instr_pc.clone()).unwrap_or_else(|| "???".to_string());
+        (decoded, text, disasm_code(memory.opcodes(), decoded.opcode).map_or(0, |(code, _)| code))
+    };
+    let old_r = cpu.r;
+
+    cpu.execute();
+
+    let p = &cpu.ptr;
+    let flags = format!("{}{}{}{}", cpu.z as u8, cpu.c as u8, cpu.n as u8, cpu.v as u8);
+    let mut debug = format!(
+        "after instr: {} at pc={:08x} (newpc={:08x} mpc={:08x} msp={:08x} ma0={:08x} ma1={:08x}) ",
+        opcode, instr_pc, p[PC], p[PC], p[SP], p[A0], p[A1]
+    );
+    debug.push_str(&format!("zcnv = {}", flags));
+    for (i, r) in cpu.r.iter().enumerate() {
+        debug.push_str(&format!(" r{}={:08x}", i, r));
+    }
+
+    let mut trace = format!("{:08x} {} ;", instr_pc, text);
+    for (i, (old, new)) in old_r.iter().zip(cpu.r).enumerate() {
+        if *old != new {
+            trace.push_str(&format!(" r{}={:08x}", i, new));
+        }
+    }
+    trace.push_str(&format!(" zcnv={}", flags));
+    if decoded.opcode == OP_JUMPIF {
+        let taken = p[PC] != instr_pc.wrapping_add(decoded.len);
+        trace.push_str(&format!(" taken={}", taken as u8));
+    }
+    SimuLines { debug, trace }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::testing::assemble;
+
+    #[test]
+    fn test_obj_format() {
+        let object = assemble("leti r0 1").unwrap().to_bytes();
+        assert_eq!(ObjFormat::detect(&object), ObjFormat::Object);
+        assert_eq!(ObjFormat::detect(b"0110 1\n"), ObjFormat::Text);
+        assert_eq!(ObjFormat::detect(&[0x6f]), ObjFormat::Binary);
+        assert_eq!(ObjFormat::from_name("obj"), Some(ObjFormat::Object));
+        assert_eq!(ObjFormat::from_name("elf"), None);
+    }
+
+    #[test]
+    fn test_simu_step() {
+        let object = assemble("leti r1 3\ncmpi r1 3\nend: jumpif eq end").unwrap();
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        memory.lock().unwrap().load_object(&object).unwrap();
+        let mut cpu = CPU::new(Arc::clone(&memory));
+        simu_profile(&mut cpu);
+
+        assert_eq!(simu_step(&mut cpu).trace, "00000000 leti r1 3 ; r1=00000003 zcnv=0000");
+        assert_eq!(simu_step(&mut cpu).trace, "00000011 cmpi r1 3 ; zcnv=1000");
+        let lines = simu_step(&mut cpu);
+        assert!(lines.trace.ends_with(" ; zcnv=1000 taken=1"), "{}", lines.trace);
+        assert!(lines.debug.starts_with("after instr: 11 at pc=00000022 (newpc="), "{}", lines.debug);
+    }
+}
@@ -1,7 +1,23 @@
 use std::sync::{Arc, Mutex};
 use std::fmt;
-use crate::memory::Memory;
-use crate::disasm::disasm_opcode;
+use std::fs;
+use std::io;
+use crate::devices::Clock;
+use crate::icache::{decode, DecodeCache, Decoded};
+use crate::journal::{CpuState, Journal, StepRecord};
+use crate::memory::{Access, Memory, TextWrites, Violation, ViolationKind};
+use crate::profiler::Profiler;
+use crate::replay::Session;
+use crate::scheduler::Scheduler;
+use crate::trace::Trace;
+use crate::disasm::{disasm_format, disasm_one, ArgType, Category, DISASM_INS_COUNT};
+use crate::disasm::{OP_ADD2, OP_ADD2I, OP_ADD3, OP_ADD3I, OP_AND2, OP_AND2I, OP_AND3, OP_AND3I, OP_ASR3, OP_CALL,
+    OP_CMP, OP_CMPI, OP_GETCTR, OP_JUMP, OP_JUMPIF, OP_LET, OP_LETI, OP_OR2, OP_OR2I, OP_OR3, OP_OR3I, OP_POP, OP_PUSH,
+    OP_RAND, OP_READSE, OP_READZE, OP_RETI, OP_RETURN, OP_SETCTR, OP_SHIFT, OP_SLEEP, OP_SUB2, OP_SUB2I, OP_SUB3,
+    OP_SUB3I, OP_WRITE, OP_XOR3, OP_XOR3I};
+use crate::util::{add_with_flags, condition_holds, logic_flags, read_extend, shift_with_carry, sub_with_flags, Flags, Rng};
+use minimisa_core::WordSize;
+use serde_json::{json, Value};
 
 /// Some names for the memory pointers
 pub const PC: usize = 0;
@@ -9,6 +25,128 @@ pub const SP: usize = 1;
 pub const A0: usize = 2;
 pub const A1: usize = 3;
 
+/// What to do when PC leaves the executable segments or an access is
+/// denied by the segment permissions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecCheck {
+    Off,     // No check
+    Warn,    // Print a warning and keep running
+    Strict,  // Print an error and halt
+}
+
+/// A failed memory access that stopped the CPU, with the address of the
+/// instruction that made it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fault {
+    pub pc: u64,
+    pub violation: Violation,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (pc={:#x})", self.violation, self.pc)
+    }
+}
+
+/// A call not yet returned from, on the shadow call stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallFrame {
+    pub site: u64,    // Address of the call instruction
+    pub target: u64,  // Address of the function called
+    pub ret: u64,     // Return address, pushed on the stack
+    pub sp: u64,      // SP after the push, where the return address is
+}
+
+/// Interrupt numbers, used as indices in the vector table
+pub const IRQ_TIMER: usize = 0;
+
+/// Periodic timer raising IRQ_TIMER every `period` cycles
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timer {
+    pub period: u64,   // Cycles between two interrupts, 0 when disabled
+    pub counter: u64,  // Cycles elapsed since the last interrupt
+}
+
+const CATEGORY_NAMES: [&str; 6] = ["arithmetic", "test", "let", "jump", "memory", "control"];
+const ACCESS_SIZES: [u32; 6] = [1, 4, 8, 16, 32, 64];
+
+/// Cycle costs of the timing model: a base cost for each instruction
+/// category, plus the cost of the memory access for instructions that have
+/// a size operand
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingModel {
+    pub category: [u64; 6],  // Arithmetic, test, let, jump, memory, control
+    pub access: [u64; 6],    // Accesses of 1, 4, 8, 16, 32 and 64 bits
+}
+
+impl Default for TimingModel {
+    fn default() -> Self {
+        TimingModel { category: [1, 1, 1, 2, 1, 1], access: [1, 1, 1, 1, 2, 3] }
+    }
+}
+
+impl TimingModel {
+    /// Read a cost table in CSV, one `key,cycles` line per cost. Keys are
+    /// category names (arithmetic, test, let, jump, memory, control) and
+    /// access sizes (access1 to access64). Missing keys keep their default
+    /// cost; lines starting with '#' are comments
+    pub fn from_csv(text: &str) -> Result<TimingModel, String> {
+        let mut model = TimingModel::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("line {}: expected key,cycles: {}", number + 1, line);
+            let (key, cost) = line.split_once(',').ok_or_else(error)?;
+            let cost = cost.trim().parse().map_err(|_| error())?;
+            let key = key.trim();
+
+            let slot = match CATEGORY_NAMES.iter().position(|&n| n == key) {
+                Some(i) => &mut model.category[i],
+                None => match key.strip_prefix("access").and_then(|n| n.parse().ok())
+                    .and_then(|size: u32| ACCESS_SIZES.iter().position(|&s| s == size)) {
+                    Some(i) => &mut model.access[i],
+                    None => return Err(format!("line {}: unknown cost '{}'", number + 1, key)),
+                },
+            };
+            *slot = cost;
+        }
+        Ok(model)
+    }
+
+    pub fn load(filename: &str) -> io::Result<TimingModel> {
+        let text = fs::read_to_string(filename)?;
+        TimingModel::from_csv(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn category_cost(&self, category: Category) -> u64 {
+        self.category[category as usize]
+    }
+
+    /// Cost of the instruction at `pc`, in cycles
+    pub fn cost(&self, memory: &Memory, pc: u64) -> u64 {
+        self.cost_of(&decode(memory, pc))
+    }
+
+    /// Cost of a decoded instruction, in cycles
+    pub fn cost_of(&self, decoded: &Decoded) -> u64 {
+        let format = match disasm_format(decoded.opcode) {
+            Some(format) => format,
+            None => return 1,
+        };
+
+        // Sizes come first, after the pointer if there is one
+        let size = match (format.arg1, format.arg2) {
+            (ArgType::Size, _) => Some(decoded.operands[0]),
+            (ArgType::Pointer, ArgType::Size) => Some(decoded.operands[1]),
+            _ => None,
+        };
+        self.category_cost(format.category)
+            + size.and_then(|size| ACCESS_SIZES.iter().position(|&s| s as u64 == size)).map_or(0, |i| self.access[i])
+    }
+}
+
 /// CPU struct holding registers, pointers, flags, and associated memory
 pub struct CPU {
     pub mem: Arc<Mutex<Memory>>,  // Memory associated with the CPU (shared)
@@ -23,14 +161,38 @@ pub struct CPU {
 
     // Debugger flags
     pub h: bool,    // Halt: detects loops of one instruction
+    pub fault: Option<Fault>,  // Why the last instruction halted the CPU, if it faulted
     pub m: bool,    // Memory: indicates changes to memory
     pub t: bool,    // Counter: signals counter changes
     pub s: bool,    // Stop: indicates stop orders from user
     pub sleep: bool,  // Current sleeping state
 
     pub ptr: [u64; 4],  // Pointers: PC, SP, A0, A1
+    pub calls: Vec<CallFrame>,  // Shadow call stack: calls not yet returned from, innermost last
 
     pub instruction_count: [usize; DISASM_INS_COUNT],  
+
+    pub exec_check: ExecCheck,  // Check for execution and access permissions
+    prev_pc: Option<u64>,        // Address of the last executed instruction
+
+    // Interrupts
+    pub cycles: u64,         // Number of executed instructions
+    pub clock: u64,          // Simulated time in cycles, from the timing model
+    pub timing: TimingModel,
+    pub timer: Timer,        // Periodic timer
+    pub in_interrupt: bool,  // Set between interrupt entry and reti
+    pub pending_irqs: u8,    // Raised interrupts not yet delivered, as bits
+    pub scheduler: Scheduler,  // Devices ticked every cycle
+    pub rtc: Option<Clock>,    // Clock device, given the cycle count before every instruction
+    pub replay: Option<Session>,  // Records or replays what the program reads from devices
+    pub rng: Rng,                 // Values of rand, from a seed (0 unless set)
+
+    pub journal: Option<Journal>,  // Undo records of the last instructions
+    pub profiler: Option<Profiler>,  // Per-address hit counts, when profiling
+    pub icache: Option<DecodeCache>,  // Decoded instructions, None to decode every time
+    pub trace: Option<Trace>,        // Line per executed instruction, when tracing
+
+    pub word_size: WordSize,  // Register width (64 bits, or 32 for simu)
 }
 
 impl CPU {
@@ -43,12 +205,31 @@ impl CPU {
             c: false,
             v: false,
             h: false,
+            fault: None,
             m: false,
             t: false,
             s: false,
             sleep: false,
             ptr: [0; 4],
+            calls: Vec::new(),
             instruction_count: [0; DISASM_INS_COUNT],
+            exec_check: ExecCheck::Off,
+            prev_pc: None,
+            cycles: 0,
+            clock: 0,
+            timing: TimingModel::default(),
+            timer: Timer::default(),
+            in_interrupt: false,
+            pending_irqs: 0,
+            scheduler: Scheduler::new(),
+            rtc: None,
+            replay: None,
+            rng: Rng::new(0),
+            journal: None,
+            profiler: None,
+            icache: Some(DecodeCache::new()),
+            trace: None,
+            word_size: WordSize::W64,
         }
     }
 
@@ -58,45 +239,465 @@ impl CPU {
 
     pub fn dump(&self) -> String {
         format!(
-            "CPU State:\nRegisters: {:?}\nPC: {:#x}\nSP: {:#x}\nFlags: Z:{} N:{} C:{} V:{}\n",
-            self.r, self.ptr[PC], self.ptr[SP], self.z, self.n, self.c, self.v
+            "CPU State:\nRegisters: {:?}\nPC: {:#x}\nSP: {:#x}\nFlags: Z:{} N:{} C:{} V:{}\nClock: {} cycles, {} instructions\n",
+            self.r, self.ptr[PC], self.ptr[SP], self.z, self.n, self.c, self.v, self.clock, self.cycles
         )
     }
 
+    /// What dump() shows, as a JSON object, plus the halt state and the
+    /// fault that caused it, if any
+    pub fn to_json(&self) -> Value {
+        json!({
+            "registers": self.r,
+            "pc": self.ptr[PC],
+            "sp": self.ptr[SP],
+            "a0": self.ptr[A0],
+            "a1": self.ptr[A1],
+            "flags": { "z": self.z, "n": self.n, "c": self.c, "v": self.v },
+            "cycles": self.clock,
+            "instructions": self.cycles,
+            "halted": self.h,
+            "fault": self.fault.map(|fault| fault.to_string()),
+        })
+    }
+
+    /// Registers, pointers and flags
+    pub fn state(&self) -> CpuState {
+        CpuState { r: self.r, ptr: self.ptr, z: self.z, n: self.n, c: self.c, v: self.v }
+    }
+
+    /// Register display for the debugger, including interrupt state
+    pub fn dump_registers(&self) -> String {
+        let mut out = self.state().dump();
+        if self.timer.period != 0 {
+            out.push_str(&format!("timer {}/{}{}\n", self.timer.counter, self.timer.period,
+                if self.in_interrupt { " (in irq)" } else { "" }));
+        }
+        out
+    }
+
+    /// Keep undo records for the last `capacity` instructions
+    pub fn enable_journal(&mut self, capacity: usize) {
+        self.journal = Some(Journal::new(capacity));
+    }
+
+    /// Undo the last instruction recorded in the journal: restore the
+    /// memory it overwrote and the CPU state before it. Device registers
+    /// are not restored. Returns false when there is nothing to undo
+    pub fn step_back(&mut self) -> bool {
+        let record = match self.journal.as_mut().and_then(|j| j.pop()) {
+            Some(record) => record,
+            None => return false,
+        };
+
+        let mem = Arc::clone(&self.mem);
+        let mut memory = mem.lock().unwrap();
+        for w in record.writes.iter().rev() {
+            memory.write(w.address, w.old, w.n);
+        }
+
+        let state = record.state;
+        self.r = state.r;
+        self.ptr = state.ptr;
+        (self.z, self.n, self.c, self.v) = (state.z, state.n, state.c, state.v);
+        self.timer.counter = record.timer_counter;
+        self.in_interrupt = record.in_interrupt;
+        self.pending_irqs = record.pending_irqs;
+        self.calls.truncate(record.call_depth);
+        self.calls.extend(record.returned);
+        self.clock = record.clock;
+        self.cycles = self.cycles.saturating_sub(1);
+        self.prev_pc = None;
+        self.h = false;
+        self.fault = None;
+        true
+    }
+
+    /// Set the timer period in cycles, 0 disables it
+    pub fn set_timer(&mut self, period: u64) {
+        self.timer = Timer { period, counter: 0 };
+    }
+
+    /// Enter an interrupt: push PC on the stack and jump to the handler
+    /// found in the vector table. Like call, a push past the stack faults
+    /// and leaves SP and PC alone
+    fn interrupt(&mut self, memory: &mut Memory, irq: usize) {
+        let sp = self.ptr[SP].wrapping_sub(64);
+        if !memory.check_stack(sp, 64, Access::Write) {
+            return;
+        }
+        self.ptr[SP] = sp;
+        memory.write(sp, self.ptr[PC], 64);
+        self.ptr[PC] = memory.read(memory.vector_address(irq), 64);
+        self.in_interrupt = true;
+    }
+
+    /// Return from an interrupt: pop PC from the stack, unless it is empty
+    fn reti(&mut self, memory: &mut Memory) {
+        if !memory.check_stack(self.ptr[SP], 64, Access::Read) {
+            return;
+        }
+        self.ptr[PC] = memory.read(self.ptr[SP], 64);
+        self.ptr[SP] = self.ptr[SP].wrapping_add(64);
+        self.in_interrupt = false;
+    }
+
+    /// Count a cycle, tick the timer then the scheduled devices, and deliver
+    /// the lowest pending interrupt. Interrupts are not reentrant: they stay
+    /// pending while a handler is running.
+    fn tick_devices(&mut self, memory: &mut Memory) {
+        self.cycles += 1;
+        if self.timer.period != 0 {
+            self.timer.counter += 1;
+            if self.timer.counter >= self.timer.period {
+                self.timer.counter = 0;
+                self.pending_irqs |= 1 << IRQ_TIMER;
+            }
+        }
+        if !self.scheduler.is_empty() {
+            for irq in self.scheduler.run(self.cycles, memory) {
+                self.pending_irqs |= 1 << irq;
+            }
+        }
+
+        if !self.in_interrupt && self.pending_irqs != 0 {
+            let irq = self.pending_irqs.trailing_zeros() as usize;
+            self.pending_irqs &= !(1 << irq);
+            self.interrupt(memory, irq);
+        }
+    }
+
+    /// Check that PC is in an executable segment (by default, only text).
+    /// Executing from the stack, data or VRAM almost always means a missing
+    /// halt or a corrupted return address, so the diagnostic shows the
+    /// instruction that led there.
+    fn check_segment(&mut self, memory: &Memory) -> bool {
+        let pc = self.ptr[PC];
+        let segment = memory.segment(pc);
+        let was_exec = self.prev_pc.is_none_or(|p| memory.permits(p, Access::Execute));
+        if self.exec_check == ExecCheck::Off || memory.permits(pc, Access::Execute) || !was_exec {
+            return true;
+        }
+
+        let previous = match self.prev_pc {
+            Some(prev) => {
+                let mut ptr = prev;
+                let ins = disasm_one(memory, &mut ptr).unwrap_or_else(|| "?".to_string());
+                format!("{:#x}: {}", prev, ins)
+            }
+            None => "none".to_string(),
+        };
+        let level = if self.exec_check == ExecCheck::Strict { "error" } else { "warning" };
+        eprintln!("{}: executing from {} segment at pc={:#x} (previous instruction {})",
+            level, segment.name(), pc, previous);
+
+        if self.exec_check == ExecCheck::Strict {
+            let violation = Violation { address: pc, access: Access::Execute, segment, kind: ViolationKind::Denied };
+            self.fault = Some(Fault { pc, violation });
+            return false;
+        }
+        true
+    }
+
+    /// Report a failed access of the last instruction (the access itself
+    /// was not performed). Accesses out of memory or outside the stack
+    /// always halt with a fault; denied ones follow exec_check and writes
+    /// to text follow the TextWrites mode of the memory
+    fn check_violation(&mut self, memory: &Memory, pc: u64) {
+        let violation = match memory.take_violation() {
+            Some(violation) => violation,
+            None => return,
+        };
+        let fatal = match violation.kind {
+            ViolationKind::Denied => self.exec_check == ExecCheck::Strict,
+            ViolationKind::TextWrite => memory.text_writes() == TextWrites::Trap,
+            _ => true,
+        };
+        if violation.kind == ViolationKind::Denied && self.exec_check == ExecCheck::Off {
+            return;
+        }
+
+        let mut ptr = pc;
+        let ins = disasm_one(memory, &mut ptr).unwrap_or_else(|| "?".to_string());
+        let level = if fatal { "error" } else { "warning" };
+        eprintln!("{}: {} by {:#x}: {}", level, violation, pc, ins);
+
+        if fatal {
+            self.h = true;
+            self.fault = Some(Fault { pc, violation });
+        }
+    }
+
     pub fn execute(&mut self) {
         let pc = self.ptr[PC];
-        let mut memory = self.mem.lock().unwrap();
+        let mem = Arc::clone(&self.mem);
+        let mut memory = mem.lock().unwrap();
+
+        self.fault = None;
+        if !self.check_segment(&memory) {
+            self.h = true;
+            return;
+        }
+        memory.take_violation();
+        self.prev_pc = Some(pc);
+
+        let before = self.state();
+        let (timer_counter, in_interrupt) = (self.timer.counter, self.in_interrupt);
+        let (pending_irqs, call_depth, clock) = (self.pending_irqs, self.calls.len(), self.clock);
+        let mut returned = None;
+        if self.journal.is_some() {
+            memory.start_write_log();
+        }
 
-        let (opcode, format) = disasm_opcode(&memory, &mut self.ptr[PC]);
+        // Disassembled first, since the instruction may overwrite itself
+        let traced = self.trace.as_ref().map(|_| disasm_one(&memory, &mut pc.clone()).unwrap_or_default());
+
+        let (decoded, cached) = match self.icache.as_mut() {
+            Some(cache) => cache.get(&mut memory, pc),
+            None => (decode(&memory, pc), false),
+        };
+        let (opcode, [op1, op2, op3]) = (decoded.opcode, decoded.operands);
+        let mut ptr = pc.wrapping_add(decoded.len);
 
         if (opcode as usize) < DISASM_INS_COUNT {
             self.instruction_count[opcode as usize] += 1;
+            let cost = self.timing.cost_of(&decoded);
+            self.clock += cost;
+            if let Some(rtc) = &self.rtc {
+                rtc.set_cycles(self.clock);
+            }
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.record(pc, cost, cached);
+            }
         }
 
+        // Operands in encoding order, see icache.rs
         match opcode {
-            0x01 => {
-                let reg = memory.read_u64(self.ptr[PC]);  
-                let addr = memory.read_u64(self.ptr[PC] + 8);  
-                self.r[reg as usize] = memory.read_u64(addr);  
-                self.ptr[PC] += 16;  
-            }
-            0x02 => {
-                let reg1 = memory.read_bits(self.ptr[PC], 3);
-                let reg2 = memory.read_bits(self.ptr[PC] + 3, 3);
-                self.r[reg1 as usize] = self.r[reg1 as usize].wrapping_add(self.r[reg2 as usize]);
-                self.ptr[PC] += 6;  
+            OP_ADD2 | OP_ADD2I | OP_SUB2 | OP_SUB2I | OP_CMP | OP_CMPI => {
+                // Wrapping on the word size, see util.rs for the flags
+                let rd = op1 as usize;
+                let value = match opcode {
+                    OP_ADD2 | OP_SUB2 | OP_CMP => self.r[op2 as usize],
+                    _ => op2,
+                };
+                let (result, flags) = match opcode {
+                    OP_ADD2 | OP_ADD2I => add_with_flags(self.r[rd], value, self.word_size),
+                    _ => sub_with_flags(self.r[rd], value, self.word_size),
+                };
+                if opcode != OP_CMP && opcode != OP_CMPI {
+                    self.r[rd] = result;
+                }
+                self.set_flags(flags);
+            }
+            OP_SHIFT => {
+                // Direction 1 is right; the carry is the last bit out
+                let rd = op2 as usize;
+                let (result, carry) = shift_with_carry(self.r[rd], op3 as u32, op1 == 1, false, self.word_size);
+                self.r[rd] = result;
+                self.z = result == 0;
+                self.c = carry.unwrap_or(self.c);
+            }
+            OP_OR2 | OP_OR2I | OP_AND2 | OP_AND2I => {
+                let rd = op1 as usize;
+                let value = match opcode {
+                    OP_OR2 | OP_AND2 => self.r[op2 as usize],
+                    _ => op2,
+                };
+                let result = self.word_size.truncate(match opcode {
+                    OP_OR2 | OP_OR2I => self.r[rd] | value,
+                    _ => self.r[rd] & value,
+                });
+                self.r[rd] = result;
+                self.set_flags(logic_flags(result, self.word_size, self.flags()));
+            }
+            OP_ADD3 | OP_ADD3I | OP_SUB3 | OP_SUB3I => {
+                // rd = rs + x, with the flags of add2 and sub2
+                let value = match opcode {
+                    OP_ADD3 | OP_SUB3 => self.r[op3 as usize],
+                    _ => op3,
+                };
+                let (result, flags) = match opcode {
+                    OP_ADD3 | OP_ADD3I => add_with_flags(self.r[op2 as usize], value, self.word_size),
+                    _ => sub_with_flags(self.r[op2 as usize], value, self.word_size),
+                };
+                self.r[op1 as usize] = result;
+                self.set_flags(flags);
+            }
+            OP_AND3 | OP_AND3I | OP_OR3 | OP_OR3I | OP_XOR3 | OP_XOR3I => {
+                let (x, y) = match opcode {
+                    OP_AND3 | OP_OR3 | OP_XOR3 => (self.r[op2 as usize], self.r[op3 as usize]),
+                    _ => (self.r[op2 as usize], op3),
+                };
+                let result = self.word_size.truncate(match opcode {
+                    OP_AND3 | OP_AND3I => x & y,
+                    OP_OR3 | OP_OR3I => x | y,
+                    _ => x ^ y,
+                });
+                self.r[op1 as usize] = result;
+                self.set_flags(logic_flags(result, self.word_size, self.flags()));
+            }
+            OP_ASR3 => {
+                let (result, carry) = shift_with_carry(self.r[op2 as usize], op3 as u32, true, true, self.word_size);
+                self.r[op1 as usize] = result;
+                self.z = result == 0;
+                self.c = carry.unwrap_or(self.c);
+            }
+            OP_LET => {
+                self.r[op1 as usize] = self.r[op2 as usize];
+            }
+            OP_LETI => {
+                self.r[op1 as usize] = op2;
+            }
+            OP_SETCTR => {
+                // Setting PC jumps, there is nothing to move past
+                if op1 as usize == PC {
+                    ptr = self.r[op2 as usize];
+                    self.h = ptr == pc;
+                } else {
+                    self.ptr[op1 as usize] = self.r[op2 as usize];
+                }
+            }
+            OP_GETCTR => {
+                // PC reads as the address of the next instruction
+                self.r[op2 as usize] = if op1 as usize == PC { ptr } else { self.ptr[op1 as usize] };
+            }
+            OP_JUMP => {
+                // Offsets are relative to the end of the jump instruction
+                ptr = ptr.wrapping_add(op1);
+                self.h = ptr == pc;
+            }
+            OP_JUMPIF => {
+                if condition_holds(op1 as u32, self.flags()) {
+                    ptr = ptr.wrapping_add(op2);
+                    // Flags do not change, so it is taken forever
+                    self.h = ptr == pc;
+                }
+            }
+            OP_CALL => {
+                // Push the return address, like an interrupt entry
+                let sp = self.ptr[SP].wrapping_sub(64);
+                if memory.check_stack(sp, 64, Access::Write) {
+                    self.ptr[SP] = sp;
+                    memory.write(sp, ptr, 64);
+                    let target = ptr.wrapping_add(op1);
+                    self.calls.push(CallFrame { site: pc, target, ret: ptr, sp });
+                    ptr = target;
+                }
+            }
+            OP_RETURN => {
+                if memory.check_stack(self.ptr[SP], 64, Access::Read) {
+                    ptr = memory.read(self.ptr[SP], 64);
+                    self.ptr[SP] = self.ptr[SP].wrapping_add(64);
+                    returned = self.calls.pop();
+                }
+            }
+            OP_READZE | OP_READSE => {
+                let value = self.access(&mut memory, &mut ptr, op1 as usize, op2 as u32, None);
+                self.r[op3 as usize] = read_extend(value, op2 as u32, opcode == OP_READSE);
+            }
+            OP_WRITE => {
+                self.access(&mut memory, &mut ptr, op1 as usize, op2 as u32, Some(self.r[op3 as usize]));
+            }
+            OP_PUSH => {
+                // The stack grows down from the top of the stack segment and
+                // SP points at the last value pushed. Pushing past the limit
+                // or popping above the top faults and leaves SP alone
+                let size = op1 as usize;
+                let sp = self.ptr[SP].wrapping_sub(op1);
+                if memory.check_stack(sp, size, Access::Write) {
+                    self.ptr[SP] = sp;
+                    memory.write(sp, self.r[op2 as usize], size);
+                }
+            }
+            OP_POP => {
+                let size = op1 as usize;
+                if memory.check_stack(self.ptr[SP], size, Access::Read) {
+                    self.r[op2 as usize] = memory.read(self.ptr[SP], size);
+                    self.ptr[SP] = self.ptr[SP].wrapping_add(op1);
+                }
+            }
+            OP_RAND => {
+                // The generator is not rewound by step_back, like device reads
+                let rng = &mut self.rng;
+                self.r[op1 as usize] = match &self.replay {
+                    Some(session) => session.rand(self.cycles, || rng.next_u64()),
+                    None => rng.next_u64(),
+                };
+            }
+            OP_SLEEP => {
+                // Sleeping only lets simulated time pass
+                self.clock += op1;
+            }
+            OP_RETI => {
+                self.ptr[PC] = ptr;
+                self.reti(&mut memory);
+                ptr = self.ptr[PC];
             }
             _ => {
-                self.h = true;  
+                // Unknown or not yet emulated instruction
+                self.h = true;
+                ptr = pc;
+            }
+        }
+        self.ptr[PC] = ptr;
+
+        // Narrower profiles keep registers truncated to the word size
+        for r in self.r.iter_mut() {
+            *r = self.word_size.truncate(*r);
+        }
+
+        self.tick_devices(&mut memory);
+        self.check_violation(&memory, pc);
+
+        if let Some(text) = traced {
+            let after = self.state();
+            let cycles = self.cycles;
+            if let Some(Err(e)) = self.trace.as_mut().map(|trace| trace.record(cycles, pc, &text, &before, &after)) {
+                eprintln!("warning: trace stopped: {}", e);
+                self.trace = None;
             }
         }
 
-        self.update_flags();
+        if let Some(journal) = self.journal.as_mut() {
+            let writes = memory.take_write_log();
+            journal.push(StepRecord { state: before, writes, timer_counter, in_interrupt,
+                pending_irqs, call_depth, returned, clock });
+        }
+    }
+
+    /// Read `size` bits at a memory pointer, or write the low bits of a
+    /// value there, then move the pointer past them. `ptr` is the address
+    /// of the next instruction, which is where PC points for the access.
+    /// Accesses in the I/O window reach devices: reading the keyboard event
+    /// register with readze pops a key event (see devices.rs), and such
+    /// reads are not undone by step_back. Device reads go through the replay
+    /// session, if any
+    fn access(&mut self, memory: &mut Memory, ptr: &mut u64, pointer: usize, size: u32,
+        value: Option<u64>) -> u64 {
+        self.ptr[PC] = *ptr;
+        let address = self.ptr[pointer];
+        let result = match value {
+            Some(value) => {
+                memory.write(address, value, size as usize);
+                value
+            }
+            None => match &self.replay {
+                Some(session) if memory.is_mmio(address) =>
+                    session.read(self.cycles, address, size as usize, || memory.read(address, size as usize)),
+                _ => memory.read(address, size as usize),
+            },
+        };
+        self.ptr[pointer] = address.wrapping_add(size as u64);
+        *ptr = self.ptr[PC];
+        result
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags { z: self.z, n: self.n, c: self.c, v: self.v }
     }
 
-    fn update_flags(&mut self) {
-        self.z = self.r[0] == 0;  
-        self.n = (self.r[0] as i64) < 0;  
+    fn set_flags(&mut self, flags: Flags) {
+        (self.z, self.n, self.c, self.v) = (flags.z, flags.n, flags.c, flags.v);
     }
 
     pub fn counts(&self) -> &[usize; DISASM_INS_COUNT] {
@@ -109,3 +710,417 @@ impl fmt::Display for CPU {
         write!(f, "{}", self.dump())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disasm_lookup;
+
+    #[test]
+    fn test_timing_model() {
+        let model = TimingModel::from_csv("# costs\njump, 3\naccess64,5\n").unwrap();
+        assert_eq!(model.category_cost(Category::Jump), 3);
+        assert_eq!(model.category_cost(Category::Arithmetic), 1);
+        assert_eq!(model.access[5], 5);
+        assert!(TimingModel::from_csv("fetch,2").is_err());
+        assert!(TimingModel::from_csv("jump").is_err());
+
+        // push 64 r1: memory category plus a 64-bit access
+        let mut memory = Memory::new(1024, 1024, 1024, 1024);
+        memory.write(0, 0b1110000, 7);
+        memory.write(7, 0b111, 3);
+        memory.write(10, 1, 3);
+        assert_eq!(model.cost(&memory, 0), 1 + 5);
+
+        // add2 r0 r0
+        memory.write(16, 0, 10);
+        assert_eq!(model.cost(&memory, 16), 1);
+    }
+
+    // Run the instructions of a program written at 0, one per field list
+    fn run(program: &[&[(u64, usize)]], setup: impl FnOnce(&mut CPU, &mut Memory)) -> CPU {
+        let mem = Arc::new(Mutex::new(Memory::new(4096, 4096, 4096, 4096)));
+        let mut cpu = CPU::new(Arc::clone(&mem));
+        {
+            let mut memory = mem.lock().unwrap();
+            let mut address = 0;
+            for &(value, width) in program.iter().flat_map(|fields| fields.iter()) {
+                memory.write(address, value, width);
+                address += width as u64;
+            }
+            setup(&mut cpu, &mut memory);
+        }
+        for _ in program {
+            cpu.execute();
+        }
+        cpu
+    }
+
+    // Fields of an instruction from its mnemonic, a size and a register
+    fn memory_ins(mnemonic: &str, pointer: Option<u64>, size: u64, reg: u64) -> Vec<(u64, usize)> {
+        let (code, length) = minimisa_core::INSTRUCTIONS[disasm_lookup(mnemonic).unwrap() as usize].bits();
+        let (size_code, size_length) = minimisa_core::size(size).unwrap();
+        let mut fields = vec![(code, length as usize)];
+        fields.extend(pointer.map(|p| (p, 2)));
+        fields.extend([(size_code, size_length as usize), (reg, 3)]);
+        fields
+    }
+
+    // Fields of an instruction on two registers
+    fn reg_ins(mnemonic: &str, rd: u64, rs: u64) -> Vec<(u64, usize)> {
+        let (code, length) = minimisa_core::INSTRUCTIONS[disasm_lookup(mnemonic).unwrap() as usize].bits();
+        vec![(code, length as usize), (rd, 3), (rs, 3)]
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        for &(subtract, x, y, word, result, flags) in crate::util::ARITHMETIC_CASES {
+            let ins = reg_ins(if subtract { "sub2" } else { "add2" }, 1, 2);
+            let cpu = run(&[&ins], |cpu, _| {
+                cpu.word_size = word;
+                (cpu.r[1], cpu.r[2]) = (x, y);
+            });
+            assert_eq!((cpu.r[1], cpu.flags()), (result, crate::util::flags_of(flags)), "case {:#x}, {:#x} on {} bits", x, y, word.bits());
+        }
+
+        // cmp sets the flags of sub2 and keeps the registers
+        let cpu = run(&[&reg_ins("cmp", 1, 2)], |cpu, _| (cpu.r[1], cpu.r[2]) = (3, 5));
+        assert_eq!((cpu.r[1], cpu.flags()), (3, crate::util::flags_of("nc")));
+
+        // jumpif v skips 10 bits when the add before it overflows
+        let (code, length) = minimisa_core::INSTRUCTIONS[disasm_lookup("jumpif").unwrap() as usize].bits();
+        let add = reg_ins("add2", 1, 1);
+        let jumpif = [(code, length as usize), (7, 3), (0, 1), (10, 8)];
+        let end = 10 + length as u64 + 12;
+        let cpu = run(&[&add, &jumpif], |cpu, _| cpu.r[1] = i64::MAX as u64);
+        assert_eq!((cpu.ptr[PC], cpu.v), (end + 10, true));
+        let cpu = run(&[&add, &jumpif], |cpu, _| cpu.r[1] = 1);
+        assert_eq!((cpu.ptr[PC], cpu.v), (end, false));
+    }
+
+    #[test]
+    fn test_three_operands() {
+        // not and neg, expanded by the compiler
+        let program = crate::testing::assemble_str("
+            leti r3 5
+            leti r4 5
+            not r3
+            neg r4
+        end:
+            jump end
+        ");
+        let state = crate::testing::run_program(&program, 100);
+        assert!(state.halted);
+        assert_eq!((state.cpu.r[3] as i64, state.cpu.r[4] as i64), (!5, -5));
+
+        let state = crate::testing::run_program(&crate::testing::assemble_str("
+            leti r1 12
+            leti r2 10
+            add3 r0 r1 r2
+            sub3i r3 r1 20
+            and3 r4 r1 r2
+            or3i r5 r1 3
+            xor3 r6 r1 r2
+            asr3 r7 r3 2
+        end:
+            jump end
+        "), 100);
+        assert_eq!(&state.cpu.r[..], [22, 12, 10, -8i64 as u64, 8, 15, 6, -2i64 as u64]);
+        // Flags of the shift: the last bit out was 0, the result is not 0
+        assert_eq!((state.cpu.z, state.cpu.c), (false, false));
+    }
+
+    #[test]
+    fn test_logic() {
+        let state = crate::testing::run_program(&crate::testing::assemble_str("
+            leti r0 12
+            let r1 r0
+            let r2 r0
+            let r3 r0
+            leti r7 10
+            or2 r0 r7
+            and2 r1 r7
+            or2i r2 1
+            and2i r3 3
+            shift left r7 62
+            shift right r0 1
+        end:
+            jump end
+        "), 100);
+        assert_eq!(&state.cpu.r[..4], [0b111, 0b1000, 0b1101, 0]);
+        // The last bit out of r0 was a 0
+        assert_eq!((state.cpu.r[7], state.cpu.c), (1 << 63, false));
+
+        let state = crate::testing::run_program(&crate::testing::assemble_str("
+            leti r1 3
+            shift right r1 1
+            and2i r1 0
+            shift left r1 0
+        end:
+            jump end
+        "), 100);
+        // and2i sets z and keeps the carry of the shift, a shift by 0 too
+        assert_eq!((state.cpu.r[1], state.cpu.z, state.cpu.c), (0, true, true));
+    }
+
+    #[test]
+    fn test_read_write() {
+        // Every size, zero- and sign-extended, from A0 which moves past
+        // the bits read
+        for &(raw, size, ze, se) in crate::util::READ_EXTEND_CASES {
+            let readze = memory_ins("readze", Some(A0 as u64), size as u64, 1);
+            let readse = memory_ins("readse", Some(A0 as u64), size as u64, 2);
+            let cpu = run(&[&readze, &readse], |cpu, memory| {
+                cpu.ptr[A0] = 2048;
+                memory.write(2048, raw, size as usize);
+                memory.write(2048 + size as u64, raw, size as usize);
+            });
+            assert_eq!((cpu.r[1], cpu.r[2] as i64), (ze, se), "size {}", size);
+            assert_eq!(cpu.ptr[A0], 2048 + 2 * size as u64);
+        }
+
+        // write keeps the low bits of the register and moves A1
+        let write = memory_ins("write", Some(A1 as u64), 16, 3);
+        let cpu = run(&[&write, &write], |cpu, _| {
+            cpu.ptr[A1] = 3000;
+            cpu.r[3] = 0x12345678;
+        });
+        assert_eq!(cpu.ptr[A1], 3032);
+        assert_eq!(cpu.mem.lock().unwrap().read(3000, 32), 0x56785678);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let push = memory_ins("push", None, 8, 1);
+        let push64 = memory_ins("push", None, 64, 2);
+        let pop = memory_ins("pop", None, 64, 3);
+        let pop8 = memory_ins("pop", None, 8, 4);
+        let cpu = run(&[&push, &push64, &pop, &pop8], |cpu, _| {
+            cpu.ptr[SP] = 8192;
+            cpu.r[1] = 0x1ff;
+            cpu.r[2] = u64::MAX;
+        });
+        assert_eq!((cpu.r[3], cpu.r[4]), (u64::MAX, 0xff));
+        assert_eq!(cpu.ptr[SP], 8192);
+        assert_eq!(cpu.mem.lock().unwrap().read(8192 - 8, 8), 0xff);
+
+        // SP moves by the size, whatever it is
+        for size in [1, 4, 16, 32] {
+            let push = memory_ins("push", None, size, 1);
+            let pop = memory_ins("pop", None, size, 2);
+            let cpu = run(&[&push, &push, &pop], |cpu, _| {
+                cpu.ptr[SP] = 8192;
+                cpu.r[1] = 0x5555_5555_5555_5555;
+            });
+            assert_eq!(cpu.ptr[SP], 8192 - size, "size {}", size);
+            assert_eq!(cpu.r[2], 0x5555_5555_5555_5555 & ((1 << size) - 1), "size {}", size);
+        }
+
+        // Popping at the top of the stack would read the data segment
+        let cpu = run(&[&pop8], |cpu, _| cpu.ptr[SP] = 8192);
+        assert_eq!(cpu.fault.map(|f| f.violation.kind), Some(ViolationKind::StackUnderflow));
+        assert_eq!((cpu.ptr[SP], cpu.r[4]), (8192, 0));
+
+        // Pushing at the bottom of the stack would write the code
+        let cpu = run(&[&push64], |cpu, _| cpu.ptr[SP] = 4096 + 32);
+        assert_eq!(cpu.fault.map(|f| f.violation.kind), Some(ViolationKind::StackOverflow));
+        assert_eq!(cpu.ptr[SP], 4096 + 32);
+        assert_eq!(cpu.mem.lock().unwrap().read(4096 - 32, 64), 0);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        // Two instructions reach the period, then the handler returns
+        let nop = reg_ins("let", 0, 0);
+        let (code, length) = minimisa_core::INSTRUCTIONS[OP_RETI as usize].bits();
+        let mut cpu = run(&[&nop, &nop], |cpu, memory| {
+            cpu.ptr[SP] = 8192;
+            cpu.set_timer(2);
+            memory.write(2000, code, length as usize);
+            memory.write(memory.vector_address(IRQ_TIMER), 2000, 64);
+        });
+        assert!(cpu.in_interrupt);
+        assert_eq!((cpu.ptr[PC], cpu.ptr[SP]), (2000, 8192 - 64));
+        assert_eq!(cpu.mem.lock().unwrap().read(8192 - 64, 64), 20);
+
+        cpu.execute();
+        assert!(!cpu.in_interrupt);
+        assert_eq!((cpu.ptr[PC], cpu.ptr[SP]), (20, 8192));
+        assert_eq!(cpu.timer.counter, 1);
+    }
+
+    #[test]
+    fn test_interrupt_stack() {
+        // Entering an interrupt with a full stack faults before the handler
+        let nop = reg_ins("let", 0, 0);
+        let cpu = run(&[&nop], |cpu, _| {
+            cpu.ptr[SP] = 4096;
+            cpu.set_timer(1);
+        });
+        assert_eq!(cpu.fault.map(|f| f.violation.kind), Some(ViolationKind::StackOverflow));
+        assert!(cpu.h && !cpu.in_interrupt);
+        assert_eq!((cpu.ptr[PC], cpu.ptr[SP]), (10, 4096));
+
+        // reti with an empty stack
+        let (code, length) = minimisa_core::INSTRUCTIONS[OP_RETI as usize].bits();
+        let cpu = run(&[&[(code, length as usize)]], |cpu, _| {
+            cpu.ptr[SP] = 8192;
+            cpu.in_interrupt = true;
+        });
+        assert_eq!(cpu.fault.map(|f| f.violation.kind), Some(ViolationKind::StackUnderflow));
+        assert!(cpu.h && cpu.in_interrupt);
+        assert_eq!(cpu.ptr[SP], 8192);
+    }
+
+    #[test]
+    fn test_counters() {
+        // rdcycles r1 as the assembler expands it, with r6 as temporary:
+        // a0 is saved, pointed at the cycle counter and restored
+        let program = crate::testing::assemble_str("
+            getctr a0 r6
+            leti r1 0xfd50
+            setctr a0 r1
+            readze a0 64 r1
+            setctr a0 r6
+        end:
+            jump end
+        ");
+        let state = crate::testing::run_program_with(&program, 100, |cpu, memory| {
+            let clock = Clock::new();
+            crate::devices::attach_clock(memory, &clock);
+            cpu.rtc = Some(clock);
+            cpu.ptr[A0] = 1234;
+        });
+        assert!(state.halted);
+        // Three let instructions, then the 64-bit read
+        assert_eq!(state.cpu.r[1], 1 + 1 + 1 + (1 + 3));
+        assert_eq!((state.cpu.r[6], state.cpu.ptr[A0]), (1234, 1234));
+
+        // getctr pc is the address of the next instruction, and setctr pc
+        // jumps there, to itself
+        let state = crate::testing::run_program(&crate::testing::assemble_str("getctr pc r0\nsetctr pc r0"), 10);
+        let after = 6 + 2 + 3;
+        assert_eq!((state.cpu.r[0], state.cpu.ptr[PC], state.steps, state.halted), (after, after, 2, true));
+    }
+
+    #[test]
+    fn test_frames() {
+        // enter 128 and leave, expanded by the compiler, around a store to
+        // the first local
+        let program = crate::testing::assemble_str("
+            leti r7 99
+            call f
+        end:
+            jump end
+        f:
+            enter 128
+            leti r1 5
+            getctr sp r2
+            setctr a1 r2
+            write a1 64 r1
+            leave
+            return
+        ");
+
+        // Right after enter, fp holds the caller's fp and the return address
+        // is above it
+        let state = crate::testing::run_program(&program, 7);
+        let (fp, base) = (state.cpu.r[7], state.memory.data_base());
+        assert_eq!((fp, state.cpu.ptr[SP]), (base - 128, base - 128 - 128));
+        assert_eq!(state.memory.read(fp, 64), 99);
+        assert_eq!(state.memory.read(fp + 64, 64), state.memory.read(base - 64, 64));
+
+        // leave and return restore both
+        let state = crate::testing::run_program(&program, 100);
+        assert!(state.halted);
+        assert_eq!((state.cpu.r[7], state.cpu.ptr[SP]), (99, base));
+        assert_eq!(state.memory.read(base - 256, 64), 5);
+    }
+
+    #[test]
+    fn test_functions() {
+        // .func f r1 r2 64 and a return in its body, expanded by the
+        // compiler: the saved registers come back after the call
+        let program = crate::testing::assemble_str("
+            leti r1 10
+            leti r2 20
+            leti r7 30
+            call f
+        end:
+            jump end
+        .func f r1 r2 64
+            leti r1 1
+            leti r2 2
+            let r0 r1
+            add2 r0 r2
+            return
+        .endfunc
+        ");
+        let state = crate::testing::run_program(&program, 100);
+        assert!(state.halted);
+        assert_eq!((state.cpu.r[0], state.cpu.r[1], state.cpu.r[2], state.cpu.r[7]), (3, 10, 20, 30));
+        assert_eq!(state.cpu.ptr[SP], state.memory.data_base());
+    }
+
+    #[test]
+    fn test_decode_cache() {
+        // add2i r2 1, run twice, then once more after its constant changes
+        let mem = Arc::new(Mutex::new(Memory::new(4096, 4096, 4096, 4096)));
+        mem.lock().unwrap().patch(0, &"0001 010 10 00000001".parse().unwrap());
+        let mut cpu = CPU::new(Arc::clone(&mem));
+        cpu.profiler = Some(Profiler::new());
+        for (step, r2) in [1, 2, 7].into_iter().enumerate() {
+            if step == 2 {
+                mem.lock().unwrap().write(9, 5, 8);
+            }
+            cpu.ptr[PC] = 0;
+            cpu.execute();
+            assert_eq!((cpu.r[2], cpu.ptr[PC]), (r2, 17));
+        }
+        let cache = cpu.icache.as_ref().unwrap();
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+        assert_eq!(cpu.profiler.unwrap().cache_rate().map(|rate| rate.round()), Some(33.0));
+    }
+
+    #[test]
+    fn test_faults() {
+        // Reading past the end of memory halts whatever exec_check says
+        let readze = memory_ins("readze", Some(A0 as u64), 64, 1);
+        let cpu = run(&[&readze], |cpu, _| cpu.ptr[A0] = 4 * 4096 - 32);
+        let fault = cpu.fault.unwrap();
+        assert!(cpu.h);
+        assert_eq!((fault.pc, fault.violation.address, fault.violation.kind), (0, 4 * 4096 - 32, ViolationKind::OutOfRange));
+        assert_eq!(cpu.r[1], 0);
+
+        // Pushing below the stack limit
+        let push = memory_ins("push", None, 64, 1);
+        let cpu = run(&[&push, &push], |cpu, memory| {
+            cpu.ptr[SP] = 8192;
+            memory.set_stack_limit(Some(64));
+        });
+        assert_eq!(cpu.fault.map(|f| f.violation.kind), Some(ViolationKind::StackOverflow));
+        assert_eq!(cpu.fault.unwrap().to_string(), format!("stack overflow: write to {:#x}, below the stack limit (pc={:#x})",
+            8192 - 128, push.iter().map(|f| f.1 as u64).sum::<u64>()));
+
+        // Denied writes only warn by default
+        let write = memory_ins("write", Some(A1 as u64), 8, 1);
+        let cpu = run(&[&write], |cpu, memory| {
+            cpu.exec_check = ExecCheck::Warn;
+            cpu.ptr[A1] = 64;
+            memory.set_protection(true);
+        });
+        assert_eq!((cpu.h, cpu.fault), (false, None));
+        assert_eq!(cpu.mem.lock().unwrap().read(64, 8), 0);
+
+        // Self-modifying code warns, or traps before the write
+        for (mode, value) in [(TextWrites::Warn, 0x5a), (TextWrites::Trap, 0)] {
+            let cpu = run(&[&write], |cpu, memory| {
+                cpu.ptr[A1] = 1024;
+                cpu.r[1] = 0x5a;
+                memory.set_text_writes(mode);
+            });
+            assert_eq!(cpu.fault.map(|f| f.violation.kind), (mode == TextWrites::Trap).then_some(ViolationKind::TextWrite));
+            assert_eq!((cpu.h, cpu.mem.lock().unwrap().read(1024, 8)), (mode == TextWrites::Trap, value));
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//---
+// emu:debugcli - line-oriented debugger
+//
+// The commands of the ncurses debugger (see debugcore.rs) on plain lines
+// of stdin and stdout, for terminals without curses, pipes and scripts.
+// Nothing is shown unless asked for, like in gdb: the instruction at PC
+// after the machine moves, code around an address with goto, registers
+// and the stack frame with info registers and info frame, the calls with bt.
+// Since stdin cannot be read without blocking, continue waits for a
+// breakpoint or the end of the program; there is no pause.
+//
+//     (emu) until loop
+//     => 00000080 loop:      add2i r0 1
+//     Executed 12 instructions.
+//---
+
+use crate::cpu::{CPU, PC};
+use crate::debugcore::{DebuggerCore, DebuggerView};
+use crate::memory::Memory;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+// Lines listed around an address by goto
+const LIST_LINES: usize = 8;
+
+pub struct LineDebugger<R: BufRead, W: Write> {
+    input: R,
+    out: W,
+    shown: Option<(usize, u64, usize)>,  // Core, PC and time offset last shown
+}
+
+impl LineDebugger<io::StdinLock<'static>, io::Stdout> {
+    /// A debugger on the terminal
+    pub fn stdio() -> Self {
+        LineDebugger::new(io::stdin().lock(), io::stdout())
+    }
+}
+
+impl<R: BufRead, W: Write> LineDebugger<R, W> {
+    pub fn new(input: R, out: W) -> Self {
+        LineDebugger { input, out, shown: None }
+    }
+
+    /// Debug a program until the input ends or exit
+    pub fn run(&mut self, cpu: Arc<Mutex<CPU>>, memory: Arc<Mutex<Memory>>, symbols: &[(String, u64)]) {
+        let mut core = DebuggerCore::new(cpu, memory);
+        core.add_labels(symbols);
+        self.run_core(&mut core);
+    }
+
+    /// Debug with a session set up by the caller (several cores, debug
+    /// info, screen format)
+    pub fn run_core(&mut self, core: &mut DebuggerCore) {
+        core.run(self);
+    }
+
+    fn print(&mut self, text: &str) {
+        let _ = self.out.write_all(text.as_bytes());
+        let _ = self.out.flush();
+    }
+}
+
+impl<R: BufRead, W: Write> DebuggerView for LineDebugger<R, W> {
+    fn prompt(&mut self, _core: &DebuggerCore) -> Option<String> {
+        self.print("(emu) ");
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                self.print("\n");
+                None
+            }
+            Ok(_) => Some(line.trim_end().to_string()),
+        }
+    }
+
+    fn log(&mut self, message: &str) {
+        self.print(&format!("{}\n", message));
+    }
+
+    fn log_error(&mut self, message: &str) {
+        self.print(&format!("error: {}\n", message));
+    }
+
+    fn show_text(&mut self, text: &str) {
+        self.print(text);
+        if !text.is_empty() && !text.ends_with('\n') {
+            self.print("\n");
+        }
+    }
+
+    /// Show the instruction at PC when it changed, with its source line
+    fn refresh(&mut self, core: &DebuggerCore) {
+        let pc = core.cpu.lock().unwrap().ptr[PC];
+        let shown = (core.focus, pc, core.time_offset);
+        if self.shown == Some(shown) {
+            return;
+        }
+        self.shown = Some(shown);
+
+        let lines = core.code_lines();
+        let mut text = String::new();
+        if core.machine.len() > 1 {
+            text += &format!("[core {}] ", core.focus);
+        }
+        if core.time_offset > 0 {
+            text += &format!("[-{} steps] ", core.time_offset);
+        }
+        match DebuggerCore::line_index(&lines, pc) {
+            Some(i) => text += &format!("=> {}\n", &core.code_text(&lines[i], pc)[1..]),
+            None => text += &format!("=> {:08x} (no code)\n", pc),
+        }
+        if let Some(source) = core.source_text(pc) {
+            text += &format!("   {}\n", source);
+        }
+        self.print(&text);
+    }
+
+    /// List the code around an address
+    fn show_code(&mut self, core: &DebuggerCore, address: u64) -> bool {
+        let lines = core.code_lines();
+        let Some(index) = DebuggerCore::line_index(&lines, address) else { return false };
+        let pc = core.cpu.lock().unwrap().ptr[PC];
+        let top = index.saturating_sub(LIST_LINES / 2);
+        let text: String = lines.iter().skip(top).take(LIST_LINES)
+            .map(|line| core.code_text(line, pc) + "\n").collect();
+        self.print(&text);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugcore::DebuggerState;
+    use crate::disasm::disasm_one;
+    use std::time::Duration;
+    use crate::testing::assemble_str;
+
+    #[test]
+    fn test_line_debugger() {
+        let program = assemble_str("leti r0 5\nloop: add2i r0 1\nadd2i r0 2\nend: jump end");
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&program).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+
+        // Instruction addresses, for the labels
+        let mut ptr = object.entry;
+        let starts: Vec<u64> = (0..4).map(|_| {
+            let at = ptr;
+            disasm_one(&memory.lock().unwrap(), &mut ptr);
+            at
+        }).collect();
+        let symbols = [("loop".to_string(), starts[1]), ("end".to_string(), starts[3])];
+
+        let commands = format!("until end\nset r1 0x10\ninfo registers\nbreak loop\ngoto {:#x}\nx/1i loop\nfrobnicate\nstepback 2\n", starts[1]);
+        let mut out = Vec::new();
+        LineDebugger::new(commands.as_bytes(), &mut out).run(Arc::clone(&cpu), Arc::clone(&memory), &symbols);
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("=> "));
+        assert!(out.contains("(emu) => 00000"));
+        assert!(out.contains("Executed 3 instructions.\n"));
+        assert!(out.contains("=> 00000033 end:       jump -13  <end>\n"));
+        assert!(out.contains("r1 = 0x10\n(emu) r0  0000000000000008\nr1  0000000000000010\n"));
+        assert!(out.contains("Breakpoint at "));
+        assert!(out.contains("\n*00000011 loop:      add2i r0 1\n"));
+        assert!(out.contains("00000011 <loop>  add2i r0 1\n"));
+        assert!(out.contains("error: Unknown command.\n"));
+        assert!(out.contains("Went back 2 instructions.\n"));
+        assert!(out.ends_with("(emu) \n"));
+        assert_eq!(cpu.lock().unwrap().r[0], 5);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let program = assemble_str("leti r0 1\ncall f\nend: jump end\nf: call g\nreturn\ng: add2i r0 1\nreturn");
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&program).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+        cpu.lock().unwrap().ptr[crate::cpu::SP] = memory.lock().unwrap().data_base();
+
+        let mut ptr = object.entry;
+        let starts: Vec<u64> = (0..7).map(|_| {
+            let at = ptr;
+            disasm_one(&memory.lock().unwrap(), &mut ptr);
+            at
+        }).collect();
+        let symbols = [("end".to_string(), starts[2]), ("f".to_string(), starts[3]), ("g".to_string(), starts[5])];
+
+        // In g, after the return to f, then in g again after undoing the
+        // return and looking back one more step
+        let commands = "step\nstep\nstep\nbt\nstep\nstep\nbt\nstepback\nback 1\nbt\n";
+        let mut out = Vec::new();
+        LineDebugger::new(commands.as_bytes(), &mut out).run(Arc::clone(&cpu), Arc::clone(&memory), &symbols);
+        let out = String::from_utf8(out).unwrap();
+
+        let innermost = format!("#0  {:08x} in g\n#1  {:08x} in f+{}\n#2  {:08x} in end\n",
+            starts[5], starts[4], starts[4] - starts[3], starts[2]);
+        assert!(out.contains(&innermost), "{}", out);
+        assert!(out.contains(&format!("(emu) #0  {:08x} in f+{}\n#1  {:08x} in end\n(emu)",
+            starts[4], starts[4] - starts[3], starts[2])), "{}", out);
+        assert_eq!(out.matches(&innermost).count(), 2, "{}", out);
+    }
+
+    #[test]
+    fn test_asm() {
+        let program = assemble_str("leti r0 5\nloop: add2i r0 1\nadd2i r0 2\nend: jump end");
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&program).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+        let symbols = [("loop".to_string(), 0x11), ("end".to_string(), 0x33)];
+
+        let commands = "asm loop add2i r0 3\nasm end jump loop\nx/1i end\nasm loop frob\nasm 0x11 add2i r0 1000\n";
+        let mut out = Vec::new();
+        LineDebugger::new(commands.as_bytes(), &mut out).run(Arc::clone(&cpu), Arc::clone(&memory), &symbols);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("(emu) 17 bits written at 0x11.\n"), "{}", out);
+        assert!(out.contains("00000033 <end>  jump -47\n"), "{}", out);
+        assert!(out.contains("error: unknown instruction 'frob'\n"), "{}", out);
+        assert!(out.contains("warning: 42 bits written over an instruction of 17; the code after it is misaligned.\n"), "{}", out);
+        let mut ptr = 0x11;
+        assert_eq!(disasm_one(&memory.lock().unwrap(), &mut ptr).as_deref(), Some("add2i r0 1000"));
+    }
+
+    #[test]
+    fn test_next() {
+        let program = assemble_str("leti r0 1\ncall f\nend: jump end\nf: call g\nreturn\ng: add2i r0 1\nreturn");
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&program).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+        cpu.lock().unwrap().ptr[crate::cpu::SP] = memory.lock().unwrap().data_base();
+
+        let mut ptr = object.entry;
+        let starts: Vec<u64> = (0..7).map(|_| {
+            let at = ptr;
+            disasm_one(&memory.lock().unwrap(), &mut ptr);
+            at
+        }).collect();
+        let symbols = [("end".to_string(), starts[2]), ("f".to_string(), starts[3]), ("g".to_string(), starts[5])];
+
+        // Over the call to f, which calls g; then into f with a breakpoint
+        // in g, which stops the next over the call to g
+        let commands = format!("next\nnext\nset pc {:#x}\nbreak g\nnext\n", starts[3]);
+        let mut out = Vec::new();
+        LineDebugger::new(commands.as_bytes(), &mut out).run(Arc::clone(&cpu), Arc::clone(&memory), &symbols);
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains(&format!("(emu) => {:08x}            call 13  <f>\n", starts[1])), "{}", out);
+        assert!(out.contains(&format!("=> {:08x} end:       jump -13  <end>\nExecuted 5 instructions.\n", starts[2])), "{}", out);
+        assert!(out.contains(&format!("=> {:08x} g:         add2i r0 1\nExecuted 1 instructions.\nBreakpoint reached.\n", starts[5])), "{}", out);
+        assert_eq!(cpu.lock().unwrap().r[0], 2);
+    }
+
+    #[test]
+    fn test_display() {
+        let program = assemble_str("leti r0 5\nloop: add2i r0 1\nadd2i r0 2\nend: jump end");
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&program).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+
+        let commands = "display r0\ndisplay r0 +\ndisplay mem[loop..loop+4] + r0\nstep\nundisplay 1\nstep\nstepback\nundisplay 1\nundisplay\nstep\ndisplay\n";
+        let mut out = Vec::new();
+        LineDebugger::new(commands.as_bytes(), &mut out).run(Arc::clone(&cpu), Arc::clone(&memory), &[("loop".to_string(), 0x11)]);
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("(emu) 1: r0 = 0x0 (0)\n"), "{}", out);
+        assert!(out.contains("error: Unexpected end of expression.\n"), "{}", out);
+        // add2i r0 1 is at loop, its opcode is 0001
+        assert!(out.contains("(emu) 2: mem[loop..loop+4] + r0 = 0x1 (1)\n"), "{}", out);
+        assert!(out.contains("1: r0 = 0x5 (5)\n2: mem[loop..loop+4] + r0 = 0x6 (6)\n"), "{}", out);
+        assert!(out.contains("2: mem[loop..loop+4] + r0 = 0x7 (7)\n"), "{}", out);
+        assert_eq!(out.matches("1: r0 = ").count(), 2, "{}", out);
+        assert_eq!(out.matches("2: mem[loop..loop+4] + r0 = 0x6 (6)\n").count(), 2, "{}", out);
+        assert!(out.contains("error: No display number 1.\n"), "{}", out);
+        assert!(out.contains("Deleted all displays.\n"), "{}", out);
+        assert!(out.contains("(emu) No displays.\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let program = assemble_str("leti r0 5\nwrite a1 8 r0\nend: jump end");
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&program).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+        let data = memory.lock().unwrap().data_base();
+
+        let commands = format!("snapshot\nsnapshot start\nset a1 {:#x}\nstep\nstep\nsnapshot done\ndiff done\n\
+            diff start done\nback 1\nsnapshot past\ndiff nope\nsnapshot\n", data);
+        let mut out = Vec::new();
+        LineDebugger::new(commands.as_bytes(), &mut out).run(Arc::clone(&cpu), Arc::clone(&memory), &[]);
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("(emu) No snapshots.\n"), "{}", out);
+        assert!(out.contains("Snapshot 'start' taken.\n"), "{}", out);
+        assert!(out.contains("(emu) No differences.\n"), "{}", out);
+        assert!(out.contains("r0   0x0000000000000000 -> 0x0000000000000005\n"), "{}", out);
+        assert!(out.contains(&format!("a1   0x0000000000000000 -> {:#018x}\n", data + 8)), "{}", out);
+        // 5 is written as 00000101
+        assert!(out.contains(&format!("data   {:#010x}..{:#010x}  3 bits\n", data + 5, data + 8)), "{}", out);
+        assert!(out.contains("error: Cannot take a snapshot of the past"), "{}", out);
+        assert!(out.contains("error: No snapshot named 'nope'.\n"), "{}", out);
+        assert!(out.contains("(emu) done\nstart\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_continue() {
+        let program = assemble_str("leti r0 5\nloop: add2i r0 1\nadd2i r0 2\nend: jump end");
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&program).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+        let mut loop_start = object.entry;
+        disasm_one(&memory.lock().unwrap(), &mut loop_start);
+        let symbols = [("loop".to_string(), loop_start)];
+
+        // The line debugger waits for the worker thread to stop
+        let mut out = Vec::new();
+        LineDebugger::new("break loop\ncontinue\nc\n".as_bytes(), &mut out).run(Arc::clone(&cpu), Arc::clone(&memory), &symbols);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Executed 1 instructions.\nBreakpoint reached.\n"), "{}", out);
+        assert!(out.contains("Executed 3 instructions.\n"), "{}", out);
+        assert_eq!(cpu.lock().unwrap().r[0], 8);
+
+        // Pausing a program that never ends
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&assemble_str("loop: add2i r0 1\njump loop")).unwrap().unwrap();
+        let cpu = Arc::new(Mutex::new(CPU::new(Arc::clone(&memory))));
+        cpu.lock().unwrap().ptr[PC] = object.entry;
+        let mut core = DebuggerCore::new(Arc::clone(&cpu), memory);
+        let mut out = Vec::new();
+        let mut view = LineDebugger::new(io::empty(), &mut out);
+        core.handle_command("continue", &mut view);
+        assert_eq!(core.state, DebuggerState::Running);
+        assert!(!core.wait(Some(Duration::from_millis(10))));
+        core.handle_command("step", &mut view);
+        core.handle_command("pause", &mut view);
+        assert_eq!(core.state, DebuggerState::Idle);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("error: The program is running (pause to stop it).\n"), "{}", out);
+        assert!(out.contains("Paused after "), "{}", out);
+        assert!(cpu.lock().unwrap().r[0] > 0);
+    }
+}
@@ -0,0 +1,967 @@
+//---
+// emu:debugcore - what the debugger does, whatever shows it
+//
+// The state of a debugging session (cores, breakpoints, symbols, the
+// point in time being looked at) and the commands acting on it. Front ends
+// implement DebuggerView: they read commands and show what the commands
+// produce. The ncurses debugger (debugger.rs) shows the machine in panels
+// that are redrawn on every change; the line debugger (debugcli.rs) prints
+// like gdb does, for terminals without curses and for scripts.
+//
+// continue runs the cores on a worker thread, so that a view can redraw
+// the machine as it runs and read commands meanwhile: pause stops the
+// cores at the next instruction boundary. Views that cannot read commands
+// without blocking simply wait for a breakpoint or the end of the program.
+//
+// display <expr> adds an expression (see expr.rs) that is shown whenever
+// the machine stops after moving: step, next, stepback, until, finish and
+// the end of a continue.
+//
+//     (emu) display mem[sp..sp+64]
+//     1: mem[sp..sp+64] = 0x2a (42)
+//
+// snapshot <name> saves the registers of the core in focus and RAM (see
+// snapshot.rs); diff <a> [b] shows what changed from snapshot a to snapshot
+// b, or to the machine as it is now.
+//---
+
+use crate::breaks::BreakpointManager;
+use crate::cpu::{A0, A1, CPU, PC, SP};
+use crate::debuginfo::DebugInfo;
+use crate::expr::{Context, Expr};
+use crate::disasm::{disasm_lines, disasm_load_map, disasm_one, disasm_return_address,
+    disasm_symbol, disasm_target, DisasmLine};
+use crate::journal::{CpuState, JOURNAL_DEFAULT_CAPACITY};
+use crate::memory::{Memory, Segment, DUMP_LINE_BITS};
+use crate::multicore::Machine;
+use crate::profiler::Profiler;
+use crate::screencmp::Image;
+use crate::snapshot::{diff, Snapshot};
+use crate::vram::ScreenFormat;
+use minimisa_core::bitvec::BitVec;
+use minimisa_core::codec::{encode_instruction_with, Insn};
+use minimisa_core::{lookup, Operand, INSTRUCTIONS};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Frame layout set up by the enter/leave pseudo-instructions: r7 is the
+// frame pointer, the saved frame pointer is at fp and the return address
+// at fp + 64, locals are between sp and fp
+const FRAME_POINTER: usize = 7;
+
+// Lines shown by x when no count is given
+const EXAMINE_DEFAULT_COUNT: u64 = 4;
+
+// Number of locals decoded with the frame
+const FRAME_LOCALS: usize = 4;
+
+// Passes over an instruction with label operands before giving up on
+// their offsets settling, which change the length that changes them
+const ASM_PASSES: usize = 8;
+
+// How often views redraw the machine while it runs
+pub const RUNNING_REFRESH: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebuggerState {
+    Idle,     // Program is ready to run
+    Running,  // Program runs on the worker thread (continue)
+    Break,    // Program has reached breakpoint
+    Halt,     // Program has reached end or infinite loop
+}
+
+// A continue, running on a worker thread
+struct Worker {
+    thread: JoinHandle<(usize, Option<usize>)>,
+    pause: Arc<AtomicBool>,  // Set to stop at the next instruction boundary
+    done: Arc<AtomicBool>,   // Set by the worker when it stops
+}
+
+/// A debugger front end
+pub trait DebuggerView {
+    /// Read a command; None when there are no more
+    fn prompt(&mut self, core: &DebuggerCore) -> Option<String>;
+    fn log(&mut self, message: &str);
+    fn log_error(&mut self, message: &str);
+    /// Show text of several lines, such as a memory dump
+    fn show_text(&mut self, text: &str);
+    /// Show the machine again after it changed
+    fn refresh(&mut self, core: &DebuggerCore);
+    /// Show the code around an address. Returns false if there is none
+    fn show_code(&mut self, core: &DebuggerCore, address: u64) -> bool;
+    /// Show the machine as it runs and read a command, waiting at most
+    /// `interval` for one. By default, wait for the program to stop
+    fn poll(&mut self, core: &DebuggerCore, _interval: Duration) -> Option<String> {
+        core.wait(None);
+        None
+    }
+    /// Run a command of the front end itself, such as the panel layout of
+    /// the ncurses debugger. Returns false if there is no such command
+    fn view_command(&mut self, _core: &DebuggerCore, _words: &[&str]) -> bool {
+        false
+    }
+}
+
+/// Execute one instruction on every core that has not halted, and on the
+/// core in focus in any case. Returns the cores that ran
+fn step_machine(machine: &Machine, focus: usize) -> Vec<usize> {
+    let mut ran = Vec::new();
+    for (n, core) in machine.cores.iter().enumerate() {
+        let mut cpu = core.lock().unwrap();
+        if n == focus || !cpu.h {
+            cpu.execute();
+            ran.push(n);
+        }
+    }
+    ran
+}
+
+/// Execute until `stop` holds for the core in focus, every core halts, a
+/// core reaches one of its breakpoints or `pause` is set. The first
+/// instruction always runs, so that resuming from a breakpoint makes
+/// progress. Returns the number of instructions executed and the core at a
+/// breakpoint, if any
+fn run_machine(machine: &Machine, breaks: &[BreakpointManager], focus: usize, stop: impl Fn(&CPU) -> bool,
+    pause: &AtomicBool) -> (usize, Option<usize>)
+{
+    let mut steps = 0;
+    loop {
+        let ran = step_machine(machine, focus);
+        steps += ran.len();
+        if machine.halted() || stop(&machine.core(focus).lock().unwrap()) {
+            return (steps, None);
+        }
+        let hit = ran.into_iter().find(|&n| breaks[n].has(machine.core(n).lock().unwrap().ptr[PC]));
+        if hit.is_some() || pause.load(Ordering::Relaxed) {
+            return (steps, hit);
+        }
+    }
+}
+
+/// Parse a decimal or 0x-prefixed hexadecimal number
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+pub struct DebuggerCore {
+    pub cpu: Arc<Mutex<CPU>>,           // The core in focus
+    pub memory: Arc<Mutex<Memory>>,
+    pub machine: Machine,               // Every core, stepped together
+    pub focus: usize,                   // Index of the core in focus
+    pub state: DebuggerState,
+    pub breaks: Vec<BreakpointManager>,  // Breakpoints of each core
+    pub labels: BTreeMap<u64, String>,  // Symbols of the program, by address
+    pub debug_info: Option<DebugInfo>,  // Source lines, when a sidecar was loaded
+    pub screen: ScreenFormat,           // How VRAM is read for screenshots
+    pub time_offset: usize,             // The state shown is this many steps ago
+    pub reg_last: Option<CpuState>,     // Registers before the last step, for diffs
+    worker: Option<Worker>,             // The continue in progress
+    displays: Vec<(usize, String, Expr)>,  // Numbered expressions shown after moving
+    display_next: usize,                // Number of the next display
+    snapshots: BTreeMap<String, Snapshot>,  // Saved states, by name
+}
+
+impl DebuggerCore {
+    pub fn new(cpu: Arc<Mutex<CPU>>, memory: Arc<Mutex<Memory>>) -> DebuggerCore {
+        cpu.lock().unwrap().enable_journal(JOURNAL_DEFAULT_CAPACITY);
+        DebuggerCore {
+            machine: Machine { cores: vec![Arc::clone(&cpu)] },
+            cpu,
+            memory,
+            focus: 0,
+            state: DebuggerState::Idle,
+            breaks: vec![BreakpointManager::new()],
+            labels: BTreeMap::new(),
+            debug_info: None,
+            screen: ScreenFormat::EMU,
+            time_offset: 0,
+            reg_last: None,
+            worker: None,
+            displays: Vec::new(),
+            display_next: 1,
+            snapshots: BTreeMap::new(),
+        }
+    }
+
+    /// Load the debug info sidecar of the program: its labels become
+    /// symbols and code is shown with source lines
+    pub fn load_debug_info(&mut self, filename: &str) -> io::Result<()> {
+        let info = DebugInfo::load(filename)?;
+        self.labels.extend(info.labels.iter().map(|(&a, n)| (a, n.clone())));
+        self.debug_info = Some(info);
+        Ok(())
+    }
+
+    /// Debug every core of a machine, with the focus on core 0
+    pub fn set_machine(&mut self, machine: Machine) {
+        for cpu in &machine.cores {
+            cpu.lock().unwrap().enable_journal(JOURNAL_DEFAULT_CAPACITY);
+        }
+        self.breaks = machine.cores.iter().map(|_| BreakpointManager::new()).collect();
+        self.machine = machine;
+        self.set_focus(0);
+    }
+
+    /// Move the focus to core n: what is shown, breakpoints, stepping back
+    /// and register changes are those of the core in focus
+    fn set_focus(&mut self, n: usize) {
+        self.focus = n;
+        self.cpu = Arc::clone(self.machine.core(n));
+        self.reg_last = None;
+        self.time_offset = 0;
+    }
+
+    /// Add symbols of the program, such as those of its object file
+    pub fn add_labels(&mut self, symbols: &[(String, u64)]) {
+        self.labels.extend(symbols.iter().map(|(n, a)| (*a, n.clone())));
+    }
+
+    /// Read and handle commands until the session ends
+    pub fn run(&mut self, view: &mut dyn DebuggerView) {
+        view.refresh(self);
+        loop {
+            match self.state {
+                DebuggerState::Idle => match view.prompt(self) {
+                    Some(cmd) => self.handle_command(&cmd, view),
+                    None => break,
+                },
+                DebuggerState::Running => match view.poll(self, RUNNING_REFRESH) {
+                    Some(cmd) => self.handle_command(&cmd, view),
+                    None if self.wait(Some(Duration::ZERO)) => self.end_continue(view),
+                    None => {}
+                },
+                DebuggerState::Break => {
+                    view.log("Breakpoint reached.");
+                    self.state = DebuggerState::Idle;
+                }
+                DebuggerState::Halt => {
+                    view.log("Program halted.");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Decode the text segment into lines
+    pub fn code_lines(&self) -> Vec<DisasmLine> {
+        let memory = self.memory.lock().unwrap();
+        disasm_lines(&memory, 0, memory.text_size(), &self.labels)
+    }
+
+    /// Index of the line containing an address, if any
+    pub fn line_index(lines: &[DisasmLine], address: u64) -> Option<usize> {
+        lines.iter().position(|l| l.address <= address && address < l.next)
+    }
+
+    /// Add the symbols of a symbol file (see disasm_load_map). Returns how
+    /// many there were
+    pub fn load_symbols(&mut self, filename: &str) -> io::Result<usize> {
+        let symbols = disasm_load_map(filename)?;
+        let count = symbols.len();
+        self.labels.extend(symbols);
+        Ok(count)
+    }
+
+    /// A line of code as listed: a marker ('>' at PC, '*' on breakpoints),
+    /// the address, the label and the instruction, with the symbol of its
+    /// target for jumps
+    pub fn code_text(&self, line: &DisasmLine, pc: u64) -> String {
+        let marker = match (line.address <= pc && pc < line.next, self.breaks[self.focus].has(line.address)) {
+            (true, _) => '>',
+            (false, true) => '*',
+            (false, false) => ' ',
+        };
+        let label = self.labels.get(&line.address).map_or(String::new(), |l| format!("{}:", l));
+        let target = line.format.and_then(|_| disasm_target(&self.memory.lock().unwrap(), line.address))
+            .and_then(|target| disasm_symbol(&self.labels, target))
+            .map_or(String::new(), |name| format!("  <{}>", name));
+        format!("{}{:08x} {:<10} {}{}", marker, line.address, label, line.text, target)
+    }
+
+    /// File, line and text of the source of an address, when debug info
+    /// is loaded
+    pub fn source_text(&self, address: u64) -> Option<String> {
+        let info = self.debug_info.as_ref()?;
+        let line = info.source_line(address)?;
+        let text = info.source_text(address).unwrap_or("").trim();
+        Some(format!("{}:{}  {}", line.file, line.line, text))
+    }
+
+    /// A 64-bit word of memory, as of the point in time being looked at
+    pub fn read_word(&self, address: u64) -> u64 {
+        self.read_bits(address, 64)
+    }
+
+    /// `width` bits of memory, as of the point in time being looked at
+    fn read_bits(&self, address: u64, width: usize) -> u64 {
+        let cpu = self.cpu.lock().unwrap();
+        let memory = self.memory.lock().unwrap();
+        match &cpu.journal {
+            Some(journal) if self.time_offset > 0 => journal.read_past(&memory, self.time_offset, address, width),
+            _ => memory.read(address, width),
+        }
+    }
+
+    /// Registers of the core in focus, as of the point in time being
+    /// looked at
+    fn shown_state(&self) -> CpuState {
+        let cpu = self.cpu.lock().unwrap();
+        match cpu.journal.as_ref().and_then(|j| j.state(self.time_offset)) {
+            Some(state) => *state,
+            None => cpu.state(),
+        }
+    }
+
+    /// Add an expression to show after moving, and show it
+    fn add_display(&mut self, text: &str, view: &mut dyn DebuggerView) {
+        match Expr::parse(text) {
+            Ok(expr) => {
+                self.displays.push((self.display_next, text.to_string(), expr));
+                self.display_next += 1;
+                self.show_displays(&self.displays[self.displays.len() - 1..], view);
+            }
+            Err(e) => view.log_error(&e),
+        }
+    }
+
+    /// Show the value of displays, or why it cannot be computed
+    fn show_displays(&self, displays: &[(usize, String, Expr)], view: &mut dyn DebuggerView) {
+        for (n, text, expr) in displays {
+            match expr.eval(self) {
+                Ok(value) => view.log(&format!("{}: {} = {:#x} ({})", n, text, value, value)),
+                Err(e) => view.log_error(&format!("{}: {}: {}", n, text, e)),
+            }
+        }
+    }
+
+    /// Dump of the registers of the core in focus as of the point in time
+    /// being looked at, and the dump before the step that led there (empty
+    /// if unknown), for showing what changed
+    pub fn registers(&self) -> (String, String) {
+        let cpu = self.cpu.lock().unwrap();
+        let journal = cpu.journal.as_ref();
+
+        // When looking back in time, diff against the step before that
+        let (text, before) = match journal.and_then(|j| j.state(self.time_offset)) {
+            Some(state) => (state.dump(), journal.and_then(|j| j.state(self.time_offset + 1)).copied()),
+            None => (cpu.dump_registers(), self.reg_last),
+        };
+        (text, before.map(|s| s.dump()).unwrap_or_default())
+    }
+
+    /// The current stack frame, decoded line by line
+    pub fn frame(&self) -> Vec<String> {
+        let (fp, sp) = {
+            let cpu = self.cpu.lock().unwrap();
+            (cpu.r[FRAME_POINTER], cpu.ptr[SP])
+        };
+        let memory = self.memory.lock().unwrap();
+
+        let mut lines = vec![format!("fp {:#x}  sp {:#x}", fp, sp)];
+        if fp < sp {
+            lines.push("no frame (fp below sp)".to_string());
+            return lines;
+        }
+        lines.push(format!("ret   {:#x}", memory.read_u64(fp + 64)));
+        lines.push(format!("saved {:#x}", memory.read_u64(fp)));
+        lines.push(format!("locals {} bits", fp - sp));
+
+        // The first locals as 64-bit words, from the top of the frame
+        let mut addr = fp;
+        while addr >= sp + 64 && lines.len() < 4 + FRAME_LOCALS {
+            addr -= 64;
+            lines.push(format!("fp-{:<4} {:016x}", fp - addr, memory.read_u64(addr)));
+        }
+        lines
+    }
+
+    /// The calls not yet returned from as of the point in time being looked
+    /// at, innermost first: where each frame is (PC, then the return
+    /// addresses) and the function it is in, from the shadow call stack
+    /// of the CPU. Functions are named after their label when there is one
+    pub fn backtrace(&self) -> Vec<String> {
+        let cpu = self.cpu.lock().unwrap();
+        let (pc, calls) = match cpu.journal.as_ref() {
+            Some(journal) if self.time_offset > 0 => match journal.state(self.time_offset) {
+                Some(state) => (state.ptr[PC], journal.calls_past(&cpu.calls, self.time_offset)),
+                None => (cpu.ptr[PC], cpu.calls.clone()),
+            },
+            _ => (cpu.ptr[PC], cpu.calls.clone()),
+        };
+
+        // Frame n is in the function of call n from the top, and the
+        // outermost one wherever the program started
+        let at = std::iter::once(pc).chain(calls.iter().rev().map(|call| call.ret));
+        let function = calls.iter().rev().map(|call| Some(call.target)).chain(std::iter::once(None));
+        at.zip(function).enumerate().map(|(n, (address, function))| {
+            let name = match function {
+                Some(target) => {
+                    let name = self.labels.get(&target).cloned().unwrap_or_else(|| format!("{:08x}", target));
+                    match address.checked_sub(target) {
+                        Some(offset) if offset > 0 => format!("{}+{}", name, offset),
+                        _ => name,
+                    }
+                }
+                None => disasm_symbol(&self.labels, address).unwrap_or_else(|| "??".to_string()),
+            };
+            format!("#{:<2} {:08x} in {}", n, address, name)
+        }).collect()
+    }
+
+    /// Write a register or pointer of the live CPU: `name` is r0-r7, pc,
+    /// sp, a0 or a1
+    fn set_register(&mut self, name: &str, value: u64) -> Result<(), String> {
+        if self.time_offset > 0 {
+            return Err("Cannot change registers while looking back in time.".to_string());
+        }
+        let mut cpu = self.cpu.lock().unwrap();
+        let value = cpu.word_size.truncate(value);
+        let slot = match name {
+            "pc" => &mut cpu.ptr[PC],
+            "sp" => &mut cpu.ptr[SP],
+            "a0" => &mut cpu.ptr[A0],
+            "a1" => &mut cpu.ptr[A1],
+            _ => match name.strip_prefix(['r', 'R']).and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n < 8 => &mut cpu.r[n],
+                _ => return Err(format!("No register named '{}'.", name)),
+            },
+        };
+        *slot = value;
+        Ok(())
+    }
+
+    /// Look `steps` further back in time (negative to come forward), within
+    /// what the journal has recorded. The live machine is left untouched
+    fn time_travel(&mut self, steps: isize, view: &mut dyn DebuggerView) {
+        let recorded = self.cpu.lock().unwrap().journal.as_ref().map_or(0, |j| j.len());
+        let offset = self.time_offset as isize + steps;
+        self.time_offset = offset.clamp(0, recorded as isize) as usize;
+
+        if self.time_offset == 0 {
+            view.log("Back to the live state.");
+        } else {
+            view.log(&format!("Showing state as of {} steps ago.", self.time_offset));
+        }
+        view.refresh(self);
+    }
+
+    // Differences from one snapshot to another, or to the present
+    fn diff_snapshots(&self, before: &str, after: Option<&str>) -> Result<String, String> {
+        let find = |name: &str| self.snapshots.get(name).ok_or_else(|| format!("No snapshot named '{}'.", name));
+        let changes = match after {
+            Some(after) => diff(find(before)?, find(after)?),
+            None => diff(find(before)?, &Snapshot::take(&self.cpu.lock().unwrap(), &self.memory.lock().unwrap())),
+        };
+        Ok(changes.format(&self.memory.lock().unwrap()))
+    }
+
+    /// Summary of the hottest instructions and of how often they were
+    /// decoded from the decode cache, if profiling is on
+    fn profile_summary(&self) -> Option<String> {
+        let cpu = self.cpu.lock().unwrap();
+        cpu.profiler.as_ref().map(|p| {
+            let total = p.total_cycles().max(1);
+            let spots: Vec<String> = p.hot_spots().iter().take(3).map(|(address, entry)| {
+                let name = disasm_symbol(&self.labels, *address).map_or(String::new(), |l| format!(" {}", l));
+                format!("{:#x}{} {}%", address, name, 100 * entry.cycles / total)
+            }).collect();
+            let cached = p.cache_rate().map_or(String::new(), |rate| format!(", {:.0}% decoded from cache", rate));
+            format!("{} cycles, hottest: {}{}", p.total_cycles(), spots.join(", "), cached)
+        })
+    }
+
+    /// Examine memory: `spec` is x/<n><fmt>, where fmt is x for n lines of
+    /// dump, a for the same with the disassembly of text, and i for n
+    /// instructions. Addresses are in bits and need not be aligned
+    fn examine(&self, spec: &str, target: &str) -> Result<String, String> {
+        let spec = spec.strip_prefix("x/").unwrap_or("");
+        let digits = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+        let count = if digits == 0 { Some(EXAMINE_DEFAULT_COUNT) } else { spec[..digits].parse().ok() };
+        let (count, format) = match (count, &spec[digits..]) {
+            (Some(count), "") => (count, "x"),
+            (Some(count), format @ ("x" | "a" | "i")) => (count, format),
+            _ => return Err("Expected x/<n><fmt> with fmt x, a or i.".to_string()),
+        };
+        let address = self.resolve(target).ok_or_else(|| format!("No address or label '{}'.", target))?;
+
+        let memory = self.memory.lock().unwrap();
+        let end = address.saturating_add(count * DUMP_LINE_BITS);
+        Ok(match format {
+            "i" => {
+                let mut ptr = address;
+                (0..count).map(|_| {
+                    let at = ptr;
+                    let ins = disasm_one(&memory, &mut ptr).unwrap_or_else(|| "?".to_string());
+                    let label = self.labels.get(&at).map_or(String::new(), |l| format!(" <{}>", l));
+                    format!("{:08x}{}  {}\n", at, label, ins)
+                }).collect()
+            }
+            "a" => {
+                // Instructions are decoded from the start of the range,
+                // each annotates the line it starts on
+                let lines = disasm_lines(&memory, address, end, &self.labels);
+                memory.dump_annotated(address..end, |range| {
+                    let texts: Vec<&str> = lines.iter()
+                        .filter(|l| range.contains(&l.address) && memory.segment(l.address) == Segment::Text)
+                        .map(|l| l.text.as_str()).collect();
+                    (!texts.is_empty()).then(|| texts.join("; "))
+                })
+            }
+            _ => memory.dump(address..end),
+        })
+    }
+
+    /// Resolve a code address given as a number or a label
+    pub fn resolve(&self, text: &str) -> Option<u64> {
+        parse_number(text).or_else(|| {
+            self.labels.iter().find(|(_, name)| name.as_str() == text).map(|(&address, _)| address)
+        })
+    }
+
+    /// Encode an instruction, in the syntax of the disassembly, to be
+    /// written at an address with the opcode table of the memory. Address
+    /// operands may also be labels, which are made relative to the end of
+    /// the instruction like the numbers of the disassembly
+    fn assemble(&self, address: u64, words: &[&str]) -> Result<BitVec, String> {
+        let codes = *self.memory.lock().unwrap().opcodes();
+        let mut words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        let operands = words.first().and_then(|m| lookup(m))
+            .map_or([Operand::None; 3], |op| INSTRUCTIONS[op as usize].operands);
+        let targets: Vec<(usize, u64)> = operands.iter().enumerate()
+            .filter(|&(_, &kind)| kind == Operand::Address)
+            .filter_map(|(k, _)| {
+                let word = words.get(k + 1)?;
+                self.labels.iter().find(|(_, name)| name == &word).map(|(&target, _)| (k + 1, target))
+            })
+            .collect();
+
+        let mut end = address;
+        for _ in 0..ASM_PASSES {
+            for &(k, target) in &targets {
+                words[k] = (target as i64 - end as i64).to_string();
+            }
+            let bits = encode_instruction_with(&words.join(" ").parse::<Insn>()?, &codes)?;
+            if targets.is_empty() || address + bits.len() == end {
+                return Ok(bits);
+            }
+            end = address + bits.len();
+        }
+        Err("The label offsets do not settle.".to_string())
+    }
+
+    /// Overwrite the instruction at an address with another. Returns the
+    /// lengths of the new and old instructions (None if no instruction
+    /// decodes there)
+    fn patch(&mut self, address: u64, words: &[&str]) -> Result<(u64, Option<u64>), String> {
+        let bits = self.assemble(address, words)?;
+        let mut memory = self.memory.lock().unwrap();
+        let mut ptr = address;
+        let old = disasm_one(&memory, &mut ptr).map(|_| ptr - address);
+        if !memory.patch(address, &bits) {
+            return Err(format!("{} bits at {:#x} do not fit in memory.", bits.len(), address));
+        }
+        Ok((bits.len(), old))
+    }
+
+    /// Execute one instruction on every core that has not halted, and on
+    /// the core in focus in any case. Returns the cores that ran
+    fn step_cores(&self) -> Vec<usize> {
+        step_machine(&self.machine, self.focus)
+    }
+
+    /// Execute until `stop` holds for the core in focus, every core halts
+    /// or a core reaches one of its breakpoints, which moves the focus to
+    /// it. The first instruction always runs, so that resuming from a
+    /// breakpoint makes progress
+    fn run_until(&mut self, stop: impl Fn(&CPU) -> bool, view: &mut dyn DebuggerView) {
+        self.reg_last = Some(self.cpu.lock().unwrap().state());
+        let (steps, hit) = run_machine(&self.machine, &self.breaks, self.focus, stop, &AtomicBool::new(false));
+        self.after_run(hit, view);
+        view.log(&format!("Executed {} instructions.", steps));
+        self.log_fault(view);
+    }
+
+    /// Execute one instruction on every core
+    fn step(&mut self, view: &mut dyn DebuggerView) {
+        self.reg_last = Some(self.cpu.lock().unwrap().state());
+        self.step_cores();
+        self.time_offset = 0;
+        view.refresh(self);
+        self.show_displays(&self.displays, view);
+        self.log_fault(view);
+    }
+
+    /// Step, over calls: a call runs until it returns, as if there were a
+    /// temporary breakpoint at its return address. Breakpoints inside the
+    /// call still stop it
+    fn next(&mut self, view: &mut dyn DebuggerView) {
+        let (pc, depth) = {
+            let cpu = self.cpu.lock().unwrap();
+            (cpu.ptr[PC], cpu.calls.len())
+        };
+        let ret = disasm_return_address(&self.memory.lock().unwrap(), pc);
+        match ret {
+            // Recursive calls come back to the same address deeper down
+            Some(ret) => self.run_until(|cpu| cpu.ptr[PC] == ret && cpu.calls.len() <= depth, view),
+            None => self.step(view),
+        }
+    }
+
+    /// Show where the cores stopped, with the focus on the core at a
+    /// breakpoint if any
+    fn after_run(&mut self, hit: Option<usize>, view: &mut dyn DebuggerView) {
+        self.state = if hit.is_some() { DebuggerState::Break } else { DebuggerState::Idle };
+        self.time_offset = 0;
+        if let Some(n) = hit.filter(|&n| n != self.focus) {
+            self.set_focus(n);
+        }
+        view.refresh(self);
+        self.show_displays(&self.displays, view);
+    }
+
+    /// Run the cores on a worker thread until a breakpoint, the end of the
+    /// program or a pause
+    fn start_continue(&mut self) {
+        self.reg_last = Some(self.cpu.lock().unwrap().state());
+        self.time_offset = 0;
+        let pause = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(AtomicBool::new(false));
+        let (machine, breaks, focus) = (self.machine.clone(), self.breaks.clone(), self.focus);
+        let (worker_pause, worker_done, waiter) = (Arc::clone(&pause), Arc::clone(&done), thread::current());
+        let thread = thread::spawn(move || {
+            let result = run_machine(&machine, &breaks, focus, |_| false, &worker_pause);
+            worker_done.store(true, Ordering::Release);
+            waiter.unpark();
+            result
+        });
+        self.worker = Some(Worker { thread, pause, done });
+        self.state = DebuggerState::Running;
+    }
+
+    /// Wait at most `timeout` (forever if None) for a continue to stop.
+    /// Returns whether none is running anymore
+    pub fn wait(&self, timeout: Option<Duration>) -> bool {
+        let Some(worker) = &self.worker else { return true };
+        let deadline = timeout.map(|t| Instant::now() + t);
+        while !worker.done.load(Ordering::Acquire) {
+            match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => thread::park_timeout(left),
+                    _ => return false,
+                },
+                None => thread::park(),
+            }
+        }
+        true
+    }
+
+    /// Stop the worker thread, at the next instruction boundary if it is
+    /// still running, and show where the cores are
+    fn end_continue(&mut self, view: &mut dyn DebuggerView) {
+        let Some(worker) = self.worker.take() else { return };
+        worker.pause.store(true, Ordering::Relaxed);
+        let (steps, hit) = worker.thread.join().unwrap();
+        // Nothing but a pause stops a continue short of a breakpoint or
+        // the end of the program
+        let paused = hit.is_none() && !self.machine.halted();
+        self.after_run(hit, view);
+        view.log(&format!("{} {} instructions.", if paused { "Paused after" } else { "Executed" }, steps));
+        self.log_fault(view);
+    }
+
+    /// Show why the CPU stopped, if the last instruction faulted
+    fn log_fault(&self, view: &mut dyn DebuggerView) {
+        if let Some(fault) = self.cpu.lock().unwrap().fault {
+            view.log_error(&format!("Fault: {}", fault));
+        }
+    }
+
+    /// Undo up to `steps` instructions of the core in focus; the other
+    /// cores are left as they are
+    fn step_back(&mut self, steps: usize, view: &mut dyn DebuggerView) {
+        let mut undone = 0;
+        {
+            let mut cpu = self.cpu.lock().unwrap();
+            self.reg_last = Some(cpu.state());
+            while undone < steps && cpu.step_back() {
+                undone += 1;
+            }
+        }
+        self.after_reverse(undone, view);
+    }
+
+    /// Undo instructions until a breakpoint or the start of the history
+    fn reverse_continue(&mut self, view: &mut dyn DebuggerView) {
+        let mut undone = 0;
+        {
+            let mut cpu = self.cpu.lock().unwrap();
+            self.reg_last = Some(cpu.state());
+            while cpu.step_back() {
+                undone += 1;
+                if self.breaks[self.focus].has(cpu.ptr[PC]) {
+                    break;
+                }
+            }
+        }
+        self.after_reverse(undone, view);
+    }
+
+    fn after_reverse(&mut self, undone: usize, view: &mut dyn DebuggerView) {
+        self.time_offset = 0;
+        view.refresh(self);
+        self.show_displays(&self.displays, view);
+        if undone == 0 {
+            view.log_error("No recorded history to go back to.");
+        } else {
+            view.log(&format!("Went back {} instructions.", undone));
+        }
+    }
+
+    /// Handle a command
+    pub fn handle_command(&mut self, cmd: &str, view: &mut dyn DebuggerView) {
+        let words: Vec<&str> = cmd.split_whitespace().collect();
+
+        // The worker thread owns the cores while a continue runs
+        if self.worker.is_some() {
+            match words.as_slice() {
+                ["pause"] => self.end_continue(view),
+                ["exit"] => {
+                    self.end_continue(view);
+                    self.state = DebuggerState::Halt;
+                }
+                [] => {}
+                _ => view.log_error("The program is running (pause to stop it)."),
+            }
+            return;
+        }
+
+        match words.as_slice() {
+            ["run"] => {
+                self.state = DebuggerState::Idle;
+            }
+            ["continue"] | ["c"] => self.start_continue(),
+            ["pause"] => view.log_error("The program is not running."),
+            ["step"] => self.step(view),
+            ["next"] | ["n"] => self.next(view),
+            ["until", target] => match self.resolve(target) {
+                Some(address) => self.run_until(|cpu| cpu.ptr[PC] == address, view),
+                None => view.log_error(&format!("No address or label '{}'.", target)),
+            },
+            ["finish"] => {
+                let depth = self.cpu.lock().unwrap().calls.len();
+                if depth == 0 {
+                    view.log_error("Not inside a call.");
+                } else {
+                    self.run_until(|cpu| cpu.calls.len() < depth, view);
+                }
+            }
+            ["core"] => view.log(&format!("Core {} of {}.", self.focus, self.machine.len())),
+            ["core", n] => match n.parse::<usize>() {
+                Ok(n) if n < self.machine.len() => {
+                    self.set_focus(n);
+                    view.refresh(self);
+                    view.log(&format!("Focus on core {}.", n));
+                }
+                _ => view.log_error(&format!("Expected a core number below {}.", self.machine.len())),
+            },
+            ["stepback"] => self.step_back(1, view),
+            ["stepback", n] => match n.parse() {
+                Ok(n) => self.step_back(n, view),
+                Err(_) => view.log_error("Expected a number of steps."),
+            },
+            ["reverse-continue"] => self.reverse_continue(view),
+            ["history", n] => match n.parse() {
+                Ok(n) => {
+                    self.cpu.lock().unwrap().enable_journal(n);
+                    self.time_offset = 0;
+                    view.log(&format!("Recording the last {} instructions.", n));
+                }
+                Err(_) => view.log_error("Expected a history depth."),
+            },
+            ["back", n] | ["forward", n] => match n.parse::<isize>() {
+                Ok(n) => self.time_travel(if words[0] == "back" { n } else { -n }, view),
+                Err(_) => view.log_error("Expected a number of steps."),
+            },
+            ["goto", address] => match parse_number(address) {
+                Some(address) => {
+                    if !view.show_code(self, address) {
+                        view.log_error(&format!("No code at address {:#x}.", address));
+                    }
+                }
+                None => view.log_error("Expected an address."),
+            },
+            ["x", target] => match self.examine("x/", target) {
+                Ok(text) => view.show_text(&text),
+                Err(e) => view.log_error(&e),
+            },
+            [spec, target] if spec.starts_with("x/") => match self.examine(spec, target) {
+                Ok(text) => view.show_text(&text),
+                Err(e) => view.log_error(&e),
+            },
+            ["display"] if self.displays.is_empty() => view.log("No displays."),
+            ["display"] => self.show_displays(&self.displays, view),
+            ["display", expr @ ..] => self.add_display(&expr.join(" "), view),
+            ["undisplay"] => {
+                self.displays.clear();
+                view.log("Deleted all displays.");
+            }
+            ["undisplay", n] => match n.parse::<usize>() {
+                Ok(n) if self.displays.iter().any(|d| d.0 == n) => {
+                    self.displays.retain(|d| d.0 != n);
+                    view.log(&format!("Deleted display {}.", n));
+                }
+                _ => view.log_error(&format!("No display number {}.", n)),
+            },
+            ["snapshot"] if self.snapshots.is_empty() => view.log("No snapshots."),
+            ["snapshot"] => view.show_text(&self.snapshots.keys().map(|name| format!("{}\n", name)).collect::<String>()),
+            ["snapshot", name] => {
+                if self.time_offset > 0 {
+                    view.log_error("Cannot take a snapshot of the past; go back to the present first.");
+                } else {
+                    let snapshot = Snapshot::take(&self.cpu.lock().unwrap(), &self.memory.lock().unwrap());
+                    self.snapshots.insert(name.to_string(), snapshot);
+                    view.log(&format!("Snapshot '{}' taken.", name));
+                }
+            }
+            ["diff", before, after @ ..] if after.len() <= 1 => match self.diff_snapshots(before, after.first().copied()) {
+                Ok(text) if text.is_empty() => view.log("No differences."),
+                Ok(text) => view.show_text(&text),
+                Err(e) => view.log_error(&e),
+            },
+            ["info", "registers"] => view.show_text(&self.registers().0),
+            ["info", "frame"] => view.show_text(&(self.frame().join("\n") + "\n")),
+            ["bt"] | ["backtrace"] => view.show_text(&(self.backtrace().join("\n") + "\n")),
+            ["set", name, value] => match parse_number(value) {
+                Some(value) => match self.set_register(name, value) {
+                    Ok(()) => {
+                        view.refresh(self);
+                        view.log(&format!("{} = {:#x}", name, value));
+                    }
+                    Err(e) => view.log_error(&e),
+                },
+                None => view.log_error("Expected a value."),
+            },
+            ["break"] => {
+                self.state = DebuggerState::Break;
+            }
+            ["break", target] => match self.resolve(target) {
+                Some(address) => {
+                    self.breaks[self.focus].add(address);
+                    view.refresh(self);
+                    view.log(&format!("Breakpoint at {:#x}.", address));
+                }
+                None => view.log_error(&format!("No address or label '{}'.", target)),
+            },
+            ["asm", target, instruction @ ..] if !instruction.is_empty() => match self.resolve(target) {
+                Some(address) => match self.patch(address, instruction) {
+                    Ok((new, old)) => {
+                        view.refresh(self);
+                        match old {
+                            Some(old) if old != new => view.log(&format!(
+                                "warning: {} bits written over an instruction of {}; the code after it is misaligned.", new, old)),
+                            _ => view.log(&format!("{} bits written at {:#x}.", new, address)),
+                        }
+                    }
+                    Err(e) => view.log_error(&e),
+                },
+                None => view.log_error(&format!("No address or label '{}'.", target)),
+            },
+            ["profile"] => match self.profile_summary() {
+                Some(summary) => view.log(&summary),
+                None => view.log_error("Profiling is off (profile on)."),
+            },
+            ["profile", "on"] => {
+                self.cpu.lock().unwrap().profiler.get_or_insert_with(Profiler::new);
+                view.log("Profiling enabled.");
+            }
+            ["profile", "off"] => {
+                self.cpu.lock().unwrap().profiler = None;
+                view.log("Profiling disabled.");
+            }
+            ["profile", "reset"] => {
+                if let Some(profiler) = self.cpu.lock().unwrap().profiler.as_mut() {
+                    profiler.clear();
+                }
+                view.log("Profile cleared.");
+            }
+            ["profile", "save", file] => {
+                let result = {
+                    let cpu = self.cpu.lock().unwrap();
+                    let memory = self.memory.lock().unwrap();
+                    cpu.profiler.as_ref().map(|p| p.save_csv(&memory, &self.labels, file))
+                };
+                match result {
+                    Some(Ok(())) => view.log(&format!("Profile saved to {}.", file)),
+                    Some(Err(e)) => view.log_error(&format!("{}: {}", file, e)),
+                    None => view.log_error("Profiling is off (profile on)."),
+                }
+            }
+            ["screenshot", file] => {
+                let image = Image::from_screen(&self.memory.lock().unwrap(), &self.screen);
+                match image.save(file) {
+                    Ok(()) => view.log(&format!("Screen saved to {}.", file)),
+                    Err(e) => view.log_error(&format!("{}: {}", file, e)),
+                }
+            }
+            ["debuginfo", file] => match self.load_debug_info(file) {
+                Ok(()) => {
+                    view.refresh(self);
+                    view.log(&format!("Debug info loaded from {}.", file));
+                }
+                Err(e) => view.log_error(&format!("{}: {}", file, e)),
+            },
+            ["symbols", file] => match self.load_symbols(file) {
+                Ok(count) => {
+                    view.refresh(self);
+                    view.log(&format!("{} symbols loaded from {}.", count, file));
+                }
+                Err(e) => view.log_error(&format!("{}: {}", file, e)),
+            },
+            ["breaks", "export", file] => match self.breaks[self.focus].export(file, &self.labels) {
+                Ok(()) => view.log(&format!("Breakpoints saved to {}.", file)),
+                Err(e) => view.log_error(&format!("{}: {}", file, e)),
+            },
+            ["breaks", "import", file] => match self.breaks[self.focus].import(file, &self.labels) {
+                Ok(n) => {
+                    view.refresh(self);
+                    view.log(&format!("{} breakpoints and watches loaded.", n));
+                }
+                Err(e) => view.log_error(&e),
+            },
+            ["exit"] => {
+                self.state = DebuggerState::Halt;
+            }
+            _ => {
+                if !view.view_command(self, &words) {
+                    view.log_error("Unknown command.");
+                }
+            }
+        }
+    }
+}
+
+// Expressions are evaluated as of the point in time being looked at
+impl Context for DebuggerCore {
+    fn register(&self, n: usize) -> u64 {
+        self.shown_state().r[n]
+    }
+
+    fn pointer(&self, n: usize) -> u64 {
+        self.shown_state().ptr[n]
+    }
+
+    fn read(&self, address: u64, width: usize) -> u64 {
+        self.read_bits(address, width)
+    }
+
+    fn symbol(&self, name: &str) -> Option<u64> {
+        self.labels.iter().find(|(_, label)| label.as_str() == name).map(|(&address, _)| address)
+    }
+}
@@ -1,29 +1,38 @@
 extern crate ncurses;
 
-use crate::cpu::CPU;
+use crate::cpu::{CPU, PC};
+use crate::debugcore::{DebuggerCore, DebuggerView};
+use crate::disasm::Category;
+use crate::layout::{Layout, Panel};
 use crate::memory::Memory;
+use crate::multicore::Machine;
+use crate::vram::ScreenFormat;
 use ncurses::*;
-use std::fmt;
+use std::io;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-// Ncurses window panels
+// Lines of the frame panel the backtrace may take before the current frame
+// is shown
+const BACKTRACE_LINES: usize = 4;
+
+/// The ncurses debugger: the commands of DebuggerCore, with the machine
+/// shown in panels
 pub struct Debugger {
-    wcode: WINDOW,
-    wreg: WINDOW,
-    wmem: WINDOW,
-    wframe: WINDOW,
-    wcli: WINDOW,
-
-    cpu: Arc<Mutex<CPU>>,
-    memory: Arc<Mutex<Memory>>,
-    state: DebuggerState,
+    core: DebuggerCore,
+    tui: Tui,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum DebuggerState {
-    Idle,   // Program is ready to run
-    Break,  // Program has reached breakpoint
-    Halt,   // Program has reached end or infinite loop
+// Ncurses window panels, placed by the layout (see layout.rs)
+struct Tui {
+    layout: Layout,
+    windows: Vec<(Panel, WINDOW)>,  // Panels that fit on the terminal
+
+    input: String,     // Command being typed
+
+    code_top: u64,     // First address shown in the code panel
+    code_pc: u64,      // PC when the code panel was last drawn
+    mem_address: u64,  // First address shown in the memory panel
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -36,42 +45,68 @@ pub enum DebuggerColor {
     Magenta = 5,
     Cyan = 6,
     White = 7,
+}
+
+// Role colors; enum discriminants cannot be shared, hence constants
+#[allow(non_upper_case_globals)]
+impl DebuggerColor {
+    pub const Command: DebuggerColor = DebuggerColor::Cyan;
+    pub const Error: DebuggerColor = DebuggerColor::Red;
+    pub const Idle: DebuggerColor = DebuggerColor::Yellow;
+    pub const Break: DebuggerColor = DebuggerColor::Cyan;
+    pub const Halt: DebuggerColor = DebuggerColor::Green;
 
-    Command = DebuggerColor::Cyan as isize,
-    Error = DebuggerColor::Red as isize,
-    Idle = DebuggerColor::Yellow as isize,
-    Break = DebuggerColor::Cyan as isize,
-    Halt = DebuggerColor::Green as isize,
-
-    Arithm = DebuggerColor::White as isize,
-    Test = DebuggerColor::White as isize,
-    Let = DebuggerColor::Green as isize,
-    Jump = DebuggerColor::Cyan as isize,
-    Memory = DebuggerColor::Red as isize,
-    Control = DebuggerColor::Magenta as isize,
+    pub const Arithm: DebuggerColor = DebuggerColor::White;
+    pub const Test: DebuggerColor = DebuggerColor::White;
+    pub const Let: DebuggerColor = DebuggerColor::Green;
+    pub const Jump: DebuggerColor = DebuggerColor::Cyan;
+    pub const Memory: DebuggerColor = DebuggerColor::Red;
+    pub const Control: DebuggerColor = DebuggerColor::Magenta;
+
+    pub const Changed: DebuggerColor = DebuggerColor::Yellow;
 }
 
 impl Debugger {
     /// Create and initialize the debugger interface
     pub fn new(cpu: Arc<Mutex<CPU>>, memory: Arc<Mutex<Memory>>) -> Debugger {
         initscr();
+        cbreak();
+        noecho();
         start_color();
         use_default_colors();
         Debugger::init_colors();
 
         Debugger {
-            wcode: newwin(10, 50, 0, 0),
-            wreg: newwin(10, 30, 0, 50),
-            wmem: newwin(10, 30, 10, 0),
-            wframe: newwin(10, 30, 10, 30),
-            wcli: newwin(5, 80, 20, 0),
-
-            cpu,
-            memory,
-            state: DebuggerState::Idle,
+            core: DebuggerCore::new(cpu, memory),
+            tui: Tui::new(),
         }
     }
 
+    /// Load the debug info sidecar of the program: its labels become
+    /// symbols and the code panel shows source lines
+    pub fn load_debug_info(&mut self, filename: &str) -> io::Result<()> {
+        self.core.load_debug_info(filename)?;
+        self.tui.code_panel(&self.core);
+        Ok(())
+    }
+
+    /// Debug every core of a machine, with the focus on core 0
+    pub fn set_machine(&mut self, machine: Machine) {
+        self.core.set_machine(machine);
+        self.tui.refresh(&self.core);
+    }
+
+    /// Set how the screen is read from VRAM for screenshots
+    pub fn set_screen_format(&mut self, format: ScreenFormat) {
+        self.core.screen = format;
+    }
+
+    /// Add symbols of the program, such as those of its object file
+    pub fn add_labels(&mut self, symbols: &[(String, u64)]) {
+        self.core.add_labels(symbols);
+        self.tui.code_panel(&self.core);
+    }
+
     /// Initialize color pairs
     fn init_colors() {
         init_pair(DebuggerColor::Black as i16, COLOR_BLACK, -1);
@@ -85,108 +120,347 @@ impl Debugger {
     }
 
     /// Run the debugger (main loop)
-    pub fn run(&mut self, filename: Option<&str>) {
-        self.draw_interface();
-        loop {
-            match self.state {
-                DebuggerState::Idle => {
-                    // Process commands
-                    let cmd = self.prompt();
-                    self.handle_command(cmd);
-                }
-                DebuggerState::Break => {
-                    self.log("Breakpoint reached.");
-                    self.state = DebuggerState::Idle;
-                }
-                DebuggerState::Halt => {
-                    self.log("Program halted.");
-                    break;
-                }
-            }
-        }
+    pub fn run(&mut self) {
+        self.core.run(&mut self.tui);
         endwin();  // End ncurses mode
     }
+}
+
+impl Tui {
+    fn new() -> Tui {
+        let mut tui = Tui {
+            layout: Layout::default(),
+            windows: Vec::new(),
+            input: String::new(),
+
+            code_top: 0,
+            code_pc: u64::MAX,
+            mem_address: 0,
+        };
+        tui.apply_layout();
+        tui
+    }
 
-    /// Draw the interface panels
-    fn draw_interface(&self) {
-        // Draw the code, register, and memory panels
-        self.code_panel();
-        self.memory_panel();
-        self.reg_panel();
-        wrefresh(self.wcli);
+    /// Create the panel windows for the current terminal size, such as
+    /// after the layout changed or the terminal was resized
+    fn apply_layout(&mut self) {
+        for (_, window) in self.windows.drain(..) {
+            delwin(window);
+        }
+        werase(stdscr());
+        wrefresh(stdscr());
+
+        let (mut height, mut width) = (0, 0);
+        getmaxyx(stdscr(), &mut height, &mut width);
+        for (panel, rect) in self.layout.geometry(height, width) {
+            self.windows.push((panel, newwin(rect.height, rect.width, rect.y, rect.x)));
+        }
+        keypad(self.cli(), true);
     }
 
-    /// Refresh the code panel, showing disassembled code
-    fn code_panel(&self) {
-        // Assuming there's a disassemble function available in CPU or Memory
-        let code_listing = self.cpu.lock().unwrap().disassemble();
-        mvwprintw(self.wcode, 1, 1, &code_listing);
-        wrefresh(self.wcode);
+    /// Window of a panel; None when it is hidden
+    fn window(&self, panel: Panel) -> Option<WINDOW> {
+        self.windows.iter().find(|(p, _)| *p == panel).map(|&(_, window)| window)
     }
 
-    /// Refresh the memory panel
-    fn memory_panel(&self) {
-        let mem_dump = self.memory.lock().unwrap().dump();
-        mvwprintw(self.wmem, 1, 1, &mem_dump);
-        wrefresh(self.wmem);
+    /// The command line, which every layout has
+    fn cli(&self) -> WINDOW {
+        self.window(Panel::Cli).expect("layout without a command line")
     }
 
-    /// Refresh the register panel
-    fn reg_panel(&self) {
-        let reg_state = self.cpu.lock().unwrap().dump_registers();
-        mvwprintw(self.wreg, 1, 1, &reg_state);
-        wrefresh(self.wreg);
+    /// Number of lines that fit in a panel, between its top and bottom rows
+    fn panel_lines(window: WINDOW) -> usize {
+        (getmaxy(window) - 2).max(1) as usize
     }
 
-    /// Move to a different section of memory
-    fn memory_move(&self, address: u64) {
-        self.memory.lock().unwrap().move_to_address(address);
-        self.memory_panel();  // Refresh the memory panel
+    /// Number of instructions shown in the code panel, the source line
+    /// taking the bottom row
+    fn code_lines(&self) -> usize {
+        self.window(Panel::Code).map_or(1, Tui::panel_lines)
     }
 
-    /// Prompt the user for input
-    fn prompt(&self) -> String {
-        let mut input = String::new();
-        mvwgetstr(self.wcli, 1, 1, &mut input);
-        input
+    /// Color of an instruction category in the code panel
+    fn category_color(category: Category) -> DebuggerColor {
+        match category {
+            Category::Arithmetic => DebuggerColor::Arithm,
+            Category::Test => DebuggerColor::Test,
+            Category::Let => DebuggerColor::Let,
+            Category::Jump => DebuggerColor::Jump,
+            Category::Memory => DebuggerColor::Memory,
+            Category::Control => DebuggerColor::Control,
+        }
     }
 
-    /// Handle user commands
-    fn handle_command(&mut self, cmd: String) {
-        match cmd.as_str() {
-            "run" => {
-                self.state = DebuggerState::Idle;
+    /// Refresh the code panel, showing disassembled code around the focus.
+    /// When PC moves out of view, the panel is centered on it again
+    fn code_panel(&mut self, core: &DebuggerCore) {
+        let Some(wcode) = self.window(Panel::Code) else { return };
+        let rows = Tui::panel_lines(wcode);
+        let pc = core.cpu.lock().unwrap().ptr[PC];
+        let lines = core.code_lines();
+
+        let mut top = DebuggerCore::line_index(&lines, self.code_top).unwrap_or(0);
+        if pc != self.code_pc {
+            if let Some(p) = DebuggerCore::line_index(&lines, pc) {
+                if p < top || p >= top + rows {
+                    top = p.saturating_sub(rows / 2);
+                }
             }
-            "step" => {
-                self.cpu.lock().unwrap().step();
-                self.reg_panel();
+            self.code_pc = pc;
+        }
+        self.code_top = lines.get(top).map_or(0, |l| l.address);
+
+        werase(wcode);
+        for (row, line) in lines.iter().skip(top).take(rows).enumerate() {
+            let row = row as i32 + 1;
+            let current = line.address <= pc && pc < line.next;
+            let color = match line.format {
+                _ if core.breaks[core.focus].has(line.address) => DebuggerColor::Break,
+                Some(format) => Tui::category_color(format.category),
+                None => DebuggerColor::Error,
+            };
+
+            let attrs = COLOR_PAIR(color as i16) | if current { A_REVERSE() } else { A_NORMAL() };
+            wattron(wcode, attrs);
+            mvwprintw(wcode, row, 1, &core.code_text(line, pc));
+            wattroff(wcode, attrs);
+        }
+
+        // Source line of the current instruction
+        if let Some(source) = core.source_text(pc) {
+            mvwprintw(wcode, rows as i32 + 1, 1, &source);
+        }
+        if core.machine.len() > 1 {
+            mvwprintw(wcode, 0, 1, &format!("core {}/{}", core.focus, core.machine.len()));
+        }
+        wrefresh(wcode);
+    }
+
+    /// Scroll the code panel by a number of pages (negative to go up)
+    fn code_scroll(&mut self, core: &DebuggerCore, pages: isize) {
+        let lines = core.code_lines();
+        let top = DebuggerCore::line_index(&lines, self.code_top).unwrap_or(0) as isize;
+        let last = lines.len().saturating_sub(1) as isize;
+        let top = (top + pages * self.code_lines() as isize).clamp(0, last) as usize;
+        self.code_top = lines.get(top).map_or(0, |l| l.address);
+        self.code_panel(core);
+    }
+
+    /// Refresh the memory panel. When looking back in time, memory is read
+    /// through the journal
+    fn memory_panel(&self, core: &DebuggerCore) {
+        let Some(wmem) = self.window(Panel::Mem) else { return };
+        werase(wmem);
+        for i in 0..Tui::panel_lines(wmem) {
+            let address = self.mem_address + 64 * i as u64;
+            mvwprintw(wmem, 1 + i as i32, 1, &format!("{:08x} {:016x}", address, core.read_word(address)));
+        }
+        wrefresh(wmem);
+    }
+
+    /// Refresh the register panel. Registers that changed during the last
+    /// step are highlighted
+    fn reg_panel(&self, core: &DebuggerCore) {
+        let Some(wreg) = self.window(Panel::Regs) else { return };
+        let (text, before) = core.registers();
+
+        werase(wreg);
+        for (i, line) in text.lines().enumerate() {
+            let changed = !before.is_empty() && before.lines().nth(i).is_some_and(|b| b != line);
+            if changed {
+                wattron(wreg, COLOR_PAIR(DebuggerColor::Changed as i16) | A_BOLD());
             }
-            "break" => {
-                self.state = DebuggerState::Break;
+            mvwprintw(wreg, 1 + i as i32, 1, line);
+            if changed {
+                wattroff(wreg, COLOR_PAIR(DebuggerColor::Changed as i16) | A_BOLD());
             }
-            "exit" => {
-                self.state = DebuggerState::Halt;
+        }
+        if core.time_offset > 0 {
+            mvwprintw(wreg, getmaxy(wreg) - 2, 14, &format!("[-{} steps]", core.time_offset));
+        }
+        wrefresh(wreg);
+    }
+
+    /// Refresh the frame panel: the innermost calls of the backtrace, then
+    /// the current stack frame, decoded
+    fn frame_panel(&self, core: &DebuggerCore) {
+        let Some(wframe) = self.window(Panel::Frame) else { return };
+        let mut lines = core.backtrace();
+        if lines.len() > BACKTRACE_LINES {
+            let more = lines.len() - (BACKTRACE_LINES - 1);
+            lines.truncate(BACKTRACE_LINES - 1);
+            lines.push(format!("... {} more (bt)", more));
+        }
+        lines.extend(core.frame());
+
+        werase(wframe);
+        for (row, line) in lines.iter().take(Tui::panel_lines(wframe)).enumerate() {
+            mvwprintw(wframe, 1 + row as i32, 1, line);
+        }
+        wrefresh(wframe);
+    }
+
+    /// Show the command being typed after a prefix
+    fn input_line(&self, prefix: &str) {
+        let wcli = self.cli();
+        wmove(wcli, 1, 1);
+        wclrtoeol(wcli);
+        mvwprintw(wcli, 1, 1, &format!("{}> {}", prefix, self.input));
+        wrefresh(wcli);
+    }
+
+    /// Handle a key typed at the command line. Returns true on Enter
+    fn input_key(&mut self, core: &DebuggerCore, key: i32) -> bool {
+        match key {
+            KEY_PPAGE => self.code_scroll(core, -1),
+            KEY_NPAGE => self.code_scroll(core, 1),
+            KEY_RESIZE => {
+                self.apply_layout();
+                self.refresh(core);
             }
-            _ => {
-                self.log_error("Unknown command.");
+            KEY_ENTER | 10 | 13 => return true,
+            KEY_BACKSPACE | 8 | 127 => { self.input.pop(); }
+            c if (32..127).contains(&c) => self.input.push(c as u8 as char),
+            _ => {}
+        }
+        false
+    }
+
+    /// Move to a different section of memory
+    fn memory_move(&mut self, core: &DebuggerCore, address: u64) {
+        self.mem_address = address;
+        self.memory_panel(core);  // Refresh the memory panel
+    }
+}
+
+impl DebuggerView for Tui {
+    /// Prompt the user for a command. PageUp and PageDown scroll the code
+    /// panel while typing; the panels follow the size of the terminal
+    fn prompt(&mut self, core: &DebuggerCore) -> Option<String> {
+        loop {
+            self.input_line("");
+            let key = wgetch(self.cli());
+            if self.input_key(core, key) {
+                return Some(std::mem::take(&mut self.input));
             }
         }
     }
 
+    /// Redraw the panels while the program runs and read a key, typed
+    /// commands carrying over from one call to the next. Ctrl-C pauses the
+    /// program; the terminal is in raw mode meanwhile so that it does not
+    /// end the debugger
+    fn poll(&mut self, core: &DebuggerCore, interval: Duration) -> Option<String> {
+        self.refresh(core);
+        self.input_line("[running] ");
+        let wcli = self.cli();
+        raw();
+        wtimeout(wcli, interval.as_millis() as i32);
+        let key = wgetch(wcli);
+        wtimeout(wcli, -1);
+        cbreak();
+
+        match key {
+            3 => Some("pause".to_string()),
+            ERR => None,
+            key if self.input_key(core, key) => Some(std::mem::take(&mut self.input)),
+            _ => None,
+        }
+    }
+
     /// Log messages to the console
-    fn log(&self, message: &str) {
-        wattron(self.wcli, COLOR_PAIR(DebuggerColor::Command as i16));
-        mvwprintw(self.wcli, 1, 1, message);
-        wattroff(self.wcli, COLOR_PAIR(DebuggerColor::Command as i16));
-        wrefresh(self.wcli);
+    fn log(&mut self, message: &str) {
+        let wcli = self.cli();
+        wattron(wcli, COLOR_PAIR(DebuggerColor::Command as i16));
+        mvwprintw(wcli, 1, 1, message);
+        wattroff(wcli, COLOR_PAIR(DebuggerColor::Command as i16));
+        wrefresh(wcli);
     }
 
     /// Log error messages
-    fn log_error(&self, message: &str) {
-        wattron(self.wcli, COLOR_PAIR(DebuggerColor::Error as i16));
-        mvwprintw(self.wcli, 1, 1, &format!("error: {}", message));
-        wattroff(self.wcli, COLOR_PAIR(DebuggerColor::Error as i16));
-        wrefresh(self.wcli);
+    fn log_error(&mut self, message: &str) {
+        let wcli = self.cli();
+        wattron(wcli, COLOR_PAIR(DebuggerColor::Error as i16));
+        mvwprintw(wcli, 1, 1, &format!("error: {}", message));
+        wattroff(wcli, COLOR_PAIR(DebuggerColor::Error as i16));
+        wrefresh(wcli);
+    }
+
+    /// Show text over the whole screen until a key is pressed
+    fn show_text(&mut self, text: &str) {
+        let (mut height, mut width) = (0, 0);
+        getmaxyx(stdscr(), &mut height, &mut width);
+        let window = newwin(height, width, 0, 0);
+        let rows = (height - 2).max(0) as usize;
+        for (i, line) in text.lines().take(rows).enumerate() {
+            mvwprintw(window, i as i32, 0, line);
+        }
+        let hidden = text.lines().count().saturating_sub(rows);
+        let more = if hidden > 0 { format!("({} more lines) ", hidden) } else { String::new() };
+        mvwprintw(window, height - 1, 0, &format!("{}Press a key to continue.", more));
+        wrefresh(window);
+        wgetch(window);
+        delwin(window);
+
+        for &(_, panel) in &self.windows {
+            touchwin(panel);
+            wrefresh(panel);
+        }
+    }
+
+    /// Draw the interface panels
+    fn refresh(&mut self, core: &DebuggerCore) {
+        self.code_panel(core);
+        self.memory_panel(core);
+        self.reg_panel(core);
+        self.frame_panel(core);
+        wrefresh(self.cli());
     }
-}
 
+    /// Center the code panel on an address
+    fn show_code(&mut self, core: &DebuggerCore, address: u64) -> bool {
+        let lines = core.code_lines();
+        match DebuggerCore::line_index(&lines, address) {
+            Some(index) => {
+                let top = index.saturating_sub(self.code_lines() / 2);
+                self.code_top = lines[top].address;
+                self.code_panel(core);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Show, reset or change the panel layout, or move the memory panel:
+    ///     layout                      the current one
+    ///     layout default
+    ///     layout code regs cli        panels in order, the others hidden
+    ///     memory <address|label>      first address of the memory panel
+    fn view_command(&mut self, core: &DebuggerCore, words: &[&str]) -> bool {
+        let layout = match words {
+            ["memory", target] => {
+                match core.resolve(target) {
+                    Some(address) => self.memory_move(core, address),
+                    None => self.log_error(&format!("No address or label '{}'.", target)),
+                }
+                return true;
+            }
+            ["layout"] => {
+                self.log(&format!("layout {}", self.layout.names()));
+                return true;
+            }
+            ["layout", "default"] => Ok(Layout::default()),
+            ["layout", names @ ..] => Layout::parse(names),
+            _ => return false,
+        };
+        match layout {
+            Ok(layout) => {
+                self.layout = layout;
+                self.apply_layout();
+                self.refresh(core);
+            }
+            Err(e) => self.log_error(&e),
+        }
+        true
+    }
+}
@@ -0,0 +1,121 @@
+//---
+// emu:debuginfo - source-level debug information
+//
+// Reads the debug info sidecar written by the assembler back end, which
+// maps bit offsets of the text segment to source lines and labels:
+//
+//     line <bit offset> <source file> <line number>
+//     label <bit offset> <name>
+//
+// Lines starting with ';' are comments. Source files are looked up
+// relative to the directory of the sidecar.
+//---
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    lines: BTreeMap<u64, SourceLine>,
+    pub labels: BTreeMap<u64, String>,
+    sources: HashMap<String, Vec<String>>,  // Contents of the source files
+}
+
+impl DebugInfo {
+    /// Parse the contents of a sidecar file
+    pub fn from_text(text: &str) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let error = || format!("line {}: invalid debug record: {}", number + 1, line);
+            let offset = fields.get(1).and_then(|o| o.parse().ok()).ok_or_else(error)?;
+
+            match fields.as_slice() {
+                ["line", _, file, n] => {
+                    let n = n.parse().map_err(|_| error())?;
+                    info.lines.insert(offset, SourceLine { file: file.to_string(), line: n });
+                }
+                ["label", _, name] => { info.labels.insert(offset, name.to_string()); }
+                _ => return Err(error()),
+            }
+        }
+        Ok(info)
+    }
+
+    /// Load a sidecar file, along with the source files it refers to. Missing
+    /// sources are not an error: only their text will not be shown
+    pub fn load(filename: &str) -> io::Result<DebugInfo> {
+        let text = fs::read_to_string(filename)?;
+        let mut info = DebugInfo::from_text(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+
+        let dir = Path::new(filename).parent().map_or_else(PathBuf::new, Path::to_path_buf);
+        let files: Vec<String> = info.lines.values().map(|l| l.file.clone()).collect();
+        for file in files {
+            if info.sources.contains_key(&file) {
+                continue;
+            }
+            if let Ok(source) = fs::read_to_string(dir.join(&file)) {
+                info.sources.insert(file, source.lines().map(str::to_string).collect());
+            }
+        }
+        Ok(info)
+    }
+
+    /// Source line of the instruction at `address`: the closest line record
+    /// at or before it
+    pub fn source_line(&self, address: u64) -> Option<&SourceLine> {
+        self.lines.range(..=address).next_back().map(|(_, l)| l)
+    }
+
+    /// Text of the source line of the instruction at `address`, if the
+    /// source file was found
+    pub fn source_text(&self, address: u64) -> Option<&str> {
+        let line = self.source_line(address)?;
+        self.sources.get(&line.file)?.get(line.line.checked_sub(1)?).map(String::as_str)
+    }
+
+    /// Address of a label
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.labels.iter().find(|(_, n)| n.as_str() == name).map(|(&address, _)| address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_info() {
+        let text = "; MinimISA debug info\n\
+            label 0 main\n\
+            line 0 prog.s 2\n\
+            line 15 prog.s 3\n\
+            label 30 loop\n\
+            line 30 prog.s 5\n";
+        let info = DebugInfo::from_text(text).unwrap();
+
+        assert_eq!(info.lookup("loop"), Some(30));
+        assert_eq!(info.lookup("nope"), None);
+        assert_eq!(info.source_line(0).map(|l| l.line), Some(2));
+        assert_eq!(info.source_line(20).map(|l| l.line), Some(3));
+        assert_eq!(info.source_line(1000).map(|l| l.line), Some(5));
+        assert_eq!(info.source_text(0), None);
+
+        assert!(DebugInfo::from_text("line x prog.s 2").is_err());
+        assert!(DebugInfo::from_text("label 3").is_err());
+    }
+}
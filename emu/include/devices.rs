@@ -0,0 +1,470 @@
+//---
+// emu:devices - memory-mapped peripherals
+//
+// Devices live in the I/O window at the end of the data segment and are
+// registered on the memory with Memory::register_mmio().
+//---
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use crate::memory::{Memory, MmioHandler};
+
+// Offsets of device registers in the I/O window
+pub const IO_CONSOLE: u64 = 0;
+pub const IO_KEYBOARD: u64 = 64;
+pub const IO_AUDIO: u64 = 256;
+pub const IO_UART: u64 = 320;
+pub const IO_CLOCK: u64 = 336;
+
+// Keyboard registers, from IO_KEYBOARD. Key k (an SDL scancode below 128)
+// is bit KEYBOARD_KEYS + k, set while the key is down, so that a 1-bit
+// read polls it. Reading the event register from its start pops the oldest
+// key event: bit 15 is set for an event, bit 8 for a press (clear for a
+// release) and bits 0-7 hold the scancode; it reads 0 when there is none.
+// The pending register counts the events left in the queue
+pub const KEYBOARD_KEYS: u64 = 0;       // 128 bits
+pub const KEYBOARD_EVENT: u64 = 128;    // 16 bits
+pub const KEYBOARD_PENDING: u64 = 144;  // 8 bits
+pub const KEYBOARD_SIZE: u64 = 152;
+
+// Events kept until the program reads them; later ones are dropped
+pub const KEYBOARD_QUEUE: usize = 16;
+
+// Audio registers, from IO_AUDIO: a square wave of the given frequency in
+// Hz and volume (255 is full scale), heard while the enable bit is set
+pub const AUDIO_FREQUENCY: u64 = 0;  // 16 bits
+pub const AUDIO_VOLUME: u64 = 16;    // 8 bits
+pub const AUDIO_ENABLE: u64 = 24;    // 1 bit
+pub const AUDIO_SIZE: u64 = 32;
+
+// Serial port registers, from IO_UART. Reading the data register from its
+// start pops the oldest received byte (0 if there is none), writing it
+// sends a byte. The status register has UART_RX_READY set when a byte can
+// be read, UART_TX_READY always, and UART_CLOSED once the host side has
+// closed and every byte was read
+pub const UART_DATA: u64 = 0;    // 8 bits
+pub const UART_STATUS: u64 = 8;  // 8 bits
+pub const UART_SIZE: u64 = 16;
+pub const UART_RX_READY: u64 = 0x01;
+pub const UART_TX_READY: u64 = 0x02;
+pub const UART_CLOSED: u64 = 0x04;
+
+// Clock registers, from IO_CLOCK: the simulated time of the CPU in cycles
+// (from the timing model) as of the current instruction, and the host time
+// in milliseconds since the clock was created. Both only increase. The
+// assembler's rdcycles and rdtime read them at their address with the
+// default segment sizes
+pub const CLOCK_CYCLES: u64 = 0;   // 64 bits
+pub const CLOCK_MILLIS: u64 = 64;  // 64 bits
+pub const CLOCK_SIZE: u64 = 128;
+
+/// Console: writing a byte prints it on stdout. Reads return 0.
+pub struct Console;
+
+impl MmioHandler for Console {
+    fn read(&self, _offset: u64, _n: usize) -> u64 {
+        0
+    }
+
+    fn write(&mut self, _offset: u64, value: u64, _n: usize) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[value as u8]);
+        let _ = stdout.flush();
+    }
+}
+
+/// Map the console at its address in the I/O window, return that address
+pub fn attach_console(memory: &mut Memory) -> u64 {
+    let address = memory.io_base() + IO_CONSOLE;
+    memory.register_mmio(address..address + 8, Box::new(Console));
+    address
+}
+
+#[derive(Debug, Default)]
+struct KeyboardState {
+    keys: u128,              // Bit k is set while key k is down
+    events: VecDeque<u16>,   // Oldest first, in the event register format
+}
+
+/// Keyboard, shared between its registers in memory and the window that
+/// feeds it key events. The window records keys with the memory locked, so
+/// that an instruction sees the bitmap and the queue in a consistent state
+#[derive(Debug, Clone, Default)]
+pub struct Keyboard(Arc<Mutex<KeyboardState>>);
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard::default()
+    }
+
+    /// Record a key going down or up; scancodes from 128 on are ignored
+    pub fn key(&self, scancode: u32, pressed: bool) {
+        if scancode >= 128 {
+            return;
+        }
+        let mut state = self.0.lock().unwrap();
+        if pressed {
+            state.keys |= 1 << scancode;
+        } else {
+            state.keys &= !(1 << scancode);
+        }
+        if state.events.len() < KEYBOARD_QUEUE {
+            state.events.push_back(0x8000 | (pressed as u16) << 8 | scancode as u16);
+        }
+    }
+}
+
+impl MmioHandler for Keyboard {
+    fn read(&self, offset: u64, n: usize) -> u64 {
+        let mut state = self.0.lock().unwrap();
+        let end = offset + n as u64;
+        let event = match offset <= KEYBOARD_EVENT && end > KEYBOARD_EVENT {
+            true => state.events.pop_front().unwrap_or(0) as u64,
+            false => state.events.front().map_or(0, |&e| e as u64),
+        };
+        let pending = state.events.len() as u64;
+
+        // Registers are read as one string of bits, first bit most significant
+        (offset..end).fold(0, |value, bit| value << 1 | match bit {
+            b if b < KEYBOARD_EVENT => (state.keys >> b) as u64 & 1,
+            b if b < KEYBOARD_PENDING => event >> (15 - (b - KEYBOARD_EVENT)) & 1,
+            b if b < KEYBOARD_SIZE => pending >> (7 - (b - KEYBOARD_PENDING)) & 1,
+            _ => 0,
+        })
+    }
+
+    // The registers are read-only
+    fn write(&mut self, _offset: u64, _value: u64, _n: usize) {}
+}
+
+/// Map a keyboard at its address in the I/O window, return that address
+pub fn attach_keyboard(memory: &mut Memory, keyboard: &Keyboard) -> u64 {
+    let address = memory.io_base() + IO_KEYBOARD;
+    memory.register_mmio(address..address + KEYBOARD_SIZE, Box::new(keyboard.clone()));
+    address
+}
+
+#[derive(Debug, Default)]
+struct AudioState {
+    registers: u32,  // Register bits, the first one most significant
+    phase: f64,      // Position in the current period of the wave, 0 to 1
+}
+
+/// Square-wave audio channel, shared between its registers in memory and
+/// the audio callback that plays it
+#[derive(Debug, Clone, Default)]
+pub struct Audio(Arc<Mutex<AudioState>>);
+
+impl Audio {
+    pub fn new() -> Audio {
+        Audio::default()
+    }
+
+    /// Frequency in Hz, volume and whether the channel is on
+    pub fn settings(&self) -> (u32, u8, bool) {
+        let registers = self.0.lock().unwrap().registers;
+        (registers >> 16, (registers >> 8) as u8, registers & 0x80 != 0)
+    }
+
+    /// Fill a buffer of samples at `rate` Hz with the wave, continuing from
+    /// where the last buffer ended
+    pub fn fill(&self, out: &mut [i16], rate: u32) {
+        let (frequency, volume, enabled) = self.settings();
+        let mut state = self.0.lock().unwrap();
+        if !enabled || frequency == 0 || rate == 0 {
+            out.fill(0);
+            return;
+        }
+        let amplitude = (volume as i32 * i16::MAX as i32 / 255) as i16;
+        let step = frequency as f64 / rate as f64;
+        for sample in out.iter_mut() {
+            *sample = if state.phase < 0.5 { amplitude } else { -amplitude };
+            state.phase = (state.phase + step).fract();
+        }
+    }
+}
+
+impl MmioHandler for Audio {
+    fn read(&self, offset: u64, n: usize) -> u64 {
+        if n == 0 || offset >= AUDIO_SIZE {
+            return 0;
+        }
+        let registers = (self.0.lock().unwrap().registers as u64) << 32;
+        (registers << offset) >> (64 - n)
+    }
+
+    fn write(&mut self, offset: u64, value: u64, n: usize) {
+        let n = n.min((AUDIO_SIZE - offset) as usize);
+        if n == 0 {
+            return;
+        }
+        let shift = AUDIO_SIZE - offset - n as u64;
+        let mask = (u64::MAX >> (64 - n)) << shift;
+        let mut state = self.0.lock().unwrap();
+        state.registers = ((state.registers as u64 & !mask) | ((value << shift) & mask)) as u32;
+    }
+}
+
+/// Map an audio channel at its address in the I/O window, return that
+/// address
+pub fn attach_audio(memory: &mut Memory, audio: &Audio) -> u64 {
+    let address = memory.io_base() + IO_AUDIO;
+    memory.register_mmio(address..address + AUDIO_SIZE, Box::new(audio.clone()));
+    address
+}
+
+#[derive(Debug, Default)]
+struct UartInput {
+    bytes: VecDeque<u8>,
+    closed: bool,  // The host side will send nothing more
+}
+
+/// Serial port, bridged to a host byte stream: the terminal, a TCP client,
+/// or anything readable and writable. A thread reads the host side so that
+/// programs can poll the status register without blocking
+pub struct Uart {
+    input: Arc<Mutex<UartInput>>,
+    output: Box<dyn Write + Send>,
+}
+
+impl Uart {
+    pub fn new(mut input: impl Read + Send + 'static, output: impl Write + Send + 'static) -> Uart {
+        let shared = Arc::new(Mutex::new(UartInput::default()));
+        let received = Arc::clone(&shared);
+        thread::spawn(move || {
+            let mut buffer = [0u8; 256];
+            loop {
+                match input.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => received.lock().unwrap().bytes.extend(&buffer[..n]),
+                }
+            }
+            received.lock().unwrap().closed = true;
+        });
+        Uart { input: shared, output: Box::new(output) }
+    }
+
+    /// Serial port on stdin and stdout
+    pub fn terminal() -> Uart {
+        Uart::new(io::stdin(), io::stdout())
+    }
+
+    /// Serial port on the first client to connect to a local TCP port;
+    /// blocks until it does
+    pub fn listen(port: u16) -> io::Result<Uart> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("uart: waiting for a connection on port {}", port);
+        let (stream, peer) = listener.accept()?;
+        eprintln!("uart: connected to {}", peer);
+        Ok(Uart::new(stream.try_clone()?, stream))
+    }
+}
+
+impl MmioHandler for Uart {
+    fn read(&self, offset: u64, n: usize) -> u64 {
+        let mut input = self.input.lock().unwrap();
+        let end = offset + n as u64;
+        let data = match offset == UART_DATA && end > UART_DATA {
+            true => input.bytes.pop_front().unwrap_or(0) as u64,
+            false => input.bytes.front().map_or(0, |&b| b as u64),
+        };
+        let mut status = UART_TX_READY;
+        if !input.bytes.is_empty() {
+            status |= UART_RX_READY;
+        } else if input.closed {
+            status |= UART_CLOSED;
+        }
+
+        let registers = data << 8 | status;
+        (offset..end).fold(0, |value, bit| {
+            value << 1 | if bit < UART_SIZE { registers >> (UART_SIZE - 1 - bit) & 1 } else { 0 }
+        })
+    }
+
+    fn write(&mut self, offset: u64, value: u64, n: usize) {
+        if offset != UART_DATA || n < 8 {
+            return;
+        }
+        let byte = (value >> (n - 8)) as u8;
+        let _ = self.output.write_all(&[byte]);
+        let _ = self.output.flush();
+    }
+}
+
+/// Map a serial port at its address in the I/O window, return that address
+pub fn attach_uart(memory: &mut Memory, uart: Uart) -> u64 {
+    let address = memory.io_base() + IO_UART;
+    memory.register_mmio(address..address + UART_SIZE, Box::new(uart));
+    address
+}
+
+/// Cycle counter and real-time clock. The CPU sets the cycle count before
+/// every instruction (see CPU::rtc); clones share it
+#[derive(Debug, Clone)]
+pub struct Clock {
+    cycles: Arc<AtomicU64>,
+    start: Instant,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new()
+    }
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock { cycles: Arc::new(AtomicU64::new(0)), start: Instant::now() }
+    }
+
+    pub fn set_cycles(&self, cycles: u64) {
+        self.cycles.store(cycles, Ordering::Relaxed);
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    /// Host milliseconds since the clock was created
+    pub fn millis(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+impl MmioHandler for Clock {
+    fn read(&self, offset: u64, n: usize) -> u64 {
+        let registers = [self.cycles(), self.millis()];
+        (offset..offset + n as u64).fold(0, |value, bit| {
+            let register = registers.get((bit / 64) as usize).copied().unwrap_or(0);
+            value << 1 | (register >> (63 - bit % 64) & 1)
+        })
+    }
+
+    // The clock is read-only
+    fn write(&mut self, _offset: u64, _value: u64, _n: usize) {}
+}
+
+/// Map a clock at its address in the I/O window, return that address
+pub fn attach_clock(memory: &mut Memory, clock: &Clock) -> u64 {
+    let address = memory.io_base() + IO_CLOCK;
+    memory.register_mmio(address..address + CLOCK_SIZE, Box::new(clock.clone()));
+    address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyboard() {
+        let mut memory = Memory::new(0, 0, 0, 0);
+        let keyboard = Keyboard::new();
+        let base = attach_keyboard(&mut memory, &keyboard);
+        assert_eq!(memory.read(base + KEYBOARD_EVENT, 16), 0);
+
+        keyboard.key(4, true);
+        keyboard.key(81, true);
+        keyboard.key(4, false);
+        keyboard.key(200, true);
+        assert_eq!(memory.read(base + KEYBOARD_KEYS + 4, 1), 0);
+        assert_eq!(memory.read(base + KEYBOARD_KEYS + 81, 1), 1);
+        assert_eq!(memory.read(base + KEYBOARD_KEYS + 64, 64), 1 << (63 - 17));
+        assert_eq!(memory.read(base + KEYBOARD_PENDING, 8), 3);
+
+        // Events come out in order, and peeking at the pending count does not pop
+        assert_eq!(memory.read(base + KEYBOARD_EVENT, 16), 0x8104);
+        assert_eq!(memory.read(base + KEYBOARD_EVENT, 24), 0x8151 << 8 | 1);
+        assert_eq!(memory.read(base + KEYBOARD_EVENT, 16), 0x8004);
+        assert_eq!(memory.read(base + KEYBOARD_PENDING, 8), 0);
+
+        for _ in 0..2 * KEYBOARD_QUEUE {
+            keyboard.key(1, true);
+        }
+        assert_eq!(memory.read(base + KEYBOARD_PENDING, 8), KEYBOARD_QUEUE as u64);
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut memory = Memory::new(0, 0, 0, 0);
+        let audio = Audio::new();
+        let base = attach_audio(&mut memory, &audio);
+
+        memory.write(base + AUDIO_FREQUENCY, 440, 16);
+        memory.write(base + AUDIO_VOLUME, 255, 8);
+        assert_eq!(audio.settings(), (440, 255, false));
+        let mut out = [1i16; 4];
+        audio.fill(&mut out, 1760);
+        assert_eq!(out, [0; 4]);
+
+        // Four samples per period at 1760 Hz, the second buffer goes on
+        memory.write(base + AUDIO_ENABLE, 1, 1);
+        assert_eq!(memory.read(base, 32), 440 << 16 | 255 << 8 | 0x80);
+        audio.fill(&mut out[..3], 1760);
+        audio.fill(&mut out[3..], 1760);
+        assert_eq!(out, [i16::MAX, i16::MAX, -i16::MAX, -i16::MAX]);
+    }
+
+    // Output of a serial port, kept for inspection
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_uart() {
+        let mut memory = Memory::new(0, 0, 0, 0);
+        let sink = Sink::default();
+        let base = attach_uart(&mut memory, Uart::new(&b"hi"[..], sink.clone()));
+
+        // The reader thread gets both bytes at once, then the end of input
+        let status = |memory: &Memory| memory.read(base + UART_STATUS, 8);
+        while status(&memory) & UART_RX_READY == 0 {
+            thread::yield_now();
+        }
+        assert_eq!(memory.read(base + UART_DATA, 16), (b'h' as u64) << 8 | UART_RX_READY | UART_TX_READY);
+        assert_eq!(memory.read(base + UART_DATA, 8), b'i' as u64);
+        while status(&memory) & UART_CLOSED == 0 {
+            thread::yield_now();
+        }
+        assert_eq!(memory.read(base + UART_DATA, 8), 0);
+        assert_eq!(status(&memory), UART_TX_READY | UART_CLOSED);
+
+        memory.write(base + UART_DATA, b'o' as u64, 8);
+        memory.write(base + UART_DATA, (b'k' as u64) << 16 | 0xff, 24);
+        memory.write(base + UART_STATUS, b'x' as u64, 8);
+        assert_eq!(*sink.0.lock().unwrap(), b"ok");
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut memory = Memory::new(0, 0, 0, 0);
+        let clock = Clock::new();
+        let base = attach_clock(&mut memory, &clock);
+        // Where rdcycles and rdtime of the assembler read
+        assert_eq!(base, 0xfd50);
+
+        clock.set_cycles(0x1234_5678_9abc);
+        assert_eq!(memory.read(base + CLOCK_CYCLES, 64), 0x1234_5678_9abc);
+        assert_eq!(memory.read(base + CLOCK_CYCLES + 48, 16), 0x9abc);
+        let millis = memory.read(base + CLOCK_MILLIS, 64);
+        thread::sleep(std::time::Duration::from_millis(2));
+        assert!(memory.read(base + CLOCK_MILLIS, 64) >= millis + 2);
+
+        memory.write(base + CLOCK_CYCLES, 0, 64);
+        assert_eq!(clock.cycles(), 0x1234_5678_9abc);
+    }
+}
@@ -1,32 +1,21 @@
 use crate::memory::Memory;
+use std::collections::BTreeMap;
+use std::io;
+use minimisa_core::object::parse_symbols;
+use minimisa_core::{CONDITIONS, DIRECTIONS, POINTERS};
 
-/// Number of different instructions (assuming 37 opcodes)
-pub const DISASM_INS_COUNT: usize = 37;
+pub use minimisa_core::op::*;
+pub use minimisa_core::{Category, Operand as ArgType};
+use minimisa_core::{Instruction, Opcodes, INSTRUCTIONS, INSTRUCTION_COUNT};
 
-#[derive(Debug, Clone, Copy)]
-pub enum ArgType {
-    None,       // No argument
-    Register,   // Register: r0..r7 on 3 bits
-    Direction,  // Direction: left/right on 1 bit
-    Condition,  // Condition: various on 3 bits
-    Address,    // Address: on 9, 18, 35 or 67 bits
-    LConst,     // Constants: on 2, 18, 35, or 67 bits
-    AConst,     // Arithmetic (signed) constants
-    Shift,      // Shifts: 1 bit or 7 bits
-    Size,       // Size: 2 or 3 bits
-    Pointer,    // Pointer: PC, SP, A0, or A1 on 2 bits
-}
+/// Number of different instructions (the 37 MinimISA opcodes plus reti)
+pub const DISASM_INS_COUNT: usize = INSTRUCTION_COUNT;
 
-#[derive(Debug, Clone, Copy)]
-pub enum Category {
-    Arithmetic,
-    Test,
-    Let,
-    Jump,
-    Memory,
-    Control,
-}
+/// Longest opcode accepted when decoding; Huffman tables built from very
+/// skewed programs can get deep, but never deeper than this
+pub const DISASM_MAX_OPCODE: u32 = 40;
 
+#[derive(Debug, Clone, Copy)]
 pub struct DisasmFormat {
     pub arg1: ArgType,
     pub arg2: ArgType,
@@ -35,169 +24,79 @@ pub struct DisasmFormat {
     pub mnemonic: &'static str,
 }
 
-/// Read an instruction code (opcode) from memory and return the format
+const fn fmt(ins: &Instruction) -> DisasmFormat {
+    let [arg1, arg2, arg3] = ins.operands;
+    DisasmFormat { arg1, arg2, arg3, category: ins.category, mnemonic: ins.mnemonic }
+}
+
+// The format table comes from the ISA definition in minimisa-core
+static DISASM_FORMATS: [DisasmFormat; DISASM_INS_COUNT] = {
+    let mut formats = [fmt(&INSTRUCTIONS[0]); DISASM_INS_COUNT];
+    let mut op = 0;
+    while op < DISASM_INS_COUNT {
+        formats[op] = fmt(&INSTRUCTIONS[op]);
+        op += 1;
+    }
+    formats
+};
+
+/// Read an instruction code (opcode) from memory and return the format.
+/// The opcode is read bit by bit until it matches a code of the opcode
+/// table of the memory; unknown prefixes return DISASM_INS_COUNT and no
+/// format
 pub fn disasm_opcode(memory: &Memory, ptr: &mut u64) -> (u32, Option<DisasmFormat>) {
-    let opcode = memory.read_u32(*ptr);
-    *ptr += 4;  // Advance the pointer
+    let codes = memory.opcodes();
+    let mut bits: u64 = 0;
 
-    let format = disasm_format(opcode);
-    (opcode, format)
+    for length in 1..=DISASM_MAX_OPCODE {
+        bits = (bits << 1) | memory.read_bits(*ptr, 1) as u64;
+        *ptr += 1;
+
+        if let Some(op) = codes.iter().position(|&c| c == (bits, length)) {
+            return (op as u32, disasm_format(op as u32));
+        }
+        // Stop as soon as no longer code starts with what has been read
+        if !codes.iter().any(|&(c, l)| l > length && c >> (l - length) == bits) {
+            break;
+        }
+    }
+    (DISASM_INS_COUNT as u32, None)
 }
 
 /// Get the format for a given instruction (based on opcode)
 pub fn disasm_format(opcode: u32) -> Option<DisasmFormat> {
-    match opcode {
-        0x00 => Some(DisasmFormat {
-            arg1: ArgType::None,
-            arg2: ArgType::None,
-            arg3: ArgType::None,
-            category: Category::Control,
-            mnemonic: "NOP",
-        }),
-        0x01 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Address,
-            arg3: ArgType::None,
-            category: Category::Memory,
-            mnemonic: "LOAD",
-        }),
-        0x02 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::LConst,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "ADD",
-        }),
-        0x03 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "SUB",
-        }),
-        0x04 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "MUL",
-        }),
-        0x05 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "DIV",
-        }),
-        0x06 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::AConst,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "MOD",
-        }),
-        0x07 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "AND",
-        }),
-        0x08 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "OR",
-        }),
-        0x09 => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "XOR",
-        }),
-        0x0A => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::Shift,
-            category: Category::Arithmetic,
-            mnemonic: "SHL",
-        }),
-        0x0B => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Register,
-            arg3: ArgType::Shift,
-            category: Category::Arithmetic,
-            mnemonic: "SHR",
-        }),
-        0x0C => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::None,
-            arg3: ArgType::None,
-            category: Category::Arithmetic,
-            mnemonic: "NEG",
-        }),
-        0x0D => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Condition,
-            arg3: ArgType::None,
-            category: Category::Test,
-            mnemonic: "CMP",
-        }),
-        0x0E => Some(DisasmFormat {
-            arg1: ArgType::Register,
-            arg2: ArgType::Pointer,
-            arg3: ArgType::None,
-            category: Category::Memory,
-            mnemonic: "STORE",
-        }),
-        0x0F => Some(DisasmFormat {
-            arg1: ArgType::None,
-            arg2: ArgType::None,
-            arg3: ArgType::None,
-            category: Category::Control,
-            mnemonic: "HALT",
-        }),
-        0x10 => Some(DisasmFormat {
-            arg1: ArgType::Address,
-            arg2: ArgType::None,
-            arg3: ArgType::None,
-            category: Category::Jump,
-            mnemonic: "JMP",
-        }),
-        0x11 => Some(DisasmFormat {
-            arg1: ArgType::Condition,
-            arg2: ArgType::Address,
-            arg3: ArgType::None,
-            category: Category::Jump,
-            mnemonic: "JZ",
-        }),
-        0x12 => Some(DisasmFormat {
-            arg1: ArgType::Condition,
-            arg2: ArgType::Address,
-            arg3: ArgType::None,
-            category: Category::Jump,
-            mnemonic: "JNZ",
-        }),
-        0x13 => Some(DisasmFormat {
-            arg1: ArgType::None,
-            arg2: ArgType::None,
-            arg3: ArgType::None,
-            category: Category::Control,
-            mnemonic: "RET",
-        }),
-        0x24 => Some(DisasmFormat {
-            arg1: ArgType::None,
-            arg2: ArgType::None,
-            arg3: ArgType::None,
-            category: Category::Control,
-            mnemonic: "END",
-        }),
-        _ => None,  // Return None for unknown opcode
+    DISASM_FORMATS.get(opcode as usize).copied()
+}
+
+/// Binary encoding (code, length) of an opcode in a table. A length of 0
+/// marks an opcode that the table does not define
+pub fn disasm_code(codes: &Opcodes, opcode: u32) -> Option<(u64, u32)> {
+    codes.get(opcode as usize).copied().filter(|&(_, length)| length > 0)
+}
+
+/// Find the opcode number of a mnemonic
+pub fn disasm_lookup(mnemonic: &str) -> Option<u32> {
+    minimisa_core::lookup(mnemonic)
+}
+
+/// Check that the decoder can read every code of an opcode table
+pub fn disasm_check_opcodes(codes: &Opcodes) -> Result<(), String> {
+    match codes.iter().position(|&(_, length)| length > DISASM_MAX_OPCODE) {
+        Some(i) => Err(format!("code of {} is longer than {} bits", DISASM_FORMATS[i].mnemonic, DISASM_MAX_OPCODE)),
+        None => Ok(()),
     }
 }
 
+/// Load an opcode table as written by the compiler (opcode.txt, see
+/// minimisa_core::format_opcodes()), to install with Memory::set_opcodes().
+/// Instructions missing from the file can no longer be decoded
+pub fn disasm_load_opcodes(filename: &str) -> io::Result<Opcodes> {
+    let text = std::fs::read_to_string(filename)?;
+    minimisa_core::parse_opcodes(&text)
+        .and_then(|codes| disasm_check_opcodes(&codes).map(|()| codes))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 /// Read a register number (3 bits)
 pub fn disasm_reg(memory: &Memory, ptr: &mut u64) -> u32 {
     let reg = memory.read_bits(*ptr, 3);
@@ -219,48 +118,74 @@ pub fn disasm_cond(memory: &Memory, ptr: &mut u64) -> u32 {
     cond
 }
 
+/// Read a 0 / 10 / 110 / 111 prefix and return the matching width
+fn disasm_prefix(memory: &Memory, ptr: &mut u64, widths: [u32; 4]) -> u32 {
+    let mut index = 0;
+    while index < 3 && memory.read_bits(*ptr, 1) == 1 {
+        *ptr += 1;
+        index += 1;
+    }
+    if index < 3 {
+        *ptr += 1;  // Terminating 0
+    }
+    widths[index]
+}
+
 /// Read a relative address (optional pointer to size)
 pub fn disasm_addr(memory: &Memory, ptr: &mut u64, size: Option<&mut u32>) -> i64 {
-    let addr_size = memory.read_bits(*ptr, 9);  // Example: read 9 bits for address
+    let addr_size = disasm_prefix(memory, ptr, [8, 16, 32, 64]);
     if let Some(size_ptr) = size {
         *size_ptr = addr_size;
     }
-    *ptr += 9;
-    memory.read_signed(*ptr, addr_size as usize)
+    let addr = memory.read_signed(*ptr, addr_size as usize);
+    *ptr += addr_size as u64;
+    addr
 }
 
 /// Read a zero-extended constant
 pub fn disasm_lconst(memory: &Memory, ptr: &mut u64, size: Option<&mut u32>) -> u64 {
-    let const_size = memory.read_bits(*ptr, 9);  // Example: read 9 bits for constant size
+    let const_size = disasm_prefix(memory, ptr, [1, 8, 32, 64]);
     if let Some(size_ptr) = size {
         *size_ptr = const_size;
     }
-    *ptr += 9;
-    memory.read_unsigned(*ptr, const_size as usize)
+    let value = memory.read_unsigned(*ptr, const_size as usize);
+    *ptr += const_size as u64;
+    value
 }
 
 /// Read a sign-extended constant
 pub fn disasm_aconst(memory: &Memory, ptr: &mut u64, size: Option<&mut u32>) -> i64 {
-    let const_size = memory.read_bits(*ptr, 9);  // Example: read 9 bits for constant size
+    let const_size = disasm_prefix(memory, ptr, [1, 8, 32, 64]);
     if let Some(size_ptr) = size {
         *size_ptr = const_size;
     }
-    *ptr += 9;
-    memory.read_signed(*ptr, const_size as usize)
+    let value = memory.read_signed(*ptr, const_size as usize);
+    *ptr += const_size as u64;
+    value
 }
 
-/// Read a shift constant (6 bits)
+/// Read a shift constant: "1" for a shift of 1, else "0" and 6 bits
 pub fn disasm_shift(memory: &Memory, ptr: &mut u64) -> u32 {
+    let one = memory.read_bits(*ptr, 1);
+    *ptr += 1;
+    if one == 1 {
+        return 1;
+    }
     let shift = memory.read_bits(*ptr, 6);
     *ptr += 6;
     shift
 }
 
-/// Read a memory operation size (e.g., 1, 4, 8, 16, 32, or 64 bits)
+/// Read a memory operation size (1, 4, 8, 16, 32, or 64 bits)
 pub fn disasm_size(memory: &Memory, ptr: &mut u64) -> u32 {
-    let size = memory.read_bits(*ptr, 3);
-    *ptr += 3;
-    size
+    let code = memory.read_bits(*ptr, 2);
+    *ptr += 2;
+    if code < 2 {
+        return [1, 4][code as usize];
+    }
+    let low = memory.read_bits(*ptr, 1);
+    *ptr += 1;
+    [8, 16, 32, 64][((code - 2) * 2 + low) as usize]
 }
 
 /// Read a pointer id (2 bits)
@@ -268,4 +193,269 @@ pub fn disasm_pointer(memory: &Memory, ptr: &mut u64) -> u32 {
     let pointer = memory.read_bits(*ptr, 2);
     *ptr += 2;
     pointer
-}
\ No newline at end of file
+}
+
+/// Number of instructions that must decode in a row before an arbitrary bit
+/// offset is trusted as a resynchronization point
+pub const DISASM_RESYNC_CHAIN: usize = 4;
+
+
+/// Decode one argument of the given type as text
+fn disasm_arg(memory: &Memory, ptr: &mut u64, arg: ArgType) -> Option<String> {
+    let text = match arg {
+        ArgType::None => return None,
+        ArgType::Register => format!("r{}", disasm_reg(memory, ptr)),
+        ArgType::Direction => DIRECTIONS[disasm_dir(memory, ptr) as usize & 1].to_string(),
+        ArgType::Condition => CONDITIONS[disasm_cond(memory, ptr) as usize & 7].to_string(),
+        ArgType::Address => format!("{}", disasm_addr(memory, ptr, None)),
+        ArgType::LConst => format!("{}", disasm_lconst(memory, ptr, None)),
+        ArgType::AConst => format!("{}", disasm_aconst(memory, ptr, None)),
+        ArgType::Shift => format!("{}", disasm_shift(memory, ptr)),
+        ArgType::Size => format!("{}", disasm_size(memory, ptr)),
+        ArgType::Pointer => POINTERS[disasm_pointer(memory, ptr) as usize & 3].to_string(),
+    };
+    Some(text)
+}
+
+/// Decode a single instruction at *ptr, advancing it. Returns None (with
+/// *ptr unspecified) if the opcode is unknown
+pub fn disasm_one(memory: &Memory, ptr: &mut u64) -> Option<String> {
+    let (_, format) = disasm_opcode(memory, ptr);
+    let format = format?;
+
+    let mut text = format.mnemonic.to_string();
+    for arg in [format.arg1, format.arg2, format.arg3] {
+        if let Some(a) = disasm_arg(memory, ptr, arg) {
+            text.push(' ');
+            text.push_str(&a);
+        }
+    }
+    Some(text)
+}
+
+/// Check whether `count` instructions decode in a row from `start`
+fn disasm_chain(memory: &Memory, start: u64, end: u64, count: usize) -> bool {
+    let mut ptr = start;
+    for _ in 0..count {
+        if ptr >= end {
+            return true;
+        }
+        if disasm_one(memory, &mut ptr).is_none() || ptr > end {
+            return false;
+        }
+    }
+    true
+}
+
+/// Find the next address after an undecodable region where decoding can
+/// resume. Label boundaries are known instruction starts and are accepted
+/// directly; other bit offsets need a chain of valid instructions.
+pub fn disasm_resync(memory: &Memory, from: u64, end: u64, labels: &BTreeMap<u64, String>) -> u64 {
+    for offset in from..end {
+        if labels.contains_key(&offset) {
+            return offset;
+        }
+        if disasm_chain(memory, offset, end, DISASM_RESYNC_CHAIN) {
+            return offset;
+        }
+    }
+    end
+}
+
+/// Destination of the jump, jumpif or call at `ptr`; offsets are relative
+/// to the end of the instruction. Returns None for other instructions
+pub fn disasm_target(memory: &Memory, mut ptr: u64) -> Option<u64> {
+    let (opcode, _) = disasm_opcode(memory, &mut ptr);
+    if opcode == OP_JUMPIF {
+        disasm_cond(memory, &mut ptr);
+    } else if opcode != OP_JUMP && opcode != OP_CALL {
+        return None;
+    }
+    let offset = disasm_addr(memory, &mut ptr, None);
+    Some(ptr.wrapping_add(offset as u64))
+}
+
+/// Address the call at `ptr` returns to, the end of the instruction.
+/// Returns None for other instructions
+pub fn disasm_return_address(memory: &Memory, mut ptr: u64) -> Option<u64> {
+    let (opcode, _) = disasm_opcode(memory, &mut ptr);
+    if opcode != OP_CALL {
+        return None;
+    }
+    disasm_addr(memory, &mut ptr, None);
+    Some(ptr)
+}
+
+/// Name every branch target of [start, end) that has no label yet, as
+/// "L<address>"
+pub fn disasm_label_targets(memory: &Memory, start: u64, end: u64, labels: &mut BTreeMap<u64, String>) {
+    let mut ptr = start;
+
+    while ptr < end {
+        let mut next = ptr;
+        if disasm_one(memory, &mut next).is_none() || next > end {
+            ptr = disasm_resync(memory, ptr + 1, end, labels);
+            continue;
+        }
+        if let Some(target) = disasm_target(memory, ptr) {
+            if (start..end).contains(&target) {
+                labels.entry(target).or_insert_with(|| format!("L{:x}", target));
+            }
+        }
+        ptr = next;
+    }
+}
+
+/// One line of a listing: a decoded instruction, or an undecodable run of
+/// bits when `format` is None
+#[derive(Debug, Clone)]
+pub struct DisasmLine {
+    pub address: u64,
+    pub next: u64,  // Address of the following line
+    pub format: Option<DisasmFormat>,
+    pub text: String,
+}
+
+/// Decode [start, end) into lines. Undecodable regions become a single
+/// line and decoding resumes at the next plausible boundary.
+pub fn disasm_lines(memory: &Memory, start: u64, end: u64, labels: &BTreeMap<u64, String>) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut ptr = start;
+
+    while ptr < end {
+        let mut next = ptr;
+        let (_, format) = disasm_opcode(memory, &mut ptr.clone());
+        match disasm_one(memory, &mut next) {
+            Some(text) if next <= end => {
+                lines.push(DisasmLine { address: ptr, next, format, text });
+                ptr = next;
+            }
+            _ => {
+                let resume = disasm_resync(memory, ptr + 1, end, labels);
+                let text = format!("? ({} undecodable bits)", resume - ptr);
+                lines.push(DisasmLine { address: ptr, next: resume, format: None, text });
+                ptr = resume;
+            }
+        }
+    }
+
+    lines
+}
+
+/// Disassemble [start, end) into a listing. Undecodable regions are shown
+/// as "?" lines and decoding resumes at the next plausible boundary.
+pub fn disasm_listing(memory: &Memory, start: u64, end: u64, labels: &BTreeMap<u64, String>) -> Vec<String> {
+    let mut listing = Vec::new();
+
+    for line in disasm_lines(memory, start, end, labels) {
+        if let Some(label) = labels.get(&line.address) {
+            listing.push(format!("{}:", label));
+        }
+
+        let target = line.format.and_then(|_| disasm_target(memory, line.address));
+        match target {
+            Some(target) => {
+                let name = labels.get(&target).cloned()
+                    .unwrap_or_else(|| format!("{:08x}", target));
+                listing.push(format!("{:08x}    {:<24}; -> {}", line.address, line.text, name));
+            }
+            None if line.format.is_none() => listing.push(format!("{:08x}  {}", line.address, line.text)),
+            None => listing.push(format!("{:08x}    {}", line.address, line.text)),
+        }
+    }
+
+    listing
+}
+
+/// Load a label map file: one "<bit address> <label>" pair per line, with
+/// addresses in decimal or 0x-prefixed hexadecimal (see parse_symbols)
+pub fn disasm_load_map(filename: &str) -> io::Result<BTreeMap<u64, String>> {
+    let symbols = parse_symbols(&std::fs::read_to_string(filename)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(symbols.into_iter().map(|(name, address)| (address, name)).collect())
+}
+
+/// Name of an address after the closest label at or before it, as "loop"
+/// or "loop+17"; None before the first label
+pub fn disasm_symbol(labels: &BTreeMap<u64, String>, address: u64) -> Option<String> {
+    let (&base, name) = labels.range(..=address).next_back()?;
+    Some(match address - base {
+        0 => name.clone(),
+        offset => format!("{}+{}", name, offset),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disasm_default_encoding() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+        let fields: &[(u64, usize)] = &[
+            (0b0111, 4), (1, 3), (0b10, 2), (37, 8),    // leti r1 37
+            (0b1110010, 7), (0, 3), (1, 3), (2, 3),     // add3 r0 r1 r2
+            (0b1011, 4), (3, 3), (0, 1), (0xfe, 8),     // jumpif slt -2
+            (0b10011, 5), (2, 2), (0b101, 3), (4, 3),   // readse a0 16 r4
+            (0b11111110, 8), (0b00, 2), (5, 3),         // pop 1 r5
+        ];
+        let mut addr = 0;
+        for &(value, width) in fields {
+            mem.write(addr, value, width);
+            addr += width as u64;
+        }
+
+        let mut ptr = 0;
+        let expected = ["leti r1 37", "add3 r0 r1 r2", "jumpif slt -2", "readse a0 16 r4", "pop 1 r5"];
+        for text in expected {
+            assert_eq!(disasm_one(&mem, &mut ptr).as_deref(), Some(text));
+        }
+        assert_eq!(ptr, addr);
+    }
+
+    #[test]
+    fn test_disasm_agrees_with_core() {
+        use minimisa_core::codec::{arbitrary_instruction, encode_instruction, Insn};
+
+        // Random instructions from the reference encoder, back to back
+        let program: Vec<Insn> = (0..2000).map(|seed| arbitrary_instruction(seed ^ 0x5eed)).collect();
+        let mut mem = Memory::new(1 << 20, 1024, 1024, 1024);
+        let mut addr = 0;
+        for ins in &program {
+            let bits = encode_instruction(ins).unwrap();
+            for i in 0..bits.len() {
+                mem.write(addr + i, bits.get(i) as u64, 1);
+            }
+            addr += bits.len();
+        }
+
+        // The operand decoders the CPU uses, and the disassembler text
+        let mut ptr = 0;
+        for ins in &program {
+            let start = ptr;
+            let (opcode, format) = disasm_opcode(&mem, &mut ptr);
+            let format = format.unwrap();
+            let mut args = [0; 3];
+            for (arg, kind) in args.iter_mut().zip([format.arg1, format.arg2, format.arg3]) {
+                *arg = match kind {
+                    ArgType::None => break,
+                    ArgType::Register => disasm_reg(&mem, &mut ptr) as u64,
+                    ArgType::Direction => disasm_dir(&mem, &mut ptr) as u64,
+                    ArgType::Condition => disasm_cond(&mem, &mut ptr) as u64,
+                    ArgType::Address => disasm_addr(&mem, &mut ptr, None) as u64,
+                    ArgType::LConst => disasm_lconst(&mem, &mut ptr, None),
+                    ArgType::AConst => disasm_aconst(&mem, &mut ptr, None) as u64,
+                    ArgType::Shift => disasm_shift(&mem, &mut ptr) as u64,
+                    ArgType::Size => disasm_size(&mem, &mut ptr) as u64,
+                    ArgType::Pointer => disasm_pointer(&mem, &mut ptr) as u64,
+                };
+            }
+            assert_eq!(Insn { opcode, args }, *ins, "at bit {}", start);
+
+            let mut text_ptr = start;
+            assert_eq!(disasm_one(&mem, &mut text_ptr), Some(ins.to_string()));
+            assert_eq!(text_ptr, ptr);
+        }
+        assert_eq!(ptr, addr);
+    }
+}
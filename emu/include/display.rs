@@ -0,0 +1,178 @@
+//---
+// emu:display - where screens are shown
+//
+// A Screen is a display backend: it opens, shows frames of RGB pixels,
+// reports the events of its user and closes. The SDL window (sdl.rs, with
+// the sdl feature) is one; the others need nothing from the host, so that
+// headless servers can build and run screen programs:
+//
+//     tty   draws frames in the terminal with Unicode half blocks, two
+//           pixels per character cell, in 24-bit color
+//     none  shows nothing
+//
+// Screens are opened on the thread that drives them (SDL windows cannot
+// move between threads), so the backend is chosen with a Display and
+// opened there. This file only needs std: the emulator and subject/simu
+// both use it.
+//---
+
+use std::io::{self, Write};
+
+/// What the user of a screen did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenEvent {
+    Quit,
+    Key(u32, bool),  // SDL scancode, pressed or released
+}
+
+/// Fills buffers of audio samples at a sample rate, for screens that can
+/// play sound
+pub type AudioFill = Box<dyn FnMut(&mut [i16], u32) + Send>;
+
+pub trait Screen {
+    /// Open the display for frames of the given size
+    fn init(&mut self, width: usize, height: usize) -> Result<(), String>;
+    /// Show a frame, row-major
+    fn blit(&mut self, pixels: &[[u8; 3]]);
+    /// Events since the last call
+    fn poll_events(&mut self) -> Vec<ScreenEvent>;
+    fn shutdown(&mut self);
+}
+
+/// The display backends, see the top of this file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Display {
+    Sdl,
+    Tty,
+    None,
+}
+
+impl Display {
+    pub fn from_name(name: &str) -> Option<Display> {
+        match name {
+            "sdl" => Some(Display::Sdl),
+            "tty" => Some(Display::Tty),
+            "none" => Some(Display::None),
+            _ => None,
+        }
+    }
+
+    /// A screen of this backend, scaled `scale` times where pixels can be,
+    /// playing `audio` where sound can be played
+    pub fn open(self, scale: u32, audio: Option<AudioFill>) -> Result<Box<dyn Screen>, String> {
+        match self {
+            #[cfg(feature = "sdl")]
+            Display::Sdl => Ok(Box::new(crate::sdl::SdlScreen::new(scale, audio))),
+            #[cfg(not(feature = "sdl"))]
+            Display::Sdl => {
+                let _ = (scale, audio);
+                Err("built without SDL, use --display tty or none".to_string())
+            }
+            Display::Tty => Ok(Box::new(TtyScreen::new(io::stdout()))),
+            Display::None => Ok(Box::new(NullScreen)),
+        }
+    }
+}
+
+/// Shows nothing and has no user
+pub struct NullScreen;
+
+impl Screen for NullScreen {
+    fn init(&mut self, _width: usize, _height: usize) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn blit(&mut self, _pixels: &[[u8; 3]]) {}
+
+    fn poll_events(&mut self) -> Vec<ScreenEvent> {
+        Vec::new()
+    }
+
+    fn shutdown(&mut self) {}
+}
+
+// Terminal columns a frame may take; wider frames skip columns and rows
+const TTY_COLUMNS: usize = 80;
+
+/// Draws frames in a terminal. It reads no keys: the terminal is left in
+/// its usual mode, where keys only arrive with enter
+pub struct TtyScreen<W: Write> {
+    out: W,
+    width: usize,
+    step: usize,           // One pixel in `step` is drawn on both axes
+    last: Vec<[u8; 3]>,    // Frame on the terminal, to skip unchanged ones
+}
+
+impl<W: Write> TtyScreen<W> {
+    pub fn new(out: W) -> Self {
+        TtyScreen { out, width: 0, step: 1, last: Vec::new() }
+    }
+
+    fn draw(&mut self, pixels: &[[u8; 3]]) -> io::Result<()> {
+        let (width, step) = (self.width, self.step);
+        let height = pixels.len() / width.max(1);
+        let mut text = String::from("\x1b[H");
+        // Each cell is an upper half block: its foreground is the top
+        // pixel, its background the one below
+        for y in (0..height).step_by(2 * step) {
+            for x in (0..width).step_by(step) {
+                let [r, g, b] = pixels[y * width + x];
+                let [br, bg, bb] = pixels.get((y + step) * width + x).copied().unwrap_or([0, 0, 0]);
+                text += &format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}", r, g, b, br, bg, bb);
+            }
+            text += "\x1b[0m\r\n";
+        }
+        self.out.write_all(text.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Screen for TtyScreen<W> {
+    fn init(&mut self, width: usize, _height: usize) -> Result<(), String> {
+        self.width = width;
+        self.step = width.div_ceil(TTY_COLUMNS).max(1);
+        // Clear the terminal and hide the cursor
+        write!(self.out, "\x1b[2J\x1b[?25l").and_then(|_| self.out.flush()).map_err(|e| e.to_string())
+    }
+
+    fn blit(&mut self, pixels: &[[u8; 3]]) {
+        if pixels != self.last.as_slice() && self.draw(pixels).is_ok() {
+            self.last = pixels.to_vec();
+        }
+    }
+
+    fn poll_events(&mut self) -> Vec<ScreenEvent> {
+        Vec::new()
+    }
+
+    fn shutdown(&mut self) {
+        let _ = write!(self.out, "\x1b[0m\x1b[?25h");
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tty() {
+        let mut out = Vec::new();
+        {
+            let mut screen = TtyScreen::new(&mut out);
+            screen.init(2, 2).unwrap();
+            let frame = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [1, 2, 3]];
+            screen.blit(&frame);
+            screen.blit(&frame);
+            screen.shutdown();
+        }
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.matches('\u{2580}').count(), 2);
+        assert!(text.contains("\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}"));
+        assert!(text.contains("\x1b[38;2;0;255;0m\x1b[48;2;1;2;3m\u{2580}"));
+        assert!(text.ends_with("\x1b[?25h"));
+
+        assert_eq!(Display::from_name("tty"), Some(Display::Tty));
+        assert_eq!(Display::from_name("x11"), None);
+    }
+}
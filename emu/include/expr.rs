@@ -0,0 +1,253 @@
+//---
+// emu:expr - expressions of the debugger
+//
+// Values the debugger computes from the machine, such as those of display:
+//
+//     r0 .. r7            registers
+//     pc sp a0 a1         pointers, also written ptr[PC] .. ptr[A1]
+//     42 0x2a             numbers
+//     loop                the address of a symbol
+//     mem[e]              the 64-bit word at bit address e
+//     mem[e1..e2]         the bits from e1 up to e2, at most 64, as a number
+//     e1 + e2  e1 - e2    wrapping arithmetic on 64 bits, with parentheses
+//
+// Expressions are parsed once and evaluated against a Context, which the
+// debugger provides as of the point in time being looked at.
+//---
+
+use crate::cpu::{A0, A1, PC, SP};
+
+/// What expressions read: the machine, and the symbols of the program
+pub trait Context {
+    fn register(&self, n: usize) -> u64;
+    fn pointer(&self, n: usize) -> u64;
+    /// `width` bits of memory at a bit address, 1 to 64
+    fn read(&self, address: u64, width: usize) -> u64;
+    fn symbol(&self, name: &str) -> Option<u64>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(u64),
+    Register(usize),
+    Pointer(usize),
+    Symbol(String),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+    Word(Box<Expr>),                // mem[e]
+    Bits(Box<Expr>, Box<Expr>),     // mem[e1..e2]
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(u64),
+    Name(String),
+    Punct(&'static str),
+}
+
+const PUNCTUATION: [&str; 7] = ["..", "+", "-", "(", ")", "[", "]"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(&p) = PUNCTUATION.iter().find(|p| rest.starts_with(*p)) {
+            tokens.push(Token::Punct(p));
+            rest = &rest[p.len()..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let word = &rest[..end];
+            let number = match word.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => word.parse().ok(),
+            };
+            tokens.push(match number {
+                Some(n) => Token::Number(n),
+                None if c.is_ascii_digit() => return Err(format!("Invalid number '{}'.", word)),
+                None => Token::Name(word.to_string()),
+            });
+            rest = &rest[end..];
+        } else {
+            return Err(format!("Unexpected '{}'.", c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// Pointer of a name, in any case
+fn pointer(name: &str) -> Option<usize> {
+    match name.to_ascii_lowercase().as_str() {
+        "pc" => Some(PC),
+        "sp" => Some(SP),
+        "a0" => Some(A0),
+        "a1" => Some(A1),
+        _ => None,
+    }
+}
+
+// Recursive descent over the tokens
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Punct(p)) if *p == punct);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if self.eat(punct) { Ok(()) } else { Err(format!("Expected '{}'.", punct)) }
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            if self.eat("+") {
+                expr = Expr::Add(Box::new(expr), Box::new(self.term()?));
+            } else if self.eat("-") {
+                expr = Expr::Sub(Box::new(expr), Box::new(self.term()?));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Punct("-")) => Ok(Expr::Neg(Box::new(self.term()?))),
+            Some(Token::Punct("(")) => {
+                let expr = self.sum()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if name == "mem" => {
+                self.expect("[")?;
+                let start = self.sum()?;
+                let expr = if self.eat("..") {
+                    Expr::Bits(Box::new(start), Box::new(self.sum()?))
+                } else {
+                    Expr::Word(Box::new(start))
+                };
+                self.expect("]")?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if name == "ptr" => {
+                self.expect("[")?;
+                let n = match self.next() {
+                    Some(Token::Name(name)) => pointer(&name).ok_or_else(|| format!("No pointer named '{}'.", name))?,
+                    _ => return Err("Expected PC, SP, A0 or A1.".to_string()),
+                };
+                self.expect("]")?;
+                Ok(Expr::Pointer(n))
+            }
+            Some(Token::Name(name)) => {
+                let register = name.strip_prefix(['r', 'R']).and_then(|n| n.parse::<usize>().ok());
+                Ok(match (pointer(&name), register) {
+                    (Some(n), _) => Expr::Pointer(n),
+                    (None, Some(n)) if n < 8 => Expr::Register(n),
+                    _ => Expr::Symbol(name),
+                })
+            }
+            Some(Token::Punct(p)) => Err(format!("Unexpected '{}'.", p)),
+            None => Err("Unexpected end of expression.".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let expr = parser.sum()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(_) => Err("Unexpected text after the expression.".to_string()),
+        }
+    }
+
+    pub fn eval(&self, context: &dyn Context) -> Result<u64, String> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Register(n) => context.register(*n),
+            Expr::Pointer(n) => context.pointer(*n),
+            Expr::Symbol(name) => context.symbol(name).ok_or_else(|| format!("No symbol named '{}'.", name))?,
+            Expr::Add(a, b) => a.eval(context)?.wrapping_add(b.eval(context)?),
+            Expr::Sub(a, b) => a.eval(context)?.wrapping_sub(b.eval(context)?),
+            Expr::Neg(a) => a.eval(context)?.wrapping_neg(),
+            Expr::Word(address) => context.read(address.eval(context)?, 64),
+            Expr::Bits(start, end) => {
+                let (start, end) = (start.eval(context)?, end.eval(context)?);
+                match end.checked_sub(start) {
+                    Some(width @ 1..=64) => context.read(start, width as usize),
+                    _ => return Err(format!("Cannot read {:#x}..{:#x} (1 to 64 bits).", start, end)),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Machine;
+
+    impl Context for Machine {
+        fn register(&self, n: usize) -> u64 {
+            n as u64 * 10
+        }
+        fn pointer(&self, n: usize) -> u64 {
+            0x100 * (n as u64 + 1)
+        }
+        // Every bit is the parity of its address
+        fn read(&self, address: u64, width: usize) -> u64 {
+            (address..address + width as u64).fold(0, |v, a| v << 1 | (a & 1))
+        }
+        fn symbol(&self, name: &str) -> Option<u64> {
+            (name == "loop").then_some(0x40)
+        }
+    }
+
+    fn eval(text: &str) -> Result<u64, String> {
+        Expr::parse(text)?.eval(&Machine)
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(eval("r3"), Ok(30));
+        assert_eq!(eval("ptr[A0]"), eval("a0"));
+        assert_eq!(eval("SP"), Ok(0x100 * (SP as u64 + 1)));
+        assert_eq!(eval("loop + 0x10 - (r1 - 2)"), Ok(0x48));
+        assert_eq!(eval("-1"), Ok(u64::MAX));
+        assert_eq!(eval("mem[1..5]"), Ok(0b1010));
+        assert_eq!(eval("mem[sp..sp+64]"), eval("mem[sp]"));
+        assert_eq!(eval("mem[r1..r1+3]"), Ok(0b010));
+
+        assert!(eval("r8").unwrap_err().contains("No symbol named 'r8'"));
+        assert!(eval("mem[8..8]").is_err());
+        assert!(eval("mem[0..65]").is_err());
+        assert!(eval("ptr[r0]").is_err());
+        assert!(eval("r1 +").is_err());
+        assert!(eval("(r1").is_err());
+        assert!(eval("r1 r2").is_err());
+        assert!(eval("0x1g").is_err());
+        assert!(eval("r1 * 2").is_err());
+    }
+}
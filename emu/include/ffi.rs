@@ -0,0 +1,341 @@
+//---
+// emu:ffi - C interface to the emulator
+//
+// The emulator as a C library, for embedding in scripts (Python's ctypes,
+// grading harnesses) that would otherwise drive the binaries through
+// stdin and stdout. mini.h declares these functions. A machine is an
+// opaque pointer from mini_create(), freed with mini_destroy(); every
+// function taking one accepts NULL and then does nothing (or returns 0 or
+// MINI_ERROR). When a function fails, mini_error() tells why.
+//
+//     mini_t *m = mini_create(0, 0, 0, 0);
+//     if (mini_load(m, "prog.obj") < 0) puts(mini_error(m));
+//     mini_set_breakpoint(m, 0x40);
+//     while (mini_step(m, 1000) == MINI_RUNNING) {}
+//     printf("%llu\n", mini_read_reg(m, 0));
+//     mini_destroy(m);
+//---
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use crate::breaks::BreakpointManager;
+use crate::cpu::{CPU, PC, SP};
+use crate::memory::Memory;
+use minimisa_core::default_opcodes;
+
+// What mini_step() returns
+pub const MINI_RUNNING: c_int = 0;  // Executed every instruction asked for
+pub const MINI_HALTED: c_int = 1;   // The program halted or faulted
+pub const MINI_BREAK: c_int = 2;    // PC reached a breakpoint
+pub const MINI_ERROR: c_int = -1;   // No machine
+
+// Register numbers of mini_read_reg() and mini_write_reg() after r0..r7
+pub const MINI_PC: c_int = 8;
+pub const MINI_SP: c_int = 9;
+pub const MINI_A0: c_int = 10;
+pub const MINI_A1: c_int = 11;
+
+/// A machine, as seen from C
+pub struct Mini {
+    cpu: CPU,
+    memory: Arc<Mutex<Memory>>,
+    breaks: BreakpointManager,
+    error: CString,  // Why the last call failed, empty if it did not
+}
+
+impl Mini {
+    fn fail(&mut self, message: String) -> c_int {
+        self.error = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        MINI_ERROR
+    }
+
+    fn register(&mut self, reg: c_int) -> Option<&mut u64> {
+        match reg {
+            0..=7 => Some(&mut self.cpu.r[reg as usize]),
+            8..=11 => Some(&mut self.cpu.ptr[(reg - MINI_PC) as usize]),
+            _ => None,
+        }
+    }
+
+    // Start a program loaded from bytes: at its entry if it is an object,
+    // at 0 otherwise, with the stack at the start of the data segment
+    fn load(&mut self, bytes: &[u8]) -> c_int {
+        let loaded = self.memory.lock().unwrap().load_bytes(bytes);
+        let object = match loaded {
+            Ok(object) => object,
+            Err(e) => return self.fail(e.to_string()),
+        };
+        // Objects without a table were assembled with the default one
+        let codes = object.as_ref().and_then(|o| o.opcodes).unwrap_or_else(default_opcodes);
+        let installed = self.memory.lock().unwrap().set_opcodes(&codes);
+        if let Err(e) = installed {
+            return self.fail(e);
+        }
+        self.cpu = CPU::new(Arc::clone(&self.memory));
+        self.cpu.ptr[PC] = object.map_or(0, |o| o.entry);
+        self.cpu.ptr[SP] = self.memory.lock().unwrap().data_base();
+        self.error = CString::default();
+        0
+    }
+}
+
+/// Create a machine with the given segment sizes in bits, 0 for the
+/// defaults. Free it with mini_destroy()
+#[no_mangle]
+pub extern "C" fn mini_create(text: u64, stack: u64, data: u64, vram: u64) -> *mut Mini {
+    let memory = Arc::new(Mutex::new(Memory::new(text, stack, data, vram)));
+    let mini = Mini {
+        cpu: CPU::new(Arc::clone(&memory)),
+        memory,
+        breaks: BreakpointManager::new(),
+        error: CString::default(),
+    };
+    Box::into_raw(Box::new(mini))
+}
+
+/// Free a machine
+///
+/// # Safety
+/// `mini` is NULL or comes from mini_create() and was not freed yet
+#[no_mangle]
+pub unsafe extern "C" fn mini_destroy(mini: *mut Mini) {
+    if !mini.is_null() {
+        drop(Box::from_raw(mini));
+    }
+}
+
+/// Why the last call on a machine failed, as a string owned by the
+/// machine; empty if it did not
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_error(mini: *const Mini) -> *const c_char {
+    match mini.as_ref() {
+        Some(mini) => mini.error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Load a program file (object file or bare binary) and reset the CPU to
+/// start it. Returns 0, or MINI_ERROR
+///
+/// # Safety
+/// `mini` is NULL or a live machine, `filename` a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn mini_load(mini: *mut Mini, filename: *const c_char) -> c_int {
+    let Some(mini) = mini.as_mut() else { return MINI_ERROR };
+    if filename.is_null() {
+        return mini.fail("no file name".to_string());
+    }
+    let filename = CStr::from_ptr(filename).to_string_lossy();
+    match std::fs::read(&*filename) {
+        Ok(bytes) => mini.load(&bytes),
+        Err(e) => mini.fail(format!("{}: {}", filename, e)),
+    }
+}
+
+/// Load a program from memory, like mini_load()
+///
+/// # Safety
+/// `mini` is NULL or a live machine, `bytes` points to `len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn mini_load_bytes(mini: *mut Mini, bytes: *const u8, len: usize) -> c_int {
+    let Some(mini) = mini.as_mut() else { return MINI_ERROR };
+    if bytes.is_null() {
+        return mini.fail("no program".to_string());
+    }
+    mini.load(slice::from_raw_parts(bytes, len))
+}
+
+/// Execute up to `count` instructions. Stops early when the program halts
+/// or PC reaches a breakpoint; the first instruction always runs, so that
+/// stepping from a breakpoint makes progress
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_step(mini: *mut Mini, count: u64) -> c_int {
+    let Some(mini) = mini.as_mut() else { return MINI_ERROR };
+    for _ in 0..count {
+        mini.cpu.execute();
+        if mini.cpu.h {
+            return MINI_HALTED;
+        }
+        if mini.breaks.has(mini.cpu.ptr[PC]) {
+            return MINI_BREAK;
+        }
+    }
+    MINI_RUNNING
+}
+
+/// Whether the program has halted
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_halted(mini: *const Mini) -> c_int {
+    mini.as_ref().map_or(0, |mini| mini.cpu.h as c_int)
+}
+
+/// Instructions executed since the program was loaded
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_cycles(mini: *const Mini) -> u64 {
+    mini.as_ref().map_or(0, |mini| mini.cpu.cycles)
+}
+
+/// Value of r0..r7, or of a pointer with MINI_PC, MINI_SP, MINI_A0 and
+/// MINI_A1; 0 for other numbers
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_read_reg(mini: *mut Mini, reg: c_int) -> u64 {
+    mini.as_mut().and_then(|mini| mini.register(reg).copied()).unwrap_or(0)
+}
+
+/// Set a register, numbered as in mini_read_reg(). Returns 0, or
+/// MINI_ERROR for an unknown register
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_write_reg(mini: *mut Mini, reg: c_int, value: u64) -> c_int {
+    let Some(mini) = mini.as_mut() else { return MINI_ERROR };
+    match mini.register(reg) {
+        Some(slot) => {
+            *slot = value;
+            0
+        }
+        None => mini.fail(format!("no register {}", reg)),
+    }
+}
+
+/// Read `bits` bits (1 to 64) at a bit address, like the CPU does
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_read_mem(mini: *const Mini, address: u64, bits: c_int) -> u64 {
+    match mini.as_ref() {
+        Some(mini) if (1..=64).contains(&bits) => mini.memory.lock().unwrap().read(address, bits as usize),
+        _ => 0,
+    }
+}
+
+/// Write the low `bits` bits (1 to 64) of a value at a bit address
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_write_mem(mini: *mut Mini, address: u64, value: u64, bits: c_int) -> c_int {
+    let Some(mini) = mini.as_mut() else { return MINI_ERROR };
+    if !(1..=64).contains(&bits) {
+        return mini.fail(format!("cannot write {} bits", bits));
+    }
+    mini.memory.lock().unwrap().write(address, value, bits as usize);
+    0
+}
+
+/// Stop mini_step() when PC reaches an address
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_set_breakpoint(mini: *mut Mini, address: u64) {
+    if let Some(mini) = mini.as_mut() {
+        mini.breaks.add(address);
+    }
+}
+
+/// Remove a breakpoint. Returns 0, or MINI_ERROR if there was none
+///
+/// # Safety
+/// `mini` is NULL or a live machine
+#[no_mangle]
+pub unsafe extern "C" fn mini_clear_breakpoint(mini: *mut Mini, address: u64) -> c_int {
+    let Some(mini) = mini.as_mut() else { return MINI_ERROR };
+    match mini.breaks.remove(address) {
+        Ok(()) => 0,
+        Err(e) => mini.fail(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{OP_ADD2I, OP_SUB2I};
+    use crate::testing::{assemble, assemble_str};
+
+    #[test]
+    fn test_ffi() {
+        let program = assemble_str("leti r0 5\nadd2i r0 37\nwrite a1 64 r0\nend: jump end");
+        unsafe {
+            let mini = mini_create(0, 0, 0, 0);
+            assert_eq!(mini_load_bytes(mini, program.as_ptr(), program.len()), 0);
+            assert_eq!(mini_write_reg(mini, MINI_A1, 0x10000), 0);
+
+            // Stop on add2i, then run to the end
+            let add = {
+                let m = &*mini;
+                let mut ptr = m.cpu.ptr[PC];
+                crate::disasm::disasm_one(&m.memory.lock().unwrap(), &mut ptr);
+                ptr
+            };
+            mini_set_breakpoint(mini, add);
+            assert_eq!(mini_step(mini, 10), MINI_BREAK);
+            assert_eq!((mini_read_reg(mini, 0), mini_cycles(mini)), (5, 1));
+            assert_eq!(mini_clear_breakpoint(mini, add), 0);
+            assert_eq!(mini_step(mini, 1), MINI_RUNNING);
+            assert_eq!(mini_step(mini, 10), MINI_HALTED);
+            assert_eq!(mini_halted(mini), 1);
+            assert_eq!(mini_read_mem(mini, 0x10000, 64), 42);
+            assert_eq!(mini_read_reg(mini, MINI_A1), 0x10040);
+
+            // Errors are kept until the next successful load
+            assert_eq!(mini_clear_breakpoint(mini, add), MINI_ERROR);
+            assert_eq!(mini_write_reg(mini, 12, 0), MINI_ERROR);
+            assert_eq!(CStr::from_ptr(mini_error(mini)).to_str(), Ok("no register 12"));
+            let missing = CString::new("/nonexistent/prog.obj").unwrap();
+            assert_eq!(mini_load(mini, missing.as_ptr()), MINI_ERROR);
+            assert!(CStr::from_ptr(mini_error(mini)).to_str().unwrap().starts_with("/nonexistent/prog.obj: "));
+
+            mini_destroy(mini);
+            assert_eq!(mini_step(ptr::null_mut(), 1), MINI_ERROR);
+            assert!(mini_error(ptr::null()).is_null());
+        }
+    }
+
+    #[test]
+    fn test_ffi_opcode_tables() {
+        // The same program with a table that swaps add2i and sub2i, which
+        // makes it compute 5 - 37
+        let mut object = assemble("leti r0 5\nadd2i r0 37\nend: jump end").unwrap();
+        let plain = object.to_bytes();
+        let mut codes = default_opcodes();
+        codes.swap(OP_ADD2I as usize, OP_SUB2I as usize);
+        object.opcodes = Some(codes);
+        let swapped = object.to_bytes();
+
+        let run = |mini: *mut Mini, program: &[u8]| unsafe {
+            assert_eq!(mini_load_bytes(mini, program.as_ptr(), program.len()), 0);
+            assert_eq!(mini_step(mini, 10), MINI_HALTED);
+            mini_read_reg(mini, 0) as i64
+        };
+        unsafe {
+            let (mini, other) = (mini_create(0, 0, 0, 0), mini_create(0, 0, 0, 0));
+            // Each machine decodes with the table of its own program
+            assert_eq!(run(mini, &swapped), -32);
+            assert_eq!(run(other, &plain), 42);
+            // and goes back to the default table for objects without one
+            assert_eq!(run(mini, &plain), 42);
+            mini_destroy(mini);
+            mini_destroy(other);
+        }
+    }
+}
@@ -1,21 +1,52 @@
-extern crate sdl2;
-
-use sdl2::event::Event;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::rect::Rect;
-use std::sync::{Arc, Mutex, Condvar};
+//---
+// emu:graphical - the screen of a program
+//
+// Shows a screen on a display (see display.rs), about 60 times a second,
+// from a thread of its own: the VRAM segment of the emulator or a buffer
+// of RGB565 pixels. Key events of the display go to a keyboard device.
+//---
+
+use std::io;
+use std::sync::{mpsc, Arc, Mutex, Condvar};
 use std::thread;
 use std::time::Duration;
+use crate::devices::{Audio, Keyboard};
+use crate::display::{AudioFill, Display, ScreenEvent};
+use crate::memory::Memory;
+use crate::vram::{PixelFormat, ScreenFormat};
+use minimisa_core::image;
 
 type Callback = Box<dyn Fn(&[u8], &mut dyn std::any::Any) + Send + 'static>;
 
+/// What the window shows
+#[derive(Clone)]
+enum Source {
+    Bytes(Arc<Mutex<Vec<u8>>>),                // RGB565 pixels, two bytes each
+    Memory(Arc<Mutex<Memory>>, ScreenFormat),  // The VRAM segment of the emulator
+}
+
+impl Source {
+    // The pixels as 8-bit channels, row-major
+    fn render(&self) -> Vec<[u8; 3]> {
+        match self {
+            Source::Bytes(vram) => vram.lock().unwrap().chunks(2)
+                .map(|p| PixelFormat::Rgb565.to_rgb888(u16::from_le_bytes([p[0], p[1]])))
+                .collect(),
+            Source::Memory(memory, format) => format.render(&memory.lock().unwrap()),
+        }
+    }
+}
+
 pub struct Graphical {
     width: usize,
     height: usize,
-    vram: Arc<Mutex<Vec<u8>>>,  
+    source: Source,
     scale: i32,
-    callback: Option<Callback>,
+    callback: Option<Arc<Mutex<Callback>>>,
     funcarg: Arc<Mutex<dyn std::any::Any + Send>>,
+    keyboard: Option<Keyboard>,  // Fed with the key events of the window
+    audio: Option<Audio>,        // Played while the window is open
+    display: Display,
     stop_signal: Arc<(Mutex<bool>, Condvar)>, 
 }
 
@@ -31,44 +62,96 @@ impl Graphical {
         Graphical {
             width,
             height,
-            vram: Arc::new(Mutex::new(vram)),
+            source: Source::Bytes(Arc::new(Mutex::new(vram))),
             scale,
-            callback,
+            callback: callback.map(|cb| Arc::new(Mutex::new(cb))),
             funcarg,
+            keyboard: None,
+            audio: None,
+            display: Display::Sdl,
+            stop_signal: Arc::new((Mutex::new(false), Condvar::new())),
+        }
+    }
+
+    /// Show the VRAM segment of the emulator's memory, in the given format
+    pub fn from_memory(memory: Arc<Mutex<Memory>>, format: ScreenFormat, scale: i32) -> Self {
+        Graphical {
+            width: format.width,
+            height: format.height,
+            source: Source::Memory(memory, format),
+            scale,
+            callback: None,
+            funcarg: Arc::new(Mutex::new(())),
+            keyboard: None,
+            audio: None,
+            display: Display::Sdl,
             stop_signal: Arc::new((Mutex::new(false), Condvar::new())),
         }
     }
 
-    /// Start the SDL thread for the screen
+    /// Send the key events of the window to a keyboard device
+    pub fn with_keyboard(mut self, keyboard: Keyboard) -> Self {
+        self.keyboard = Some(keyboard);
+        self
+    }
+
+    /// Play an audio channel while the window is open
+    pub fn with_audio(mut self, audio: Audio) -> Self {
+        self.audio = Some(audio);
+        self
+    }
+
+    /// Show the screen on another display than an SDL window
+    pub fn with_display(mut self, display: Display) -> Self {
+        self.display = display;
+        self
+    }
+
+    /// Render the screen off-screen and save it as PNG or PPM (from the
+    /// file extension). This does not need a display
+    pub fn screenshot(&self, filename: &str) -> io::Result<()> {
+        image::save(filename, self.width, self.height, &self.source.render())
+    }
+
+    /// Start the thread of the screen, which opens it on the display
     pub fn start(&self) -> Result<(), String> {
-        let vram = Arc::clone(&self.vram);
+        let source = self.source.clone();
         let funcarg = Arc::clone(&self.funcarg);
-        let callback = self.callback.as_ref().map(|cb| Arc::new(Mutex::new(cb)));
+        let callback = self.callback.clone();
         let stop_signal = Arc::clone(&self.stop_signal);
+        let keyboard = self.keyboard.clone();
+        let audio: Option<AudioFill> = self.audio.clone()
+            .map(|audio| Box::new(move |out: &mut [i16], rate: u32| audio.fill(out, rate)) as AudioFill);
 
-        let (width, height, scale) = (self.width, self.height, self.scale);
+        let (width, height, scale, display) = (self.width, self.height, self.scale as u32, self.display);
+        let (opened, open_result) = mpsc::channel();
 
         thread::spawn(move || {
-            let sdl_context = sdl2::init().unwrap();
-            let video_subsystem = sdl_context.video().unwrap();
-
-            let window = video_subsystem
-                .window(
-                    "Graphical Window",
-                    (width * scale as usize) as u32,
-                    (height * scale as usize) as u32,
-                )
-                .position_centered()
-                .build()
-                .unwrap();
-
-            let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-            let texture_creator = canvas.texture_creator();
-            let mut texture = texture_creator
-                .create_texture_streaming(PixelFormatEnum::RGB565, width as u32, height as u32)
-                .unwrap();
-
-            let mut event_pump = sdl_context.event_pump().unwrap();
+            let screen = display.open(scale, audio)
+                .and_then(|mut screen| screen.init(width, height).map(|()| screen));
+            let mut screen = match screen {
+                Ok(screen) => {
+                    let _ = opened.send(Ok(()));
+                    screen
+                }
+                Err(e) => {
+                    *stop_signal.0.lock().unwrap() = true;
+                    let _ = opened.send(Err(e));
+                    return;
+                }
+            };
+
+            // Keys are recorded with the memory locked, between instructions
+            let key = |scancode: u32, pressed: bool| {
+                if let Some(keyboard) = &keyboard {
+                    let _memory = match &source {
+                        Source::Memory(memory, _) => Some(memory.lock().unwrap()),
+                        Source::Bytes(_) => None,
+                    };
+                    keyboard.key(scancode, pressed);
+                }
+            };
+            let mut held = Vec::new();  // Scancodes of the keys held down
 
             // Keep running until a stop signal is received
             let (lock, cvar) = &*stop_signal;
@@ -78,46 +161,41 @@ impl Graphical {
                     break 'running;
                 }
 
-                // Poll for SDL events
-                for event in event_pump.poll_iter() {
+                for event in screen.poll_events() {
                     match event {
-                        Event::Quit { .. } => break 'running,
-                        _ => {}
+                        ScreenEvent::Quit => break 'running,
+                        ScreenEvent::Key(scancode, pressed) => {
+                            held.retain(|&s| s != scancode as u8);
+                            if pressed {
+                                held.push(scancode as u8);
+                            }
+                            key(scancode, pressed);
+                        }
                     }
                 }
 
                 // Call the callback function at 60 Hz
                 if let Some(cb) = &callback {
-                    let keyboard_state = event_pump.keyboard_state().scancodes().collect::<Vec<_>>();
                     let mut funcarg_locked = funcarg.lock().unwrap();
-                    cb.lock().unwrap()(&keyboard_state, &mut *funcarg_locked);
+                    cb.lock().unwrap()(&held, &mut *funcarg_locked);
                 }
 
-                // Lock the video memory (vram) and update the texture with it
-                let vram_locked = vram.lock().unwrap();
-                texture
-                    .update(None, &vram_locked, (width * 2) as usize)
-                    .expect("Failed to update texture");
-
-                // Render the texture to the screen
-                canvas.clear();
-                canvas
-                    .copy(&texture, None, Some(Rect::new(0, 0, (width * scale) as i32, (height * scale) as i32)))
-                    .unwrap();
-                canvas.present();
+                screen.blit(&source.render());
 
                 // Sleep to maintain ~60Hz
                 thread::sleep(Duration::from_millis(16));
             }
 
-            // Clean up when the thread stops
+            // Clean up when the thread stops, also when the window is closed
+            screen.shutdown();
+            *lock.lock().unwrap() = true;
             cvar.notify_all();
         });
 
-        Ok(())
+        open_result.recv().unwrap_or_else(|_| Err("the screen thread stopped".to_string()))
     }
 
-    /// Send a refresh signal to the SDL thread (refreshes screen)
+    /// Send a refresh signal to the screen thread (refreshes screen)
     pub fn refresh(&self) {
         // In this case, the event loop already handles refreshing
     }
@@ -127,7 +205,7 @@ impl Graphical {
         // In this case, the event loop already handles refreshing
     }
 
-    /// Wait for the SDL thread to stop and clean up
+    /// Wait for the screen thread to stop and clean up
     pub fn wait(&self) {
         let (lock, cvar) = &*self.stop_signal;
         let mut stopped = lock.lock().unwrap();
@@ -136,9 +214,9 @@ impl Graphical {
         }
     }
 
-    /// Stop the SDL thread, send a quit event, and clean up
+    /// Stop the screen thread, which closes the screen
     pub fn stop(&self) {
-        // Send the stop signal to the SDL thread
+        // Send the stop signal to the screen thread
         let (lock, cvar) = &*self.stop_signal;
         let mut stop_flag = lock.lock().unwrap();
         *stop_flag = true;
@@ -0,0 +1,231 @@
+//---
+// emu:icache - decoded-instruction cache
+//
+// Instructions have variable lengths and are decoded bit by bit, which is
+// most of what executing one costs. The cache keeps what decode() makes
+// of the instruction at each bit address: its opcode, its operand fields
+// and its length. It watches RAM (see WriteObserver in memory.rs) and
+// forgets the instructions that a write overlaps, so self-modifying code
+// and debugger patches are seen, and it starts over when the opcode table
+// of the memory changes.
+//---
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, Weak};
+use crate::disasm::{disasm_aconst, disasm_addr, disasm_cond, disasm_dir, disasm_lconst, disasm_opcode,
+    disasm_pointer, disasm_reg, disasm_shift, disasm_size, ArgType};
+use crate::memory::{Memory, WriteObserver};
+
+/// An instruction as the CPU executes it. Operands are its fields in
+/// encoding order, 0 when there are fewer than three: registers, pointers,
+/// conditions and sizes as numbers, constants and offsets as 64-bit
+/// values (sign-extended for aconst and addresses)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub opcode: u32,
+    pub operands: [u64; 3],
+    pub len: u64,   // In bits
+}
+
+fn decode_arg(memory: &Memory, ptr: &mut u64, arg: ArgType) -> u64 {
+    match arg {
+        ArgType::None => 0,
+        ArgType::Register => disasm_reg(memory, ptr) as u64,
+        ArgType::Direction => disasm_dir(memory, ptr) as u64,
+        ArgType::Condition => disasm_cond(memory, ptr) as u64,
+        ArgType::Address => disasm_addr(memory, ptr, None) as u64,
+        ArgType::LConst => disasm_lconst(memory, ptr, None),
+        ArgType::AConst => disasm_aconst(memory, ptr, None) as u64,
+        ArgType::Shift => disasm_shift(memory, ptr) as u64,
+        ArgType::Size => disasm_size(memory, ptr) as u64,
+        ArgType::Pointer => disasm_pointer(memory, ptr) as u64,
+    }
+}
+
+/// Decode the instruction at `pc`. Unknown opcodes (DISASM_INS_COUNT) have
+/// no operands and the length of the bits that were read
+pub fn decode(memory: &Memory, pc: u64) -> Decoded {
+    let mut ptr = pc;
+    let (opcode, format) = disasm_opcode(memory, &mut ptr);
+    let mut operands = [0; 3];
+    if let Some(format) = format {
+        for (operand, arg) in operands.iter_mut().zip([format.arg1, format.arg2, format.arg3]) {
+            *operand = decode_arg(memory, &mut ptr, arg);
+        }
+    }
+    Decoded { opcode, operands, len: ptr.wrapping_sub(pc) }
+}
+
+// Decoded instructions by address, shared with the write observer
+#[derive(Debug, Default)]
+struct Entries {
+    map: BTreeMap<u64, Decoded>,
+    longest: u64,   // Longest instruction in the map, to find overlaps
+}
+
+impl Entries {
+    fn invalidate(&mut self, range: Range<u64>) {
+        if self.map.is_empty() {
+            return;
+        }
+        let start = range.start.saturating_sub(self.longest.saturating_sub(1));
+        let stale: Vec<u64> = self.map.range(start..range.end)
+            .filter(|(&pc, decoded)| pc.saturating_add(decoded.len) > range.start)
+            .map(|(&pc, _)| pc)
+            .collect();
+        for pc in stale {
+            self.map.remove(&pc);
+        }
+    }
+}
+
+// Drops overwritten instructions for as long as the cache exists
+struct Invalidator(Weak<Mutex<Entries>>);
+
+impl WriteObserver for Invalidator {
+    fn written(&mut self, range: Range<u64>) {
+        if let Some(entries) = self.0.upgrade() {
+            entries.lock().unwrap().invalidate(range);
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.0.strong_count() > 0
+    }
+}
+
+/// Decoded instructions of one CPU, with hit counts. It registers with
+/// the memory of the first lookup; a CPU given another memory needs a new
+/// cache
+#[derive(Debug, Default)]
+pub struct DecodeCache {
+    entries: Arc<Mutex<Entries>>,
+    attached: bool,
+    generation: u64,  // Of the opcode table the entries were decoded with
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache::default()
+    }
+
+    /// The instruction at `pc`, and whether it came from the cache
+    pub fn get(&mut self, memory: &mut Memory, pc: u64) -> (Decoded, bool) {
+        if !self.attached {
+            memory.add_observer(Box::new(Invalidator(Arc::downgrade(&self.entries))));
+            self.attached = true;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation != memory.opcode_generation() {
+            self.generation = memory.opcode_generation();
+            entries.map.clear();
+        }
+        if let Some(&decoded) = entries.map.get(&pc) {
+            self.hits += 1;
+            return (decoded, true);
+        }
+        let decoded = decode(memory, pc);
+        entries.longest = entries.longest.max(decoded.len);
+        entries.map.insert(pc, decoded);
+        self.misses += 1;
+        (decoded, false)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget every instruction, keeping the hit counts
+    pub fn clear(&mut self) {
+        self.entries.lock().unwrap().map.clear();
+    }
+
+    /// Percentage of lookups served from the cache, None before the first
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| 100.0 * self.hits as f64 / lookups as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minimisa_core::bitvec::BitVec;
+    use crate::disasm::{DISASM_MAX_OPCODE, OP_ADD2I, OP_JUMP, OP_SUB2I};
+
+    // Instructions of the default encoding, as bits
+    fn program(text: &str) -> BitVec {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut memory = Memory::new(1024, 1024, 1024, 1024);
+        // add2i r2 -1 (8-bit constant), jump -9 (8-bit offset)
+        memory.patch(0, &program("0001 010 10 11111111  1010 0 11110111"));
+        assert_eq!(decode(&memory, 0), Decoded { opcode: OP_ADD2I, operands: [2, u64::MAX, 0], len: 17 });
+        assert_eq!(decode(&memory, 17), Decoded { opcode: OP_JUMP, operands: [-9i64 as u64, 0, 0], len: 13 });
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut memory = Memory::new(1024, 1024, 1024, 1024);
+        memory.patch(0, &program("0001 010 10 11111111  1010 0 11110111"));
+        let mut cache = DecodeCache::new();
+        assert!(!cache.get(&mut memory, 0).1);
+        assert!(!cache.get(&mut memory, 17).1);
+        assert!(cache.get(&mut memory, 0).1);
+        assert_eq!((cache.hits, cache.misses, cache.len()), (1, 2, 2));
+
+        // Writes elsewhere keep both, a write to the last bit of the jump
+        // drops it only
+        memory.write(30, 1, 1);
+        memory.write(29, 0, 1);
+        assert_eq!(cache.len(), 1);
+        let (decoded, cached) = cache.get(&mut memory, 17);
+        assert_eq!((decoded.operands[0] as i64, cached), (-10, false));
+
+        // Patching the constant of add2i changes what executes
+        memory.patch(9, &program("00000001"));
+        assert_eq!(cache.get(&mut memory, 0), (Decoded { opcode: OP_ADD2I, operands: [2, 1, 0], len: 17 }, false));
+        assert_eq!(cache.hit_rate(), Some(20.0));
+
+        // A dropped cache stops observing
+        drop(cache);
+        memory.add_observer(Box::new(|_| {}));
+        memory.write(0, 0, 1);
+    }
+
+    #[test]
+    fn test_opcode_tables() {
+        // The same bits in two machines, one of which swaps the codes of
+        // add2i and sub2i
+        let mut memory = Memory::new(1024, 1024, 1024, 1024);
+        let mut other = Memory::new(1024, 1024, 1024, 1024);
+        memory.patch(0, &program("0001 010 10 11111111"));
+        other.patch(0, &program("0001 010 10 11111111"));
+        let mut codes = *other.opcodes();
+        codes.swap(OP_ADD2I as usize, OP_SUB2I as usize);
+        other.set_opcodes(&codes).unwrap();
+        assert_eq!(decode(&memory, 0).opcode, OP_ADD2I);
+        assert_eq!(decode(&other, 0).opcode, OP_SUB2I);
+
+        // Decoded instructions are dropped when the table changes
+        let mut cache = DecodeCache::new();
+        assert_eq!(cache.get(&mut memory, 0).0.opcode, OP_ADD2I);
+        memory.set_opcodes(&codes).unwrap();
+        assert_eq!(cache.get(&mut memory, 0), (decode(&other, 0), false));
+
+        // Codes too long for the decoder are refused
+        codes[OP_ADD2I as usize] = (0, DISASM_MAX_OPCODE + 1);
+        assert!(memory.set_opcodes(&codes).is_err());
+        assert_eq!(memory.opcodes(), other.opcodes());
+    }
+}
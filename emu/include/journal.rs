@@ -0,0 +1,171 @@
+//---
+// emu:journal - execution journal for looking back in time
+//
+// Every executed instruction leaves a record of the CPU state before it
+// and of the memory it overwrote, which is enough to look at past states
+// and to undo instructions. The journal is a bounded ring buffer: the
+// oldest records are dropped when it is full.
+//---
+
+use std::collections::VecDeque;
+use crate::cpu::CallFrame;
+use crate::memory::{Memory, WriteRecord};
+
+pub const JOURNAL_DEFAULT_CAPACITY: usize = 10000;
+
+/// Architectural state of the CPU: registers, pointers and flags
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuState {
+    pub r: [u64; 8],
+    pub ptr: [u64; 4],
+    pub z: bool,
+    pub n: bool,
+    pub c: bool,
+    pub v: bool,
+}
+
+impl CpuState {
+    /// Register display, as shown in the debugger
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for (i, r) in self.r.iter().enumerate() {
+            out.push_str(&format!("r{}  {:016x}\n", i, r));
+        }
+        out.push_str(&format!("pc  {:016x}\nsp  {:016x}\n", self.ptr[0], self.ptr[1]));
+        out.push_str(&format!("a0  {:016x}\na1  {:016x}\n", self.ptr[2], self.ptr[3]));
+        out.push_str(&format!("z{} n{} c{} v{}\n", self.z as u8, self.n as u8, self.c as u8, self.v as u8));
+        out
+    }
+}
+
+/// What is needed to undo one instruction
+#[derive(Debug, Clone)]
+pub struct StepRecord {
+    pub state: CpuState,           // CPU state before the instruction
+    pub writes: Vec<WriteRecord>,  // Memory overwritten, in write order
+    pub timer_counter: u64,        // Timer and interrupt state before it
+    pub in_interrupt: bool,
+    pub pending_irqs: u8,
+    pub call_depth: usize,         // Shadow call depth before it
+    pub returned: Option<CallFrame>,  // Frame it returned from, if it was a return
+    pub clock: u64,                // Simulated time before it
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    records: VecDeque<StepRecord>,
+    capacity: usize,
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Journal {
+        Journal { records: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Number of steps that can be looked back
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn push(&mut self, record: StepRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Remove the most recent record, to undo it
+    pub fn pop(&mut self) -> Option<StepRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// CPU state as of `steps` steps ago, None for the live state or when
+    /// the journal does not go back that far
+    pub fn state(&self, steps: usize) -> Option<&CpuState> {
+        if steps == 0 || steps > self.records.len() {
+            return None;
+        }
+        Some(&self.records[self.records.len() - steps].state)
+    }
+
+    /// Shadow call stack as of `steps` steps ago, given the live one
+    pub fn calls_past(&self, calls: &[CallFrame], steps: usize) -> Vec<CallFrame> {
+        let mut calls = calls.to_vec();
+        for record in self.records.iter().rev().take(steps) {
+            calls.truncate(record.call_depth);
+            calls.extend(record.returned);
+        }
+        calls
+    }
+
+    /// Read `n` bits of memory as they were `steps` steps ago. The oldest
+    /// write of the window that covers a bit holds its value back then.
+    pub fn read_past(&self, memory: &Memory, steps: usize, address: u64, n: usize) -> u64 {
+        let steps = steps.min(self.records.len());
+        let window: Vec<&WriteRecord> = self.records.iter()
+            .skip(self.records.len() - steps)
+            .flat_map(|r| r.writes.iter())
+            .collect();
+
+        let mut value = 0;
+        for a in address..address + n as u64 {
+            let bit = window.iter()
+                .find(|w| a >= w.address && a < w.address + w.n as u64)
+                .map(|w| (w.old >> (w.n as u64 - 1 - (a - w.address))) & 1)
+                .unwrap_or_else(|| memory.read(a, 1));
+            value = (value << 1) | bit;
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(r0: u64) -> CpuState {
+        CpuState { r: [r0, 0, 0, 0, 0, 0, 0, 0], ptr: [0; 4], z: false, n: false, c: false, v: false }
+    }
+
+    #[test]
+    fn test_read_past() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+        let mut journal = Journal::new(2);
+
+        // Three steps writing 0x11, 0x22 then 0x33 over the same byte
+        for (i, value) in [0x11, 0x22, 0x33].into_iter().enumerate() {
+            mem.start_write_log();
+            mem.write(64, value, 8);
+            let writes = mem.take_write_log();
+            journal.push(StepRecord { state: state(i as u64), writes, timer_counter: 0, in_interrupt: false,
+                pending_irqs: 0, call_depth: 0, returned: None, clock: 0 });
+        }
+
+        // The first step fell out of the journal
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal.state(1).map(|s| s.r[0]), Some(2));
+        assert_eq!(journal.state(2).map(|s| s.r[0]), Some(1));
+        assert_eq!(journal.state(3), None);
+
+        assert_eq!(journal.read_past(&mem, 0, 64, 8), 0x33);
+        assert_eq!(journal.read_past(&mem, 1, 64, 8), 0x22);
+        assert_eq!(journal.read_past(&mem, 2, 64, 8), 0x11);
+        assert_eq!(journal.read_past(&mem, 2, 60, 8), 0x01);
+        assert_eq!(mem.read(64, 8), 0x33);
+    }
+}
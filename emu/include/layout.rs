@@ -0,0 +1,187 @@
+//---
+// emu:layout - panel geometry of the ncurses debugger
+//
+// The debugger screen is a column of rows of panels, computed from the
+// terminal size when the debugger starts and whenever the terminal is
+// resized. Panels come in the order of the layout, two per row on
+// terminals wide enough for it and one per row otherwise; the command line
+// is a full-width strip at the bottom, or at the top when it comes first.
+// Panels left out of the layout are hidden, and those that do not fit in
+// the height of the terminal too.
+//
+//     layout code regs mem frame cli     the default
+//     layout cli code regs               no memory or frame panel
+//---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Panel {
+    Code,
+    Regs,
+    Mem,
+    Frame,
+    Cli,
+}
+
+impl Panel {
+    pub const ALL: [Panel; 5] = [Panel::Code, Panel::Regs, Panel::Mem, Panel::Frame, Panel::Cli];
+
+    pub fn from_name(name: &str) -> Option<Panel> {
+        match name {
+            "code" => Some(Panel::Code),
+            "regs" | "registers" => Some(Panel::Regs),
+            "mem" | "memory" => Some(Panel::Mem),
+            "frame" | "stack" => Some(Panel::Frame),
+            "cli" => Some(Panel::Cli),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Panel::Code => "code",
+            Panel::Regs => "regs",
+            Panel::Mem => "mem",
+            Panel::Frame => "frame",
+            Panel::Cli => "cli",
+        }
+    }
+}
+
+/// Position and size of a panel, in terminal cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub y: i32,
+    pub x: i32,
+    pub height: i32,
+    pub width: i32,
+}
+
+// Rows of the command line strip
+const CLI_HEIGHT: i32 = 5;
+
+// Smallest panel height worth showing: a line between two border rows
+const MIN_HEIGHT: i32 = 3;
+
+// Width of the panels next to the code panel; two panels share a row
+// when both get at least this much
+const SIDE_WIDTH: i32 = 30;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    panels: Vec<Panel>,  // Shown panels in order, the command line included
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout { panels: Panel::ALL.to_vec() }
+    }
+}
+
+impl Layout {
+    /// Layout of panel names. The command line is added at the end when
+    /// it is not given, since commands are read there
+    pub fn parse(names: &[&str]) -> Result<Layout, String> {
+        let mut panels = Vec::new();
+        for name in names {
+            let panel = Panel::from_name(name).ok_or_else(|| format!("No panel named '{}'.", name))?;
+            if panels.contains(&panel) {
+                return Err(format!("Panel '{}' is given twice.", name));
+            }
+            panels.push(panel);
+        }
+        if !panels.contains(&Panel::Cli) {
+            panels.push(Panel::Cli);
+        }
+        Ok(Layout { panels })
+    }
+
+    pub fn panels(&self) -> &[Panel] {
+        &self.panels
+    }
+
+    /// The layout as the command that sets it takes it
+    pub fn names(&self) -> String {
+        self.panels.iter().map(|p| p.name()).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Geometry of the panels that fit on a terminal of the given size, in
+    /// layout order. The command line always gets a strip, even on tiny
+    /// terminals
+    pub fn geometry(&self, height: i32, width: i32) -> Vec<(Panel, Rect)> {
+        let (height, width) = (height.max(1), width.max(1));
+        let cli_height = CLI_HEIGHT.min(height);
+        let body = height - cli_height;
+        let cli_top = self.panels.first() == Some(&Panel::Cli);
+        let top = if cli_top { cli_height } else { 0 };
+
+        let others: Vec<Panel> = self.panels.iter().copied().filter(|&p| p != Panel::Cli).collect();
+        let per_row = if width >= 2 * SIDE_WIDTH { 2 } else { 1 };
+        let rows: Vec<&[Panel]> = others.chunks(per_row).take((body / MIN_HEIGHT) as usize).collect();
+
+        let mut geometry = Vec::new();
+        if cli_top {
+            geometry.push((Panel::Cli, Rect { y: 0, x: 0, height: cli_height, width }));
+        }
+        let count = rows.len().max(1) as i32;
+        for (i, row) in rows.iter().enumerate() {
+            let y = top + body * i as i32 / count;
+            let h = top + body * (i as i32 + 1) / count - y;
+            // The code panel takes what the side panel leaves
+            let left = match row {
+                [Panel::Code, _] => width - SIDE_WIDTH,
+                [_, Panel::Code] => SIDE_WIDTH,
+                _ => width / 2,
+            };
+            match row {
+                [panel] => geometry.push((*panel, Rect { y, x: 0, height: h, width })),
+                [a, b] => {
+                    geometry.push((*a, Rect { y, x: 0, height: h, width: left }));
+                    geometry.push((*b, Rect { y, x: left, height: h, width: width - left }));
+                }
+                _ => unreachable!(),
+            }
+        }
+        if !cli_top {
+            geometry.push((Panel::Cli, Rect { y: body, x: 0, height: cli_height, width }));
+        }
+        geometry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(geometry: &[(Panel, Rect)], panel: Panel) -> Option<Rect> {
+        geometry.iter().find(|(p, _)| *p == panel).map(|&(_, r)| r)
+    }
+
+    #[test]
+    fn test_geometry() {
+        // The classic 80x25 screen
+        let geometry = Layout::default().geometry(25, 80);
+        assert_eq!(find(&geometry, Panel::Code), Some(Rect { y: 0, x: 0, height: 10, width: 50 }));
+        assert_eq!(find(&geometry, Panel::Regs), Some(Rect { y: 0, x: 50, height: 10, width: 30 }));
+        assert_eq!(find(&geometry, Panel::Frame), Some(Rect { y: 10, x: 40, height: 10, width: 40 }));
+        assert_eq!(find(&geometry, Panel::Cli), Some(Rect { y: 20, x: 0, height: 5, width: 80 }));
+
+        // Narrow terminals stack panels, short ones drop the last ones
+        let geometry = Layout::default().geometry(20, 40);
+        assert_eq!(find(&geometry, Panel::Code), Some(Rect { y: 0, x: 0, height: 3, width: 40 }));
+        assert_eq!(find(&geometry, Panel::Frame), Some(Rect { y: 11, x: 0, height: 4, width: 40 }));
+        let geometry = Layout::default().geometry(12, 40);
+        assert_eq!(geometry.iter().map(|(p, _)| p.name()).collect::<Vec<_>>(), ["code", "regs", "cli"]);
+        assert_eq!(Layout::default().geometry(2, 10), [(Panel::Cli, Rect { y: 0, x: 0, height: 2, width: 10 })]);
+
+        // Reordered and hidden panels
+        let layout = Layout::parse(&["cli", "regs", "code"]).unwrap();
+        assert_eq!(layout.names(), "cli regs code");
+        let geometry = layout.geometry(25, 100);
+        assert_eq!(find(&geometry, Panel::Cli), Some(Rect { y: 0, x: 0, height: 5, width: 100 }));
+        assert_eq!(find(&geometry, Panel::Code), Some(Rect { y: 5, x: 30, height: 20, width: 70 }));
+        assert_eq!(find(&geometry, Panel::Mem), None);
+        assert_eq!(Layout::parse(&["code", "mem"]).unwrap().names(), "code mem cli");
+        assert!(Layout::parse(&["code", "code"]).is_err());
+        assert!(Layout::parse(&["disasm"]).is_err());
+    }
+}
@@ -2,12 +2,26 @@
 // emu:memory - emulate a random-access bit-addressable memory
 //
 // This module provides routines for manipulating the bit-addressable
-// memory used by the fictional CPU. 
+// memory used by the fictional CPU. RAM is sparse: pages are allocated
+// when first written, so segments can span a large address space.
 //---
 
+use std::cell::Cell;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Read};
+use std::ops::Range;
 use std::path::Path;
+use minimisa_core::bitvec::BitVec;
+use minimisa_core::object::Object;
+use minimisa_core::pages::Pages;
+use minimisa_core::{default_opcodes, Opcodes};
+use serde_json::{json, Value};
+use crate::disasm::disasm_check_opcodes;
+use crate::util::sign_extend;
+
+// Bits shown on a line of Memory::dump()
+pub const DUMP_LINE_BITS: u64 = 32;
 
 // Default memory geometry
 const MEMORY_DEFAULT_TEXT: u64 = 32 << 10;
@@ -15,6 +29,202 @@ const MEMORY_DEFAULT_STACK: u64 = 16 << 10;
 const MEMORY_DEFAULT_DATA: u64 = 16 << 10;
 const MEMORY_DEFAULT_VRAM: u64 = 327680;
 
+// I/O window holding device registers, at the end of the data segment
+pub const MEMORY_IO_SIZE: u64 = 1024;
+
+// Interrupt vector table: 64-bit handler addresses at the start of data
+pub const MEMORY_VECTOR_COUNT: usize = 8;
+
+/// Memory segments, in address order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Text,
+    Stack,
+    Data,
+    Vram,
+    Outside,
+}
+
+impl Segment {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::Text => "text",
+            Segment::Stack => "stack",
+            Segment::Data => "data",
+            Segment::Vram => "vram",
+            Segment::Outside => "outside memory",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Segment> {
+        match name {
+            "text" => Some(Segment::Text),
+            "stack" => Some(Segment::Stack),
+            "data" => Some(Segment::Data),
+            "vram" => Some(Segment::Vram),
+            _ => None,
+        }
+    }
+}
+
+/// Access permissions of a segment, as R/W/X bits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Perm(pub u8);
+
+impl Perm {
+    pub const R: u8 = 4;
+    pub const W: u8 = 2;
+    pub const X: u8 = 1;
+
+    /// Parse "rwx"-style strings, "-" standing for a missing permission
+    pub fn parse(s: &str) -> Option<Perm> {
+        let mut bits = 0;
+        for c in s.chars() {
+            bits |= match c {
+                'r' => Perm::R,
+                'w' => Perm::W,
+                'x' => Perm::X,
+                '-' => 0,
+                _ => return None,
+            };
+        }
+        Some(Perm(bits))
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        self.0 & access.bit() != 0
+    }
+}
+
+impl fmt::Display for Perm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |bit, c| if self.0 & bit != 0 { c } else { '-' };
+        write!(f, "{}{}{}", flag(Perm::R, 'r'), flag(Perm::W, 'w'), flag(Perm::X, 'x'))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn bit(&self) -> u8 {
+        match self {
+            Access::Read => Perm::R,
+            Access::Write => Perm::W,
+            Access::Execute => Perm::X,
+        }
+    }
+}
+
+/// Why an access failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViolationKind {
+    Denied,         // Not allowed by the segment permissions
+    OutOfRange,     // Past the end of memory
+    StackOverflow,  // A write below the stack limit
+    StackUnderflow, // A stack access above the top of the stack
+    TextWrite,      // A write to text, see TextWrites
+}
+
+/// An access that was not performed: denied by the segment permissions,
+/// out of memory, or outside the stack. Reads that fail return 0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Violation {
+    pub address: u64,
+    pub access: Access,
+    pub segment: Segment,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.access {
+            Access::Read => "read from",
+            Access::Write => "write to",
+            Access::Execute => "execution in",
+        };
+        match self.kind {
+            ViolationKind::Denied => write!(f, "{} {} segment at {:#x}", what, self.segment.name(), self.address),
+            ViolationKind::OutOfRange => write!(f, "{} {:#x}, past the end of memory", what, self.address),
+            ViolationKind::StackOverflow => write!(f, "stack overflow: {} {:#x}, below the stack limit",
+                what, self.address),
+            ViolationKind::StackUnderflow => write!(f, "stack underflow: {} {:#x}, above the top of the stack",
+                what, self.address),
+            ViolationKind::TextWrite => write!(f, "self-modifying code: {} text segment at {:#x}", what, self.address),
+        }
+    }
+}
+
+/// A memory-mapped device. Offsets are relative to the start of the range
+/// the device was registered at.
+pub trait MmioHandler: Send {
+    fn read(&self, offset: u64, n: usize) -> u64;
+    fn write(&mut self, offset: u64, value: u64, n: usize);
+}
+
+/// What to do with writes to the text segment that the permissions allow,
+/// as in self-modifying code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextWrites {
+    #[default]
+    Allow,  // Write silently
+    Warn,   // Write and report a TextWrite violation
+    Trap,   // Drop the write and report a TextWrite violation
+}
+
+impl TextWrites {
+    pub fn from_name(name: &str) -> Option<TextWrites> {
+        match name {
+            "allow" => Some(TextWrites::Allow),
+            "warn" => Some(TextWrites::Warn),
+            "trap" => Some(TextWrites::Trap),
+            _ => None,
+        }
+    }
+}
+
+/// Told about every change to RAM, whether by a program, the loader or the
+/// debugger, so that what is derived from memory (decoded instructions,
+/// say) can be invalidated. Closures taking the changed bit range are
+/// observers too. Devices are not RAM: their writes are not observed
+pub trait WriteObserver: Send {
+    fn written(&mut self, range: Range<u64>);
+
+    // Observers that are no longer alive are dropped
+    fn is_alive(&self) -> bool {
+        true
+    }
+}
+
+impl<F: FnMut(Range<u64>) + Send> WriteObserver for F {
+    fn written(&mut self, range: Range<u64>) {
+        self(range)
+    }
+}
+
+struct Observers(Vec<Box<dyn WriteObserver>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+struct MmioRegion {
+    range: Range<u64>,
+    handler: Box<dyn MmioHandler>,
+}
+
+impl fmt::Debug for MmioRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MmioRegion({:#x}..{:#x})", self.range.start, self.range.end)
+    }
+}
+
 #[derive(Debug)]
 pub struct Memory {
     memsize: u64,   // Total memory size
@@ -22,42 +232,347 @@ pub struct Memory {
     stack: u64,     // Bottom stack address
     data: u64,      // Address of the data segment
     vram: u64,      // Address of the VRAM segment
-    mem: Vec<u64>,  // Actual chunk of data
+    mem: Pages,     // Actual data, allocated on first write
+    mmio: Vec<MmioRegion>,  // Devices, checked before RAM on every access
+    write_log: Option<Vec<WriteRecord>>,  // Overwritten RAM, when logging
+    observers: Observers,
+
+    // Segment permissions, checked only when protection is enabled. Denied
+    // writes are dropped; the first denied access is kept for the CPU
+    perms: [Perm; 4],  // Text, stack, data, VRAM
+    protect: bool,
+    violation: Cell<Option<Violation>>,
+    stack_limit: Option<u64>,  // Lowest address the stack may be written at
+    text_writes: TextWrites,
+
+    // Opcode table the program is decoded with, and the number of times it
+    // was changed, so that decoded instructions can tell they are out of date
+    opcodes: Opcodes,
+    opcode_generation: u64,
+}
+
+/// A RAM write as seen by the write log: n bits at address, which held
+/// `old` before the write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteRecord {
+    pub address: u64,
+    pub old: u64,
+    pub n: usize,
 }
 
 impl Memory {
     pub fn new(text: u64, stack: u64, data: u64, vram: u64) -> Memory {
+        let text = if text != 0 { text } else { MEMORY_DEFAULT_TEXT };
+        let stack = if stack != 0 { stack } else { MEMORY_DEFAULT_STACK };
+        let data = if data != 0 { data } else { MEMORY_DEFAULT_DATA };
+        let vram = if vram != 0 { vram } else { MEMORY_DEFAULT_VRAM };
+
         let memsize = text + stack + data + vram;
-        let mem = vec![0u64; (memsize as usize) / 64]; 
+        let mem = Pages::new();
 
         Memory {
             memsize,
-            text: if text != 0 { text } else { MEMORY_DEFAULT_TEXT },
-            stack: if stack != 0 { stack } else { MEMORY_DEFAULT_STACK },
-            data: if data != 0 { data } else { MEMORY_DEFAULT_DATA },
-            vram: if vram != 0 { vram } else { MEMORY_DEFAULT_VRAM },
+            text,
+            stack,
+            data,
+            vram,
             mem,
+            mmio: Vec::new(),
+            write_log: None,
+            observers: Observers(Vec::new()),
+            perms: [Perm(Perm::R | Perm::X), Perm(Perm::R | Perm::W), Perm(Perm::R | Perm::W), Perm(Perm::R | Perm::W)],
+            protect: false,
+            violation: Cell::new(None),
+            stack_limit: None,
+            text_writes: TextWrites::Allow,
+            opcodes: default_opcodes(),
+            opcode_generation: 0,
+        }
+    }
+
+    /// Opcode table the program in memory is decoded with, the default one
+    /// unless set_opcodes() was called
+    pub fn opcodes(&self) -> &Opcodes {
+        &self.opcodes
+    }
+
+    pub fn opcode_generation(&self) -> u64 {
+        self.opcode_generation
+    }
+
+    /// Decode the program with another opcode table, such as the one
+    /// carried by an object file
+    pub fn set_opcodes(&mut self, codes: &Opcodes) -> Result<(), String> {
+        disasm_check_opcodes(codes)?;
+        if self.opcodes != *codes {
+            self.opcodes = *codes;
+            self.opcode_generation += 1;
+        }
+        Ok(())
+    }
+
+    // Permissions of a segment; addresses outside memory have none
+    pub fn permissions(&self, segment: Segment) -> Perm {
+        match segment {
+            Segment::Text => self.perms[0],
+            Segment::Stack => self.perms[1],
+            Segment::Data => self.perms[2],
+            Segment::Vram => self.perms[3],
+            Segment::Outside => Perm(0),
+        }
+    }
+
+    pub fn set_permissions(&mut self, segment: Segment, perm: Perm) {
+        match segment {
+            Segment::Text => self.perms[0] = perm,
+            Segment::Stack => self.perms[1] = perm,
+            Segment::Data => self.perms[2] = perm,
+            Segment::Vram => self.perms[3] = perm,
+            Segment::Outside => {}
+        }
+    }
+
+    // Enable or disable permission checks; loading is usually done before
+    pub fn set_protection(&mut self, protect: bool) {
+        self.protect = protect;
+    }
+
+    // Limit the stack to `size` bits below the top of the stack segment.
+    // Writes to the stack segment below the limit are stack overflows
+    pub fn set_stack_limit(&mut self, size: Option<u64>) {
+        self.stack_limit = size.map(|size| self.data_base().saturating_sub(size).max(self.text));
+    }
+
+    pub fn text_writes(&self) -> TextWrites {
+        self.text_writes
+    }
+
+    pub fn set_text_writes(&mut self, mode: TextWrites) {
+        self.text_writes = mode;
+    }
+
+    // Whether an access to an address is allowed by its segment
+    pub fn permits(&self, address: u64, access: Access) -> bool {
+        self.permissions(self.segment(address)).allows(access)
+    }
+
+    // Return and clear the first denied access since the last call
+    pub fn take_violation(&self) -> Option<Violation> {
+        self.violation.take()
+    }
+
+    // Record the first failed access since the last take_violation()
+    fn fault(&self, address: u64, access: Access, kind: ViolationKind) {
+        if self.violation.get().is_none() {
+            self.violation.set(Some(Violation { address, access, segment: self.segment(address), kind }));
+        }
+    }
+
+    // Check an access of n bits against the stack limit and, when
+    // protection is on, the permissions of the segments of its first and
+    // last bits; true if it is allowed. Instructions are fetched with
+    // ordinary reads, so executable segments are readable
+    fn check_access(&self, address: u64, n: usize, access: Access) -> bool {
+        if access == Access::Write && self.segment(address) == Segment::Stack
+            && self.stack_limit.is_some_and(|limit| address < limit) {
+            self.fault(address, access, ViolationKind::StackOverflow);
+            return false;
+        }
+        let allowed = |a| self.permits(a, access) || (access == Access::Read && self.permits(a, Access::Execute));
+        let last = address.saturating_add(n.max(1) as u64 - 1);
+        if !self.protect || (allowed(address) && allowed(last)) {
+            return true;
+        }
+        self.fault(if allowed(address) { last } else { address }, access, ViolationKind::Denied);
+        false
+    }
+
+    // Check an access of n bits by push, pop, call or return: it must be
+    // between the stack limit (the bottom of the stack segment if none is
+    // set) and the top of the stack, or it would spill into the text or
+    // data segment. Records a fault and returns false if not
+    pub fn check_stack(&self, address: u64, n: usize, access: Access) -> bool {
+        let bottom = self.stack_limit.unwrap_or(self.text);
+        if address.checked_add(n as u64).is_none_or(|end| end > self.data_base()) {
+            self.fault(address, access, ViolationKind::StackUnderflow);
+            return false;
+        }
+        if address < bottom {
+            self.fault(address, access, ViolationKind::StackOverflow);
+            return false;
+        }
+        true
+    }
+
+    // Whether n bits at an address are in memory; records a fault if not
+    fn check_range(&self, address: u64, n: usize, access: Access) -> bool {
+        if address.checked_add(n as u64).is_some_and(|end| end <= self.memsize) {
+            return true;
+        }
+        self.fault(address, access, ViolationKind::OutOfRange);
+        false
+    }
+
+    // Start recording the previous contents of every RAM write
+    pub fn start_write_log(&mut self) {
+        self.write_log = Some(Vec::new());
+    }
+
+    // Stop recording and return the writes since start_write_log()
+    pub fn take_write_log(&mut self) -> Vec<WriteRecord> {
+        self.write_log.take().unwrap_or_default()
+    }
+
+    // Call an observer after every change to RAM from now on
+    pub fn add_observer(&mut self, observer: Box<dyn WriteObserver>) {
+        self.observers.0.retain(|o| o.is_alive());
+        self.observers.0.push(observer);
+    }
+
+    // Map a device over a range of addresses; accesses that start in the
+    // range go to the handler instead of RAM
+    pub fn register_mmio(&mut self, range: Range<u64>, handler: Box<dyn MmioHandler>) {
+        if self.mmio.iter().any(|r| r.range.start < range.end && range.start < r.range.end) {
+            panic!("MMIO range {:#x}..{:#x} overlaps another device", range.start, range.end);
+        }
+        self.mmio.push(MmioRegion { range, handler });
+    }
+
+    // Whether an address belongs to a device
+    pub fn is_mmio(&self, address: u64) -> bool {
+        self.mmio.iter().any(|r| r.range.contains(&address))
+    }
+
+    // Start of the device register window
+    pub fn io_base(&self) -> u64 {
+        self.data_base() + self.data - MEMORY_IO_SIZE
+    }
+
+    // Find which segment an address belongs to
+    pub fn segment(&self, address: u64) -> Segment {
+        if address < self.text {
+            Segment::Text
+        } else if address < self.text + self.stack {
+            Segment::Stack
+        } else if address < self.text + self.stack + self.data {
+            Segment::Data
+        } else if address < self.text + self.stack + self.data + self.vram {
+            Segment::Vram
+        } else {
+            Segment::Outside
         }
     }
 
-    // Load a program from a file into memory
-    pub fn load_program(&mut self, filename: &str) -> io::Result<()> {
+    // Address of the data segment, which follows text and stack
+    pub fn data_base(&self) -> u64 {
+        self.text + self.stack
+    }
+
+    // Address of the entry holding the handler for an interrupt
+    pub fn vector_address(&self, irq: usize) -> u64 {
+        assert!(irq < MEMORY_VECTOR_COUNT);
+        self.data_base() + 64 * irq as u64
+    }
+
+    // Address and size of the VRAM segment, which follows text, stack and data
+    pub fn vram_base(&self) -> u64 {
+        self.text + self.stack + self.data
+    }
+
+    pub fn text_size(&self) -> u64 {
+        self.text
+    }
+
+    pub fn stack_size(&self) -> u64 {
+        self.stack
+    }
+
+    pub fn vram_size(&self) -> u64 {
+        self.vram
+    }
+
+    // The words of RAM, for snapshots. Devices are not part of it
+    pub fn ram(&self) -> &Pages {
+        &self.mem
+    }
+
+    // Host memory used by RAM, in bytes
+    pub fn allocated(&self) -> usize {
+        self.mem.allocated() * minimisa_core::pages::PAGE_WORDS as usize * 8
+    }
+
+    // Load a program from a file into memory: an object file, whose
+    // segments are placed at their addresses, or a bare binary loaded at
+    // address 0. Returns the object so that the caller can use its entry
+    // point, symbols and opcode table
+    pub fn load_program(&mut self, filename: &str) -> io::Result<Option<Object>> {
         let mut file = File::open(filename)?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
-        
-        if (buffer.len() * 8) > self.text as usize {
-            panic!("Program does not fit in the code/stack segment");
+        self.load_bytes(&buffer)
+    }
+
+    // Load a program from the contents of a file, see load_program()
+    pub fn load_bytes(&mut self, buffer: &[u8]) -> io::Result<Option<Object>> {
+        if Object::is_object(buffer) {
+            let object = Object::from_bytes(buffer)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.load_object(&object)?;
+            return Ok(Some(object));
         }
 
-        self.mem[..buffer.len()].copy_from_slice(&buffer.iter().map(|&b| b as u64).collect::<Vec<u64>>()[..]);
+        if (buffer.len() * 8) as u64 > self.text {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Program does not fit in the text segment"));
+        }
+        self.write_bytes(0, buffer);
+        Ok(None)
+    }
 
+    // Copy the segments of an object to memory. The text segment must fit
+    // in the text segment of the memory, others anywhere in memory
+    pub fn load_object(&mut self, object: &Object) -> io::Result<()> {
+        for segment in &object.segments {
+            let limit = if segment.address == 0 { self.text } else { self.memsize };
+            if segment.address > limit || segment.length > limit - segment.address {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "Segment at {:#x} ({} bits) does not fit in memory", segment.address, segment.length)));
+            }
+        }
+        for segment in &object.segments {
+            let whole = (segment.length / 8) as usize;
+            self.write_bytes(segment.address, &segment.bytes[..whole]);
+            for i in 8 * whole as u64..segment.length {
+                self.write(segment.address + i, segment.bit(i) as u64, 1);
+            }
+        }
         Ok(())
     }
 
-    // Load a text program into memory
+    // Load a text program into memory: an ASCII object made of '0' and '1'
+    // characters, as read by subject/simu. Other characters are ignored
     pub fn load_text(&mut self, filename: &str) -> io::Result<()> {
-        self.load_program(filename)
+        let mut file = File::open(filename)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        let bits: Vec<u64> = buffer.iter().filter_map(|b| match b {
+            b'0' => Some(0),
+            b'1' => Some(1),
+            _ => None,
+        }).collect();
+        if bits.len() as u64 > self.text {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Program does not fit in the text segment"));
+        }
+
+        for (address, &bit) in bits.iter().enumerate() {
+            self.write(address as u64, bit, 1);
+        }
+        Ok(())
+    }
+
+    // Whether a file looks like an ASCII object (only 0, 1 and whitespace)
+    pub fn is_text_object(bytes: &[u8]) -> bool {
+        !bytes.is_empty() && bytes.iter().all(|b| matches!(b, b'0' | b'1') || b.is_ascii_whitespace())
     }
 
     // Load an additional file into memory at the given address
@@ -66,47 +581,369 @@ impl Memory {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
-        if (buffer.len() * 8) > (self.memsize - address) as usize {
+        if address > self.memsize || (buffer.len() * 8) as u64 > self.memsize - address {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "File does not fit in memory"));
         }
 
-        let offset = (address / 64) as usize;
-        self.mem[offset..offset + buffer.len()].copy_from_slice(&buffer.iter().map(|&b| b as u64).collect::<Vec<u64>>()[..]);
-
+        self.write_bytes(address, &buffer);
         Ok(())
     }
 
+    // Copy bytes to memory, most significant bit of each byte first
+    fn write_bytes(&mut self, address: u64, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.write(address + 8 * i as u64, byte as u64, 8);
+        }
+    }
+
     // Free the memory object (automatically done in Rust)
     // Rust will handle memory cleanup, so no need for an explicit destroy function
 
-    // Read n bits from an address (up to 64)
+    // Read n bits from an address (up to 64). Reads past the end of memory
+    // return 0
     pub fn read(&self, address: u64, n: usize) -> u64 {
-        assert!(n <= 64);
-        let bit_pos = address % 64;
-        let word_index = (address / 64) as usize;
+        self.check_access(address, n, Access::Read);
+        if let Some(region) = self.mmio.iter().find(|r| r.range.contains(&address)) {
+            return region.handler.read(address - region.range.start, n);
+        }
+        if !self.check_range(address, n, Access::Read) {
+            return 0;
+        }
+        self.read_ram(address, n)
+    }
 
-        let mut result = self.mem[word_index] >> (64 - n - bit_pos);
+    // Write n bits to an address (up to 64). Writes past the end of memory
+    // are dropped
+    pub fn write(&mut self, address: u64, value: u64, n: usize) {
+        if !self.check_access(address, n, Access::Write) {
+            return;
+        }
+        if self.text_writes != TextWrites::Allow && self.segment(address) == Segment::Text {
+            self.fault(address, Access::Write, ViolationKind::TextWrite);
+            if self.text_writes == TextWrites::Trap {
+                return;
+            }
+        }
+        if let Some(region) = self.mmio.iter_mut().find(|r| r.range.contains(&address)) {
+            let offset = address - region.range.start;
+            region.handler.write(offset, value, n);
+            return;
+        }
+        if !self.check_range(address, n, Access::Write) {
+            return;
+        }
+        if self.write_log.is_some() {
+            let old = self.read_ram(address, n);
+            if let Some(log) = self.write_log.as_mut() {
+                log.push(WriteRecord { address, old, n });
+            }
+        }
+        self.write_ram(address, value, n)
+    }
 
-        if bit_pos + n > 64 && word_index + 1 < self.mem.len() {
-            result |= self.mem[word_index + 1] << (64 - bit_pos);
+    // Overwrite RAM with bits, as the debugger does to patch code: neither
+    // permissions nor devices are involved. Returns false, writing nothing,
+    // if the bits do not fit in memory
+    pub fn patch(&mut self, address: u64, bits: &BitVec) -> bool {
+        if address.checked_add(bits.len()).is_none_or(|end| end > self.memsize) {
+            return false;
         }
+        for offset in (0..bits.len()).step_by(64) {
+            let n = (bits.len() - offset).min(64) as u32;
+            self.write_ram(address + offset, bits.read(offset, n).unwrap(), n as usize);
+        }
+        true
+    }
 
-        result
+    // Bits are stored most significant first: the bit at address a is bit
+    // 63 - (a % 64) of word a / 64. Accesses go through a 128-bit window
+    // made of two consecutive words so they can cross a word boundary.
+    fn read_ram(&self, address: u64, n: usize) -> u64 {
+        assert!(n <= 64);
+        if n == 0 {
+            return 0;
+        }
+        let bit_pos = (address % 64) as usize;
+        let word_index = address / 64;
+
+        let hi = self.mem.word(word_index) as u128;
+        let lo = self.mem.word(word_index + 1) as u128;
+        let window = (hi << 64) | lo;
+
+        ((window << bit_pos) >> (128 - n)) as u64
     }
 
-    // Write n bits to an address (up to 64)
-    pub fn write(&mut self, address: u64, value: u64, n: usize) {
+    fn write_ram(&mut self, address: u64, value: u64, n: usize) {
         assert!(n <= 64);
-        let bit_pos = address % 64;
-        let word_index = (address / 64) as usize;
+        if n == 0 {
+            return;
+        }
+        let bit_pos = (address % 64) as usize;
+        let word_index = address / 64;
+
+        let hi = self.mem.word(word_index) as u128;
+        let lo = self.mem.word(word_index + 1) as u128;
+        let mut window = (hi << 64) | lo;
+
+        let shift = 128 - n - bit_pos;
+        let mask = ((1u128 << n) - 1) << shift;
+        window = (window & !mask) | (((value as u128) << shift) & mask);
+
+        self.mem.set_word(word_index, (window >> 64) as u64);
+        self.mem.set_word(word_index + 1, window as u64);
+        for observer in self.observers.0.iter_mut() {
+            observer.written(address..address + n as u64);
+        }
+    }
+
+    // Typed accessors used by the disassembler and the CPU
+
+    // Read up to 32 bits as a small unsigned field
+    pub fn read_bits(&self, address: u64, n: usize) -> u32 {
+        assert!(n <= 32);
+        self.read(address, n) as u32
+    }
+
+    // Read n bits, zero-extended
+    pub fn read_unsigned(&self, address: u64, n: usize) -> u64 {
+        self.read(address, n)
+    }
+
+    // Read n bits, sign-extended
+    pub fn read_signed(&self, address: u64, n: usize) -> i64 {
+        sign_extend(self.read(address, n), n as u32)
+    }
+
+    pub fn read_u32(&self, address: u64) -> u32 {
+        self.read(address, 32) as u32
+    }
+
+    pub fn read_u64(&self, address: u64) -> u64 {
+        self.read(address, 64)
+    }
+
+    // Hex, binary and ASCII view of a range, DUMP_LINE_BITS per line from
+    // the start of the range, which need not be aligned; the last line may
+    // be shorter. Only RAM is shown: devices are not read, and neither
+    // permissions nor bounds are checked
+    pub fn dump(&self, range: Range<u64>) -> String {
+        self.dump_annotated(range, |_| None)
+    }
+
+    // Same lines as dump, as a JSON array of {"address", "bits", "value"}
+    // objects, for scripts
+    pub fn dump_json(&self, range: Range<u64>) -> Value {
+        (range.start..range.end).step_by(DUMP_LINE_BITS as usize).map(|address| {
+            let n = (range.end - address).min(DUMP_LINE_BITS) as usize;
+            json!({ "address": address, "bits": n, "value": self.read_ram(address, n) })
+        }).collect()
+    }
+
+    // Same as dump, with a note from `annotate` at the end of the lines it
+    // returns one for, given the range of the line
+    pub fn dump_annotated(&self, range: Range<u64>, mut annotate: impl FnMut(Range<u64>) -> Option<String>) -> String {
+        let mut out = String::new();
+        let mut address = range.start;
+        while address < range.end {
+            let n = (range.end - address).min(DUMP_LINE_BITS) as usize;
+            let value = self.read_ram(address, n);
+
+            let hex = format!("{:0width$x}", value, width = n.div_ceil(4));
+            let bits = format!("{:0width$b}", value, width = n);
+            let bytes: Vec<&str> = (0..n).step_by(8).map(|i| &bits[i..(i + 8).min(n)]).collect();
+            let ascii: String = (0..n / 8).map(|i| {
+                let byte = (value >> (n - 8 * (i + 1))) as u8;
+                if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }
+            }).collect();
+
+            let mut line = format!("{:08x}  {:<8}  {:<35}  {}", address, hex, bytes.join(" "), ascii);
+            if let Some(note) = annotate(address..address + n as u64) {
+                line = format!("{:<63}; {}", line, note);
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+            address += n as u64;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
 
-        let mask = (1u64 << n) - 1;
-        self.mem[word_index] &= !(mask << (64 - n - bit_pos));
-        self.mem[word_index] |= (value & mask) << (64 - n - bit_pos);
+        mem.write(0, 0b101, 3);
+        assert_eq!(mem.read(0, 3), 0b101);
+        assert_eq!(mem.read(0, 1), 1);
+        assert_eq!(mem.read(1, 1), 0);
 
-        if bit_pos + n > 64 && word_index + 1 < self.mem.len() {
-            self.mem[word_index + 1] &= !(mask >> (64 - bit_pos));
-            self.mem[word_index + 1] |= (value & mask) >> (64 - bit_pos);
+        // Across a word boundary
+        mem.write(60, 0xabcd, 16);
+        assert_eq!(mem.read(60, 16), 0xabcd);
+        assert_eq!(mem.read_bits(60, 4), 0xa);
+        assert_eq!(mem.read_bits(72, 4), 0xd);
+
+        mem.write(128, u64::MAX, 64);
+        assert_eq!(mem.read_u64(128), u64::MAX);
+        assert_eq!(mem.read(127, 1), 0);
+        assert_eq!(mem.read(192, 1), 0);
+    }
+
+    #[test]
+    fn test_typed_reads() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+
+        mem.write(10, 0xff, 8);
+        assert_eq!(mem.read_unsigned(10, 8), 255);
+        assert_eq!(mem.read_signed(10, 8), -1);
+        assert_eq!(mem.read_signed(11, 4), -1);
+
+        mem.write(300, 0x8000_0000, 32);
+        assert_eq!(mem.read_u32(300), 0x8000_0000);
+        assert_eq!(mem.read_signed(300, 32), -0x8000_0000);
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+        mem.write(0, 0xab, 8);
+
+        // Nothing is checked until protection is enabled
+        mem.set_protection(true);
+        mem.write(8, 0xcd, 8);
+        assert_eq!(mem.read(0, 16), 0xab00);
+        assert_eq!(mem.take_violation(), Some(Violation {
+            address: 8, access: Access::Write, segment: Segment::Text, kind: ViolationKind::Denied }));
+        assert_eq!(mem.take_violation(), None);
+
+        mem.write(1024, 0x12, 8);
+        assert_eq!(mem.read(1024, 8), 0x12);
+        assert_eq!(mem.take_violation(), None);
+
+        mem.set_permissions(Segment::Stack, Perm::parse("-w-").unwrap());
+        mem.read(1024, 8);
+        assert_eq!(mem.take_violation().map(|v| v.access), Some(Access::Read));
+        assert_eq!(format!("{}", mem.permissions(Segment::Stack)), "-w-");
+        assert_eq!(Perm::parse("rwz"), None);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+        let end = 4096;
+
+        // Out of memory, whether or not protection is on
+        assert_eq!(mem.read(end - 8, 16), 0);
+        let fault = mem.take_violation().unwrap();
+        assert_eq!((fault.address, fault.kind, fault.segment), (end - 8, ViolationKind::OutOfRange, Segment::Vram));
+        mem.write(u64::MAX, 1, 1);
+        assert_eq!(mem.take_violation().map(|v| v.access), Some(Access::Write));
+        assert_eq!(mem.read(end - 8, 8), 0);
+        assert_eq!(mem.take_violation(), None);
+
+        // A write from the stack that ends in a read-only data segment
+        mem.set_protection(true);
+        mem.set_permissions(Segment::Data, Perm(Perm::R));
+        mem.write(2044, 0xff, 8);
+        let fault = mem.take_violation().unwrap();
+        assert_eq!((fault.address, fault.segment), (2051, Segment::Data));
+        assert_eq!(fault.to_string(), "write to data segment at 0x803");
+        assert_eq!(mem.read(2040, 16), 0);
+
+        // 256 bits of stack below data
+        mem.set_stack_limit(Some(256));
+        mem.write(2048 - 256, 1, 64);
+        assert_eq!(mem.take_violation(), None);
+        mem.write(2048 - 320, 1, 64);
+        assert_eq!(mem.take_violation().map(|v| v.kind), Some(ViolationKind::StackOverflow));
+        assert_eq!(mem.read(2048 - 320, 64), 0);
+
+        // Stack accesses stay between the limit and the top of the stack
+        assert!(mem.check_stack(2048 - 64, 64, Access::Read));
+        assert!(!mem.check_stack(2048 - 32, 64, Access::Read));
+        assert_eq!(mem.take_violation().map(|v| v.to_string()),
+            Some("stack underflow: read from 0x7e0, above the top of the stack".to_string()));
+        assert!(!mem.check_stack(2048 - 320, 64, Access::Write));
+        assert_eq!(mem.take_violation().map(|v| v.kind), Some(ViolationKind::StackOverflow));
+        assert!(!mem.check_stack(u64::MAX - 8, 64, Access::Write));
+        assert_eq!(mem.take_violation().map(|v| v.kind), Some(ViolationKind::StackUnderflow));
+    }
+
+    #[test]
+    fn test_text_writes() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = std::sync::Arc::clone(&written);
+        mem.add_observer(Box::new(move |range| log.lock().unwrap().push(range)));
+
+        // Program writes, debugger patches and loads are all observed
+        mem.write(100, 0xf, 4);
+        mem.patch(1500, &"101".parse().unwrap());
+        mem.load_bytes(&[0xff]).unwrap();
+        mem.write(mem.io_base(), 1, 8);
+        assert_eq!(written.lock().unwrap()[..3], [100..104, 1500..1503, 0..8]);
+
+        // Warnings write anyway, traps do not
+        mem.set_text_writes(TextWrites::Warn);
+        mem.write(200, 0b11, 2);
+        assert_eq!(mem.take_violation().map(|v| v.to_string()),
+            Some("self-modifying code: write to text segment at 0xc8".to_string()));
+        mem.set_text_writes(TextWrites::Trap);
+        mem.write(202, 0b11, 2);
+        assert_eq!(mem.take_violation().map(|v| v.kind), Some(ViolationKind::TextWrite));
+        assert_eq!(mem.read(200, 4), 0b1100);
+        mem.write(1024, 1, 1);
+        assert_eq!(mem.take_violation(), None);
+        assert_eq!(written.lock().unwrap().last(), Some(&(1024..1025)));
+    }
+
+    #[test]
+    fn test_sparse() {
+        // 2^32 bits of data, of which only the pages written are allocated
+        let mut mem = Memory::new(1024, 1024, 1 << 32, 1024);
+        let far = mem.data_base() + (1 << 32) - 80;
+        assert_eq!(mem.read(far, 64), 0);
+        assert_eq!(mem.allocated(), 0);
+
+        mem.write(far, 0x0123_4567_89ab_cdef, 64);
+        mem.write(far + 64, 0x5a5a, 16);
+        assert_eq!((mem.read(far, 64), mem.read(far + 60, 20)), (0x0123_4567_89ab_cdef, 0xf5a5a));
+        assert_eq!(mem.allocated(), 64 << 10);
+        assert_eq!(mem.take_violation(), None);
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+        for (i, &byte) in b"Hi!\n\x7f".iter().enumerate() {
+            mem.write(4 + 8 * i as u64, byte as u64, 8);
         }
+        let dump = mem.dump(4..48);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "00000004  4869210a  01001000 01101001 00100001 00001010  Hi!.");
+        assert_eq!(lines[1], format!("00000024  7f0       {:<35}  .", "01111111 0000"));
+        assert_eq!(lines.len(), 2);
+
+        let notes = mem.dump_annotated(0..64, |r| (r.start == 0).then(|| "start".to_string()));
+        assert!(notes.lines().next().unwrap().ends_with("  ; start"));
+        assert!(!notes.lines().nth(1).unwrap().contains(';'));
+    }
+
+    #[test]
+    fn test_load_object() {
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+        let mut object = Object::from_text("1011 0110 1");
+        object.segments.push(minimisa_core::object::Segment::from_bits(2048, "111"));
+        mem.load_object(&object).unwrap();
+        assert_eq!(mem.read(0, 10), 0b1011011010);
+        assert_eq!(mem.read(2047, 5), 0b01110);
+
+        // The text segment is bounded by the text size, not the memory size
+        let object = Object::from_text(&"1".repeat(1025));
+        assert!(mem.load_object(&object).is_err());
     }
 }
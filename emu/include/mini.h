@@ -0,0 +1,55 @@
+//---
+// mini.h - C interface to the MinimISA emulator
+//
+// Link with the emu library (libemu.so or libemu.a, built by cargo from
+// emu/src). See ffi.rs for how the functions behave.
+//---
+
+#ifndef MINI_H
+#define MINI_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Mini mini_t;
+
+/* Values returned by mini_step() */
+#define MINI_RUNNING 0
+#define MINI_HALTED  1
+#define MINI_BREAK   2
+#define MINI_ERROR   (-1)
+
+/* Register numbers after r0..r7 */
+#define MINI_PC 8
+#define MINI_SP 9
+#define MINI_A0 10
+#define MINI_A1 11
+
+mini_t *mini_create(uint64_t text, uint64_t stack, uint64_t data, uint64_t vram);
+void mini_destroy(mini_t *mini);
+const char *mini_error(const mini_t *mini);
+
+int mini_load(mini_t *mini, const char *filename);
+int mini_load_bytes(mini_t *mini, const uint8_t *bytes, size_t len);
+
+int mini_step(mini_t *mini, uint64_t count);
+int mini_halted(const mini_t *mini);
+uint64_t mini_cycles(const mini_t *mini);
+
+uint64_t mini_read_reg(mini_t *mini, int reg);
+int mini_write_reg(mini_t *mini, int reg, uint64_t value);
+uint64_t mini_read_mem(const mini_t *mini, uint64_t address, int bits);
+int mini_write_mem(mini_t *mini, uint64_t address, uint64_t value, int bits);
+
+void mini_set_breakpoint(mini_t *mini, uint64_t address);
+int mini_clear_breakpoint(mini_t *mini, uint64_t address);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MINI_H */
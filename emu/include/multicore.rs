@@ -0,0 +1,162 @@
+//---
+// emu:multicore - several CPUs over one memory
+//
+// Cores share the memory and its devices; each has its own registers,
+// pointers, flags, timer, scheduler and journal. Core n starts at the
+// entry point with n in r0 and a stack of its own: the stack segment is
+// split evenly and sp starts at the top of slice n, so core 0 gets the
+// usual stack top, the start of the data segment.
+//
+// Cores are stepped either interleaved, one instruction per running core
+// in core order, which is deterministic, or on threads of their own, which
+// interleave wherever the memory lock lets them. An instruction is atomic
+// either way, since a CPU holds the memory lock while it executes one.
+//---
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::cpu::{CPU, PC, SP};
+
+// Clones share the cores
+#[derive(Clone)]
+pub struct Machine {
+    pub cores: Vec<Arc<Mutex<CPU>>>,
+}
+
+impl Machine {
+    /// Run `count` cores from a CPU set up for the program, which becomes
+    /// core 0. The others start where it does, with its access checks,
+    /// timing model, word size and replay session, and a generator for rand
+    /// seeded from its own; its devices (scheduler,
+    /// timer, clock, profiler) stay its own
+    pub fn new(cpu: CPU, count: usize) -> Machine {
+        assert!(count > 0, "a machine needs at least one core");
+        let (top, slice) = {
+            let memory = cpu.mem.lock().unwrap();
+            (memory.data_base(), memory.stack_size() / count as u64)
+        };
+        let mut cores = vec![cpu];
+        for _ in 1..count {
+            let mut core = CPU::new(Arc::clone(&cores[0].mem));
+            core.ptr[PC] = cores[0].ptr[PC];
+            core.exec_check = cores[0].exec_check;
+            core.timing = cores[0].timing.clone();
+            core.word_size = cores[0].word_size;
+            core.replay = cores[0].replay.clone();
+            core.rng = cores[0].rng.split();
+            cores.push(core);
+        }
+        for (n, core) in cores.iter_mut().enumerate() {
+            core.ptr[SP] = top - n as u64 * slice;
+            core.r[0] = n as u64;
+        }
+        Machine { cores: cores.into_iter().map(|cpu| Arc::new(Mutex::new(cpu))).collect() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cores.is_empty()
+    }
+
+    pub fn core(&self, n: usize) -> &Arc<Mutex<CPU>> {
+        &self.cores[n]
+    }
+
+    /// Whether every core has halted
+    pub fn halted(&self) -> bool {
+        self.cores.iter().all(|cpu| cpu.lock().unwrap().h)
+    }
+
+    /// Execute one instruction on each core that has not halted, in core
+    /// order. Returns the cores that ran
+    pub fn step(&self) -> Vec<usize> {
+        let mut ran = Vec::new();
+        for (n, cpu) in self.cores.iter().enumerate() {
+            let mut cpu = cpu.lock().unwrap();
+            if !cpu.h {
+                cpu.execute();
+                ran.push(n);
+            }
+        }
+        ran
+    }
+
+    /// Step the cores interleaved until they all halt
+    pub fn run(&self) {
+        while !self.step().is_empty() {}
+    }
+
+    /// Run every core on a thread of its own until they all halt
+    pub fn run_threaded(&self) {
+        let threads: Vec<_> = self.cores.iter().map(|cpu| {
+            let cpu = Arc::clone(cpu);
+            thread::spawn(move || {
+                let mut cpu = cpu.lock().unwrap();
+                while !cpu.h {
+                    cpu.execute();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{A0, A1};
+    use crate::memory::Memory;
+    use crate::testing::assemble_str;
+
+    fn machine(source: &str, count: usize) -> (Arc<Mutex<Memory>>, Machine) {
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let mut cpu = CPU::new(Arc::clone(&memory));
+        cpu.ptr[PC] = memory.lock().unwrap().load_bytes(&assemble_str(source)).unwrap().map_or(0, |o| o.entry);
+        let machine = Machine::new(cpu, count);
+        (memory, machine)
+    }
+
+    #[test]
+    fn test_own_stacks() {
+        let source = "add2i r0 1\npush 64 r0\nend: jump end";
+        for threaded in [false, true] {
+            let (memory, machine) = machine(source, 4);
+            if threaded { machine.run_threaded() } else { machine.run() }
+            assert!(machine.halted());
+
+            let memory = memory.lock().unwrap();
+            let slice = memory.stack_size() / 4;
+            for n in 0..4 {
+                let top = memory.data_base() - n * slice;
+                assert_eq!(memory.read(top - 64, 64), n + 1);
+                assert_eq!(machine.core(n as usize).lock().unwrap().ptr[SP], top - 64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interleaved() {
+        // Both cores write the same word, then read it back: core 0 sees
+        // what core 1 wrote in the same round
+        let source = "add2i r0 1\nwrite a1 64 r0\nreadze a0 64 r1\nend: jump end";
+        let (memory, machine) = machine(source, 2);
+        let shared = memory.lock().unwrap().data_base() + 4096;
+        for cpu in &machine.cores {
+            let mut cpu = cpu.lock().unwrap();
+            cpu.ptr[A0] = shared;
+            cpu.ptr[A1] = shared;
+        }
+
+        assert_eq!(machine.step(), [0, 1]);
+        machine.run();
+        assert_eq!(memory.lock().unwrap().read(shared, 64), 2);
+        assert_eq!(machine.core(0).lock().unwrap().r[1], 2);
+        assert_eq!(machine.core(1).lock().unwrap().r[1], 2);
+        assert!(machine.step().is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+//---
+// emu:profiler - per-instruction hit counts and cycle estimates
+//
+// The CPU counts executions per opcode; the profiler refines this to every
+// instruction address, so that hot spots of compiled code show up. Cycles
+// are those of the CPU timing model. Executions of instructions that were
+// still decoded in the decode cache (see icache.rs) are counted too.
+//---
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use crate::disasm::{disasm_one, disasm_symbol};
+use crate::memory::Memory;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProfileEntry {
+    pub hits: u64,
+    pub cycles: u64,
+    pub cached: u64,  // Hits decoded from the decode cache
+}
+
+impl ProfileEntry {
+    /// Percentage of hits decoded from the cache
+    pub fn cache_rate(&self) -> f64 {
+        100.0 * self.cached as f64 / self.hits.max(1) as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    entries: HashMap<u64, ProfileEntry>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Count one execution of the instruction at `pc`, which took `cost`
+    /// cycles and was decoded from the cache if `cached`
+    pub fn record(&mut self, pc: u64, cost: u64, cached: bool) {
+        let entry = self.entries.entry(pc).or_default();
+        entry.hits += 1;
+        entry.cycles += cost;
+        entry.cached += cached as u64;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.entries.values().map(|e| e.cycles).sum()
+    }
+
+    /// Percentage of all executions decoded from the cache, None before
+    /// the first one
+    pub fn cache_rate(&self) -> Option<f64> {
+        let (hits, cached) = self.entries.values().fold((0, 0), |(h, c), e| (h + e.hits, c + e.cached));
+        (hits > 0).then(|| 100.0 * cached as f64 / hits as f64)
+    }
+
+    /// Entries sorted by hotness: most cycles first, then by address
+    pub fn hot_spots(&self) -> Vec<(u64, ProfileEntry)> {
+        let mut spots: Vec<(u64, ProfileEntry)> = self.entries.iter().map(|(&a, &e)| (a, e)).collect();
+        spots.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    /// CSV table sorted by hotness, with the disassembly of every
+    /// instruction and where it is from the labels (as "loop+17", empty
+    /// before the first label). The last column is the cache hit rate
+    pub fn to_csv(&self, memory: &Memory, labels: &BTreeMap<u64, String>) -> String {
+        let total = self.total_cycles().max(1) as f64;
+        let mut out = String::from("address,label,instruction,hits,cycles,percent,cached\n");
+        for (address, entry) in self.hot_spots() {
+            let mut ptr = address;
+            let text = disasm_one(memory, &mut ptr).unwrap_or_else(|| "?".to_string());
+            let _ = writeln!(out, "{:#x},{},{},{},{},{:.2},{:.2}", address, disasm_symbol(labels, address).unwrap_or_default(),
+                text, entry.hits, entry.cycles, 100.0 * entry.cycles as f64 / total, entry.cache_rate());
+        }
+        out
+    }
+
+    pub fn save_csv(&self, memory: &Memory, labels: &BTreeMap<u64, String>, filename: &str) -> io::Result<()> {
+        fs::write(filename, self.to_csv(memory, labels))
+    }
+
+    /// The rows of the CSV table as a JSON array of objects, with a null
+    /// label before the first one
+    pub fn to_json(&self, memory: &Memory, labels: &BTreeMap<u64, String>) -> Value {
+        let total = self.total_cycles().max(1) as f64;
+        self.hot_spots().into_iter().map(|(address, entry)| {
+            let mut ptr = address;
+            json!({
+                "address": address,
+                "label": disasm_symbol(labels, address),
+                "instruction": disasm_one(memory, &mut ptr).unwrap_or_else(|| "?".to_string()),
+                "hits": entry.hits,
+                "cycles": entry.cycles,
+                "percent": 100.0 * entry.cycles as f64 / total,
+                "cached": entry.cache_rate(),
+            })
+        }).collect()
+    }
+
+    pub fn save_json(&self, memory: &Memory, labels: &BTreeMap<u64, String>, filename: &str) -> io::Result<()> {
+        fs::write(filename, self.to_json(memory, labels).to_string() + "\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_spots() {
+        let mut profiler = Profiler::new();
+        assert_eq!(profiler.cache_rate(), None);
+        for i in 0..3 {
+            profiler.record(0, 1, i > 0);
+        }
+        profiler.record(10, 2, false);
+        profiler.record(10, 2, true);
+        profiler.record(20, 1, false);
+
+        let spots = profiler.hot_spots();
+        assert_eq!(spots[0], (10, ProfileEntry { hits: 2, cycles: 4, cached: 1 }));
+        assert_eq!(spots[1], (0, ProfileEntry { hits: 3, cycles: 3, cached: 2 }));
+        assert_eq!(spots[2].0, 20);
+        assert_eq!(profiler.total_cycles(), 8);
+        assert_eq!(profiler.cache_rate(), Some(50.0));
+
+        let memory = Memory::new(1024, 1024, 1024, 1024);
+        let mut labels = BTreeMap::new();
+        let csv = profiler.to_csv(&memory, &labels);
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.lines().nth(1).unwrap().starts_with("0xa,,"));
+        assert!(csv.lines().nth(1).unwrap().ends_with(",50.00,50.00"));
+        labels.insert(4, "loop".to_string());
+        assert!(profiler.to_csv(&memory, &labels).lines().nth(1).unwrap().starts_with("0xa,loop+6,"));
+        let json = profiler.to_json(&memory, &labels);
+        assert_eq!((json[0]["address"].as_u64(), json[0]["hits"].as_u64()), (Some(10), Some(2)));
+        assert_eq!((json[0]["label"].as_str(), json[1]["label"].is_null()), (Some("loop+6"), true));
+        assert_eq!(json[0]["percent"].as_f64(), Some(50.0));
+        assert_eq!(json[2]["cached"].as_f64(), Some(0.0));
+    }
+}
@@ -0,0 +1,268 @@
+//---
+// emu:progen - deterministic random program generator
+//
+// Generates well-formed programs from a seed, for fuzzing the emulator and
+// for producing exercise inputs. The same configuration always gives the
+// same program, bit for bit.
+//
+// Programs are built from the format table of the disassembler, with the
+// opcode table of the configuration (see disasm_load_opcodes()). Addresses are
+// always encoded on 16 bits, which keeps the layout independent of the
+// branch targets.
+//---
+
+use std::fmt::Write as _;
+use crate::disasm::{disasm_code, disasm_format, ArgType, OP_ADD2, OP_ADD2I, OP_ADD3,
+    OP_ADD3I, OP_AND2, OP_AND2I, OP_AND3, OP_AND3I, OP_CALL, OP_CMP, OP_CMPI, OP_JUMP,
+    OP_JUMPIF, OP_LET, OP_LETI, OP_OR2, OP_OR2I, OP_OR3, OP_OR3I, OP_RETI, OP_RETURN,
+    OP_SHIFT, OP_SUB2, OP_SUB2I, OP_SUB3, OP_SUB3I, OP_XOR3, OP_XOR3I, OP_ASR3};
+use crate::memory::Memory;
+use crate::util::Rng;
+use minimisa_core::{default_opcodes, encode_address, encode_const, Opcodes, ADDRESS_WIDTHS, PREFIXES};
+
+// Pointer ids, as encoded in Pointer arguments
+const POINTER_A0: u64 = 2;
+
+// Branch targets use the 16-bit address encoding
+const ADDRESS_16: usize = 1;
+
+/// What the generator may produce
+#[derive(Debug, Clone)]
+pub struct ProgenConfig {
+    pub seed: u64,
+    pub allowed: Vec<u32>,  // Opcode numbers that may appear
+    pub max_size: u64,      // Upper bound on the program size, in bits
+    pub must_halt: bool,    // Forward branches only, and a final halt
+    pub opcodes: Opcodes,   // Encoding of the instructions
+}
+
+impl Default for ProgenConfig {
+    /// Register-only instructions and forward branches, which any
+    /// emulator can run without setting up memory
+    fn default() -> Self {
+        ProgenConfig {
+            seed: 0,
+            allowed: vec![
+                OP_ADD2, OP_ADD2I, OP_SUB2, OP_SUB2I, OP_CMP, OP_CMPI, OP_LET, OP_LETI,
+                OP_SHIFT, OP_JUMP, OP_JUMPIF, OP_OR2, OP_OR2I, OP_AND2, OP_AND2I,
+                OP_ADD3, OP_ADD3I, OP_SUB3, OP_SUB3I, OP_AND3, OP_AND3I, OP_OR3, OP_OR3I,
+                OP_XOR3, OP_XOR3I, OP_ASR3,
+            ],
+            max_size: 1024,
+            must_halt: true,
+            opcodes: default_opcodes(),
+        }
+    }
+}
+
+/// A generated program: encoded fields and the address of every instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub fields: Vec<(u64, usize)>,  // (value, width), most significant bit first
+    pub instructions: Vec<u64>,     // Address of each instruction
+    pub size: u64,                  // Size in bits
+}
+
+impl Program {
+    /// Write the program into memory from `address`
+    pub fn load(&self, memory: &mut Memory, mut address: u64) {
+        for &(value, width) in &self.fields {
+            memory.write(address, value, width);
+            address += width as u64;
+        }
+    }
+
+    /// Program as a string of '0' and '1', the format of text objects
+    pub fn to_text(&self) -> String {
+        let mut out = String::with_capacity(self.size as usize);
+        for &(value, width) in &self.fields {
+            let _ = write!(out, "{:0width$b}", value & mask(width), width = width);
+        }
+        out
+    }
+}
+
+fn mask(width: usize) -> u64 {
+    if width >= 64 { u64::MAX } else { (1 << width) - 1 }
+}
+
+// Instruction being generated; branch targets are resolved once the
+// addresses of all instructions are known
+struct Pending {
+    fields: Vec<(u64, usize)>,
+    target: Option<(usize, usize)>,  // (field index, instruction index)
+}
+
+// Fields of the core encoders, with the widths used here
+fn widen(fields: [(u64, u32); 2]) -> [(u64, usize); 2] {
+    fields.map(|(value, width)| (value, width as usize))
+}
+
+// Draw a random constant, with small values more likely than large ones
+fn random_const(rng: &mut Rng, signed: bool) -> u64 {
+    let width = [1, 8, 32, 64][rng.below(4) as usize];
+    let value = rng.next_u64() & mask(width);
+    if signed && width < 64 && value >> (width - 1) & 1 == 1 {
+        value | !mask(width)
+    } else {
+        value
+    }
+}
+
+/// Size of an encoded instruction, in bits
+fn fields_size(fields: &[(u64, usize)]) -> u64 {
+    fields.iter().map(|&(_, w)| w as u64).sum()
+}
+
+fn halt_fields(codes: &Opcodes) -> Result<Vec<(u64, usize)>, String> {
+    let (code, len) = disasm_code(codes, OP_JUMP).ok_or("jump has no encoding")?;
+    // Jump to itself: the offset is relative to the end of the instruction,
+    // whose size depends on the width picked for the offset
+    let mut size = len as u64;
+    loop {
+        let offset = widen(encode_address(-(size as i64)));
+        let new_size = len as u64 + fields_size(&offset);
+        if new_size == size {
+            return Ok([(code, len as usize)].into_iter().chain(offset).collect());
+        }
+        size = new_size;
+    }
+}
+
+/// Generate a random program. With `must_halt`, the program only branches
+/// forward, never calls or returns, never writes PC and ends with a halt
+/// (a jump to itself), so it terminates on any correct emulator
+pub fn generate(config: &ProgenConfig) -> Result<Program, String> {
+    let allowed: Vec<u32> = config.allowed.iter().copied()
+        .filter(|&op| !config.must_halt || ![OP_CALL, OP_RETURN, OP_RETI].contains(&op))
+        .collect();
+    if allowed.is_empty() {
+        return Err("no instruction is allowed".to_string());
+    }
+
+    let halt = halt_fields(&config.opcodes)?;
+    let halt_size = fields_size(&halt);
+    if config.max_size < halt_size {
+        return Err(format!("programs need at least {} bits", halt_size));
+    }
+    if config.max_size > 1 << 15 {
+        return Err("branch offsets are 16-bit, programs are limited to 32768 bits".to_string());
+    }
+
+    let mut rng = Rng::new(config.seed);
+    let mut pending: Vec<Pending> = Vec::new();
+    let mut size = 0;
+    let budget = config.max_size - if config.must_halt { halt_size } else { 0 };
+
+    // Give up after a few instructions in a row that do not fit
+    let mut misses = 0;
+    while misses < 16 {
+        let opcode = allowed[rng.below(allowed.len() as u64) as usize];
+        let instr = encode_random(opcode, &config.opcodes, &mut rng, config.must_halt)?;
+        let instr_size = fields_size(&instr.fields);
+        if size + instr_size > budget {
+            misses += 1;
+            continue;
+        }
+        misses = 0;
+        size += instr_size;
+        pending.push(instr);
+    }
+    if config.must_halt {
+        pending.push(Pending { fields: halt, target: None });
+    }
+
+    // Lay out the instructions, then pick the branch targets
+    let mut instructions = Vec::with_capacity(pending.len());
+    let mut address = 0;
+    for p in &pending {
+        instructions.push(address);
+        address += fields_size(&p.fields);
+    }
+
+    let count = pending.len();
+    for (i, p) in pending.iter_mut().enumerate() {
+        if let Some((field, _)) = p.target {
+            let target = if config.must_halt {
+                i + 1 + rng.below((count - i - 1) as u64) as usize
+            } else {
+                rng.below(count as u64) as usize
+            };
+            let end = instructions[i] + fields_size(&p.fields);
+            let offset = instructions[target] as i64 - end as i64;
+            p.fields[field] = (offset as u64 & 0xffff, 16);
+            p.target = Some((field, target));
+        }
+    }
+
+    let fields = pending.into_iter().flat_map(|p| p.fields).collect();
+    Ok(Program { fields, instructions, size: address })
+}
+
+// Encode one instruction with random arguments. Branch offsets are left
+// as zero and recorded in `target`
+fn encode_random(opcode: u32, codes: &Opcodes, rng: &mut Rng, must_halt: bool) -> Result<Pending, String> {
+    let format = disasm_format(opcode).ok_or(format!("unknown opcode {}", opcode))?;
+    let (code, len) = disasm_code(codes, opcode).ok_or(format!("{} has no encoding", format.mnemonic))?;
+
+    let mut fields = vec![(code, len as usize)];
+    let mut target = None;
+    for arg in [format.arg1, format.arg2, format.arg3] {
+        match arg {
+            ArgType::None => {}
+            ArgType::Register | ArgType::Condition => fields.push((rng.below(8), 3)),
+            ArgType::Direction => fields.push((rng.below(2), 1)),
+            ArgType::Address => {
+                fields.push((PREFIXES[ADDRESS_16].0, PREFIXES[ADDRESS_16].1 as usize));
+                target = Some((fields.len(), 0));
+                fields.push((0, ADDRESS_WIDTHS[ADDRESS_16] as usize));
+            }
+            ArgType::LConst => fields.extend(widen(encode_const(random_const(rng, false), false))),
+            ArgType::AConst => fields.extend(widen(encode_const(random_const(rng, true), true))),
+            ArgType::Shift => match rng.below(64) {
+                0 | 1 => fields.push((1, 1)),
+                n => fields.extend([(0, 1), (n, 6)]),
+            },
+            ArgType::Size => match rng.below(6) {
+                n @ 0..=1 => fields.push((n, 2)),
+                n => fields.extend([(0b10 + (n - 2) / 2, 2), ((n - 2) % 2, 1)]),
+            },
+            // Never write PC or SP in programs that must halt
+            ArgType::Pointer => fields.push((if must_halt { POINTER_A0 + rng.below(2) } else { rng.below(4) }, 2)),
+        }
+    }
+    Ok(Pending { fields, target })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{disasm_one, disasm_opcode, disasm_target};
+
+    #[test]
+    fn test_generate() {
+        let config = ProgenConfig { seed: 42, max_size: 2048, ..ProgenConfig::default() };
+        let program = generate(&config).unwrap();
+        assert_eq!(generate(&config).unwrap(), program);
+        assert_ne!(generate(&ProgenConfig { seed: 43, ..config.clone() }).unwrap(), program);
+        assert!(program.size <= 2048);
+        assert_eq!(program.to_text().len() as u64, program.size);
+
+        // Every instruction decodes, and decoding lands on the next one
+        let mut mem = Memory::new(4096, 1024, 1024, 1024);
+        program.load(&mut mem, 0);
+        for pair in program.instructions.windows(2) {
+            let mut ptr = pair[0];
+            assert!(disasm_one(&mem, &mut ptr).is_some());
+            assert_eq!(ptr, pair[1]);
+        }
+
+        // The program ends with a jump to itself
+        let last = *program.instructions.last().unwrap();
+        let mut ptr = last;
+        assert_eq!(disasm_opcode(&mem, &mut ptr).0, OP_JUMP);
+        assert_eq!(disasm_target(&mem, last), Some(last));
+
+        assert!(generate(&ProgenConfig { allowed: vec![], ..config }).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//---
+// emu:replay - record the inputs of a run and replay them
+//
+// Everything a program sees from outside comes through device registers
+// (key events, bytes from the serial port, the host time of the clock) or
+// through rand, which draws from the seeded generator of the CPU.
+// Recording logs every value the program reads from a device, with the
+// instruction count of the read, its address and its size, and every value
+// rand returns with its instruction count; replaying feeds the logged
+// values back instead of asking the devices and the generator, so the run
+// is identical bit for bit whatever happens on the host and whatever the
+// seed. The CPU reads through the session on memory accesses that reach a
+// device (see CPU::access) and on rand; the debugger panels and the screen
+// do not.
+//
+// Logs are text, one read per line:
+//
+//     # emu replay log: cycle address bits value
+//     1042 0xfcc0 16 0x8116
+//     1057 rand 0x3c9e0d2f41a7b658
+//
+// A replay that reads elsewhere or at another cycle than the log says has
+// diverged (another program, other options); from then on the devices
+// are read live again and the first difference is reported.
+//---
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, LineWriter, Write};
+use std::sync::{Arc, Mutex};
+
+const REPLAY_HEADER: &str = "# emu replay log: cycle address bits value";
+
+/// Where a logged value comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Device { address: u64, n: usize },
+    Rand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Input {
+    pub cycle: u64,    // Instructions executed before the read
+    pub source: Source,
+    pub value: u64,
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source {
+            Source::Device { address, n } => write!(f, "{} {:#x} {} {:#x}", self.cycle, address, n, self.value),
+            Source::Rand => write!(f, "{} rand {:#x}", self.cycle, self.value),
+        }
+    }
+}
+
+impl Input {
+    fn parse(line: &str) -> Option<Input> {
+        let number = |s: &str| match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let [cycle, "rand", value] = words[..] {
+            return Some(Input { cycle: number(cycle)?, source: Source::Rand, value: number(value)? });
+        }
+        let fields: Vec<u64> = words.into_iter().map(number).collect::<Option<_>>()?;
+        match fields[..] {
+            [cycle, address, n, value] if (1..=64).contains(&n) =>
+                Some(Input { cycle, source: Source::Device { address, n: n as usize }, value }),
+            _ => None,
+        }
+    }
+}
+
+enum Mode {
+    Record(Box<dyn Write + Send>),
+    Replay(VecDeque<Input>),
+}
+
+struct SessionState {
+    mode: Mode,
+    diverged: Option<String>,  // First difference with the log, when replaying
+}
+
+/// A recording or a replay, shared by the cores of a machine
+#[derive(Clone)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.0.lock().unwrap();
+        match &state.mode {
+            Mode::Record(_) => write!(f, "Session(recording)"),
+            Mode::Replay(inputs) => write!(f, "Session(replaying, {} inputs left)", inputs.len()),
+        }
+    }
+}
+
+impl Session {
+    /// Record the inputs of the run to a log, written as they happen
+    pub fn record(filename: &str) -> io::Result<Session> {
+        let mut out = LineWriter::new(File::create(filename)?);
+        writeln!(out, "{}", REPLAY_HEADER)?;
+        Ok(Session::new(Mode::Record(Box::new(out))))
+    }
+
+    /// Replay the inputs of a log
+    pub fn replay(filename: &str) -> Result<Session, String> {
+        let text = fs::read_to_string(filename).map_err(|e| e.to_string())?;
+        Session::from_log(&text)
+    }
+
+    /// Replay the inputs of the text of a log
+    pub fn from_log(text: &str) -> Result<Session, String> {
+        let mut inputs = VecDeque::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let input = Input::parse(line)
+                .ok_or_else(|| format!("line {}: expected cycle address bits value: {}", number + 1, line))?;
+            inputs.push_back(input);
+        }
+        Ok(Session::new(Mode::Replay(inputs)))
+    }
+
+    fn new(mode: Mode) -> Session {
+        Session(Arc::new(Mutex::new(SessionState { mode, diverged: None })))
+    }
+
+    /// Read n bits of a device at an address: `live` reads the device.
+    /// Recording logs its value; replaying returns the logged one, unless
+    /// the run has diverged from the log
+    pub fn read(&self, cycle: u64, address: u64, n: usize, live: impl FnOnce() -> u64) -> u64 {
+        self.input(cycle, Source::Device { address, n }, live)
+    }
+
+    /// The value of a rand: `live` draws it from the generator, like read
+    pub fn rand(&self, cycle: u64, live: impl FnOnce() -> u64) -> u64 {
+        self.input(cycle, Source::Rand, live)
+    }
+
+    fn input(&self, cycle: u64, source: Source, live: impl FnOnce() -> u64) -> u64 {
+        let mut state = self.0.lock().unwrap();
+        let state = &mut *state;
+        match &mut state.mode {
+            Mode::Record(out) => {
+                let value = live();
+                let _ = writeln!(out, "{}", Input { cycle, source, value });
+                value
+            }
+            Mode::Replay(_) if state.diverged.is_some() => live(),
+            Mode::Replay(inputs) => match inputs.pop_front() {
+                Some(input) if (input.cycle, input.source) == (cycle, source) => input.value,
+                next => {
+                    let expected = next.map_or("the end of the log".to_string(), |i| i.to_string());
+                    let read = match source {
+                        Source::Device { address, n } => format!("read {} bits at {:#x}", n, address),
+                        Source::Rand => "rand".to_string(),
+                    };
+                    let message = format!("{} at cycle {}, the log has {}", read, cycle, expected);
+                    eprintln!("warning: replay diverged: {}", message);
+                    state.diverged = Some(message);
+                    live()
+                }
+            },
+        }
+    }
+
+    /// How the replay diverged from the log, if it did
+    pub fn diverged(&self) -> Option<String> {
+        self.0.lock().unwrap().diverged.clone()
+    }
+
+    /// Logged inputs the replay has not read yet
+    pub fn remaining(&self) -> usize {
+        match &self.0.lock().unwrap().mode {
+            Mode::Record(_) => 0,
+            Mode::Replay(inputs) => inputs.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{A0, A1, CPU, PC};
+    use crate::devices::{attach_keyboard, Keyboard, KEYBOARD_EVENT};
+    use crate::memory::Memory;
+    use crate::testing::assemble_str;
+    use crate::util::Rng;
+
+    // Run two event reads of the keyboard, feeding it keys if given
+    fn run(session: &Session, keys: &[u32]) -> [u64; 2] {
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let keyboard = Keyboard::new();
+        let base = attach_keyboard(&mut memory.lock().unwrap(), &keyboard);
+        for &key in keys {
+            keyboard.key(key, true);
+        }
+
+        let program = assemble_str("readze a0 16 r1\nreadze a1 16 r2\nend: jump end");
+        let entry = memory.lock().unwrap().load_bytes(&program).unwrap().map_or(0, |o| o.entry);
+        let mut cpu = CPU::new(Arc::clone(&memory));
+        cpu.ptr[PC] = entry;
+        cpu.ptr[A0] = base + KEYBOARD_EVENT;
+        cpu.ptr[A1] = base + KEYBOARD_EVENT;
+        cpu.replay = Some(session.clone());
+        for _ in 0..3 {
+            cpu.execute();
+        }
+        [cpu.r[1], cpu.r[2]]
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let log = std::env::temp_dir().join(format!("emu-replay-{}.log", std::process::id()));
+        let log = log.to_str().unwrap();
+        let recorded = run(&Session::record(log).unwrap(), &[4, 5]);
+        assert_eq!(recorded, [0x8104, 0x8105]);
+
+        // No keys this time, the log has them
+        let text = fs::read_to_string(log).unwrap();
+        fs::remove_file(log).unwrap();
+        assert!(text.starts_with(REPLAY_HEADER));
+        let session = Session::from_log(&text).unwrap();
+        assert_eq!(run(&session, &[]), recorded);
+        assert_eq!((session.diverged(), session.remaining()), (None, 0));
+
+        // Another program reads elsewhere: the devices are read live
+        let session = Session::from_log("0 0x10 8 1\n").unwrap();
+        assert_eq!(run(&session, &[6]), [0x8106, 0]);
+        assert!(session.diverged().unwrap().ends_with("the log has 0 0x10 8 0x1"));
+
+        assert!(Session::from_log("1 2 3").is_err());
+        assert!(Session::from_log("1 2 65 4").is_err());
+    }
+
+    // Run a program drawing three random numbers, from a generator seeded
+    // with seed
+    fn run_rand(session: &Session, seed: u64) -> [u64; 3] {
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let program = assemble_str("rand r1\nrand r2\nrand r3\nend: jump end");
+        let entry = memory.lock().unwrap().load_bytes(&program).unwrap().map_or(0, |o| o.entry);
+        let mut cpu = CPU::new(Arc::clone(&memory));
+        cpu.ptr[PC] = entry;
+        cpu.rng = Rng::new(seed);
+        cpu.replay = Some(session.clone());
+        for _ in 0..4 {
+            cpu.execute();
+        }
+        [cpu.r[1], cpu.r[2], cpu.r[3]]
+    }
+
+    #[test]
+    fn test_rand() {
+        let log = std::env::temp_dir().join(format!("emu-replay-rand-{}.log", std::process::id()));
+        let log = log.to_str().unwrap();
+        let recorded = run_rand(&Session::record(log).unwrap(), 1);
+        assert_ne!(recorded[0], recorded[1]);
+
+        // Another seed, the log has the values
+        let text = fs::read_to_string(log).unwrap();
+        fs::remove_file(log).unwrap();
+        assert_eq!(text.lines().nth(1), Some(format!("0 rand {:#x}", recorded[0]).as_str()));
+        let session = Session::from_log(&text).unwrap();
+        assert_eq!(run_rand(&session, 2), recorded);
+        assert_eq!((session.diverged(), session.remaining()), (None, 0));
+
+        // A device read in the log where the program draws
+        let session = Session::from_log("0 0x10 8 1\n").unwrap();
+        run_rand(&session, 1);
+        assert!(session.diverged().unwrap().starts_with("rand at cycle 0"));
+    }
+}
@@ -0,0 +1,113 @@
+//---
+// emu:scheduler - deterministic device ticking
+//
+// Devices that act over time (DMA, screen vsync, RTC...) are ticked by the
+// machine loop on emulated cycles, never on host time, so a program sees
+// the same sequence of device events on every run and every host. Devices
+// due on the same cycle run by priority (lower first), then in the order
+// they were added.
+//---
+
+use crate::memory::{Memory, MEMORY_VECTOR_COUNT};
+
+/// A device ticked by the scheduler
+pub trait Device: Send {
+    fn name(&self) -> &str;
+
+    /// Called every `interval` cycles; may return an interrupt to raise
+    fn tick(&mut self, cycle: u64, memory: &mut Memory) -> Option<usize>;
+}
+
+struct Entry {
+    device: Box<dyn Device>,
+    interval: u64,  // Cycles between two ticks
+    priority: i32,  // Lower ticks first
+    next: u64,      // Cycle of the next tick
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    entries: Vec<Entry>,  // Sorted by priority, then insertion order
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { entries: Vec::new() }
+    }
+
+    /// Tick `device` every `interval` cycles, the first time at `interval`
+    pub fn add(&mut self, device: Box<dyn Device>, interval: u64, priority: i32) {
+        assert!(interval > 0, "device interval must be at least one cycle");
+        let position = self.entries.iter().position(|e| e.priority > priority)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(position, Entry { device, interval, priority, next: interval });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Devices in tick order, with their interval and priority
+    pub fn devices(&self) -> Vec<(&str, u64, i32)> {
+        self.entries.iter().map(|e| (e.device.name(), e.interval, e.priority)).collect()
+    }
+
+    /// Tick the devices due at `cycle` and return the interrupts they
+    /// raised, in tick order. A device that fell behind (e.g. the machine
+    /// loop skipped cycles) is ticked once and rescheduled after `cycle`.
+    pub fn run(&mut self, cycle: u64, memory: &mut Memory) -> Vec<usize> {
+        let mut irqs = Vec::new();
+        for entry in self.entries.iter_mut().filter(|e| e.next <= cycle) {
+            if let Some(irq) = entry.device.tick(cycle, memory) {
+                assert!(irq < MEMORY_VECTOR_COUNT, "{} raised invalid irq {}", entry.device.name(), irq);
+                irqs.push(irq);
+            }
+            while entry.next <= cycle {
+                entry.next += entry.interval;
+            }
+        }
+        irqs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Probe {
+        name: &'static str,
+        log: Arc<Mutex<Vec<(u64, &'static str)>>>,
+        irq: Option<usize>,
+    }
+
+    impl Device for Probe {
+        fn name(&self) -> &str { self.name }
+
+        fn tick(&mut self, cycle: u64, _memory: &mut Memory) -> Option<usize> {
+            self.log.lock().unwrap().push((cycle, self.name));
+            self.irq
+        }
+    }
+
+    #[test]
+    fn test_tick_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let probe = |name, irq| Box::new(Probe { name, log: Arc::clone(&log), irq });
+        let mut mem = Memory::new(1024, 1024, 1024, 1024);
+
+        let mut scheduler = Scheduler::new();
+        scheduler.add(probe("rtc", None), 4, 1);
+        scheduler.add(probe("vsync", Some(1)), 2, 0);
+        scheduler.add(probe("dma", Some(2)), 4, 1);
+
+        let mut irqs = Vec::new();
+        for cycle in 1..=4 {
+            irqs.extend(scheduler.run(cycle, &mut mem));
+        }
+
+        assert_eq!(*log.lock().unwrap(), vec![(2, "vsync"), (4, "vsync"), (4, "rtc"), (4, "dma")]);
+        assert_eq!(irqs, vec![1, 1, 2]);
+        assert_eq!(scheduler.devices()[0], ("vsync", 2, 0));
+    }
+}
@@ -0,0 +1,211 @@
+//---
+// emu:screencmp - compare the emulated screen against reference images
+//
+// Used to autograde graphical exercises: the program is run headlessly for
+// a number of frames, then the VRAM contents are compared to a PPM image.
+// Capture saves the screen every so many cycles, without a display.
+//---
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use minimisa_core::image;
+use crate::cpu::CPU;
+use crate::memory::Memory;
+use crate::scheduler::Device;
+use crate::vram::ScreenFormat;
+
+// Number of instructions executed per emulated frame in headless mode
+pub const CYCLES_PER_FRAME: usize = 10000;
+
+/// RGB888 image, row-major
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    /// Extract the screen from the VRAM segment
+    pub fn from_vram(memory: &Memory) -> Image {
+        Image::from_screen(memory, &ScreenFormat::EMU)
+    }
+
+    /// Extract a screen of another format from the VRAM segment
+    pub fn from_screen(memory: &Memory, format: &ScreenFormat) -> Image {
+        Image { width: format.width, height: format.height, pixels: format.render(memory) }
+    }
+
+    /// Load a binary PPM (P6) file with 8-bit channels
+    pub fn load_ppm(filename: &str) -> io::Result<Image> {
+        let mut reader = BufReader::new(File::open(filename)?);
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", filename, msg));
+
+        // Header fields are whitespace-separated, with # comments
+        let mut fields = Vec::new();
+        while fields.len() < 4 {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(invalid("truncated header"));
+            }
+            let line = line.split('#').next().unwrap_or("");
+            fields.extend(line.split_whitespace().map(|f| f.to_string()));
+        }
+
+        if fields[0] != "P6" {
+            return Err(invalid("not a binary PPM file"));
+        }
+        let width: usize = fields[1].parse().map_err(|_| invalid("bad width"))?;
+        let height: usize = fields[2].parse().map_err(|_| invalid("bad height"))?;
+        if fields[3] != "255" {
+            return Err(invalid("only 8-bit channels are supported"));
+        }
+
+        let mut data = vec![0u8; width * height * 3];
+        reader.read_exact(&mut data)?;
+        let pixels = data.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+
+        Ok(Image { width, height, pixels })
+    }
+
+    pub fn save_ppm(&self, filename: &str) -> io::Result<()> {
+        image::write_ppm(&mut File::create(filename)?, self.width, self.height, &self.pixels)
+    }
+
+    /// Save as PNG if the file name ends in .png, PPM otherwise
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        image::save(filename, self.width, self.height, &self.pixels)
+    }
+}
+
+/// Device saving the screen as numbered frames (frame-0001.png,
+/// frame-0002.png and so on for frame.png) each time the scheduler ticks it
+pub struct Capture {
+    filename: String,
+    format: ScreenFormat,
+    frames: usize,  // Frames written so far
+}
+
+impl Capture {
+    pub fn new(filename: &str, format: ScreenFormat) -> Capture {
+        Capture { filename: filename.to_string(), format, frames: 0 }
+    }
+}
+
+impl Device for Capture {
+    fn name(&self) -> &str {
+        "capture"
+    }
+
+    fn tick(&mut self, _cycle: u64, memory: &mut Memory) -> Option<usize> {
+        self.frames += 1;
+        let filename = image::numbered(&self.filename, self.frames);
+        if let Err(e) = Image::from_screen(memory, &self.format).save(&filename) {
+            eprintln!("error: cannot write {}: {}", filename, e);
+        }
+        None
+    }
+}
+
+/// Compare two images; a pixel matches when every channel is within
+/// `tolerance`. Returns the number of mismatching pixels and a diff image
+/// where mismatches are red over a dimmed copy of the reference.
+pub fn compare_images(actual: &Image, reference: &Image, tolerance: u8) -> Option<(usize, Image)> {
+    if actual.width != reference.width || actual.height != reference.height {
+        return None;
+    }
+
+    let mut mismatches = 0;
+    let mut diff = Vec::with_capacity(actual.pixels.len());
+
+    for (a, r) in actual.pixels.iter().zip(reference.pixels.iter()) {
+        let differs = (0..3).any(|c| a[c].abs_diff(r[c]) > tolerance);
+        if differs {
+            mismatches += 1;
+            diff.push([255, 0, 0]);
+        } else {
+            let grey = ((r[0] as u16 + r[1] as u16 + r[2] as u16) / 12) as u8;
+            diff.push([grey, grey, grey]);
+        }
+    }
+
+    Some((mismatches, Image { width: actual.width, height: actual.height, pixels: diff }))
+}
+
+/// Run the program for `frames` frames (or until it halts) and compare the
+/// final screen, read in `format`. Returns the process exit code: 0 on
+/// match, 1 on mismatch, 2 if the reference cannot be used.
+pub fn compare_screens(cpu: &mut CPU, format: &ScreenFormat, frames: usize, reference: &str, tolerance: u8,
+    diff_file: &str) -> i32 {
+    for _ in 0..frames * CYCLES_PER_FRAME {
+        cpu.execute();
+        if cpu.h {
+            break;
+        }
+    }
+
+    let reference = match Image::load_ppm(reference) {
+        Ok(img) => img,
+        Err(e) => {
+            eprintln!("error: cannot load reference image: {}", e);
+            return 2;
+        }
+    };
+    let actual = Image::from_screen(&cpu.mem.lock().unwrap(), format);
+
+    match compare_images(&actual, &reference, tolerance) {
+        None => {
+            eprintln!("error: reference is {}x{}, screen is {}x{}",
+                reference.width, reference.height, actual.width, actual.height);
+            2
+        }
+        Some((0, _)) => 0,
+        Some((mismatches, diff)) => {
+            eprintln!("screen differs from reference on {} pixels, diff written to {}", mismatches, diff_file);
+            if let Err(e) = diff.save_ppm(diff_file) {
+                eprintln!("error: cannot write diff image: {}", e);
+            }
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(color: [u8; 3]) -> Image {
+        Image { width: 2, height: 2, pixels: vec![color; 4] }
+    }
+
+    #[test]
+    fn test_compare_images() {
+        let (n, _) = compare_images(&solid([10, 10, 10]), &solid([12, 9, 10]), 2).unwrap();
+        assert_eq!(n, 0);
+
+        let (n, diff) = compare_images(&solid([10, 10, 10]), &solid([20, 10, 10]), 2).unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(diff.pixels[0], [255, 0, 0]);
+
+        let other = Image { width: 1, height: 4, pixels: vec![[0; 3]; 4] };
+        assert!(compare_images(&solid([0; 3]), &other, 0).is_none());
+    }
+
+    #[test]
+    fn test_capture() {
+        let dir = std::env::temp_dir().join(format!("minimisa-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("frame.ppm");
+
+        let mut memory = Memory::new(0, 0, 0, 0);
+        let mut capture = Capture::new(filename.to_str().unwrap(), ScreenFormat::EMU);
+        capture.tick(100, &mut memory);
+        memory.write(memory.vram_base(), 0xf800, 16);
+        capture.tick(200, &mut memory);
+
+        let first = Image::load_ppm(dir.join("frame-0001.ppm").to_str().unwrap()).unwrap();
+        let second = Image::load_ppm(dir.join("frame-0002.ppm").to_str().unwrap()).unwrap();
+        assert_eq!((first.pixels[0], second.pixels[0]), ([0, 0, 0], [255, 0, 0]));
+        assert_eq!(second.pixels, Image::from_vram(&memory).pixels);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
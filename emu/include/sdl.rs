@@ -0,0 +1,116 @@
+//---
+// emu:sdl - screens in SDL windows
+//
+// The Screen of --display sdl (see display.rs): a window showing frames
+// scaled up, whose key events are reported by scancode, and which plays
+// the audio it was given while it is open.
+//---
+
+extern crate sdl2;
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::{EventPump, Sdl};
+use crate::display::{AudioFill, Screen, ScreenEvent};
+
+// SDL callback playing audio
+struct Playback {
+    fill: AudioFill,
+    rate: u32,  // Samples per second
+}
+
+impl AudioCallback for Playback {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        (self.fill)(out, self.rate);
+    }
+}
+
+// What an open window needs; the context goes last, after its users
+struct OpenWindow {
+    canvas: Canvas<Window>,
+    events: EventPump,
+    _audio: Option<AudioDevice<Playback>>,
+    _context: Sdl,
+}
+
+pub struct SdlScreen {
+    scale: u32,
+    width: usize,
+    height: usize,
+    audio: Option<AudioFill>,  // Played from init on
+    window: Option<OpenWindow>,
+}
+
+impl SdlScreen {
+    pub fn new(scale: u32, audio: Option<AudioFill>) -> SdlScreen {
+        SdlScreen { scale, width: 0, height: 0, audio, window: None }
+    }
+}
+
+impl Screen for SdlScreen {
+    fn init(&mut self, width: usize, height: usize) -> Result<(), String> {
+        let context = sdl2::init()?;
+        let video = context.video()?;
+        let window = video
+            .window("Graphical Window", width as u32 * self.scale, height as u32 * self.scale)
+            .position_centered()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().present_vsync().build().map_err(|e| e.to_string())?;
+        let events = context.event_pump()?;
+
+        // The audio callback runs on its own SDL thread until the device
+        // is dropped with the window
+        let audio = self.audio.take().and_then(|fill| {
+            let desired = AudioSpecDesired { freq: Some(44100), channels: Some(1), samples: None };
+            let device = context.audio()
+                .and_then(|a| a.open_playback(None, &desired, |spec| Playback { fill, rate: spec.freq as u32 }));
+            match device {
+                Ok(device) => {
+                    device.resume();
+                    Some(device)
+                }
+                Err(e) => {
+                    eprintln!("warning: no audio: {}", e);
+                    None
+                }
+            }
+        });
+
+        self.width = width;
+        self.height = height;
+        self.window = Some(OpenWindow { canvas, events, _audio: audio, _context: context });
+        Ok(())
+    }
+
+    fn blit(&mut self, pixels: &[[u8; 3]]) {
+        let Some(window) = &mut self.window else { return };
+        let creator = window.canvas.texture_creator();
+        let Ok(mut texture) = creator.create_texture_streaming(PixelFormatEnum::RGB24, self.width as u32, self.height as u32) else {
+            return;
+        };
+        texture.update(None, &pixels.concat(), self.width * 3).expect("Failed to update texture");
+        window.canvas.clear();
+        window.canvas.copy(&texture, None, None).unwrap();
+        window.canvas.present();
+    }
+
+    fn poll_events(&mut self) -> Vec<ScreenEvent> {
+        let Some(window) = &mut self.window else { return Vec::new() };
+        window.events.poll_iter().filter_map(|event| match event {
+            Event::Quit { .. } => Some(ScreenEvent::Quit),
+            Event::KeyDown { scancode: Some(s), repeat: false, .. } => Some(ScreenEvent::Key(s as i32 as u32, true)),
+            Event::KeyUp { scancode: Some(s), .. } => Some(ScreenEvent::Key(s as i32 as u32, false)),
+            _ => None,
+        }).collect()
+    }
+
+    fn shutdown(&mut self) {
+        self.window = None;
+    }
+}
@@ -0,0 +1,139 @@
+//---
+// emu:snapshot - saved machine states and what changed between them
+//
+// A snapshot holds the registers of a core and a copy of RAM; devices are
+// not saved. diff() lists the registers and flags that differ and the bit
+// ranges of memory that do, split and named by segment, e.g. to check that
+// a routine only writes the memory it should:
+//
+//     (emu) snapshot before
+//     (emu) finish
+//     (emu) diff before
+//     r0   0x0000000000000005 -> 0x0000000000000006
+//     stack  0x00010f80..0x00010fc0  64 bits
+//---
+
+use std::ops::Range;
+use crate::cpu::CPU;
+use crate::journal::CpuState;
+use crate::memory::Memory;
+use minimisa_core::pages::Pages;
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub state: CpuState,
+    pub ram: Pages,
+}
+
+impl Snapshot {
+    pub fn take(cpu: &CPU, memory: &Memory) -> Snapshot {
+        Snapshot { state: cpu.state(), ram: memory.ram().clone() }
+    }
+}
+
+/// What changed from one snapshot to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    pub registers: Vec<(&'static str, u64, u64)>,   // Name, before, after
+    pub memory: Vec<Range<u64>>,                    // Bit ranges, in address order
+}
+
+const REGISTER_NAMES: [&str; 8] = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7"];
+const POINTER_NAMES: [&str; 4] = ["pc", "sp", "a0", "a1"];
+
+// Registers and flags of a state, by name
+fn registers(state: &CpuState) -> Vec<(&'static str, u64)> {
+    let flags = [("z", state.z), ("n", state.n), ("c", state.c), ("v", state.v)];
+    REGISTER_NAMES.iter().copied().zip(state.r)
+        .chain(POINTER_NAMES.iter().copied().zip(state.ptr))
+        .chain(flags.into_iter().map(|(name, flag)| (name, flag as u64)))
+        .collect()
+}
+
+/// Differences from `before` to `after`. Memory ranges go from the first to
+/// the last changed bit of each word, and ranges that meet are merged
+pub fn diff(before: &Snapshot, after: &Snapshot) -> Diff {
+    let registers = registers(&before.state).into_iter().zip(registers(&after.state))
+        .filter(|((_, a), (_, b))| a != b)
+        .map(|((name, a), (_, b))| (name, a, b))
+        .collect();
+
+    // Bit a is bit 63 - (a % 64) of word a / 64, see Memory
+    let mut memory: Vec<Range<u64>> = Vec::new();
+    for index in before.ram.changed_words(&after.ram) {
+        let changed = before.ram.word(index) ^ after.ram.word(index);
+        let start = 64 * index + changed.leading_zeros() as u64;
+        let end = 64 * index + 64 - changed.trailing_zeros() as u64;
+        match memory.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => memory.push(start..end),
+        }
+    }
+    Diff { registers, memory }
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.memory.is_empty()
+    }
+
+    /// The differences as text, one per line: registers, then memory
+    /// ranges split at segment boundaries, with their segment
+    pub fn format(&self, memory: &Memory) -> String {
+        let mut out = String::new();
+        for (name, before, after) in &self.registers {
+            out.push_str(&format!("{:<4} {:#018x} -> {:#018x}\n", name, before, after));
+        }
+        let ends = [memory.text_size(), memory.data_base(), memory.vram_base(), memory.vram_base() + memory.vram_size()];
+        for range in &self.memory {
+            let mut start = range.start;
+            while start < range.end {
+                let end = ends.iter().copied().find(|&e| e > start).unwrap_or(u64::MAX).min(range.end);
+                let segment = memory.segment(start);
+                out.push_str(&format!("{:<6} {:#010x}..{:#010x}  {} bits\n", segment.name(), start, end, end - start));
+                start = end;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::memory::Segment;
+
+    #[test]
+    fn test_diff() {
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let mut cpu = CPU::new(Arc::clone(&memory));
+        let before = Snapshot::take(&cpu, &memory.lock().unwrap());
+        assert!(diff(&before, &before).is_empty());
+
+        cpu.r[3] = 5;
+        cpu.z = true;
+        let (data, stack_top) = {
+            let mut memory = memory.lock().unwrap();
+            let (data, stack_top) = (memory.data_base(), memory.data_base() - 8);
+            // Across the boundary between stack and data
+            memory.write(stack_top, 0xffff, 16);
+            memory.write(200, 0b11, 2);
+            (data, stack_top)
+        };
+        let after = Snapshot::take(&cpu, &memory.lock().unwrap());
+        let changes = diff(&before, &after);
+        assert_eq!(changes.registers, [("r3", 0, 5), ("z", 0, 1)]);
+        assert_eq!(changes.memory, [200..202, stack_top..data + 8]);
+
+        let text = changes.format(&memory.lock().unwrap());
+        assert!(text.starts_with("r3   0x0000000000000000 -> 0x0000000000000005\nz    "), "{}", text);
+        assert!(text.contains("text   0x000000c8..0x000000ca  2 bits\n"), "{}", text);
+        assert!(text.contains(&format!("stack  {:#010x}..{:#010x}  8 bits\ndata   {:#010x}..{:#010x}  8 bits\n",
+            stack_top, data, data, data + 8)), "{}", text);
+        assert_eq!(memory.lock().unwrap().segment(stack_top), Segment::Stack);
+
+        // Going back shows the same changes the other way
+        assert_eq!(diff(&after, &before).memory, changes.memory);
+    }
+}
@@ -0,0 +1,142 @@
+//---
+// emu:testing - assemble and run programs from tests
+//
+// Programs are assembled by the compiler, as a library, with the default
+// opcode table, and a runner loads them into a fresh machine. Nothing
+// touches the filesystem, so that a test can do:
+//
+//     let state = run_program(&assemble_str("leti r0 5\nend: jump end"), 100);
+//     assert_eq!(state.cpu.r[0], 5);
+//
+// The source is in the language of the compiler: operations (add, jump...)
+// or instruction names (add2i, jumpif...), pseudo-instructions, labels,
+// macros and directives. Labels may be followed by an instruction on the
+// same line.
+//---
+
+use std::sync::{Arc, Mutex};
+use minimisa_compiler::compileuh::{compile_object, OpcodeTable};
+use minimisa_compiler::pseudo::PseudoOptions;
+use minimisa_core::object::Object;
+use crate::cpu::{CPU, PC, SP};
+use crate::journal::CpuState;
+use crate::memory::Memory;
+
+/// Assemble a program with the compiler (see compiler/compileuh.rs), into
+/// an object with the default opcode table
+pub fn assemble(source: &str) -> Result<Object, String> {
+    compile_object(source, &OpcodeTable::Default, &PseudoOptions::default())
+}
+
+/// Bytes of the object of a program, panicking on assembly errors
+pub fn assemble_str(source: &str) -> Vec<u8> {
+    assemble(source).unwrap_or_else(|e| panic!("assembly failed:\n{}", e)).to_bytes()
+}
+
+/// State of the machine once a program has run
+#[derive(Debug)]
+pub struct MachineState {
+    pub cpu: CpuState,
+    pub halted: bool,   // Stopped on a one-instruction loop or an unknown instruction
+    pub steps: usize,   // Instructions executed
+    pub memory: Memory,
+}
+
+/// Load a program (object or bare binary) into a machine with the default
+/// memory layout and run it for at most max_steps instructions. The stack
+/// grows down from the start of the data segment
+pub fn run_program(program: &[u8], max_steps: usize) -> MachineState {
+    run_program_with(program, max_steps, |_, _| ())
+}
+
+/// run_program(), with a chance to set up the machine (attach devices,
+/// set registers) once the program is loaded
+pub fn run_program_with(program: &[u8], max_steps: usize, setup: impl FnOnce(&mut CPU, &mut Memory)) -> MachineState {
+    let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+    let object = memory.lock().unwrap().load_bytes(program)
+        .unwrap_or_else(|e| panic!("cannot load program: {}", e));
+
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    cpu.ptr[SP] = memory.lock().unwrap().data_base();
+    if let Some(object) = object {
+        if let Some(codes) = &object.opcodes {
+            memory.lock().unwrap().set_opcodes(codes)
+                .unwrap_or_else(|e| panic!("cannot use the opcode table: {}", e));
+        }
+        cpu.ptr[PC] = object.entry;
+    }
+    setup(&mut cpu, &mut memory.lock().unwrap());
+
+    let mut steps = 0;
+    while steps < max_steps && !cpu.h {
+        cpu.execute();
+        steps += 1;
+    }
+
+    let (state, halted) = (cpu.state(), cpu.h);
+    drop(cpu);
+    let memory = Arc::try_unwrap(memory).expect("memory still shared").into_inner().unwrap();
+    MachineState { cpu: state, halted, steps, memory }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let program = assemble_str("
+            leti r0 5
+            leti r1 37      ; on 8 bits
+            add2 r0 r1
+            call double
+            add2i r0 1
+        end:
+            jump end
+        double:
+            add2 r0 r0
+            return
+        ");
+        let state = run_program(&program, 100);
+        assert!(state.halted);
+        assert_eq!(state.cpu.r[0], 2 * (5 + 37) + 1);
+        assert_eq!(state.cpu.ptr[SP], state.memory.data_base());
+
+        // The return address is still below the stack pointer
+        let end = assemble("leti r0 5\nleti r1 37\nadd2 r0 r1\ncall 0").unwrap().text().unwrap().length;
+        assert_eq!(state.memory.read(state.memory.data_base() - 64, 64), end);
+    }
+
+    #[test]
+    fn test_signed_immediates() {
+        // Immediates of add and sub are signed, -1 on a single bit
+        let program = assemble_str("
+            leti r0 5
+            add2i r0 -1
+            add2i r0 -300
+        end:
+            jump end
+        ");
+        let state = run_program(&program, 10);
+        assert_eq!(state.cpu.r[0] as i64, 5 - 1 - 300);
+        assert_eq!(assemble("add2i r0 -1").unwrap().text().unwrap().length, 4 + 3 + 1 + 1);
+        assert!(assemble("and2i r0 -1").is_err());
+    }
+
+    #[test]
+    fn test_labels() {
+        // 200 bits of code between a jump and its label need 16 bits
+        let filler = "add2i r0 0\n".repeat(20);
+        let source = format!("jump skip\n{}skip: jump skip", filler);
+        let object = assemble(&source).unwrap();
+        let ins = 4 + 3 + 1 + 1;
+        assert_eq!(object.text().unwrap().length, (4 + 18) + 20 * ins + (4 + 9));
+        let state = run_program(&object.to_bytes(), 10);
+        assert_eq!((state.cpu.r[0], state.steps), (0, 2));
+
+        // Errors are reported as the compiler prints them
+        assert!(assemble("jump nowhere").unwrap_err().contains("undefined label 'nowhere'"));
+        assert!(assemble("add2 r0").unwrap_err().starts_with("<source>:1:1: error: 'add2' takes 2 argument(s)"));
+        assert!(assemble("\nleti r9 1").unwrap_err().starts_with("<source>:2:6: error: Register out of range"));
+    }
+}
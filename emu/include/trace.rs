@@ -0,0 +1,124 @@
+//---
+// emu:trace - execution traces
+//
+// One line per executed instruction, written as the CPU goes. In text, the
+// address, the disassembly and the registers that changed, like simu -t:
+//
+//     00000011 add2i r0 1 ; r0=0000000000000006 zcnv=0000
+//
+// and in JSON, one object per line with every register, for scripts:
+//
+//     {"cycle":2,"flags":{"c":false,...},"instruction":"add2i r0 1","label":null,"pc":17,"registers":[6,0,...]}
+//
+// The cycle is the number of instructions executed, this one included.
+// Given the symbols of the program, instructions are also located from
+// the labels, as <loop+17> after the address in text.
+//---
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use serde_json::json;
+use crate::disasm::disasm_symbol;
+use crate::journal::CpuState;
+
+pub struct Trace {
+    out: Box<dyn Write + Send>,
+    json: bool,
+    labels: BTreeMap<u64, String>,
+}
+
+impl Trace {
+    pub fn new(out: Box<dyn Write + Send>, json: bool) -> Trace {
+        Trace { out, json, labels: BTreeMap::new() }
+    }
+
+    /// Locate instructions from these labels
+    pub fn with_labels(mut self, labels: BTreeMap<u64, String>) -> Trace {
+        self.labels = labels;
+        self
+    }
+
+    /// Trace to a file, in JSON lines or in text
+    pub fn create(filename: &str, json: bool) -> io::Result<Trace> {
+        Ok(Trace::new(Box::new(BufWriter::new(File::create(filename)?)), json))
+    }
+
+    /// Line of an instruction, given the states before and after it
+    pub fn line(&self, cycle: u64, pc: u64, instruction: &str, before: &CpuState, after: &CpuState) -> String {
+        let label = disasm_symbol(&self.labels, pc);
+        if self.json {
+            return json!({
+                "cycle": cycle,
+                "pc": pc,
+                "label": label,
+                "instruction": instruction,
+                "registers": after.r,
+                "flags": { "z": after.z, "n": after.n, "c": after.c, "v": after.v },
+            }).to_string();
+        }
+        let mut line = match label {
+            Some(label) => format!("{:08x} <{}> {} ;", pc, label, instruction),
+            None => format!("{:08x} {} ;", pc, instruction),
+        };
+        for (i, (old, new)) in before.r.iter().zip(after.r.iter()).enumerate() {
+            if old != new {
+                line += &format!(" r{}={:016x}", i, new);
+            }
+        }
+        line + &format!(" zcnv={}{}{}{}", after.z as u8, after.c as u8, after.n as u8, after.v as u8)
+    }
+
+    pub fn record(&mut self, cycle: u64, pc: u64, instruction: &str, before: &CpuState, after: &CpuState) -> io::Result<()> {
+        let line = self.line(cycle, pc, instruction, before, after);
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::cpu::{CPU, PC};
+    use crate::memory::Memory;
+    use crate::testing::assemble_str;
+
+    #[test]
+    fn test_trace() {
+        let before = CpuState { r: [5, 0, 0, 0, 0, 0, 0, 0], ptr: [0; 4], z: false, n: false, c: false, v: false };
+        let after = CpuState { r: [6, 0, 0, 0, 0, 0, 0, 0], c: true, ..before };
+        let text = Trace::new(Box::new(io::sink()), false);
+        assert_eq!(text.line(2, 0x11, "add2i r0 1", &before, &after),
+            "00000011 add2i r0 1 ; r0=0000000000000006 zcnv=0100");
+        let labels = BTreeMap::from([(0, "main".to_string())]);
+        assert_eq!(text.with_labels(labels.clone()).line(2, 0x11, "add2i r0 1", &before, &after),
+            "00000011 <main+17> add2i r0 1 ; r0=0000000000000006 zcnv=0100");
+
+        let line = Trace::new(Box::new(io::sink()), true).with_labels(labels).line(2, 0x11, "add2i r0 1", &before, &after);
+        let value = json!({
+            "cycle": 2, "pc": 17, "label": "main+17", "instruction": "add2i r0 1", "registers": after.r,
+            "flags": { "z": false, "n": false, "c": true, "v": false },
+        });
+        assert_eq!(line, value.to_string());
+
+        // Final state of a run
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        let object = memory.lock().unwrap().load_bytes(&assemble_str("leti r0 5\nadd2i r0 1\nend: jump end")).unwrap().unwrap();
+        let mut cpu = CPU::new(Arc::clone(&memory));
+        cpu.ptr[PC] = object.entry;
+        cpu.trace = Some(Trace::new(Box::new(io::sink()), true));
+        while !cpu.h {
+            cpu.execute();
+        }
+        let state = cpu.to_json();
+        assert_eq!(state["registers"][0], 6);
+        assert_eq!((state["instructions"].as_u64(), state["halted"].as_bool()), (Some(3), Some(true)));
+        assert!(state["fault"].is_null());
+        let dump = memory.lock().unwrap().dump_json(object.entry..object.entry + 40);
+        assert_eq!((dump[0]["address"].as_u64(), dump[1]["bits"].as_u64()), (Some(object.entry), Some(8)));
+    }
+}
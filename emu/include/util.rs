@@ -1,3 +1,6 @@
+use minimisa_core::WordSize;
+use minimisa_core::WordSize::{W32, W64};
+
 /// Sign-extend from a variable-width storage format.
 ///
 /// Performs sign extension of the value `x`, which is stored in a signed
@@ -12,11 +15,212 @@
 ///
 /// Returns `x` in signed 64-bit format.
 pub fn sign_extend(x: u64, n: u32) -> i64 {
+    if n == 0 {
+        return 0;
+    }
+    if n >= 64 {
+        return x as i64;
+    }
     // Shift x left and right to perform sign extension
     let shift = 64 - n;
     ((x << shift) as i64) >> shift
 }
 
+/// Zero-extend from a variable-width storage format.
+///
+/// Keeps only the `n` low bits of `x`.
+pub fn zero_extend(x: u64, n: u32) -> u64 {
+    match n {
+        0 => 0,
+        64.. => x,
+        _ => x & ((1u64 << n) - 1),
+    }
+}
+
+/// Extend an `n`-bit value read from memory to 64 bits.
+///
+/// This is the behavior shared by `readze` (`signed` false) and `readse`
+/// (`signed` true) in both the emulator and the simulator.
+pub fn read_extend(x: u64, n: u32, signed: bool) -> u64 {
+    if signed {
+        sign_extend(x, n) as u64
+    } else {
+        zero_extend(x, n)
+    }
+}
+
+/// Reference cases for memory reads: (raw bits, size, readze result,
+/// readse result), for every size the instructions can encode.
+pub const READ_EXTEND_CASES: &[(u64, u32, u64, i64)] = &[
+    (0b0, 1, 0, 0),
+    (0b1, 1, 1, -1),
+    (0x7, 4, 7, 7),
+    (0x8, 4, 8, -8),
+    (0xf, 4, 15, -1),
+    (0x7f, 8, 127, 127),
+    (0x80, 8, 128, -128),
+    (0xff, 8, 255, -1),
+    (0x7fff, 16, 32767, 32767),
+    (0x8000, 16, 32768, -32768),
+    (0xfffe, 16, 65534, -2),
+    (0x7fff_ffff, 32, 0x7fff_ffff, 0x7fff_ffff),
+    (0x8000_0000, 32, 0x8000_0000, -0x8000_0000),
+    (0xffff_ffff, 32, 0xffff_ffff, -1),
+    (0x7fff_ffff_ffff_ffff, 64, 0x7fff_ffff_ffff_ffff, i64::MAX),
+    (0x8000_0000_0000_0000, 64, 0x8000_0000_0000_0000, i64::MIN),
+    (0xffff_ffff_ffff_ffff, 64, 0xffff_ffff_ffff_ffff, -1),
+    // Bits above the size are ignored
+    (0x1ff, 8, 255, -1),
+];
+
+/// Flags set by arithmetic and comparisons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+    pub z: bool,  // Zero
+    pub n: bool,  // Negative
+    pub c: bool,  // Carry or borrow
+    pub v: bool,  // Signed overflow
+}
+
+/// Add two words.
+///
+/// Returns the sum, wrapped to the word, and its flags: `z` if it is zero,
+/// `n` if its top bit is set, `c` on a carry out of the top bit and `v` if
+/// the signed sum does not fit. This is add2 and add2i in both the emulator
+/// and the simulator.
+pub fn add_with_flags(x: u64, y: u64, word: WordSize) -> (u64, Flags) {
+    let (x, y) = (word.truncate(x), word.truncate(y));
+    let wide = x as u128 + y as u128;
+    let result = word.truncate(wide as u64);
+    let sign = 1u64 << (word.bits() - 1);
+    (result, Flags {
+        z: result == 0,
+        n: result & sign != 0,
+        c: wide >> word.bits() != 0,
+        v: (x ^ result) & (y ^ result) & sign != 0,
+    })
+}
+
+/// Subtract two words, `x - y`.
+///
+/// Returns the difference, wrapped to the word, and the flags of a
+/// comparison of `x` with `y`: `z` if they are equal, `n` if `x < y` as
+/// signed words, `c` if `x < y` as unsigned words (a borrow) and `v` if the
+/// signed difference does not fit. sub2, sub2i, cmp and cmpi set these.
+pub fn sub_with_flags(x: u64, y: u64, word: WordSize) -> (u64, Flags) {
+    let (x, y) = (word.truncate(x), word.truncate(y));
+    let result = word.truncate(x.wrapping_sub(y));
+    let sign = 1u64 << (word.bits() - 1);
+    (result, Flags {
+        z: x == y,
+        n: word.signed(x) < word.signed(y),
+        c: x < y,
+        v: (x ^ y) & (x ^ result) & sign != 0,
+    })
+}
+
+/// Flags of a bitwise operation (and, or, xor).
+///
+/// `z` is set if the result is zero and `n` if its top bit is set; `c` and
+/// `v` keep their value from `flags`.
+pub fn logic_flags(result: u64, word: WordSize, flags: Flags) -> Flags {
+    let sign = 1u64 << (word.bits() - 1);
+    Flags { z: word.truncate(result) == 0, n: result & sign != 0, ..flags }
+}
+
+/// Shift a word by `amount` bits, 0 to 63.
+///
+/// Returns the result, cut to the word, and the carry: the last bit shifted
+/// out, or None for a shift by 0, which leaves the carry alone. Right shifts
+/// bring in zeros, or copies of the sign bit if `arithmetic` is set. This is
+/// shift and asr3 in both the emulator and the simulator.
+pub fn shift_with_carry(x: u64, amount: u32, right: bool, arithmetic: bool, word: WordSize) -> (u64, Option<bool>) {
+    let x = word.truncate(x);
+    if amount == 0 {
+        return (x, None);
+    }
+    if !right {
+        let wide = (x as u128) << amount;
+        return (word.truncate(wide as u64), Some((wide >> word.bits()) & 1 != 0));
+    }
+    let x = if arithmetic { word.signed(x) } else { x as i64 };
+    let result = if arithmetic { (x >> amount) as u64 } else { (x as u64) >> amount };
+    (word.truncate(result), Some((x as u64 >> (amount - 1)) & 1 != 0))
+}
+
+/// Whether the condition of a jumpif holds, from its 3-bit code: eq, neq,
+/// sgt, slt, gt, ge, lt, v.
+pub fn condition_holds(cond: u32, flags: Flags) -> bool {
+    match cond & 7 {
+        0 => flags.z,
+        1 => !flags.z,
+        2 => !flags.n && !flags.z,
+        3 => flags.n,
+        4 => !flags.c && !flags.z,
+        5 => !flags.c,
+        6 => flags.c,
+        _ => flags.v,
+    }
+}
+
+/// Reference cases for arithmetic: (subtract, x, y, word size, result,
+/// flags set), at the boundaries of 32- and 64-bit words.
+pub const ARITHMETIC_CASES: &[(bool, u64, u64, WordSize, u64, &str)] = &[
+    (false, 1, 2, W32, 3, ""),
+    (false, 0xffff_ffff, 1, W32, 0, "zc"),
+    (false, 0x7fff_ffff, 1, W32, 0x8000_0000, "nv"),
+    (false, 0x8000_0000, 0x8000_0000, W32, 0, "zcv"),
+    (false, 0xffff_ffff, 0xffff_ffff, W32, 0xffff_fffe, "nc"),
+    (false, u64::MAX, 1, W64, 0, "zc"),
+    (false, i64::MAX as u64, 1, W64, 1 << 63, "nv"),
+    (false, 1 << 63, 1 << 63, W64, 0, "zcv"),
+    (true, 5, 5, W32, 0, "z"),
+    (true, 3, 5, W32, 0xffff_fffe, "nc"),
+    (true, 0x8000_0000, 1, W32, 0x7fff_ffff, "nv"),
+    (true, 0x7fff_ffff, 0xffff_ffff, W32, 0x8000_0000, "cv"),
+    (true, 0, 0x8000_0000, W32, 0x8000_0000, "cv"),
+    (true, 0, 1, W64, u64::MAX, "nc"),
+    (true, 1 << 63, 1, W64, i64::MAX as u64, "nv"),
+    (true, u64::MAX, 1, W64, u64::MAX - 1, "n"),
+];
+
+/// The flags of a reference case, such as "nc".
+pub fn flags_of(names: &str) -> Flags {
+    Flags { z: names.contains('z'), n: names.contains('n'), c: names.contains('c'), v: names.contains('v') }
+}
+
+/// xorshift64*, small and good enough to spread choices: progen draws its
+/// programs from it and the CPU its rand values.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// The seed goes through a splitmix64 round, so that close seeds give
+    /// unrelated sequences and a zero seed is usable.
+    pub fn new(seed: u64) -> Rng {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Rng((z ^ (z >> 31)).max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Another generator, seeded from this one.
+    pub fn split(&mut self) -> Rng {
+        Rng::new(self.next_u64())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -28,4 +232,48 @@ mod tests {
         assert_eq!(sign_extend(0b1000, 4), -8); // Larger negative value is correctly extended
         assert_eq!(sign_extend(0b0000, 4), 0);  // Zero stays zero
     }
+
+    #[test]
+    fn test_arithmetic() {
+        for &(subtract, x, y, word, result, flags) in ARITHMETIC_CASES {
+            let op = if subtract { sub_with_flags } else { add_with_flags };
+            assert_eq!(op(x, y, word), (result, flags_of(flags)), "{:#x} {} {:#x} on {} bits",
+                x, if subtract { "-" } else { "+" }, y, word.bits());
+        }
+        // Bits above the word size are ignored
+        assert_eq!(add_with_flags(0x1_0000_0001, 1, W32), (2, Flags::default()));
+
+        let less = sub_with_flags(0xffff_fffe, 1, W32).1;  // -2 < 1 signed, not unsigned
+        let holds: Vec<bool> = (0..8).map(|cond| condition_holds(cond, less)).collect();
+        assert_eq!(holds, [false, true, false, true, true, true, false, false]);
+        assert!(condition_holds(7, add_with_flags(0x7fff_ffff, 1, W32).1));
+    }
+
+    #[test]
+    fn test_shift() {
+        // Carry out of the top bit of the word, or of the bottom bit
+        assert_eq!(shift_with_carry(0x8000_0001, 1, false, false, W32), (2, Some(true)));
+        assert_eq!(shift_with_carry(0x8000_0001, 1, false, false, W64), (0x1_0000_0002, Some(false)));
+        assert_eq!(shift_with_carry(0b110, 2, true, false, W32), (1, Some(true)));
+        assert_eq!(shift_with_carry(1 << 63, 63, true, false, W64), (1, Some(false)));
+        assert_eq!(shift_with_carry(u64::MAX, 63, false, false, W64), (1 << 63, Some(true)));
+        assert_eq!(shift_with_carry(u64::MAX, 63, false, false, W32), (0, Some(false)));
+        // Shifts by 0 keep the value and the carry
+        assert_eq!(shift_with_carry(0x1_2345_6789, 0, true, false, W32), (0x2345_6789, None));
+        // Arithmetic shifts keep the sign of the word
+        assert_eq!(shift_with_carry(0xffff_fff0, 4, true, true, W32), (0xffff_ffff, Some(false)));
+        assert_eq!(shift_with_carry(0xffff_fff0, 4, true, true, W64), (0x0fff_ffff, Some(false)));
+
+        let flags = logic_flags(0x8000_0000, W32, flags_of("cv"));
+        assert_eq!(flags, flags_of("ncv"));
+        assert_eq!(logic_flags(0, W64, flags_of("n")), flags_of("z"));
+    }
+
+    #[test]
+    fn test_read_extend() {
+        for &(raw, size, ze, se) in READ_EXTEND_CASES {
+            assert_eq!(read_extend(raw, size, false), ze, "readze {:#x} on {} bits", raw, size);
+            assert_eq!(read_extend(raw, size, true), se as u64, "readse {:#x} on {} bits", raw, size);
+        }
+    }
 }
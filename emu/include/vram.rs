@@ -0,0 +1,96 @@
+//---
+// emu:vram - pixel formats of the screen in VRAM
+//
+// The screen is an array of 16-bit pixels from the start of the VRAM
+// segment, row-major. Programs written for emu store RGB565 pixels with
+// the most significant bit first, like every other value in memory.
+// Programs written for subject/simu store RGB555 pixels packed four per
+// 64-bit word, least significant bit first, since simu numbers the bits of
+// its words from the bottom. ScreenFormat describes either, so the
+// graphical window and screen comparisons can read both.
+//---
+
+use crate::memory::Memory;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Rgb565,  // rrrrrggg gggbbbbb
+    Rgb555,  // xrrrrrgg gggbbbbb
+}
+
+impl PixelFormat {
+    /// Expand a pixel to 8-bit channels, replicating the high bits into
+    /// the low ones so that full intensity stays 255
+    pub fn to_rgb888(self, p: u16) -> [u8; 3] {
+        let (r, g, b, green_bits) = match self {
+            PixelFormat::Rgb565 => ((p >> 11) & 0x1f, (p >> 5) & 0x3f, p & 0x1f, 6),
+            PixelFormat::Rgb555 => ((p >> 10) & 0x1f, (p >> 5) & 0x1f, p & 0x1f, 5),
+        };
+        let expand = |c: u16, bits: u32| ((c << (8 - bits)) | (c >> (2 * bits - 8))) as u8;
+        [expand(r, 5), expand(g, green_bits), expand(b, 5)]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenFormat {
+    pub width: usize,
+    pub height: usize,
+    pub pixel: PixelFormat,
+    pub lsb_first: bool,  // Pixels are stored least significant bit first
+}
+
+impl ScreenFormat {
+    /// The screen of emu programs
+    pub const EMU: ScreenFormat = ScreenFormat { width: 160, height: 128, pixel: PixelFormat::Rgb565, lsb_first: false };
+
+    /// The screen of subject/simu programs
+    pub const SIMU: ScreenFormat = ScreenFormat { width: 160, height: 128, pixel: PixelFormat::Rgb555, lsb_first: true };
+
+    pub fn from_name(name: &str) -> Option<ScreenFormat> {
+        match name {
+            "emu" => Some(ScreenFormat::EMU),
+            "simu" => Some(ScreenFormat::SIMU),
+            _ => None,
+        }
+    }
+
+    /// Size of the screen in memory, in bits
+    pub fn size(&self) -> u64 {
+        16 * (self.width * self.height) as u64
+    }
+
+    /// Raw value of pixel i of a screen at `base`
+    pub fn pixel(&self, memory: &Memory, base: u64, i: usize) -> u16 {
+        let p = memory.read(base + 16 * i as u64, 16) as u16;
+        if self.lsb_first { p.reverse_bits() } else { p }
+    }
+
+    /// Pixels of the screen at the VRAM base as 8-bit channels, row-major
+    pub fn render(&self, memory: &Memory) -> Vec<[u8; 3]> {
+        let base = memory.vram_base();
+        (0..self.width * self.height).map(|i| self.pixel.to_rgb888(self.pixel(memory, base, i))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(PixelFormat::Rgb565.to_rgb888(0xffff), [255, 255, 255]);
+        assert_eq!(PixelFormat::Rgb565.to_rgb888(0xf800), [255, 0, 0]);
+        assert_eq!(PixelFormat::Rgb555.to_rgb888(0x03e0), [0, 255, 0]);
+        assert_eq!(PixelFormat::Rgb555.to_rgb888(0x0010), [0, 0, 132]);
+
+        // The second pixel of simu's first word, as simu writes it: bit k
+        // of the pixel at address base + 16 + k
+        let mut memory = Memory::new(0, 0, 0, 0);
+        let base = memory.vram_base();
+        let red: u16 = 0x1f << 10;
+        memory.write(base + 16, red.reverse_bits() as u64, 16);
+        let pixels = ScreenFormat::SIMU.render(&memory);
+        assert_eq!((pixels[0], pixels[1]), ([0, 0, 0], [255, 0, 0]));
+        assert_eq!(ScreenFormat::EMU.render(&memory)[1], PixelFormat::Rgb565.to_rgb888(red.reverse_bits()));
+    }
+}
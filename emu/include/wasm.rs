@@ -0,0 +1,156 @@
+//---
+// emu:wasm - JavaScript interface to the emulator
+//
+// With the wasm feature and without the default ones (the debugger and the
+// screen window need ncurses and SDL), the library builds for
+// wasm32-unknown-unknown and wasm-bindgen exports an Emulator class, for
+// running programs in a web page:
+//
+//     const emu = new Emulator("emu");
+//     emu.load(new Uint8Array(await (await fetch("prog.obj")).arrayBuffer()));
+//     function frame() {
+//         emu.run(10000);
+//         ctx.putImageData(new ImageData(new Uint8ClampedArray(emu.framebuffer()),
+//             emu.width(), emu.height()), 0, 0);
+//         if (!emu.halted()) requestAnimationFrame(frame);
+//     }
+//
+// Nothing here reads the host clock or starts threads, neither of which
+// wasm32-unknown-unknown has; devices that need them are left out.
+//---
+
+use std::sync::{Arc, Mutex};
+use wasm_bindgen::prelude::*;
+use crate::cpu::{CPU, PC, SP};
+use crate::disasm::disasm_one;
+use crate::memory::Memory;
+use crate::vram::ScreenFormat;
+use minimisa_core::default_opcodes;
+
+#[wasm_bindgen]
+pub struct Emulator {
+    cpu: CPU,
+    memory: Arc<Mutex<Memory>>,
+    screen: ScreenFormat,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /// A machine with the default segment sizes and a screen format, "emu"
+    /// or "simu"
+    #[wasm_bindgen(constructor)]
+    pub fn new(screen: &str) -> Result<Emulator, JsValue> {
+        let screen = ScreenFormat::from_name(screen)
+            .ok_or_else(|| JsValue::from_str(&format!("unknown screen format '{}'", screen)))?;
+        let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+        Ok(Emulator { cpu: CPU::new(Arc::clone(&memory)), memory, screen })
+    }
+
+    /// Load a program (object file or bare binary) and reset the CPU to
+    /// start it, with the stack at the start of the data segment
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        let error = |e: String| JsValue::from_str(&e);
+        let object = self.memory.lock().unwrap().load_bytes(bytes).map_err(|e| error(e.to_string()))?;
+        // Objects without a table were assembled with the default one
+        let codes = object.as_ref().and_then(|o| o.opcodes).unwrap_or_else(default_opcodes);
+        self.memory.lock().unwrap().set_opcodes(&codes).map_err(error)?;
+        self.cpu = CPU::new(Arc::clone(&self.memory));
+        self.cpu.ptr[PC] = object.map_or(0, |o| o.entry);
+        self.cpu.ptr[SP] = self.memory.lock().unwrap().data_base();
+        Ok(())
+    }
+
+    /// Execute one instruction. Returns whether the program has halted
+    pub fn step(&mut self) -> bool {
+        if !self.cpu.h {
+            self.cpu.execute();
+        }
+        self.cpu.h
+    }
+
+    /// Execute up to `count` instructions, fewer if the program halts.
+    /// Returns how many ran
+    pub fn run(&mut self, count: u32) -> u32 {
+        let mut ran = 0;
+        while ran < count && !self.cpu.h {
+            self.cpu.execute();
+            ran += 1;
+        }
+        ran
+    }
+
+    pub fn halted(&self) -> bool {
+        self.cpu.h
+    }
+
+    /// Instructions executed since the program was loaded
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles
+    }
+
+    /// Value of r0..r7, 0 for other numbers
+    pub fn register(&self, n: usize) -> u64 {
+        self.cpu.r.get(n).copied().unwrap_or(0)
+    }
+
+    /// Value of a pointer: 0 for PC, 1 for SP, 2 for A0, 3 for A1
+    pub fn pointer(&self, n: usize) -> u64 {
+        self.cpu.ptr.get(n).copied().unwrap_or(0)
+    }
+
+    /// Read `bits` bits (1 to 64) at a bit address, 0 for other sizes
+    pub fn read_memory(&self, address: u64, bits: usize) -> u64 {
+        match bits {
+            1..=64 => self.memory.lock().unwrap().read(address, bits),
+            _ => 0,
+        }
+    }
+
+    /// Disassembly of the instruction at an address, empty if there is
+    /// none
+    pub fn disassemble(&self, address: u64) -> String {
+        let mut ptr = address;
+        disasm_one(&self.memory.lock().unwrap(), &mut ptr).unwrap_or_default()
+    }
+
+    pub fn width(&self) -> usize {
+        self.screen.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.screen.height
+    }
+
+    /// The screen as RGBA bytes, row-major, opaque: the data of an
+    /// ImageData of width() by height()
+    pub fn framebuffer(&self) -> Vec<u8> {
+        let pixels = self.screen.render(&self.memory.lock().unwrap());
+        pixels.iter().flat_map(|&[r, g, b]| [r, g, b, 255]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::A0;
+    use crate::testing::assemble_str;
+
+    #[test]
+    fn test_emulator() {
+        let mut emu = Emulator::new("emu").unwrap();
+        emu.load(&assemble_str("leti r0 0xf800\nwrite a0 16 r0\nend: jump end")).unwrap();
+        let vram = emu.memory.lock().unwrap().vram_base();
+        emu.cpu.ptr[A0] = vram;
+        assert!(!emu.step());
+        assert_eq!(emu.run(10), 2);
+        assert!(emu.halted());
+        assert_eq!((emu.register(0), emu.cycles()), (0xf800, 3));
+
+        // The first pixel is red
+        let frame = emu.framebuffer();
+        assert_eq!(frame.len(), 4 * emu.width() * emu.height());
+        assert_eq!(frame[..8], [255, 0, 0, 255, 0, 0, 0, 255]);
+        assert_eq!(emu.read_memory(vram, 16), 0xf800);
+        assert!(emu.disassemble(emu.pointer(PC)).starts_with("jump"));
+    }
+}
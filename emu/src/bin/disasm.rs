@@ -0,0 +1,139 @@
+//---
+// disasm - convert a MinimISA program back to assembly text
+//
+// Reads an object file, a binary program, or a text file of 0s and 1s such
+// as the bitcode written by the assemblers, and prints an address-annotated
+// listing of the text. Branch targets are shown as labels: the symbols of
+// the object and those of a map file when given, else generated names.
+//---
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::process::exit;
+use emu::disasm::{disasm_label_targets, disasm_listing, disasm_load_map, disasm_load_opcodes};
+use emu::memory::Memory;
+use minimisa_core::default_opcodes;
+use minimisa_core::object::Object;
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} [options] <program>", program);
+    eprintln!("  -m <file>    label map (\"<address> <label>\" lines, from compileuh --symbols)");
+    eprintln!("  -t <file>    opcode table (opcode.txt from the compiler)");
+    eprintln!("  -f           use the -t table even for objects that carry one");
+    eprintln!("  -s <addr>    start address in bits (default 0)");
+    eprintln!("  -e <addr>    end address in bits (default end of program)");
+    eprintln!("  -n           do not generate labels for branch targets");
+    exit(1);
+}
+
+// Parse a decimal or 0x-prefixed hexadecimal address
+fn parse_address(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// Turn the file contents into bits: text files of 0s and 1s are read as
+// bitcode, anything else as raw bytes, most significant bit first
+fn program_bits(bytes: &[u8]) -> Vec<bool> {
+    let is_text = !bytes.is_empty()
+        && bytes.iter().all(|b| matches!(b, b'0' | b'1') || b.is_ascii_whitespace());
+
+    if is_text {
+        bytes.iter().filter(|b| !b.is_ascii_whitespace()).map(|&b| b == b'1').collect()
+    } else {
+        bytes.iter().flat_map(|&b| (0..8).rev().map(move |i| (b >> i) & 1 == 1)).collect()
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut map = None;
+    let mut start = 0;
+    let mut end = None;
+    let mut auto_labels = true;
+    let mut opcodes = None;
+    let mut force_opcodes = false;
+    let mut filename = None;
+
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match args[i].as_str() {
+            "-m" => { map = value.cloned(); i += 1; }
+            "-t" => {
+                let table = value.unwrap_or_else(|| usage(&args[0]));
+                let codes = disasm_load_opcodes(table).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", table, e);
+                    exit(1);
+                });
+                opcodes = Some((table.clone(), codes));
+                i += 1;
+            }
+            "-s" => { start = value.and_then(|v| parse_address(v)).unwrap_or_else(|| usage(&args[0])); i += 1; }
+            "-e" => { end = Some(value.and_then(|v| parse_address(v)).unwrap_or_else(|| usage(&args[0]))); i += 1; }
+            "-n" => auto_labels = false,
+            "-f" => force_opcodes = true,
+            arg if !arg.starts_with('-') && filename.is_none() => filename = Some(arg.to_string()),
+            _ => usage(&args[0]),
+        }
+        i += 1;
+    }
+    let filename = filename.unwrap_or_else(|| usage(&args[0]));
+
+    let bytes = fs::read(&filename).unwrap_or_else(|e| {
+        eprintln!("{}: {}", filename, e);
+        exit(1);
+    });
+    // Objects bring their own opcode table and symbols; their table wins
+    // over -t unless -f is given
+    let object = Object::is_object(&bytes).then(|| Object::from_bytes(&bytes).unwrap_or_else(|e| {
+        eprintln!("{}: {}", filename, e);
+        exit(1);
+    }));
+    let mut codes = opcodes.as_ref().map_or_else(default_opcodes, |&(_, codes)| codes);
+    if let Some(object_codes) = object.as_ref().and_then(|o| o.opcodes).filter(|_| !force_opcodes) {
+        if let Some((table, _)) = &opcodes {
+            eprintln!("disasm: {} has an opcode table, {} is ignored (see -f)", filename, table);
+        }
+        codes = object_codes;
+    }
+    let bits = match object.as_ref().map(|o| o.text()) {
+        Some(Some(text)) => (0..text.length).map(|i| text.bit(i)).collect(),
+        Some(None) => Vec::new(),
+        None => program_bits(&bytes),
+    };
+
+    // Size the text segment after the program so that large files fit
+    let size = bits.len() as u64;
+    let mut memory = Memory::new(size.max(1), 0, 0, 0);
+    if let Err(e) = memory.set_opcodes(&codes) {
+        eprintln!("{}: {}", filename, e);
+        exit(1);
+    }
+    for (address, &bit) in bits.iter().enumerate() {
+        memory.write(address as u64, bit as u64, 1);
+    }
+
+    let mut labels = match &map {
+        Some(map) => disasm_load_map(map).unwrap_or_else(|e| {
+            eprintln!("{}: {}", map, e);
+            exit(1);
+        }),
+        None => BTreeMap::new(),
+    };
+    if let Some(object) = &object {
+        for (name, address) in &object.symbols {
+            labels.entry(*address).or_insert_with(|| name.clone());
+        }
+    }
+
+    let end = end.unwrap_or(size).min(size);
+    if auto_labels {
+        disasm_label_targets(&memory, start, end, &mut labels);
+    }
+    for line in disasm_listing(&memory, start, end, &labels) {
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,783 @@
+//---
+// emu - command-line front end of the MinimISA emulator
+//
+// Runs a program until it halts (jumps to itself) and prints the final
+// CPU state. Segment permissions can be changed with --perm and are
+// enforced with --check (by default, text is read-only once loaded);
+// accesses past the end of memory or below --stack-limit always stop the
+// program with a fault. Programs that write their own code need text to
+// be writable (--perm text=rwx); --text-writes warn or trap reports or
+// stops such writes. With --run, nothing is printed and the exit code
+// is the low byte of r0 when the program halts, for batch testing. With
+// --debugger, the program is loaded in the ncurses debugger instead, or in
+// the line debugger with --tui=off (the only one without ncurses).
+// With --screen, VRAM is shown in a window whose keys programs read from
+// the keyboard registers of the I/O window (see devices.rs), and --audio
+// plays the audio channel of the I/O window along with it; --display shows
+// it in the terminal or nowhere instead (see display.rs). With
+// --capture-every, the screen is saved to numbered image files instead.
+// With --compare-screens, the program runs without a window and the exit
+// code tells whether its screen matches a reference image, in the format
+// of --screen (see screencmp.rs).
+// With --uart, the serial port of the I/O window talks to the terminal or
+// to the first client of a local TCP port. Programs can always read the
+// cycle count and the host time from the clock registers. With --cores,
+// several CPUs run the program over the same memory (see multicore.rs),
+// interleaved or, with --threaded, each on a thread of its own. With
+// --record, what the program reads from devices is logged, and --replay
+// feeds a log back for an identical run (see replay.rs); rand draws from
+// a generator seeded with --seed. Registers and arithmetic are 64-bit, or
+// 32-bit with --word-size 32 like subject/simu.
+// Labels come from the symbols of the object and of --symbols files
+// (compileuh --symbols); the debugger, the profile and the trace show them.
+// With --json, the final state (and the memory regions of --dump) is
+// printed as one JSON object, and the profile and trace are JSON too, for
+// autograders and scripts.
+// With --compat simu, the command line, object loading and debug output
+// of subject/simu are reproduced.
+//---
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process::exit;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use emu::compat::{simu_load, simu_profile, simu_step, ObjFormat, SIMU_WORD_SIZE};
+use emu::cpu::{ExecCheck, TimingModel, CPU, PC};
+use emu::debugcli::LineDebugger;
+use emu::debugcore::DebuggerCore;
+#[cfg(feature = "debugger")]
+use emu::debugger::Debugger;
+use emu::devices::{attach_audio, attach_clock, attach_keyboard, attach_uart, Audio, Clock, Keyboard, Uart};
+use emu::disasm::{disasm_load_map, disasm_load_opcodes};
+use emu::display::Display;
+use emu::graphical::Graphical;
+use emu::memory::{Memory, Perm, Segment, TextWrites};
+use emu::multicore::Machine;
+use emu::profiler::Profiler;
+use emu::replay::Session;
+use emu::screencmp::{compare_screens, Capture};
+use emu::trace::Trace;
+use emu::util::Rng;
+use emu::vram::ScreenFormat;
+use minimisa_core::WordSize;
+
+fn usage() -> ! {
+    eprintln!("usage: emu [options] <program>");
+    eprintln!("  <program> is an object file from the compiler, or a bare binary loaded at 0");
+    eprintln!("  --text|--stack|--data|--vram <bits>  segment sizes (0 for the default)");
+    eprintln!("  --load <file>@<address>  load a data file at a bit address (repeatable)");
+    eprintln!("  --run                   batch mode: no output, exit with r0 & 0xff");
+    eprintln!("  --debugger              run the program in the debugger");
+    eprintln!("  --tui=on|off            with --debugger, ncurses panels or gdb-like lines (default on)");
+    eprintln!("  --cores <n>             run n cores over the same memory, core k with k in r0");
+    eprintln!("  --threaded              with --cores, run each core on a thread of its own");
+    eprintln!("  --word-size 32|64       width of registers and arithmetic (default 64)");
+    eprintln!("  --profile <file.csv>    write per-instruction hit counts and decode cache hit rates, hottest first");
+    eprintln!("  --trace <file>          write a line per executed instruction (core 0)");
+    eprintln!("  --dump <address>:<bits> print a memory region with the final state (repeatable)");
+    eprintln!("  --json                  print the final state, profile and trace as JSON");
+    eprintln!("  --screen emu|simu       show VRAM in a window, in the pixel format of emu or simu");
+    eprintln!("  --display sdl|tty|none  with --screen, where to show it (default sdl, a window)");
+    eprintln!("  --audio                 with --screen, play the square-wave channel of the I/O window");
+    eprintln!("  --capture <file>        frame name for --capture-every, .png or .ppm (default frame.png)");
+    eprintln!("  --capture-every <n>     save the screen every n cycles, as numbered frames");
+    eprintln!("  --compare-screens <file.ppm>  run for --frames frames, exit 0 if the screen matches the image");
+    eprintln!("  --frames <n>            with --compare-screens, frames of 10000 instructions to run (default 1)");
+    eprintln!("  --tolerance <n>         with --compare-screens, accepted difference per channel (default 0)");
+    eprintln!("  --diff <file.ppm>       with --compare-screens, where to write mismatches (default diff.ppm)");
+    eprintln!("  --uart stdio|tcp:<port> bridge the serial port to the terminal or a local TCP client");
+    eprintln!("  --record <file>         log the values the program reads from devices");
+    eprintln!("  --replay <file>         read devices from a log of --record instead");
+    eprintln!("  --seed <n>              seed of the values of rand (default 0)");
+    eprintln!("  --timing <file.csv>     cycle costs of the timing model (key,cycles lines)");
+    eprintln!("  --opcodes <file>        opcode table the program was compiled with (opcode.txt)");
+    eprintln!("  --force-opcodes         use the --opcodes table even for objects that carry one");
+    eprintln!("  --symbols <file>        labels of the program (\"<address> <label>\" lines, repeatable)");
+    eprintln!("  --perm <segment>=<rwx>  set the permissions of text, stack, data or vram");
+    eprintln!("  --check off|warn|strict report (or stop on) permission violations (default warn)");
+    eprintln!("  --stack-limit <bits>    fault on stack writes more than <bits> below its top");
+    eprintln!("  --text-writes allow|warn|trap  report (or stop on) writes to text that are allowed (default allow)");
+    eprintln!("  --compat simu   behave like subject/simu, with its options:");
+    eprintln!("      -d              debug output after every instruction");
+    eprintln!("      -s              step by step (press enter between instructions)");
+    eprintln!("      -g              graphical screen");
+    eprintln!("      --display sdl|tty|none  where -g shows the screen");
+    eprintln!("      -t <file>       write an execution trace");
+    eprintln!("      --format bin|txt|obj  force the object format");
+    eprintln!("      --word-size 32|64  width of registers (default 32)");
+    eprintln!("      --capture-every <n>  save the screen every n instructions, named after --capture");
+    exit(1);
+}
+
+// The simu command line: simu [-d] [-s] [-g] [--display sdl|tty|none]
+// [-t <file>] [--format bin|txt|obj] [--word-size 32|64]
+// [--capture-every <n>] [--capture <file>] file.obj
+fn run_simu(args: &[String]) {
+    let mut debug = false;
+    let mut step_by_step = false;
+    let mut format = None;
+    let mut graphical = false;
+    let mut display = Display::Sdl;
+    let mut trace = None;
+    let mut word_size = SIMU_WORD_SIZE;
+    let mut capture = "frame.png".to_string();
+    let mut capture_every = None;
+    let mut filename = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-d" => debug = true,
+            "-s" => step_by_step = true,
+            "-g" => graphical = true,
+            "--display" => {
+                i += 1;
+                display = args.get(i).and_then(|name| Display::from_name(name)).unwrap_or_else(|| usage());
+            }
+            "-t" => {
+                i += 1;
+                trace = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "--format" => {
+                i += 1;
+                format = args.get(i).and_then(|name| ObjFormat::from_name(name));
+                if format.is_none() {
+                    eprintln!("Unknown object format {}", args.get(i).map_or("", String::as_str));
+                    usage();
+                }
+            }
+            "--word-size" => {
+                i += 1;
+                word_size = args.get(i).and_then(|n| n.parse::<u64>().ok()).and_then(WordSize::from_bits)
+                    .unwrap_or_else(|| {
+                        eprintln!("--word-size expects 32 or 64");
+                        usage();
+                    });
+            }
+            "--capture" => {
+                i += 1;
+                capture = args.get(i).unwrap_or_else(|| usage()).clone();
+            }
+            "--capture-every" => {
+                i += 1;
+                capture_every = Some(args.get(i).and_then(|n| n.parse::<u64>().ok()).filter(|&n| n > 0)
+                    .unwrap_or_else(|| {
+                        eprintln!("--capture-every expects a number of instructions");
+                        usage();
+                    }));
+            }
+            "--cosim" => {
+                eprintln!("emu: --cosim runs emu alongside simu, use simu itself");
+                exit(1);
+            }
+            arg if !arg.starts_with('-') && filename.is_none() => filename = Some(arg.to_string()),
+            _ => usage(),
+        }
+        i += 1;
+    }
+    let filename = filename.unwrap_or_else(|| usage());
+
+    let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+    let object = simu_load(&mut memory.lock().unwrap(), &filename, format).unwrap_or_else(|e| {
+        eprintln!("Can't access obj file: {}", e);
+        usage();
+    });
+    let mut trace = trace.map(|file| match File::create(&file) {
+        Ok(out) => BufWriter::new(out),
+        Err(e) => {
+            eprintln!("Can't create trace file {}: {}", file, e);
+            usage();
+        }
+    });
+
+    // simu's screen is at the start of VRAM with the default geometry
+    let screen = graphical.then(|| open_screen(&memory, ScreenFormat::SIMU, display, false));
+
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    simu_profile(&mut cpu);
+    cpu.word_size = word_size;
+    // Unlike simu, objects may have their own opcode table and entry point
+    if let Some(object) = &object {
+        if let Some(codes) = &object.opcodes {
+            if let Err(e) = memory.lock().unwrap().set_opcodes(codes) {
+                eprintln!("{}: {}", filename, e);
+                exit(1);
+            }
+        }
+        cpu.ptr[PC] = object.entry;
+    }
+    if let Some(n) = capture_every {
+        cpu.scheduler.add(Box::new(Capture::new(&capture, ScreenFormat::SIMU)), n, 0);
+    }
+    while !cpu.h {
+        let lines = simu_step(&mut cpu);
+        if debug {
+            println!("{}", lines.debug);
+        }
+        // Like emu --trace, a trace that cannot be written is dropped
+        if let Some(Err(e)) = trace.as_mut().map(|out| writeln!(out, "{}", lines.trace)) {
+            eprintln!("warning: trace stopped: {}", e);
+            trace = None;
+        }
+        if step_by_step {
+            let _ = std::io::stdin().read_line(&mut String::new());
+        }
+    }
+    if let Some(Err(e)) = trace.as_mut().map(|out| out.flush()) {
+        eprintln!("warning: trace stopped: {}", e);
+    }
+    // The screen stays up until its window is closed
+    if let Some(screen) = &screen {
+        screen.wait();
+    }
+}
+
+// Show the screen on a display, with a keyboard and optionally an audio
+// channel in the I/O window
+fn open_screen(memory: &Arc<Mutex<Memory>>, format: ScreenFormat, display: Display, sound: bool) -> Graphical {
+    let keyboard = Keyboard::new();
+    attach_keyboard(&mut memory.lock().unwrap(), &keyboard);
+    let mut screen = Graphical::from_memory(Arc::clone(memory), format, 4)
+        .with_keyboard(keyboard)
+        .with_display(display);
+    if sound {
+        let audio = Audio::new();
+        attach_audio(&mut memory.lock().unwrap(), &audio);
+        screen = screen.with_audio(audio);
+    }
+    if let Err(e) = screen.start() {
+        eprintln!("emu: cannot open the screen: {}", e);
+        exit(1);
+    }
+    screen
+}
+
+// Parse a decimal or 0x-prefixed hexadecimal number
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if let Some(pos) = args.iter().position(|a| a == "--compat") {
+        if args.get(pos + 1).map(String::as_str) != Some("simu") {
+            eprintln!("emu: unknown compatibility mode (only 'simu' is supported)");
+            exit(1);
+        }
+        let rest: Vec<String> = args.iter().enumerate()
+            .filter(|&(i, _)| i != pos && i != pos + 1)
+            .map(|(_, a)| a.clone())
+            .collect();
+        run_simu(&rest);
+        return;
+    }
+
+    let mut perms = Vec::new();
+    let mut check = ExecCheck::Warn;
+    let mut stack_limit = None;
+    let mut text_writes = TextWrites::Allow;
+    let mut sizes = [0u64; 4];  // text, stack, data, vram
+    let mut loads = Vec::new();
+    let mut batch = false;
+    let mut debugger = false;
+    let mut tui = None;
+    let mut profile = None;
+    let mut trace = None;
+    let mut dumps = Vec::new();
+    let mut json = false;
+    let mut labels = BTreeMap::new();
+    let mut screen_format = None;
+    let mut display = None;
+    let mut sound = false;
+    let mut capture = "frame.png".to_string();
+    let mut capture_every = None;
+    let mut compare = None;
+    let mut frames = 1;
+    let mut tolerance = 0;
+    let mut diff = "diff.ppm".to_string();
+    let mut uart = None;
+    let mut cores = 1;
+    let mut word_size = WordSize::W64;
+    let mut threaded = false;
+    let mut record = None;
+    let mut replay = None;
+    let mut seed = 0;
+    let mut timing = TimingModel::default();
+    let mut opcodes = None;
+    let mut force_opcodes = false;
+    let mut filename = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--perm" => {
+                i += 1;
+                let spec = args.get(i).unwrap_or_else(|| usage());
+                let parsed = spec.split_once('=')
+                    .and_then(|(seg, perm)| Some((Segment::from_name(seg)?, Perm::parse(perm)?)));
+                match parsed {
+                    Some(p) => perms.push(p),
+                    None => {
+                        eprintln!("emu: invalid permission '{}' (expected e.g. text=rx)", spec);
+                        exit(1);
+                    }
+                }
+            }
+            "--check" => {
+                i += 1;
+                check = match args.get(i).map(String::as_str) {
+                    Some("off") => ExecCheck::Off,
+                    Some("warn") => ExecCheck::Warn,
+                    Some("strict") => ExecCheck::Strict,
+                    _ => usage(),
+                };
+            }
+            "--stack-limit" => {
+                i += 1;
+                stack_limit = match args.get(i).and_then(|n| parse_number(n)) {
+                    Some(size) => Some(size),
+                    None => {
+                        eprintln!("emu: --stack-limit expects a size in bits");
+                        exit(1);
+                    }
+                };
+            }
+            "--text-writes" => {
+                i += 1;
+                text_writes = match args.get(i).and_then(|name| TextWrites::from_name(name)) {
+                    Some(mode) => mode,
+                    None => usage(),
+                };
+            }
+            option @ ("--text" | "--stack" | "--data" | "--vram") => {
+                i += 1;
+                let index = ["--text", "--stack", "--data", "--vram"].iter().position(|o| *o == option).unwrap();
+                sizes[index] = match args.get(i).and_then(|n| parse_number(n)) {
+                    Some(size) => size,
+                    None => {
+                        eprintln!("emu: {} expects a size in bits", option);
+                        exit(1);
+                    }
+                };
+            }
+            "--load" => {
+                i += 1;
+                let spec = args.get(i).unwrap_or_else(|| usage());
+                match spec.rsplit_once('@').and_then(|(file, addr)| Some((file.to_string(), parse_number(addr)?))) {
+                    Some(load) => loads.push(load),
+                    None => {
+                        eprintln!("emu: invalid load '{}' (expected file@address)", spec);
+                        exit(1);
+                    }
+                }
+            }
+            "--run" => batch = true,
+            "--cores" => {
+                i += 1;
+                cores = match args.get(i).and_then(|n| n.parse::<usize>().ok()).filter(|&n| n > 0) {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("emu: --cores expects a number of cores");
+                        exit(1);
+                    }
+                };
+            }
+            "--threaded" => threaded = true,
+            "--word-size" => {
+                i += 1;
+                word_size = match args.get(i).and_then(|n| n.parse::<u64>().ok()).and_then(WordSize::from_bits) {
+                    Some(word_size) => word_size,
+                    None => {
+                        eprintln!("emu: --word-size expects 32 or 64");
+                        exit(1);
+                    }
+                };
+            }
+            "--record" => {
+                i += 1;
+                record = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "--replay" => {
+                i += 1;
+                replay = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "--seed" => {
+                i += 1;
+                seed = match args.get(i).and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("emu: --seed expects a number");
+                        exit(1);
+                    }
+                };
+            }
+            "--debugger" => debugger = true,
+            "--tui=on" => tui = Some(true),
+            "--tui=off" => tui = Some(false),
+            "--timing" => {
+                i += 1;
+                let file = args.get(i).unwrap_or_else(|| usage());
+                timing = TimingModel::load(file).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", file, e);
+                    exit(1);
+                });
+            }
+            "--opcodes" => {
+                i += 1;
+                let file = args.get(i).unwrap_or_else(|| usage());
+                let codes = disasm_load_opcodes(file).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", file, e);
+                    exit(1);
+                });
+                opcodes = Some((file.clone(), codes));
+            }
+            "--force-opcodes" => force_opcodes = true,
+            "--symbols" => {
+                i += 1;
+                let file = args.get(i).unwrap_or_else(|| usage());
+                labels.extend(disasm_load_map(file).unwrap_or_else(|e| {
+                    eprintln!("{}: {}", file, e);
+                    exit(1);
+                }));
+            }
+            "--screen" => {
+                i += 1;
+                screen_format = args.get(i).and_then(|name| ScreenFormat::from_name(name));
+                if screen_format.is_none() {
+                    usage();
+                }
+            }
+            "--display" => {
+                i += 1;
+                display = args.get(i).and_then(|name| Display::from_name(name));
+                if display.is_none() {
+                    usage();
+                }
+            }
+            "--audio" => sound = true,
+            "--capture" => {
+                i += 1;
+                capture = args.get(i).unwrap_or_else(|| usage()).clone();
+            }
+            "--capture-every" => {
+                i += 1;
+                capture_every = match args.get(i).and_then(|n| parse_number(n)).filter(|&n| n > 0) {
+                    Some(cycles) => Some(cycles),
+                    None => {
+                        eprintln!("emu: --capture-every expects a number of cycles");
+                        exit(1);
+                    }
+                };
+            }
+            "--compare-screens" => {
+                i += 1;
+                compare = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "--frames" => {
+                i += 1;
+                frames = match args.get(i).and_then(|n| parse_number(n)) {
+                    Some(n) => n as usize,
+                    None => {
+                        eprintln!("emu: --frames expects a number of frames");
+                        exit(1);
+                    }
+                };
+            }
+            "--tolerance" => {
+                i += 1;
+                tolerance = match args.get(i).and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n) => n,
+                    None => {
+                        eprintln!("emu: --tolerance expects a number from 0 to 255");
+                        exit(1);
+                    }
+                };
+            }
+            "--diff" => {
+                i += 1;
+                diff = args.get(i).unwrap_or_else(|| usage()).clone();
+            }
+            "--uart" => {
+                i += 1;
+                uart = match args.get(i).map(String::as_str) {
+                    Some("stdio") => Some(None),
+                    Some(spec) if spec.starts_with("tcp:") => match spec[4..].parse::<u16>() {
+                        Ok(port) => Some(Some(port)),
+                        Err(_) => {
+                            eprintln!("emu: invalid port in '{}'", spec);
+                            exit(1);
+                        }
+                    },
+                    _ => usage(),
+                };
+            }
+            "--profile" => {
+                i += 1;
+                profile = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "--trace" => {
+                i += 1;
+                trace = Some(args.get(i).unwrap_or_else(|| usage()).clone());
+            }
+            "--dump" => {
+                i += 1;
+                let spec = args.get(i).unwrap_or_else(|| usage());
+                match spec.split_once(':').and_then(|(addr, bits)| Some((parse_number(addr)?, parse_number(bits)?))) {
+                    Some((address, bits)) => dumps.push(address..address.saturating_add(bits)),
+                    None => {
+                        eprintln!("emu: invalid dump '{}' (expected address:bits)", spec);
+                        exit(1);
+                    }
+                }
+            }
+            "--json" => json = true,
+            arg if !arg.starts_with('-') && filename.is_none() => filename = Some(arg.to_string()),
+            _ => usage(),
+        }
+        i += 1;
+    }
+    let filename = filename.unwrap_or_else(|| usage());
+    if batch && debugger {
+        eprintln!("emu: --run and --debugger are exclusive");
+        exit(1);
+    }
+    if tui.is_some() && !debugger {
+        eprintln!("emu: --tui needs --debugger");
+        exit(1);
+    }
+    if tui == Some(true) && !cfg!(feature = "debugger") {
+        eprintln!("emu: built without ncurses, use --tui=off");
+        exit(1);
+    }
+    if sound && screen_format.is_none() {
+        eprintln!("emu: --audio needs --screen");
+        exit(1);
+    }
+    if display.is_some() && screen_format.is_none() {
+        eprintln!("emu: --display needs --screen");
+        exit(1);
+    }
+    let display = display.unwrap_or(Display::Sdl);
+    if sound && display != Display::Sdl {
+        eprintln!("emu: --audio needs --display sdl");
+        exit(1);
+    }
+    if display == Display::Tty && (debugger || uart == Some(None)) {
+        eprintln!("emu: --display tty needs the terminal for itself");
+        exit(1);
+    }
+    if record.is_some() && replay.is_some() {
+        eprintln!("emu: --record and --replay are exclusive");
+        exit(1);
+    }
+    if debugger && threaded {
+        eprintln!("emu: the debugger steps cores in turn, --threaded does not apply");
+        exit(1);
+    }
+    if debugger && (json || !dumps.is_empty()) {
+        eprintln!("emu: --json and --dump print the final state, which the debugger does not");
+        exit(1);
+    }
+    if compare.is_some() && (batch || debugger || cores > 1) {
+        eprintln!("emu: --compare-screens runs the program alone, without --run, --debugger or --cores");
+        exit(1);
+    }
+    if debugger && uart == Some(None) {
+        eprintln!("emu: --uart stdio and --debugger both need the terminal");
+        exit(1);
+    }
+
+    let [text, stack, data, vram] = sizes;
+    let memory = Arc::new(Mutex::new(Memory::new(text, stack, data, vram)));
+    let object = {
+        let mut memory = memory.lock().unwrap();
+        let object = memory.load_program(&filename).unwrap_or_else(|e| {
+            eprintln!("{}: {}", filename, e);
+            exit(1);
+        });
+        for (file, address) in &loads {
+            if let Err(e) = memory.load_file(*address, file) {
+                eprintln!("{}: {}", file, e);
+                exit(1);
+            }
+        }
+        // Permissions apply to the program, not to the loader
+        for &(segment, perm) in &perms {
+            memory.set_permissions(segment, perm);
+        }
+        memory.set_protection(check != ExecCheck::Off);
+        memory.set_stack_limit(stack_limit);
+        memory.set_text_writes(text_writes);
+        // Waits for a client with tcp:<port>
+        match uart {
+            Some(None) => {
+                attach_uart(&mut memory, Uart::terminal());
+            }
+            Some(Some(port)) => {
+                let uart = Uart::listen(port).unwrap_or_else(|e| {
+                    eprintln!("emu: port {}: {}", port, e);
+                    exit(1);
+                });
+                attach_uart(&mut memory, uart);
+            }
+            None => {}
+        }
+        object
+    };
+
+    // An object carries the opcode table it was assembled with, which
+    // replaces the one given with --opcodes unless --force-opcodes is
+    let mut codes = opcodes.as_ref().map(|&(_, codes)| codes);
+    if let Some(object_codes) = object.as_ref().and_then(|o| o.opcodes).filter(|_| !force_opcodes) {
+        if let Some((file, _)) = &opcodes {
+            eprintln!("emu: {} has an opcode table, {} is ignored (see --force-opcodes)", filename, file);
+        }
+        codes = Some(object_codes);
+    }
+    if let Some(codes) = &codes {
+        if let Err(e) = memory.lock().unwrap().set_opcodes(codes) {
+            eprintln!("{}: {}", filename, e);
+            exit(1);
+        }
+    }
+
+    // Symbol files name addresses the object leaves unnamed
+    if let Some(object) = &object {
+        for (name, address) in &object.symbols {
+            labels.entry(*address).or_insert_with(|| name.clone());
+        }
+    }
+    let symbols: Vec<(String, u64)> = labels.iter().map(|(&address, name)| (name.clone(), address)).collect();
+
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    if let Some(object) = &object {
+        cpu.ptr[PC] = object.entry;
+    }
+    cpu.exec_check = check;
+    cpu.timing = timing;
+    cpu.word_size = word_size;
+    cpu.rng = Rng::new(seed);
+    let rtc = Clock::new();
+    attach_clock(&mut memory.lock().unwrap(), &rtc);
+    cpu.rtc = Some(rtc);
+    if profile.is_some() {
+        cpu.profiler = Some(Profiler::new());
+    }
+    if let Some(file) = &trace {
+        cpu.trace = Some(Trace::create(file, json).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file, e);
+            exit(1);
+        }).with_labels(labels.clone()));
+    }
+    if let Some(file) = &record {
+        cpu.replay = Some(Session::record(file).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file, e);
+            exit(1);
+        }));
+    }
+    if let Some(file) = &replay {
+        cpu.replay = Some(Session::replay(file).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file, e);
+            exit(1);
+        }));
+    }
+
+    let format = screen_format.unwrap_or(ScreenFormat::EMU);
+    if let Some(cycles) = capture_every {
+        cpu.scheduler.add(Box::new(Capture::new(&capture, format)), cycles, 0);
+    }
+    if let Some(reference) = &compare {
+        exit(compare_screens(&mut cpu, &format, frames, reference, tolerance, &diff));
+    }
+    let screen = screen_format.map(|format| open_screen(&memory, format, display, sound));
+
+    let machine = match cores {
+        1 => Machine { cores: vec![Arc::new(Mutex::new(cpu))] },
+        _ => Machine::new(cpu, cores),
+    };
+
+    if debugger {
+        #[cfg(feature = "debugger")]
+        if tui != Some(false) {
+            let mut debugger = Debugger::new(Arc::clone(machine.core(0)), memory);
+            if machine.len() > 1 {
+                debugger.set_machine(machine);
+            }
+            debugger.add_labels(&symbols);
+            debugger.set_screen_format(format);
+            debugger.run();
+            return;
+        }
+        let mut core = DebuggerCore::new(Arc::clone(machine.core(0)), memory);
+        if machine.len() > 1 {
+            core.set_machine(machine);
+        }
+        core.add_labels(&symbols);
+        core.screen = format;
+        LineDebugger::stdio().run_core(&mut core);
+        return;
+    }
+
+    if threaded {
+        machine.run_threaded();
+    } else {
+        machine.run();
+    }
+    {
+        let mut cpu = machine.core(0).lock().unwrap();
+        if let (Some(file), Some(trace)) = (&trace, cpu.trace.as_mut()) {
+            if let Err(e) = trace.flush() {
+                eprintln!("{}: {}", file, e);
+            }
+        }
+        if let (Some(file), Some(session)) = (&replay, &cpu.replay) {
+            if session.diverged().is_none() && session.remaining() > 0 {
+                eprintln!("warning: {}: {} inputs were not read", file, session.remaining());
+            }
+        }
+        if let (Some(file), Some(profiler)) = (&profile, &cpu.profiler) {
+            let memory = memory.lock().unwrap();
+            let saved = if json {
+                profiler.save_json(&memory, &labels, file)
+            } else {
+                profiler.save_csv(&memory, &labels, file)
+            };
+            if let Err(e) = saved {
+                eprintln!("{}: {}", file, e);
+            }
+        }
+        if batch {
+            exit((cpu.r[0] & 0xff) as i32);
+        }
+    }
+    if json {
+        let memory = memory.lock().unwrap();
+        let cores: Vec<_> = machine.cores.iter().map(|core| core.lock().unwrap().to_json()).collect();
+        let regions: Vec<_> = dumps.iter().map(|range| json!({
+            "address": range.start,
+            "bits": range.end - range.start,
+            "lines": memory.dump_json(range.clone()),
+        })).collect();
+        println!("{}", json!({ "cores": cores, "memory": regions }));
+    } else {
+        for (n, core) in machine.cores.iter().enumerate() {
+            if machine.len() > 1 {
+                println!("Core {}:", n);
+            }
+            print!("{}", core.lock().unwrap().dump());
+        }
+        let memory = memory.lock().unwrap();
+        for range in &dumps {
+            print!("Memory at {:#x}:\n{}", range.start, memory.dump(range.clone()));
+        }
+    }
+    if let Some(screen) = &screen {
+        screen.wait();
+    }
+}
@@ -0,0 +1,46 @@
+//---
+// Assemble a small program directly in memory and run it
+//
+// Instructions are written field by field with the default opcode table:
+// each field is a (value, width) pair, stored most significant bit first.
+//---
+
+use std::sync::{Arc, Mutex};
+use emu::cpu::CPU;
+use emu::memory::Memory;
+
+// Write a sequence of (value, width) fields from `address`, return the end
+fn assemble(memory: &mut Memory, mut address: u64, fields: &[(u64, usize)]) -> u64 {
+    for &(value, width) in fields {
+        memory.write(address, value, width);
+        address += width as u64;
+    }
+    address
+}
+
+fn main() {
+    let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+
+    let end = {
+        let mut mem = memory.lock().unwrap();
+        assemble(&mut mem, 0, &[
+            // leti r0 5
+            (0b0111, 4), (0, 3), (0b10, 2), (5, 8),
+            // leti r1 37
+            (0b0111, 4), (1, 3), (0b10, 2), (37, 8),
+            // add2 r0 r1
+            (0b0000, 4), (0, 3), (1, 3),
+            // jump -13 (loop on itself to halt)
+            (0b1010, 4), (0b0, 1), (-13i64 as u64 & 0xff, 8),
+        ])
+    };
+    println!("assembled {} bits", end);
+
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    while !cpu.h {
+        cpu.execute();
+    }
+
+    println!("{}", cpu.dump());
+    println!("r0 = {}", cpu.r[0]);
+}
@@ -0,0 +1,59 @@
+//---
+// Branch statistics and prediction experiments
+//
+// Reads the conditional branches of a simu trace (simu -t trace.txt), or
+// runs a program in the emulator with --run, then prints per-branch
+// taken/not-taken counts and the accuracy of the simple predictors.
+//---
+
+use std::sync::{Arc, Mutex};
+use emu::branch::{branch_event, read_simu_trace, BranchEvent, BranchStats};
+use emu::cpu::{CPU, PC};
+use emu::memory::Memory;
+
+// Run a program and collect its branches
+fn run(filename: &str, max_steps: usize) -> Vec<BranchEvent> {
+    let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+    memory.lock().unwrap().load_program(filename).expect("cannot load program");
+
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    let mut events = Vec::new();
+    for _ in 0..max_steps {
+        if cpu.h {
+            break;
+        }
+        let pc = cpu.ptr[PC];
+        cpu.execute();
+        if let Some(event) = branch_event(&memory.lock().unwrap(), pc, cpu.ptr[PC]) {
+            events.push(event);
+        }
+    }
+    events
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let events = match args.get(1).map(String::as_str) {
+        Some("--run") if args.len() >= 3 => {
+            let max_steps = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(1_000_000);
+            run(&args[2], max_steps)
+        }
+        Some(trace) if !trace.starts_with('-') => {
+            read_simu_trace(trace).unwrap_or_else(|e| {
+                eprintln!("{}: {}", trace, e);
+                std::process::exit(1);
+            })
+        }
+        _ => {
+            eprintln!("usage: {} <trace.txt>", args[0]);
+            eprintln!("       {} --run <program.bin> [max steps]", args[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let mut stats = BranchStats::new();
+    for event in events {
+        stats.record(event);
+    }
+    print!("{}", stats.report());
+}
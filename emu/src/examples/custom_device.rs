@@ -0,0 +1,51 @@
+//---
+// Attach a custom device to the emulator
+//
+// The device is an uppercase console: whenever the program writes a byte
+// to its address, the byte is printed in uppercase.
+//---
+
+use std::sync::{Arc, Mutex};
+use emu::cpu::CPU;
+use emu::memory::{Memory, MmioHandler};
+
+struct UpperConsole {
+    written: usize,
+}
+
+impl MmioHandler for UpperConsole {
+    // Reading the register gives the number of characters printed so far
+    fn read(&self, _offset: u64, _n: usize) -> u64 {
+        self.written as u64
+    }
+
+    fn write(&mut self, _offset: u64, value: u64, _n: usize) {
+        print!("{}", (value as u8 as char).to_ascii_uppercase());
+        self.written += 1;
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <program.bin>", args[0]);
+        std::process::exit(1);
+    }
+
+    let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+    memory.lock().unwrap().load_program(&args[1]).expect("cannot load program");
+
+    // Put the device at the start of the I/O window
+    {
+        let mut mem = memory.lock().unwrap();
+        let address = mem.io_base();
+        mem.register_mmio(address..address + 8, Box::new(UpperConsole { written: 0 }));
+        println!("device mapped at {:#x}", address);
+    }
+
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    while !cpu.h {
+        cpu.execute();
+    }
+    println!();
+}
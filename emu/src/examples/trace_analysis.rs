@@ -0,0 +1,52 @@
+//---
+// Run a program with tracing and analyze the trace
+//
+// Records the PC of every executed instruction, then reports the most
+// executed addresses and the per-instruction counts kept by the CPU.
+//---
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use emu::cpu::CPU;
+use emu::disasm::{disasm_format, DISASM_INS_COUNT};
+use emu::memory::Memory;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <program.bin> [max steps]", args[0]);
+        std::process::exit(1);
+    }
+    let max_steps: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(1_000_000);
+
+    let memory = Arc::new(Mutex::new(Memory::new(0, 0, 0, 0)));
+    memory.lock().unwrap().load_program(&args[1]).expect("cannot load program");
+
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    let mut trace = Vec::new();
+    while !cpu.h && trace.len() < max_steps {
+        trace.push(cpu.ptr[emu::cpu::PC]);
+        cpu.execute();
+    }
+
+    let mut hits: HashMap<u64, usize> = HashMap::new();
+    for pc in &trace {
+        *hits.entry(*pc).or_insert(0) += 1;
+    }
+    let mut hits: Vec<_> = hits.into_iter().collect();
+    hits.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+
+    println!("{} instructions executed", trace.len());
+    println!("hottest addresses:");
+    for (pc, count) in hits.iter().take(10) {
+        println!("  {:08x}  {}", pc, count);
+    }
+
+    println!("instruction counts:");
+    for (opcode, count) in cpu.counts().iter().enumerate().take(DISASM_INS_COUNT) {
+        if *count > 0 {
+            let name = disasm_format(opcode as u32).map(|f| f.mnemonic).unwrap_or("?");
+            println!("  {:<8} {}", name, count);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//---
+// emu - library interface of the MinimISA emulator
+//
+// The modules live in include/ for historical reasons; they are exposed
+// here so that front ends and the programs in examples/ can use them. The
+// ncurses debugger and SDL windows are behind the debugger and sdl
+// features, since they need ncurses and SDL; wasm.rs is the interface of builds for web
+// pages.
+//---
+
+#[path = "../include/defs.rs"]
+pub mod defs;
+#[path = "../include/util.rs"]
+pub mod util;
+#[path = "../include/errors.rs"]
+pub mod errors;
+#[path = "../include/memory.rs"]
+pub mod memory;
+#[path = "../include/scheduler.rs"]
+pub mod scheduler;
+#[path = "../include/devices.rs"]
+pub mod devices;
+#[path = "../include/debuginfo.rs"]
+pub mod debuginfo;
+#[path = "../include/disasm.rs"]
+pub mod disasm;
+#[path = "../include/progen.rs"]
+pub mod progen;
+#[path = "../include/icache.rs"]
+pub mod icache;
+#[path = "../include/journal.rs"]
+pub mod journal;
+#[path = "../include/profiler.rs"]
+pub mod profiler;
+#[path = "../include/trace.rs"]
+pub mod trace;
+#[path = "../include/replay.rs"]
+pub mod replay;
+#[path = "../include/cpu.rs"]
+pub mod cpu;
+#[path = "../include/multicore.rs"]
+pub mod multicore;
+#[path = "../include/compat.rs"]
+pub mod compat;
+#[path = "../include/breaks.rs"]
+pub mod breaks;
+#[path = "../include/branch.rs"]
+pub mod branch;
+#[path = "../include/vram.rs"]
+pub mod vram;
+#[path = "../include/screencmp.rs"]
+pub mod screencmp;
+#[path = "../include/display.rs"]
+pub mod display;
+#[cfg(feature = "sdl")]
+#[path = "../include/sdl.rs"]
+pub mod sdl;
+#[path = "../include/graphical.rs"]
+pub mod graphical;
+#[path = "../include/layout.rs"]
+pub mod layout;
+#[path = "../include/expr.rs"]
+pub mod expr;
+#[path = "../include/snapshot.rs"]
+pub mod snapshot;
+#[path = "../include/debugcore.rs"]
+pub mod debugcore;
+#[path = "../include/debugcli.rs"]
+pub mod debugcli;
+#[cfg(feature = "debugger")]
+#[path = "../include/debugger.rs"]
+pub mod debugger;
+#[path = "../include/testing.rs"]
+pub mod testing;
+#[path = "../include/ffi.rs"]
+pub mod ffi;
+#[cfg(feature = "wasm")]
+#[path = "../include/wasm.rs"]
+pub mod wasm;
@@ -0,0 +1,49 @@
+//---
+// emu --compare-screens, from the command line
+//---
+
+use std::path::Path;
+use std::process::Command;
+use emu::memory::Memory;
+use emu::screencmp::Image;
+use emu::testing::assemble;
+
+// Exit code of emu --compare-screens on the program, with extra options
+fn compare(dir: &Path, options: &[&str]) -> i32 {
+    let output = Command::new(env!("CARGO_BIN_EXE_emu"))
+        .args(["--compare-screens", dir.join("reference.ppm").to_str().unwrap()])
+        .args(["--diff", dir.join("diff.ppm").to_str().unwrap()])
+        .args(options)
+        .arg(dir.join("program.obj"))
+        .output()
+        .unwrap();
+    output.status.code().unwrap()
+}
+
+#[test]
+fn test_compare_screens() {
+    let dir = std::env::temp_dir().join(format!("minimisa-compare-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // A red first pixel in the format of simu: RGB555, least significant
+    // bit first
+    let vram = Memory::new(0, 0, 0, 0).vram_base();
+    let source = format!("leti r0 {}\nsetctr a0 r0\nleti r1 {}\nwrite a0 16 r1\nend: jump end",
+        vram, 0x7c00u16.reverse_bits());
+    std::fs::write(dir.join("program.obj"), assemble(&source).unwrap().to_bytes()).unwrap();
+    let mut pixels = vec![[0, 0, 0]; 160 * 128];
+    pixels[0] = [255, 0, 0];
+    Image { width: 160, height: 128, pixels }.save_ppm(dir.join("reference.ppm").to_str().unwrap()).unwrap();
+
+    assert_eq!(compare(&dir, &["--screen", "simu"]), 0);
+    assert!(!dir.join("diff.ppm").exists());
+
+    // Read as an emu screen, the pixel is blue
+    assert_eq!(compare(&dir, &[]), 1);
+    let diff = Image::load_ppm(dir.join("diff.ppm").to_str().unwrap()).unwrap();
+    assert_eq!((diff.pixels[0], diff.pixels[1]), ([255, 0, 0], [0, 0, 0]));
+
+    std::fs::remove_file(dir.join("reference.ppm")).unwrap();
+    assert_eq!(compare(&dir, &[]), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
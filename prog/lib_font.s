@@ -76,7 +76,7 @@ _putc_pixel_end:
 	leti	r3 155
 	shift	left r3 4
 	getctr	a1 r4
-	add2i	r4 r3
+	add2	r4 r3
 	setctr	a1 r4
 
 	sub2i	r5 1
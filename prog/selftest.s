@@ -0,0 +1,344 @@
+;-----------------------------------------------------------------------------;
+;  Self-test of the toolchain: the instructions and the drawing routines      ;
+;-----------------------------------------------------------------------------;
+
+; Each check compares a result and calls check, which counts it as passed or
+; failed. The counters live at the start of the data segment while the tests
+; run, since the library routines are free to use every register. There is
+; no serial device yet, so the report is left in registers when halting:
+;   r0 = number of checks that did not pass (failed or never reached)
+;   r1 = number of passed checks
+;   r2 = number of checks in this file
+;   r7 = 0x5e1f, only once the report is complete
+; With emu --run the exit code is thus 0 when everything passed. Run it all
+; with ../selftest.sh.
+;
+; Only the instructions the emulator executes are used (not rand), and only
+; the routines that take their arguments in registers are called: call
+; pushes the return address, so the routines that pop their arguments off
+; the stack (fill, draw, putc) do not run on the emulator.
+
+	jump	main
+
+	.include lib_draw.s
+
+;	check()
+;	Counts a check as passed if Z is set, as failed otherwise. Modifies r5,
+;	r6 and a1 only.
+;
+;	@flags	Z set when the check passed
+check:
+	leti	r6 0xc000
+	jumpif	z _check_count
+	leti	r6 0xc040
+_check_count:
+	setctr	a1 r6
+	readze	a1 64 r5
+	add2i	r5 1
+	setctr	a1 r6
+	write	a1 64 r5
+	return
+
+;	set_r0()
+;	Target of the call test.
+set_r0:
+	leti	r0 7
+	return
+
+main:
+	; Stack at the end of the stack segment, counters to zero
+	leti	r0 0xc000
+	setctr	sp r0
+	setctr	a1 r0
+	leti	r1 0
+	write	a1 64 r1
+	write	a1 64 r1
+
+; Moves
+
+	leti	r0 -0x538ba20c467c034b
+	let	r1 r0
+	cmpi	r1 -0x538ba20c467c034b
+	call	check
+
+; Arithmetic
+
+	leti	r0 40
+	leti	r1 2
+	add2	r0 r1
+	cmpi	r0 42
+	call	check
+	add2i	r0 0x100
+	cmpi	r0 0x12a
+	call	check
+	add3	r2 r0 r1
+	cmpi	r2 0x12c
+	call	check
+	add3i	r2 r1 1000
+	cmpi	r2 1002
+	call	check
+
+	sub2	r0 r1
+	cmpi	r0 0x128
+	call	check
+	sub2i	r0 0x28
+	cmpi	r0 0x100
+	call	check
+	sub3	r2 r1 r0
+	cmpi	r2 -254
+	call	check
+	sub3i	r2 r0 1
+	cmpi	r2 0xff
+	call	check
+
+; Logic
+
+	leti	r0 0xf0f0
+	leti	r1 0x0ff0
+	and3	r2 r0 r1
+	cmpi	r2 0x00f0
+	call	check
+	or3	r2 r0 r1
+	cmpi	r2 0xfff0
+	call	check
+	xor3	r2 r0 r1
+	cmpi	r2 0xff00
+	call	check
+	and3i	r2 r0 0xff
+	cmpi	r2 0xf0
+	call	check
+	or3i	r2 r0 0x0f
+	cmpi	r2 0xf0ff
+	call	check
+	xor3i	r2 r0 0xffff
+	cmpi	r2 0x0f0f
+	call	check
+
+	and2	r0 r1
+	cmpi	r0 0x00f0
+	call	check
+	or2	r0 r1
+	cmpi	r0 0x0ff0
+	call	check
+	and2i	r0 0x0f00
+	cmpi	r0 0x0f00
+	call	check
+	or2i	r0 0x000f
+	cmpi	r0 0x0f0f
+	call	check
+
+; Shifts
+
+	leti	r0 1
+	shift	left r0 62
+	cmpi	r0 0x4000000000000000
+	call	check
+	shift	left r0 1
+	asr3	r1 r0 60
+	cmpi	r1 -8
+	call	check
+	shift	right r0 60
+	cmpi	r0 8
+	call	check
+
+; Comparisons and conditional jumps (each taken, then some not taken)
+
+	leti	r0 5
+	leti	r1 5
+	leti	r2 0
+	cmp	r0 r1
+	jumpif	eq _cond_eq
+	leti	r2 1
+_cond_eq:
+	cmpi	r2 0
+	call	check
+
+	leti	r2 0
+	cmpi	r0 3
+	jumpif	neq _cond_neq
+	leti	r2 1
+_cond_neq:
+	cmpi	r2 0
+	call	check
+
+	leti	r2 0
+	cmpi	r0 3
+	jumpif	gt _cond_gt
+	leti	r2 1
+_cond_gt:
+	cmpi	r2 0
+	call	check
+
+	leti	r2 0
+	cmpi	r0 5
+	jumpif	ge _cond_ge
+	leti	r2 1
+_cond_ge:
+	cmpi	r2 0
+	call	check
+
+	leti	r2 0
+	cmpi	r0 6
+	jumpif	lt _cond_lt
+	leti	r2 1
+_cond_lt:
+	cmpi	r2 0
+	call	check
+
+	leti	r0 -1
+	leti	r2 0
+	cmpi	r0 3
+	jumpif	slt _cond_slt
+	leti	r2 1
+_cond_slt:
+	cmpi	r2 0
+	call	check
+
+	leti	r2 0
+	cmpi	r0 -5
+	jumpif	sgt _cond_sgt
+	leti	r2 1
+_cond_sgt:
+	cmpi	r2 0
+	call	check
+
+	; -1 is the largest unsigned value
+	leti	r2 1
+	cmpi	r0 3
+	jumpif	lt _cond_nlt
+	leti	r2 0
+_cond_nlt:
+	cmpi	r2 0
+	call	check
+
+	leti	r2 1
+	cmpi	r0 3
+	jumpif	eq _cond_neq2
+	leti	r2 0
+_cond_neq2:
+	cmpi	r2 0
+	call	check
+
+; Jumps, calls and returns
+
+	leti	r0 0
+	jump	_jump_over
+	leti	r0 1
+_jump_over:
+	cmpi	r0 0
+	call	check
+
+	leti	r0 0
+	call	set_r0
+	cmpi	r0 7
+	call	check
+
+; Pointers and memory accesses of every size
+
+	leti	r0 0xc100
+	setctr	a0 r0
+	getctr	a0 r1
+	cmp	r0 r1
+	call	check
+
+	leti	r1 0xbeef
+	write	a0 16 r1
+	getctr	a0 r2
+	cmpi	r2 0xc110
+	call	check
+	setctr	a0 r0
+	readze	a0 16 r2
+	cmpi	r2 0xbeef
+	call	check
+	setctr	a0 r0
+	readse	a0 16 r2
+	cmpi	r2 -0x4111
+	call	check
+
+	leti	r1 -1
+	setctr	a0 r0
+	write	a0 1 r1
+	write	a0 4 r1
+	write	a0 8 r1
+	write	a0 32 r1
+	write	a0 64 r1
+	setctr	a0 r0
+	readze	a0 1 r2
+	cmpi	r2 1
+	call	check
+	readze	a0 4 r2
+	cmpi	r2 15
+	call	check
+	readze	a0 8 r2
+	cmpi	r2 0xff
+	call	check
+	readze	a0 32 r2
+	cmpi	r2 0xffffffff
+	call	check
+	readse	a0 64 r2
+	cmpi	r2 -1
+	call	check
+
+; Stack
+
+	getctr	sp r3
+	leti	r0 0x1234
+	push	16 r0
+	leti	r0 -2
+	push	64 r0
+	pop	64 r1
+	pop	16 r2
+	getctr	sp r4
+	cmpi	r1 -2
+	call	check
+	cmpi	r2 0x1234
+	call	check
+	cmp	r3 r4
+	call	check
+
+; Time
+
+	leti	r0 3
+	sleep	10
+	cmpi	r0 3
+	call	check
+
+; Drawing library
+
+	; clear_screen(color): first and last pixels of VRAM
+	leti	r1 0x1234
+	call	clear_screen
+	leti	r0 0x10000
+	setctr	a0 r0
+	readze	a0 16 r1
+	cmpi	r1 0x1234
+	call	check
+	leti	r0 0x5fff0
+	setctr	a0 r0
+	readze	a0 16 r1
+	cmpi	r1 0x1234
+	call	check
+
+	; plot(3, 5, color) returns a pointer to the pixel
+	leti	r1 3
+	leti	r2 5
+	leti	r3 0xf00d
+	call	plot
+	cmpi	r1 0x5c430
+	call	check
+	setctr	a0 r1
+	readze	a0 16 r2
+	cmpi	r2 0xf00d
+	call	check
+
+; Report: r0 = not passed, r1 = passed, r2 = total, r7 = marker
+
+	leti	r0 0xc000
+	setctr	a1 r0
+	readze	a1 64 r1
+	leti	r2 50
+	sub3	r0 r2 r1
+	leti	r7 0x5e1f
+
+; Halt program (the emulator will detect this and avoid looping forever)
+	jump	-13
@@ -0,0 +1,21 @@
+#! /usr/bin/env bash
+
+# Smoke test of the whole toolchain: compile prog/selftest.s along with the
+# drawing library, run it with emu --run and take its exit code, which is the
+# number of checks that did not pass (see the header of prog/selftest.s).
+# The compiler driver is taken from $MINIMISA, or minimisa in the PATH.
+
+cd "$(dirname "$0")" || exit 1
+
+"${MINIMISA:-minimisa}" build prog/selftest.s -o prog/selftest.obj || exit 1
+
+cargo run --quiet --release --no-default-features --manifest-path emu/src/Cargo.toml \
+	--bin emu -- --run prog/selftest.obj
+failed=$?
+
+if [[ "$failed" == 0 ]]; then
+	echo "selftest: all checks passed"
+else
+	echo "selftest: $failed checks did not pass"
+fi
+[[ "$failed" == 0 ]]
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
 use std::path::Path;
 use std::process;
+use minimisa_core::{instruction, lookup, Operand};
 
 static mut LINE: usize = 0;
 static mut CURRENT_ADDR: u64 = 0;
@@ -26,14 +27,27 @@ fn asm_reg(s: &str) -> String {
     format!("{:03b} ", val) // 3 bits
 }
 
+// Parse a decimal or 0x-prefixed hexadecimal number, possibly negative
+fn parse_signed(s: &str) -> i64 {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let val = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).map(|v| v as i64),
+        None => digits.parse::<i64>(),
+    }.unwrap_or_else(|_| error("Invalid number"));
+    if negative { val.wrapping_neg() } else { val }
+}
+
 fn asm_addr_signed(s: &str) -> String {
-    let val: i64 = s.parse().expect("Failed to parse address");
+    let val = parse_signed(s);
     if (-128..=127).contains(&val) {
-        format!("0 {:08b} ", val)
+        format!("0 {:08b} ", val as u8)
     } else if (-32768..=32767).contains(&val) {
-        format!("10 {:016b} ", val)
+        format!("10 {:016b} ", val as u16)
     } else if (-2i64.pow(31)..=2i64.pow(31) - 1).contains(&val) {
-        format!("110 {:032b} ", val)
+        format!("110 {:032b} ", val as u32)
     } else {
         format!("111 {:064b} ", val)
     }
@@ -57,6 +71,35 @@ fn asm_const_unsigned(s: &str) -> String {
     }
 }
 
+fn asm_const_signed(s: &str) -> String {
+    let val = parse_signed(s);
+    if (-1..=0).contains(&val) {
+        format!("0 {} ", val & 1)
+    } else if (-128..=127).contains(&val) {
+        format!("10 {:08b} ", val as u8)
+    } else if (-2i64.pow(31)..=2i64.pow(31) - 1).contains(&val) {
+        format!("110 {:032b} ", val as u32)
+    } else {
+        format!("111 {:064b} ", val)
+    }
+}
+
+fn asm_shift(s: &str) -> String {
+    match parse_signed(s) {
+        1 => "1 ".to_string(),
+        val @ 0..=63 => format!("0 {:06b} ", val),
+        _ => error("Invalid shift amount"),
+    }
+}
+
+fn asm_direction(dir: &str) -> String {
+    match dir {
+        "left" => "0 ".to_string(),
+        "right" => "1 ".to_string(),
+        _ => error("Invalid direction"),
+    }
+}
+
 fn asm_condition(cond: &str) -> String {
     let condlist = HashMap::from([
         ("eq", "000"), ("z", "000"), ("neq", "001"), ("nz", "001"),
@@ -85,7 +128,31 @@ fn asm_size(s: &str) -> String {
     codelist.get(s).unwrap_or_else(|| error("Invalid size")).to_string()
 }
 
-fn asm_pass(iteration: u32, s_file: &str) -> Vec<String> {
+// Encode one operand of the given kind
+fn asm_operand(kind: Operand, s: &str) -> String {
+    match kind {
+        Operand::None => String::new(),
+        Operand::Register => asm_reg(s),
+        Operand::Direction => asm_direction(s),
+        Operand::Condition => asm_condition(s) + " ",
+        Operand::Address => asm_addr_signed(s),
+        Operand::LConst => asm_const_unsigned(s),
+        Operand::AConst => asm_const_signed(s),
+        Operand::Shift => asm_shift(s),
+        Operand::Size => asm_size(s) + " ",
+        Operand::Pointer => asm_counter(s) + " ",
+    }
+}
+
+// One source line after assembly
+struct AsmLine {
+    address: u64,           // Bit address of the line
+    label: Option<String>,  // Label defined on this line
+    encoding: String,       // Fields separated by spaces, empty without an instruction
+    source: String,
+}
+
+fn asm_pass(iteration: u32, s_file: &str) -> Vec<AsmLine> {
     let mut code = vec![];
     let mut current_address = 0;
 
@@ -102,32 +169,28 @@ fn asm_pass(iteration: u32, s_file: &str) -> Vec<String> {
         let line_content = source_line.split(';').next().unwrap_or("").to_string();
         let tokens: Vec<&str> = line_content.split_whitespace().collect();
 
-        if !tokens.is_empty() {
-            if let Some(label) = tokens.get(0) {
-                if label.ends_with(':') {
-                    unsafe {
-                        LABELS.get_or_insert(HashMap::new()).insert(label.trim_end_matches(':'), current_address);
-                    }
-                }
+        let mut tokens = &tokens[..];
+        let address = current_address;
+        let mut label = None;
+        if let Some(name) = tokens.first().filter(|t| t.ends_with(':')) {
+            let name = name.trim_end_matches(':').to_string();
+            unsafe {
+                LABELS.get_or_insert(HashMap::new()).insert(name.clone(), current_address);
             }
+            label = Some(name);
+            tokens = &tokens[1..];
         }
 
         if !tokens.is_empty() {
-            let opcode = tokens[0];
-            let token_count = tokens.len();
-            match opcode {
-                "add2" if token_count == 3 => {
-                    instruction_encoding = format!("0000 {} {}", asm_reg(tokens[1]), asm_reg(tokens[2]));
-                }
-                "add2i" if token_count == 3 => {
-                    instruction_encoding = format!("0001 {} {}", asm_reg(tokens[1]), asm_const_unsigned(tokens[2]));
-                }
-                "jump" if token_count == 2 => {
-                    instruction_encoding = format!("1010 {}", asm_addr_signed(tokens[1]));
-                }
-                _ => {
-                    error("Unknown opcode or incorrect token count");
-                }
+            let ins = lookup(tokens[0]).and_then(instruction)
+                .unwrap_or_else(|| error("Unknown opcode"));
+            if tokens.len() != ins.arity() + 1 {
+                error("Incorrect token count");
+            }
+
+            instruction_encoding = format!("{} ", ins.code);
+            for (&kind, token) in ins.operands.iter().zip(&tokens[1..]) {
+                instruction_encoding += &asm_operand(kind, token);
             }
 
             if !instruction_encoding.is_empty() {
@@ -145,28 +208,114 @@ fn asm_pass(iteration: u32, s_file: &str) -> Vec<String> {
         unsafe {
             LINE += 1;
         }
-        code.push(instruction_encoding);
+        code.push(AsmLine { address, label, encoding: instruction_encoding, source: source_line });
     }
 
     code
 }
 
+// Pack a string of '0' and '1' into bytes, most significant bit first, as
+// read by the binary object loader. The last byte is padded with zeros
+fn pack_bits(bits: &str) -> Vec<u8> {
+    bits.as_bytes().chunks(8).map(|chunk| {
+        chunk.iter().enumerate().fold(0u8, |byte, (i, &b)| byte | (((b == b'1') as u8) << (7 - i)))
+    }).collect()
+}
+
+// Encoding in hexadecimal; the last digit is padded with zeros
+fn bits_to_hex(bits: &str) -> String {
+    let hex: String = pack_bits(bits).iter().map(|b| format!("{:02x}", b)).collect();
+    hex[..(bits.len() + 3) / 4].to_string()
+}
+
+// Classic listing: bit address, encoding in hexadecimal and in binary
+// fields, and the source line. Labels get a line of their own
+fn listing(code: &[AsmLine]) -> String {
+    let mut out = format!("{:<8}  {:<20}  {:<40}  {}\n", "address", "hex", "encoding", "source");
+    for line in code {
+        let bits: String = line.encoding.split_whitespace().collect();
+        let mut encoding = line.encoding.trim_end().to_string();
+        if let Some(label) = &line.label {
+            let annotation = format!("<{}>", label);
+            if bits.is_empty() {
+                encoding = annotation;
+            } else {
+                out += &format!("{:08x}  {:<20}  {}\n", line.address, "", annotation);
+            }
+        }
+        let address = if encoding.is_empty() { String::new() } else { format!("{:08x}", line.address) };
+        out += &format!("{:<8}  {:<20}  {:<40}  {}\n", address, bits_to_hex(&bits), encoding,
+            line.source.trim_end());
+    }
+    out
+}
+
+// Write to a hidden temporary file next to the destination, named like
+// the compiler's (.name.pid.tmp), renamed over it once complete; it is
+// removed if anything fails
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let name = path.file_name().map_or_else(|| "out".into(), |n| n.to_string_lossy().into_owned());
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+    let result = File::create(&tmp)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: asm <source file>");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut binary = false;
+    let mut listing_file = None;
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-b" => binary = true,
+            "--listing" => {
+                i += 1;
+                listing_file = args.get(i);
+                if listing_file.is_none() {
+                    files.clear();
+                    break;
+                }
+            }
+            _ => files.push(&args[i]),
+        }
+        i += 1;
+    }
+    if files.len() != 1 {
+        eprintln!("Usage: asm [-b] [--listing <file.lst>] <source file>");
+        eprintln!("  -b         write a packed binary object instead of '0' and '1' characters");
+        eprintln!("  --listing  write addresses, encodings and source lines to a listing file");
         process::exit(1);
     }
 
-    let filename = &args[1];
+    let filename = files[0];
     let basefilename = Path::new(filename).file_stem().unwrap().to_str().unwrap();
     let obj_file = format!("{}.obj", basefilename);
 
     let code = asm_pass(1, filename);
 
-    let mut outfile = File::create(obj_file).expect("Cannot create output file");
-    for instr in &code {
-        writeln!(outfile, "{}", instr).expect("Failed to write to file");
+    let contents = if binary {
+        let bits: String = code.iter().flat_map(|line| line.encoding.split_whitespace()).collect();
+        pack_bits(&bits)
+    } else {
+        code.iter().map(|line| format!("{}\n", line.encoding)).collect::<String>().into_bytes()
+    };
+
+    if let Err(e) = write_atomic(Path::new(&obj_file), &contents) {
+        eprintln!("Cannot write {}: {}", obj_file, e);
+        process::exit(1);
+    }
+
+    if let Some(file) = listing_file {
+        if let Err(e) = write_atomic(Path::new(file), listing(&code).as_bytes()) {
+            eprintln!("Cannot write {}: {}", file, e);
+            process::exit(1);
+        }
     }
 
     println!("Average instruction size: {}", unsafe { CURRENT_ADDR } as f64 / code.len() as f64);
@@ -0,0 +1,179 @@
+use std::sync::{Arc, Mutex};
+use emu::compat::{simu_load, simu_profile, ObjFormat};
+use emu::cpu::{CPU, A0, A1, PC, SP};
+use emu::disasm::disasm_one;
+
+use crate::processor::Processor;
+
+// Co-simulation (simu --cosim)
+//
+// Runs the program on this Processor and on the CPU of emu, in its simu
+// profile with the word size of the processor, one instruction at a
+// time. Pointers, registers and flags are compared after every
+// instruction, and so are the memory words either engine wrote; the run
+// stops at the first difference with a report of it. The engines do not
+// store bits in the same order in a word: emu keeps the first bit of a
+// word as its most significant one, simu as its least significant one, so
+// simu words are reversed before the comparison.
+
+// Whatever differs between the two engines after an instruction
+pub struct Divergence {
+    pub steps: usize,      // Instructions executed, including this one
+    pub pc: u64,           // Address of the instruction, in emu
+    pub instruction: String,
+    pub differences: Vec<(&'static str, u64, u64)>,  // Name, simu, emu
+    pub memory: Vec<(u64, u64, u64)>,  // Bit address of a word, simu, emu
+}
+
+impl Divergence {
+    pub fn report(&self) -> String {
+        let mut text = format!("Divergence after {} instructions, at {:#010x}: {}\n",
+            self.steps, self.pc, self.instruction);
+        text.push_str(&format!("      {:<18}  {}\n", "simu", "emu"));
+        for (name, simu, emu) in &self.differences {
+            text.push_str(&format!("  {:<4}{:#018x}  {:#018x}\n", name, simu, emu));
+        }
+        for (address, simu, emu) in &self.memory {
+            text.push_str(&format!("  mem {:#018x}  {:#018x}  at {:#x}\n", simu, emu, address));
+        }
+        text
+    }
+}
+
+// Pointers, registers and flags of emu, named as in Processor::registers
+fn emu_registers(cpu: &CPU) -> Vec<(&'static str, u64)> {
+    let mut registers = vec![("pc", cpu.ptr[PC]), ("sp", cpu.ptr[SP]), ("a0", cpu.ptr[A0]), ("a1", cpu.ptr[A1])];
+    let names = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7"];
+    registers.extend(names.into_iter().zip(cpu.r));
+    registers.extend([("z", cpu.z as u64), ("c", cpu.c as u64), ("n", cpu.n as u64), ("v", cpu.v as u64)]);
+    registers
+}
+
+// Run until both engines halt or they differ. The processor must have the
+// program loaded already. Returns the number of instructions executed
+pub fn cosim(processor: &mut Processor, filename: &str, format: ObjFormat, debug: bool)
+    -> Result<usize, Divergence>
+{
+    let memory = Arc::new(Mutex::new(emu::memory::Memory::new(0, 0, 0, 0)));
+    if let Err(e) = simu_load(&mut memory.lock().unwrap(), filename, Some(format)) {
+        eprintln!("emu cannot load {}: {}", filename, e);
+        std::process::exit(1);
+    }
+    let mut cpu = CPU::new(Arc::clone(&memory));
+    simu_profile(&mut cpu);
+    cpu.word_size = processor.word();
+
+    let mut steps = 0;
+    loop {
+        let pc = cpu.ptr[PC];
+        let instruction = disasm_one(&memory.lock().unwrap(), &mut pc.clone()).unwrap_or_else(|| "???".to_string());
+        processor.memory().lock().unwrap().start_write_log();
+        memory.lock().unwrap().start_write_log();
+        processor.von_neumann_step(debug);
+        cpu.execute();
+        steps += 1;
+
+        // Words either engine wrote, in the bit order of emu
+        let simu_writes = processor.memory().lock().unwrap().take_write_log();
+        let emu_writes = memory.lock().unwrap().take_write_log();
+        let mut words: Vec<u64> = simu_writes.into_iter().map(|(address, n)| (address as u64, n))
+            .chain(emu_writes.into_iter().map(|write| (write.address, write.n)))
+            .filter(|&(_, n)| n > 0)
+            .flat_map(|(address, n)| address / 64..=(address + n as u64 - 1) / 64)
+            .collect();
+        words.sort_unstable();
+        words.dedup();
+        let memory_differences: Vec<_> = {
+            let (simu, emu) = (processor.memory().lock().unwrap(), memory.lock().unwrap());
+            words.into_iter()
+                .map(|word| (word * 64, simu.m.word(word).reverse_bits(), emu.ram().word(word)))
+                .filter(|(_, simu, emu)| simu != emu)
+                .collect()
+        };
+
+        let mut differences: Vec<_> = processor.registers().into_iter().zip(emu_registers(&cpu))
+            .filter(|((_, simu), (_, emu))| simu != emu)
+            .map(|((name, simu), (_, emu))| (name, simu, emu))
+            .collect();
+        if processor.halted() != cpu.h {
+            differences.push(("halt", processor.halted() as u64, cpu.h as u64));
+        }
+        if !differences.is_empty() || !memory_differences.is_empty() {
+            return Err(Divergence { steps, pc, instruction, differences, memory: memory_differences });
+        }
+        if processor.halted() {
+            return Ok(steps);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::testing::assemble;
+    use minimisa_core::WordSize;
+    use crate::memory::Memory;
+
+    // Assemble a program to a file and run it on both engines
+    fn run(source: &str, word: WordSize) -> Result<usize, Divergence> {
+        let file = std::env::temp_dir().join(format!("simu-cosim-{}-{}.obj", std::process::id(), word.bits()));
+        let filename = file.to_str().unwrap();
+        std::fs::write(filename, assemble(source).unwrap().to_bytes()).unwrap();
+        let memory = Arc::new(Mutex::new(Memory::new()));
+        memory.lock().unwrap().fill_with_obj_file(filename, Some(ObjFormat::Object));
+        let mut processor = Processor::new(memory, word);
+        let result = cosim(&mut processor, filename, ObjFormat::Object, false);
+        std::fs::remove_file(filename).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_cosim() {
+        // Every kind of instruction but reti, which needs an interrupt
+        let source = "
+            leti r0 0xc000
+            setctr sp r0
+            setctr a0 r0
+            leti r1 -7
+            let r2 r1
+            or2i r2 0x30
+            and2 r2 r1
+            add3i r3 r1 100
+            sub3 r4 r3 r1
+            xor3i r5 r4 0xff
+            asr3 r6 r1 1
+            shift left r5 3
+            cmpi r5 0
+            jumpif eq skip
+            call f
+        skip:
+            rand r7
+            sleep 10
+            push 64 r7
+            pop 32 r6
+            write a0 16 r3
+            write a0 8 r5
+            getctr a0 r1
+            setctr a1 r0
+            readse a1 16 r2
+            readze a1 8 r4
+        end:
+            jump end
+        f:
+            add2i r1 1
+            or3 r3 r3 r1
+            return
+        ";
+        for word in [WordSize::W32, WordSize::W64] {
+            match run(source, word) {
+                Ok(steps) => assert_eq!(steps, 29),
+                Err(divergence) => panic!("{}", divergence.report()),
+            }
+        }
+
+        // A difference in memory is found where it is written
+        let divergence = Divergence { steps: 1, pc: 0, instruction: "write a0 8 r1".to_string(),
+            differences: Vec::new(), memory: vec![(0x40, 0x8000_0000_0000_0000, 0)] };
+        assert!(divergence.report().ends_with("  mem 0x8000000000000000  0x0000000000000000  at 0x40\n"));
+    }
+}
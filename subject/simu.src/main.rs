@@ -1,24 +1,35 @@
-extern crate sdl2;
-
 use std::env;
 use std::fs::File;
-use std::io::BufReader;
 use std::process::exit;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use std::thread;
-use sdl2::event::Event;
 use std::sync::Mutex;
 
+mod cosim;
+// Modules of emu, which uses the parts simu does not (the events only come
+// from the SDL window)
+#[path = "../../emu/include/display.rs"]
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+mod display;
 mod memory;
 mod processor;
 mod screen;
-
+#[cfg(feature = "sdl")]
+#[path = "../../emu/include/sdl.rs"]
+mod sdl;
+#[path = "../../emu/include/util.rs"]
+#[allow(dead_code)]
+mod util;
+
+use display::Display;
+use minimisa_core::WordSize;
+use emu::compat::ObjFormat;
 use memory::Memory;
 use processor::Processor;
-use screen::simulate_screen;
+use screen::{save_screen, simulate_screen};
 
 fn usage() {
-    eprintln!("Usage: simu [options] file.obj\nOptions: -d for debug, -s for step by step, -g for graphical screen");
+    eprintln!("Usage: simu [options] file.obj\nOptions: -d for debug, -s for step by step, -g for graphical screen, --display sdl|tty|none to show it in a window (default), the terminal or nowhere, -t <file> to write an execution trace, --format bin|txt|obj to force the object format, --capture-every <n> to save the screen every n instructions as numbered frames named after --capture <file> (.png or .ppm, default frame.png), --word-size 32|64 for the width of registers (default 32), --cosim to run emu alongside and stop where they differ");
     exit(1);
 }
 
@@ -45,44 +56,121 @@ fn main() {
     let debug = cmd_option_exists(&args, "-d");
     let step_by_step = cmd_option_exists(&args, "-s");
     let graphical_output = cmd_option_exists(&args, "-g");
+    let cosim = cmd_option_exists(&args, "--cosim");
+    let display = match get_cmd_option(&args, "--display") {
+        Some(name) => Display::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown display {}", name);
+            usage();
+            Display::None
+        }),
+        None => Display::Sdl,
+    };
 
     let filename = args.last().expect("No filename provided").clone();
 
-    if let Err(_) = File::open(&filename) {
+    if File::open(&filename).is_err() {
         eprintln!("Can't access obj file");
         usage();
     }
 
+    let word = match get_cmd_option(&args, "--word-size") {
+        Some(bits) => bits.parse().ok().and_then(WordSize::from_bits).unwrap_or_else(|| {
+            eprintln!("--word-size expects 32 or 64");
+            usage();
+            WordSize::W32
+        }),
+        None => WordSize::W32,
+    };
+
     let memory = Arc::new(Mutex::new(Memory::new()));
-    let processor = Processor::new(Arc::clone(&memory));
+    let mut processor = Processor::new(Arc::clone(&memory), word);
+
+    if let Some(tracefile) = get_cmd_option(&args, "-t") {
+        if let Err(e) = processor.set_trace(&tracefile) {
+            eprintln!("Can't create trace file {}: {}", tracefile, e);
+            usage();
+        }
+    }
 
-    memory.lock().unwrap().fill_with_obj_file(&filename);
+    // Object format is detected from the contents unless given explicitly
+    let format = match get_cmd_option(&args, "--format") {
+        Some(name) => match ObjFormat::from_name(&name) {
+            Some(format) => Some(format),
+            None => {
+                eprintln!("Unknown object format {}", name);
+                usage();
+                None
+            }
+        },
+        None => None,
+    };
+
+    memory.lock().unwrap().fill_with_obj_file(&filename, format);
+
+    // Lockstep run with emu, without the screen
+    if cosim {
+        let format = format.unwrap_or_else(|| ObjFormat::detect(&std::fs::read(&filename).unwrap_or_default()));
+        match cosim::cosim(&mut processor, &filename, format, debug) {
+            Ok(steps) => println!("simu and emu agree over {} instructions", steps),
+            Err(divergence) => {
+                print!("{}", divergence.report());
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    // Headless screen captures
+    let capture = get_cmd_option(&args, "--capture").unwrap_or_else(|| "frame.png".to_string());
+    let capture_every = match get_cmd_option(&args, "--capture-every") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                eprintln!("--capture-every expects a number of instructions");
+                usage();
+                None
+            }
+        },
+        None => None,
+    };
+    let mut steps: usize = 0;
+    let mut frames = 0;
 
-    let refresh = Arc::new(AtomicBool::new(true));
     let quit_signal = Arc::new(AtomicBool::new(false));
 
     let screen_thread = if graphical_output {
         let mem_clone = Arc::clone(&memory);
-        let refresh_clone = Arc::clone(&refresh);
         let quit_signal_clone = Arc::clone(&quit_signal);
 
         Some(thread::spawn(move || {
-            simulate_screen(&mem_clone, &refresh_clone, &quit_signal_clone);
+            simulate_screen(mem_clone, quit_signal_clone, display);
         }))
     } else {
         None
     };
 
-    loop {
+    // Stop when the screen window is closed or the program halts
+    while !quit_signal.load(Ordering::SeqCst) {
         processor.von_neumann_step(debug);
 
+        steps += 1;
+        if capture_every.is_some_and(|n| steps.is_multiple_of(n)) {
+            frames += 1;
+            let frame = minimisa_core::image::numbered(&capture, frames);
+            if let Err(e) = save_screen(&memory.lock().unwrap(), &frame) {
+                eprintln!("Can't write {}: {}", frame, e);
+            }
+        }
+        if processor.halted() {
+            break;
+        }
         if step_by_step {
             let _ = std::io::stdin().read_line(&mut String::new());
         }
     }
 
     if let Some(screen_thread) = screen_thread {
-        quit_signal.store(true, Ordering::SeqCst);  
-        screen_thread.join().unwrap();  
+        quit_signal.store(true, Ordering::SeqCst);
+        screen_thread.join().unwrap();
     }
 }
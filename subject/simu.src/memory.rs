@@ -1,84 +1,271 @@
 use std::fs::File;
-use std::io::{BufReader, Read};
-use std::io::prelude::*;
+use std::io::Read;
 use std::fmt;
+use emu::compat::ObjFormat;
+use minimisa_core::object::Object;
+use minimisa_core::pages::Pages;
 
-pub const MEMSIZE: usize = 1 << 24; 
+use crate::screen::{MEM_KEYBOARD, MEM_SCREEN_BEGIN, WIDTH};
+
+// Memory holds 2^32 bits, only allocated where written
+pub const MEMSIZE: usize = 1 << 32;
 pub const PC: usize = 0;
 pub const SP: usize = 1;
 pub const A0: usize = 2;
 pub const A1: usize = 3;
 
-pub type UWord = u32;
+pub type UWord = u64;
 
 pub struct Memory {
     pub counter: [usize; 4],  
-    pub m: [u64; MEMSIZE / 64], 
+    pub m: Pages,
+    // End of the loaded program, which the stack must not grow into
+    pub code_end: usize,
+    // One bit per screen row, set when a write lands in that row of VRAM
+    dirty_rows: u128,
+    // Address and length of every write, when logging (for --cosim)
+    write_log: Option<Vec<(usize, usize)>>,
 }
 
 impl Memory {
     pub fn new() -> Self {
         Memory {
             counter: [0; 4], 
-            m: [0; MEMSIZE / 64], 
+            m: Pages::new(),
+            code_end: 0,
+            dirty_rows: !0,
+            write_log: None,
         }
     }
 
-    pub fn read_bit(&mut self, ctr: usize) -> u64 {
-        let word_addr = self.counter[ctr] >> 6;
-        let word = self.m[word_addr]; 
-        let shift = self.counter[ctr] & 63; 
-        let bit = (word >> shift) & 1; 
-        self.counter[ctr] += 1;
-        bit
+    // Start recording the address and length of every write
+    pub fn start_write_log(&mut self) {
+        self.write_log = Some(Vec::new());
+    }
+
+    // Stop recording and return the writes since start_write_log()
+    pub fn take_write_log(&mut self) -> Vec<(usize, usize)> {
+        self.write_log.take().unwrap_or_default()
+    }
+
+    // Record a write to the given bit address if it falls in VRAM
+    fn mark_dirty(&mut self, addr: usize) {
+        if (MEM_SCREEN_BEGIN..MEM_KEYBOARD).contains(&addr) {
+            let row = (addr - MEM_SCREEN_BEGIN) / (16 * WIDTH);
+            self.dirty_rows |= 1u128 << row;
+        }
+    }
+
+    // Return the rows written since the last call and mark them clean
+    pub fn take_dirty_rows(&mut self) -> u128 {
+        let rows = self.dirty_rows;
+        self.dirty_rows = 0;
+        rows
     }
 
     pub fn write_bit(&mut self, ctr: usize, bit: u64) {
         if bit != 0 && bit != 1 {
             panic!("Expecting a bit (0 or 1)");
         }
-        let word_addr = self.counter[ctr] >> 6;
-        let mut word = self.m[word_addr]; 
+        let word_addr = (self.counter[ctr] >> 6) as u64;
+        let mut word = self.m.word(word_addr);
         let shift = self.counter[ctr] & 63; 
         let bit64 = bit << shift;
         let mask = !(1u64 << shift);
         word = (word & mask) | bit64;
-        self.m[word_addr] = word;
+        self.m.set_word(word_addr, word);
+        self.mark_dirty(self.counter[ctr]);
+        if let Some(log) = self.write_log.as_mut() {
+            log.push((self.counter[ctr], 1));
+        }
         self.counter[ctr] += 1;
     }
 
+    // Update the memory-mapped keyboard register; key k lives at bit
+    // address MEM_KEYBOARD + k so programs can poll it with a 1-bit read.
+    // The register has 64 keys, others are ignored
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        if key >= 64 {
+            return;
+        }
+        let word_addr = (MEM_KEYBOARD >> 6) as u64;
+        let mask = 1u64 << key;
+        let word = self.m.word(word_addr);
+        self.m.set_word(word_addr, if pressed { word | mask } else { word & !mask });
+    }
+
+    // Read n bits (up to 64) at a counter, first bit as most significant
+    pub fn read_bits(&mut self, ctr: usize, n: usize) -> u64 {
+        if n > 64 {
+            panic!("Can't read more than 64 bits at once");
+        }
+        if n == 0 {
+            return 0;
+        }
+        let addr = self.counter[ctr];
+        let word_addr = (addr >> 6) as u64;
+        let shift = addr & 63;
+
+        // Memory bit addr + i ends up as bit i of raw
+        let mut raw = self.m.word(word_addr) >> shift;
+        if shift + n > 64 {
+            raw |= self.m.word(word_addr + 1) << (64 - shift);
+        }
+        self.counter[ctr] += n;
+        raw.reverse_bits() >> (64 - n)
+    }
+
+    // Write the n low bits of value at a counter, most significant first
+    pub fn write_bits(&mut self, ctr: usize, value: u64, n: usize) {
+        if n > 64 {
+            panic!("Can't write more than 64 bits at once");
+        }
+        if n == 0 {
+            return;
+        }
+        let addr = self.counter[ctr];
+        let word_addr = (addr >> 6) as u64;
+        let shift = addr & 63;
+
+        let mask = if n == 64 { !0u64 } else { (1u64 << n) - 1 };
+        let raw = (value & mask).reverse_bits() >> (64 - n);
+
+        self.m.set_word(word_addr, (self.m.word(word_addr) & !(mask << shift)) | (raw << shift));
+        if shift + n > 64 {
+            let next = word_addr + 1;
+            self.m.set_word(next, (self.m.word(next) & !(mask >> (64 - shift))) | (raw >> (64 - shift)));
+        }
+        self.mark_dirty(addr);
+        self.mark_dirty(addr + n - 1);
+        if let Some(log) = self.write_log.as_mut() {
+            log.push((addr, n));
+        }
+        self.counter[ctr] += n;
+    }
+
     pub fn set_counter(&mut self, ctr: usize, val: UWord) {
         self.counter[ctr] = val as usize;
     }
 
-    pub fn fill_with_obj_file(&mut self, filename: &str) {
+    pub fn fill_with_obj_file(&mut self, filename: &str, format: Option<ObjFormat>) {
         println!("Loading...");
+        let mut bytes = Vec::new();
+        File::open(filename)
+            .expect("Failed to open file.")
+            .read_to_end(&mut bytes)
+            .expect("Failed to read file.");
+
         self.counter[0] = 0; 
-        let file = File::open(filename).expect("Failed to open file.");
-        let reader = BufReader::new(file);
-
-        for line in reader.lines() {
-            for ch in line.unwrap().chars() {
-                match ch {
-                    '0' => {
-                        print!("{}", ch);
-                        self.write_bit(0, 0);
-                    }
-                    '1' => {
-                        print!("{}", ch);
-                        self.write_bit(0, 1);
-                    }
-                    _ => continue, 
-                }
+        match format.unwrap_or_else(|| ObjFormat::detect(&bytes)) {
+            ObjFormat::Text => self.fill_with_text(&bytes),
+            ObjFormat::Binary => self.fill_with_bin(&bytes),
+            ObjFormat::Object => {
+                let object = Object::from_bytes(&bytes).expect("Invalid object file.");
+                self.fill_with_object(&object);
             }
         }
         println!(" Done.");
         self.counter[0] = 0; 
     }
+
+    // ASCII '0'/'1' characters, anything else is ignored
+    fn fill_with_text(&mut self, bytes: &[u8]) {
+        for &ch in bytes {
+            match ch {
+                b'0' => {
+                    print!("{}", ch as char);
+                    self.write_bit(0, 0);
+                }
+                b'1' => {
+                    print!("{}", ch as char);
+                    self.write_bit(0, 1);
+                }
+                _ => continue, 
+            }
+        }
+        self.code_end = self.code_end.max(self.counter[0]);
+    }
+
+    // Packed bytes, most significant bit of each byte first
+    fn fill_with_bin(&mut self, bytes: &[u8]) {
+        print!("{} bytes", bytes.len());
+        for &byte in bytes {
+            self.write_bits(0, byte as u64, 8);
+        }
+        self.code_end = self.code_end.max(self.counter[0]);
+    }
+
+    // Segments of an object file at their addresses. The processor only
+    // knows the default encoding and starts at 0, so objects that need
+    // another opcode table or entry point are refused
+    pub fn fill_with_object(&mut self, object: &Object) {
+        if object.opcodes.is_some() || object.entry != 0 {
+            panic!("Object needs an opcode table or entry point simu does not support (use emu)");
+        }
+        for segment in &object.segments {
+            if segment.address + segment.length > MEMSIZE as u64 {
+                panic!("Segment at {:#x} does not fit in memory", segment.address);
+            }
+            print!("{} bits at {:#x} ", segment.length, segment.address);
+            self.counter[0] = segment.address as usize;
+            for i in 0..segment.length {
+                self.write_bit(0, segment.bit(i) as u64);
+            }
+            self.code_end = self.code_end.max(self.counter[0]);
+        }
+    }
 }
 
 impl fmt::Debug for Memory {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Memory {{ counter: {:?}, m: [memory... of size {}] }}", self.counter, MEMSIZE)
+        write!(f, "Memory {{ counter: {:?}, m: {:?} of size {} }}", self.counter, self.m, MEMSIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write at a bit address, then read back from there
+    fn round_trip(memory: &mut Memory, addr: usize, value: u64, n: usize) -> u64 {
+        memory.set_counter(A0, addr as UWord);
+        memory.write_bits(A0, value, n);
+        assert_eq!(memory.counter[A0], addr + n);
+        memory.set_counter(A0, addr as UWord);
+        memory.read_bits(A0, n)
+    }
+
+    #[test]
+    fn test_bits() {
+        let mut memory = Memory::new();
+        assert_eq!(round_trip(&mut memory, 0, 0xa5, 8), 0xa5);
+        // Across the boundary of the first two words
+        assert_eq!(round_trip(&mut memory, 60, 0xc3, 8), 0xc3);
+        assert_eq!(round_trip(&mut memory, 60, 0x0123_4567_89ab_cdef, 64), 0x0123_4567_89ab_cdef);
+        assert_eq!(round_trip(&mut memory, 128, !0, 64), !0);
+
+        // The first bit written is the most significant one, and the bits
+        // around the value are left alone
+        let mut memory = Memory::new();
+        memory.set_counter(A0, 60);
+        memory.write_bits(A0, 0x81, 8);
+        let bits: Vec<u64> = (58..70).map(|addr| {
+            memory.set_counter(A1, addr);
+            memory.read_bits(A1, 1)
+        }).collect();
+        assert_eq!(bits, [0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        memory.set_counter(A1, 56);
+        assert_eq!(memory.read_bits(A1, 16), 0x0810);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut memory = Memory::new();
+        memory.set_key(3, true);
+        memory.set_key(64, true);
+        memory.set_key(usize::MAX, true);
+        assert_eq!(memory.m.word((MEM_KEYBOARD >> 6) as u64), 1 << 3);
+        memory.set_key(3, false);
+        assert_eq!(memory.m.word((MEM_KEYBOARD >> 6) as u64), 0);
     }
 }
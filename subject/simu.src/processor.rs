@@ -1,167 +1,331 @@
 extern crate std;
 
-use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::sync::{Arc, Mutex};
 
-pub const WORDSIZE: usize = 32;
-pub type UWord = u32;
-pub type SWord = i32;
-pub type DoubleWord = u64;
+use crate::memory::{Memory, A0, A1, MEMSIZE, PC, SP};
+use crate::util::{add_with_flags, condition_holds, logic_flags, read_extend, shift_with_carry, sign_extend, sub_with_flags,
+    Flags, Rng};
+use minimisa_core::op::*;
+use minimisa_core::{decode, instruction, Operand, WordSize, ADDRESS_WIDTHS, CONDITIONS, CONST_WIDTHS, DIRECTIONS,
+    INSTRUCTIONS, POINTERS, SIZES};
 
-#[derive(Debug)]
-pub struct Memory {
-    pub m: Vec<u64>,
-    pub counter: [UWord; 4],
-}
-
-impl Memory {
-    pub fn new(size: usize) -> Self {
-        Memory {
-            m: vec![0; size],
-            counter: [0; 4],
-        }
-    }
-
-    pub fn read_bit(&self, _pc: usize) -> u64 {
-        0
-    }
-
-    pub fn set_counter(&mut self, idx: usize, value: UWord) {
-        self.counter[idx] = value;
-    }
-}
+// Registers are held on 64 bits and cut to the word size of the machine
+pub type UWord = u64;
+pub type SWord = i64;
 
 pub struct Processor {
     m: Arc<Mutex<Memory>>,
+    word: WordSize,
     pc: UWord,
-    sp: UWord,
-    a1: UWord,
-    a2: UWord,
     r: [UWord; 8],
     zflag: bool,
     cflag: bool,
     nflag: bool,
+    vflag: bool,
+    halted: bool,
+    trace: Option<BufWriter<File>>,
+    operands: Vec<String>,
+    branch: Option<bool>,  // Outcome of the last jumpif, for the trace
+    rng: Rng,              // Values of rand, from the seed emu uses by default
+}
+
+// Longest opcode of the default encoding, in bits
+fn max_opcode_length() -> u32 {
+    INSTRUCTIONS.iter().map(|ins| ins.bits().1).max().unwrap_or(0)
 }
 
 impl Processor {
-    pub fn new(m: Arc<Mutex<Memory>>) -> Self {
+    pub fn new(m: Arc<Mutex<Memory>>, word: WordSize) -> Self {
         Processor {
             m,
+            word,
             pc: 0,
-            sp: 0,
-            a1: 0,
-            a2: 0,
             r: [0; 8],
             zflag: false,
             cflag: false,
             nflag: false,
+            vflag: false,
+            halted: false,
+            trace: None,
+            operands: Vec::new(),
+            branch: None,
+            rng: Rng::new(0),
         }
     }
 
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn word(&self) -> WordSize {
+        self.word
+    }
+
+    pub fn memory(&self) -> &Arc<Mutex<Memory>> {
+        &self.m
+    }
+
+    // Pointers, registers and flags by name, as compared by --cosim
+    pub fn registers(&self) -> Vec<(&'static str, u64)> {
+        let mem = self.m.lock().unwrap();
+        let mut registers = vec![
+            ("pc", self.pc),
+            ("sp", mem.counter[SP] as u64),
+            ("a0", mem.counter[A0] as u64),
+            ("a1", mem.counter[A1] as u64),
+        ];
+        let names = ["r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7"];
+        registers.extend(names.into_iter().zip(self.r));
+        registers.extend([("z", self.zflag as u64), ("c", self.cflag as u64), ("n", self.nflag as u64), ("v", self.vflag as u64)]);
+        registers
+    }
+
+    // Write one line per executed instruction to the given file
+    pub fn set_trace(&mut self, filename: &str) -> std::io::Result<()> {
+        self.trace = Some(BufWriter::new(File::create(filename)?));
+        Ok(())
+    }
+
     pub fn von_neumann_step(&mut self, debug: bool) {
         let mut opcode = 0;
-        let mut regnum1 = 0;
-        let mut regnum2 = 0;
-        let mut shiftval = 0;
-        let mut condcode = 0;
-        let mut counter = 0;
-        let mut size = 0;
-        let mut offset: UWord = 0;
-        let mut constop: u64 = 0;
-        let mut dir = 0;
-        let mut uop1: UWord;
-        let mut uop2: UWord;
-        let mut ur: UWord = 0;
-        let mut fullr: DoubleWord;
-        let mut manage_flags = false;
         let instr_pc = self.pc;
+        let old_r = self.r;
+        self.operands.clear();
+        self.branch = None;
+
+        // Read the opcode bit by bit until it is one of the core table; the
+        // codes are prefix-free
+        let mut length = 0;
+        let op = loop {
+            self.read_bit_from_pc(&mut opcode);
+            length += 1;
+            if let Some(op) = decode(opcode as u64, length) {
+                break Some(op);
+            }
+            if length == max_opcode_length() {
+                break None;
+            }
+        };
+
+        // Operands are read with their kinds in the core table, so that
+        // every instruction is read whole
+        let mut args = [0; 3];
+        if let Some(op) = op {
+            for (arg, &kind) in args.iter_mut().zip(&INSTRUCTIONS[op as usize].operands) {
+                *arg = self.read_operand(kind);
+            }
+        }
+        let [x, y, z] = args;
 
-        // Read 4 bits for opcode
-        self.read_bit_from_pc(&mut opcode);
-        self.read_bit_from_pc(&mut opcode);
-        self.read_bit_from_pc(&mut opcode);
-        self.read_bit_from_pc(&mut opcode);
-
-        match opcode {
-            0x0 => { // add2
-                self.read_reg_from_pc(&mut regnum1);
-                self.read_reg_from_pc(&mut regnum2);
-                uop1 = self.r[regnum1 as usize];
-                uop2 = self.r[regnum2 as usize];
-                fullr = uop1 as DoubleWord + uop2 as DoubleWord; // for flags
-                ur = uop1 + uop2;
-                self.r[regnum1 as usize] = ur;
-                manage_flags = true;
+        match op {
+            Some(op @ (OP_ADD2 | OP_ADD2I | OP_SUB2 | OP_SUB2I | OP_CMP | OP_CMPI)) => {
+                let uop1 = self.r[x as usize];
+                let uop2 = if matches!(op, OP_ADD2 | OP_SUB2 | OP_CMP) { self.r[y as usize] } else { y };
+                // Wrapping on the word, flags as in emu (see util.rs)
+                let (result, flags) = if matches!(op, OP_ADD2 | OP_ADD2I) {
+                    add_with_flags(uop1, uop2, self.word)
+                } else {
+                    sub_with_flags(uop1, uop2, self.word)
+                };
+                if !matches!(op, OP_CMP | OP_CMPI) {
+                    self.r[x as usize] = result;
+                }
+                self.set_flags(flags);
+            }
+            Some(op @ (OP_OR2 | OP_OR2I | OP_AND2 | OP_AND2I)) => {
+                let value = if matches!(op, OP_OR2 | OP_AND2) { self.r[y as usize] } else { y };
+                let result = self.word.truncate(if matches!(op, OP_OR2 | OP_OR2I) {
+                    self.r[x as usize] | value
+                } else {
+                    self.r[x as usize] & value
+                });
+                self.r[x as usize] = result;
+                self.set_flags(logic_flags(result, self.word, self.flags()));
+            }
+            Some(op @ (OP_ADD3 | OP_ADD3I | OP_SUB3 | OP_SUB3I)) => {
+                // rd = rs + x, with the flags of add2 and sub2
+                let value = if matches!(op, OP_ADD3 | OP_SUB3) { self.r[z as usize] } else { z };
+                let (result, flags) = if matches!(op, OP_ADD3 | OP_ADD3I) {
+                    add_with_flags(self.r[y as usize], value, self.word)
+                } else {
+                    sub_with_flags(self.r[y as usize], value, self.word)
+                };
+                self.r[x as usize] = result;
+                self.set_flags(flags);
             }
-            0x1 => { // add2i
-                self.read_reg_from_pc(&mut regnum1);
-                self.read_const_from_pc(&mut constop);
-                uop1 = self.r[regnum1 as usize];
-                uop2 = constop as UWord;
-                fullr = uop1 as DoubleWord + uop2 as DoubleWord; // for flags
-                ur = uop1 + uop2;
-                self.r[regnum1 as usize] = ur;
-                manage_flags = true;
+            Some(op @ (OP_AND3 | OP_AND3I | OP_OR3 | OP_OR3I | OP_XOR3 | OP_XOR3I)) => {
+                let value = if matches!(op, OP_AND3 | OP_OR3 | OP_XOR3) { self.r[z as usize] } else { z };
+                let result = self.word.truncate(match op {
+                    OP_AND3 | OP_AND3I => self.r[y as usize] & value,
+                    OP_OR3 | OP_OR3I => self.r[y as usize] | value,
+                    _ => self.r[y as usize] ^ value,
+                });
+                self.r[x as usize] = result;
+                self.set_flags(logic_flags(result, self.word, self.flags()));
             }
-            0xa => { // jump
-                self.read_addr_from_pc(&mut offset);
-                self.pc += offset;
+            Some(OP_ASR3) => {
+                let (result, carry) = shift_with_carry(self.r[y as usize], z as u32, true, true, self.word);
+                if let Some(carry) = carry {
+                    self.cflag = carry;
+                }
+                self.r[x as usize] = result;
+                self.zflag = result == 0;
+            }
+            Some(OP_LET) => self.r[x as usize] = self.r[y as usize],
+            Some(OP_LETI) => self.r[x as usize] = y,
+            Some(OP_JUMP) => {
+                self.pc = self.pc.wrapping_add(x);
                 let mut mem = self.m.lock().unwrap();
                 mem.set_counter(0, self.pc);
-                manage_flags = false;
             }
-            0x8 => { // shift
-                self.read_bit_from_pc(&mut dir);
-                self.read_reg_from_pc(&mut regnum1);
-                self.read_shiftval_from_pc(&mut shiftval);
-                uop1 = self.r[regnum1 as usize];
-                if dir == 1 {
-                    ur = uop1 >> shiftval;
-                    self.cflag = ((uop1 >> (shiftval - 1)) & 1) == 1;
-                } else {
-                    self.cflag = ((uop1 << (shiftval - 1)) & (1 << (WORDSIZE - 1))) != 0;
-                    ur = uop1 << shiftval;
+            Some(OP_JUMPIF) => {
+                let taken = condition_holds(x as u32, self.flags());
+                self.branch = Some(taken);
+                if taken {
+                    self.pc = self.pc.wrapping_add(y);
+                    let mut mem = self.m.lock().unwrap();
+                    mem.set_counter(0, self.pc);
                 }
-                self.r[regnum1 as usize] = ur;
+            }
+            Some(OP_SHIFT) => {
+                // A shift by 0 leaves the carry alone
+                let (ur, carry) = shift_with_carry(self.r[y as usize], z as u32, x == 1, false, self.word);
+                if let Some(carry) = carry {
+                    self.cflag = carry;
+                }
+                self.r[y as usize] = ur;
                 self.zflag = ur == 0;
-                manage_flags = false;
             }
-            0xc | 0xd => {
-                self.read_bit_from_pc(&mut opcode);
-                self.read_bit_from_pc(&mut opcode);
-                if opcode == 0b110100 {
-                    // Handle write operation
-                    self.handle_write_operation();
+            Some(op @ (OP_READZE | OP_READSE)) => {
+                let value = self.m.lock().unwrap().read_bits(x as usize, y as usize);
+                self.r[z as usize] = self.word.truncate(read_extend(value, y as u32, op == OP_READSE));
+            }
+            Some(OP_WRITE) => {
+                // The low size bits of the register at the counter, which
+                // moves past them
+                self.m.lock().unwrap().write_bits(x as usize, self.r[z as usize], y as usize);
+            }
+            Some(OP_SETCTR) => {
+                let mut mem = self.m.lock().unwrap();
+                mem.set_counter(x as usize, self.r[y as usize]);
+            }
+            Some(OP_GETCTR) => {
+                let mem = self.m.lock().unwrap();
+                self.r[y as usize] = self.word.truncate(mem.counter[x as usize] as UWord);
+            }
+            Some(op @ (OP_PUSH | OP_POP)) => {
+                // The stack grows down and SP points at the last value
+                // pushed, as in emu. Growing into the program loaded below
+                // is an overflow, popping past the end of memory an
+                // underflow, and both halt
+                let (size, reg) = (x as usize, y as usize);
+                let mut fault = None;
+                let mut mem = self.m.lock().unwrap();
+                if op == OP_PUSH {
+                    let code_end = mem.code_end;
+                    match mem.counter[SP].checked_sub(size).filter(|&sp| sp >= code_end) {
+                        Some(sp) => {
+                            mem.set_counter(SP, sp as UWord);
+                            mem.write_bits(SP, self.r[reg], size);
+                            mem.set_counter(SP, sp as UWord);
+                        }
+                        None => fault = Some(("overflow", mem.counter[SP])),
+                    }
+                } else if mem.counter[SP] + size <= MEMSIZE {
+                    self.r[reg] = self.word.truncate(mem.read_bits(SP, size));
+                } else {
+                    fault = Some(("underflow", mem.counter[SP]));
+                }
+                if let Some((what, sp)) = fault {
+                    eprintln!("stack {} at pc={:08x}: sp={:08x}", what, instr_pc, sp);
+                    self.halted = true;
+                }
+            }
+            Some(op @ (OP_CALL | OP_RETURN | OP_RETI)) => {
+                // call pushes the return address like push 64 and jumps
+                // relative to the end of the instruction. There are no
+                // interrupts, so reti only returns, as in emu
+                let mut mem = self.m.lock().unwrap();
+                let code_end = mem.code_end;
+                let fault = if op == OP_CALL {
+                    match mem.counter[SP].checked_sub(64).filter(|&sp| sp >= code_end) {
+                        Some(sp) => {
+                            mem.set_counter(SP, sp as UWord);
+                            mem.write_bits(SP, self.pc, 64);
+                            mem.set_counter(SP, sp as UWord);
+                            self.pc = self.pc.wrapping_add(x);
+                            None
+                        }
+                        None => Some(("overflow", mem.counter[SP])),
+                    }
+                } else if mem.counter[SP] + 64 <= MEMSIZE {
+                    self.pc = mem.read_bits(SP, 64);
+                    None
+                } else {
+                    Some(("underflow", mem.counter[SP]))
+                };
+                mem.set_counter(PC, self.pc);
+                if let Some((what, sp)) = fault {
+                    eprintln!("stack {} at pc={:08x}: sp={:08x}", what, instr_pc, sp);
+                    self.halted = true;
                 }
             }
-            0xe | 0xf => {
-                self.read_bit_from_pc(&mut opcode);
-                self.read_bit_from_pc(&mut opcode);
-                self.read_bit_from_pc(&mut opcode);
-                // Handle additional cases if needed
+            // Only simulated time passes, which simu does not keep
+            Some(OP_SLEEP) => {}
+            Some(OP_RAND) => self.r[x as usize] = self.rng.next_u64(),
+            Some(op) => {
+                eprintln!("unimplemented opcode {} at pc={:08x}", INSTRUCTIONS[op as usize].mnemonic, instr_pc);
+                self.halted = true;
+            }
+            None => {
+                eprintln!("invalid opcode {:b} at pc={:08x}", opcode, instr_pc);
+                self.halted = true;
             }
-            _ => {}
         }
 
-        // Flag management
-        if manage_flags {
-            self.zflag = ur == 0;
-            self.cflag = fullr > (1u64 << WORDSIZE);
-            self.nflag = (ur as SWord) < 0;
+        // Registers are kept truncated to the word, as in emu
+        for r in self.r.iter_mut() {
+            *r = self.word.truncate(*r);
         }
 
+        // A jump to itself is how programs stop
+        self.halted |= self.pc == instr_pc;
+
         if debug {
             self.debug_output(opcode, instr_pc);
         }
+        if self.trace.is_some() {
+            self.trace_output(op, instr_pc, &old_r);
+        }
     }
 
-    fn handle_write_operation(&mut self) {
-        let mut regnum = 0;
-        let mut size = 0;
-        self.read_reg_from_pc(&mut regnum);
-        self.read_size_from_pc(&mut size);
-        let value = self.r[regnum as usize];
-        // Handle memory writing operation using size and value
+    // Trace format: pc, instruction, changed registers and flags, then
+    // taken=0/1 for conditional branches
+    fn trace_output(&mut self, op: Option<u32>, instr_pc: UWord, old_r: &[UWord; 8]) {
+        let mnemonic = op.and_then(instruction).map_or("???", |ins| ins.mnemonic);
+        let mut line = format!("{:08x} {}", instr_pc, mnemonic);
+        for op in &self.operands {
+            line.push(' ');
+            line.push_str(op);
+        }
+        line.push_str(" ;");
+        for (i, (new, old)) in self.r.iter().zip(old_r).enumerate() {
+            if new != old {
+                line.push_str(&format!(" r{}={:08x}", i, new));
+            }
+        }
+        line.push_str(&format!(" zcnv={}{}{}{}", self.zflag as u8, self.cflag as u8, self.nflag as u8, self.vflag as u8));
+        if let Some(taken) = self.branch {
+            line.push_str(&format!(" taken={}", taken as u8));
+        }
+
+        // Like emu --trace, a trace that cannot be written is dropped
+        if let Some(Err(e)) = self.trace.as_mut().map(|trace| writeln!(trace, "{}", line)) {
+            eprintln!("warning: trace stopped: {}", e);
+            self.trace = None;
+        }
     }
 
     fn debug_output(&self, opcode: i32, instr_pc: UWord) {
@@ -170,7 +334,7 @@ impl Processor {
             "after instr: {} at pc={:08x} (newpc={:08x} mpc={:08x} msp={:08x} ma0={:08x} ma1={:08x}) ",
             opcode, instr_pc, self.pc, mem.counter[0], mem.counter[1], mem.counter[2], mem.counter[3]
         );
-        print!("zcn = {}{}{}", self.zflag as u8, self.cflag as u8, self.nflag as u8);
+        print!("zcnv = {}{}{}{}", self.zflag as u8, self.cflag as u8, self.nflag as u8, self.vflag as u8);
         for i in 0..8 {
             print!(" r{}={:08x}", i, self.r[i]);
         }
@@ -179,95 +343,207 @@ impl Processor {
 
     // Helper methods
 
+    fn read_bits_from_pc(&mut self, n: usize) -> u64 {
+        let bits = self.m.lock().unwrap().read_bits(PC, n);
+        self.pc += n as UWord;
+        bits
+    }
+
     fn read_bit_from_pc(&mut self, var: &mut i32) {
-        let bit = self.m.lock().unwrap().read_bit(self.pc as usize);
+        let bit = self.read_bits_from_pc(1);
         *var = (*var << 1) + bit as i32;
-        self.pc += 1;
     }
 
-    fn read_reg_from_pc(&mut self, var: &mut i32) {
-        *var = 0;
-        self.read_bit_from_pc(var);
-        self.read_bit_from_pc(var);
-        self.read_bit_from_pc(var);
+    // An operand of the given kind as a number, with its text for the
+    // trace. Constants and addresses are extended to 64 bits as in emu:
+    // arithmetic constants and addresses are signed, the others are not
+    fn read_operand(&mut self, kind: Operand) -> u64 {
+        let (value, text) = match kind {
+            Operand::None => return 0,
+            Operand::Register => {
+                let reg = self.read_bits_from_pc(3);
+                (reg, format!("r{}", reg))
+            }
+            Operand::Direction => {
+                let dir = self.read_bits_from_pc(1);
+                (dir, DIRECTIONS[dir as usize].to_string())
+            }
+            Operand::Condition => {
+                let cond = self.read_bits_from_pc(3);
+                (cond, CONDITIONS[cond as usize].to_string())
+            }
+            Operand::Pointer => {
+                let counter = self.read_bits_from_pc(2);
+                (counter, POINTERS[counter as usize].to_string())
+            }
+            Operand::Address => {
+                let width = ADDRESS_WIDTHS[self.read_prefix_from_pc()];
+                let offset = sign_extend(self.read_bits_from_pc(width as usize), width) as UWord;
+                (offset, format!("{}", offset as SWord))
+            }
+            Operand::LConst | Operand::AConst => {
+                let width = CONST_WIDTHS[self.read_prefix_from_pc()];
+                let value = self.read_bits_from_pc(width as usize);
+                if kind == Operand::AConst {
+                    let value = sign_extend(value, width);
+                    (value as UWord, format!("{}", value))
+                } else {
+                    (value, format!("{}", value))
+                }
+            }
+            // Shift amounts are either "1" alone or "0" followed by 6 bits
+            Operand::Shift => {
+                let shift = if self.read_bits_from_pc(1) == 1 { 1 } else { self.read_bits_from_pc(6) };
+                (shift, format!("{}", shift))
+            }
+            Operand::Size => {
+                let code = self.read_bits_from_pc(2);
+                let (code, length) = if code < 2 { (code, 2) } else { (code << 1 | self.read_bits_from_pc(1), 3) };
+                let size = SIZES.iter().find(|(_, encoding)| *encoding == (code, length)).map_or(0, |&(size, _)| size);
+                (size, format!("{}", size))
+            }
+        };
+        self.operands.push(text);
+        value
     }
 
-    fn read_const_from_pc(&mut self, var: &mut u64) {
-        *var = 0;
-        let mut header = 0;
-        let mut size = 0;
-        self.read_bit_from_pc(&mut header);
-        if header == 0 {
-            size = 1;
-        } else {
-            self.read_bit_from_pc(&mut header);
-            if header == 2 {
-                size = 8;
-            } else {
-                self.read_bit_from_pc(&mut header);
-                size = if header == 6 { 32 } else { 64 };
-            }
-        }
-        for _ in 0..size {
-            *var = (*var << 1) + self.m.lock().unwrap().read_bit(self.pc as usize) as u64;
-            self.pc += 1;
+    // Index of the width prefix of a constant or an address: 0, 10, 110
+    // or 111
+    fn read_prefix_from_pc(&mut self) -> usize {
+        let mut prefix = 0;
+        while prefix < 3 && self.read_bits_from_pc(1) == 1 {
+            prefix += 1;
         }
+        prefix
     }
 
-    fn read_addr_from_pc(&mut self, var: &mut UWord) {
-        let mut header = 0;
-        let mut size = 0;
-        *var = 0;
-        self.read_bit_from_pc(&mut header);
-        size = if header == 0 { 8 } else {
-            self.read_bit_from_pc(&mut header);
-            if header == 2 { 16 } else {
-                self.read_bit_from_pc(&mut header);
-                if header == 6 { 32 } else { 64 }
-            }
-        };
-        for _ in 0..size {
-            *var = (*var << 1) + self.m.lock().unwrap().read_bit(self.pc as usize) as UWord;
-            self.pc += 1;
+    fn flags(&self) -> Flags {
+        Flags { z: self.zflag, n: self.nflag, c: self.cflag, v: self.vflag }
+    }
+
+    fn set_flags(&mut self, flags: Flags) {
+        (self.zflag, self.nflag, self.cflag, self.vflag) = (flags.z, flags.n, flags.c, flags.v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::testing::assemble;
+    use crate::util::READ_EXTEND_CASES;
+
+    // Load a program at 0 and run it until it halts, after `setup`
+    fn run(source: &str, word: WordSize, setup: impl FnOnce(&mut Processor)) -> Processor {
+        let memory = Arc::new(Mutex::new(Memory::new()));
+        {
+            let mut memory = memory.lock().unwrap();
+            memory.fill_with_object(&assemble(source).unwrap());
+            memory.set_counter(PC, 0);
         }
-        let sign = (*var >> (size - 1)) & 1;
-        for i in size..WORDSIZE {
-            *var += sign << i;
+        let mut cpu = Processor::new(memory, word);
+        setup(&mut cpu);
+        for _ in 0..100 {
+            if cpu.halted() {
+                break;
+            }
+            cpu.von_neumann_step(false);
         }
+        assert!(cpu.halted());
+        cpu
     }
 
-    fn read_shiftval_from_pc(&mut self, var: &mut i32) {
-        *var = 0;
-        self.read_bit_from_pc(var);
-        for _ in 0..6 {
-            self.read_bit_from_pc(var);
-        }
+    #[test]
+    fn test_write() {
+        let cpu = run("write a0 16 r1\nwrite a0 4 r2\nend: jump end", WordSize::W64, |cpu| {
+            cpu.r[1] = 0xbeef;
+            cpu.r[2] = 0x1f;
+            cpu.m.lock().unwrap().set_counter(A0, 0x1000);
+        });
+        let mut memory = cpu.m.lock().unwrap();
+        assert_eq!(memory.counter[A0], 0x1014);
+        memory.set_counter(A1, 0x1000);
+        assert_eq!(memory.read_bits(A1, 20), 0xbeeff);
     }
 
-    fn read_cond_from_pc(&mut self, var: &mut i32) {
-        *var = 0;
-        self.read_bit_from_pc(var);
-        self.read_bit_from_pc(var);
-        self.read_bit_from_pc(var);
+    #[test]
+    fn test_read_extend() {
+        for &(raw, size, ze, se) in READ_EXTEND_CASES {
+            let source = format!("readze a0 {} r1\nreadse a1 {} r2\nend: jump end", size, size);
+            let cpu = run(&source, WordSize::W64, |cpu| {
+                let mut memory = cpu.m.lock().unwrap();
+                memory.set_counter(A0, 0x1000);
+                memory.write_bits(A0, raw, size as usize);
+                memory.set_counter(A0, 0x1000);
+                memory.set_counter(A1, 0x1000);
+            });
+            assert_eq!(cpu.r[1], ze, "readze {:#x} on {} bits", raw, size);
+            assert_eq!(cpu.r[2], se as u64, "readse {:#x} on {} bits", raw, size);
+            assert_eq!(cpu.m.lock().unwrap().counter[A0], 0x1000 + size as usize);
+        }
     }
 
-    fn cond_true(&self, cond: i32) -> bool {
-        match cond {
-            0 => self.zflag,
-            1 => !self.zflag,
-            _ => panic!("Unexpected condition code"),
+    #[test]
+    fn test_shift() {
+        let source = "shift left r1 0\nshift right r2 63\nshift left r3 63\nend: jump end";
+        let cpu = run(source, WordSize::W64, |cpu| {
+            cpu.r = [0, 0x8000_0000_0000_0001, 0xc000_0000_0000_0000, 3, 0, 0, 0, 0];
+        });
+        assert_eq!(&cpu.r[1..4], [0x8000_0000_0000_0001, 1, 0x8000_0000_0000_0000]);
+        assert!(cpu.cflag && !cpu.zflag);
+
+        // Shifting by 0 keeps the carry, set or not
+        for carry in [false, true] {
+            let cpu = run("shift left r1 0\nend: jump end", WordSize::W64, |cpu| {
+                cpu.r[1] = 6;
+                cpu.cflag = carry;
+            });
+            assert_eq!((cpu.r[1], cpu.cflag, cpu.zflag), (6, carry, false));
         }
+
+        // On 32 bits, 63 shifts everything out
+        let cpu = run(source, WordSize::W32, |cpu| {
+            cpu.r = [0, 0x8000_0001, 0xc000_0000, 3, 0, 0, 0, 0];
+            cpu.cflag = true;
+        });
+        assert_eq!(&cpu.r[1..4], [0x8000_0001, 0, 0]);
+        assert!(!cpu.cflag && cpu.zflag);
+        let cpu = run("shift left r1 31\nend: jump end", WordSize::W32, |cpu| cpu.r[1] = 3);
+        assert_eq!((cpu.r[1], cpu.cflag), (0x8000_0000, true));
     }
 
-    fn read_counter_from_pc(&mut self, var: &mut i32) {
-        *var = 0;
-        self.read_bit_from_pc(var);
-        self.read_bit_from_pc(var);
+    #[test]
+    fn test_push_pop() {
+        let cpu = run("push 16 r1\npush 64 r2\npop 64 r3\npop 16 r4\nend: jump end", WordSize::W64, |cpu| {
+            cpu.r[1] = 0x1234;
+            cpu.r[2] = u64::MAX;
+            cpu.m.lock().unwrap().set_counter(SP, 0x10000);
+        });
+        assert_eq!((cpu.r[3], cpu.r[4]), (u64::MAX, 0x1234));
+        assert_eq!(cpu.m.lock().unwrap().counter[SP], 0x10000);
+
+        // Pushing into the program halts at once and leaves SP alone
+        let cpu = run("push 64 r1\npush 64 r1\nend: jump end", WordSize::W64, |cpu| {
+            let mut memory = cpu.m.lock().unwrap();
+            let sp = memory.code_end + 32;
+            memory.set_counter(SP, sp as UWord);
+        });
+        let memory = cpu.m.lock().unwrap();
+        assert_eq!((cpu.pc, memory.counter[SP]), (13, memory.code_end + 32));
+
+        // Popping past the end of memory
+        let cpu = run("pop 64 r1\npop 64 r1\nend: jump end", WordSize::W64, |cpu| {
+            cpu.r[1] = 5;
+            cpu.m.lock().unwrap().set_counter(SP, (MEMSIZE - 32) as UWord);
+        });
+        assert_eq!((cpu.pc, cpu.r[1]), (14, 5));
     }
 
-    fn read_size_from_pc(&mut self, size: &mut i32) {
-        *size = 0;
-        self.read_bit_from_pc(size);
-        self.read_bit_from_pc(size);
+    #[test]
+    fn test_operands() {
+        // Instructions are read whole by their operand kinds, whether they
+        // are executed or not
+        let cpu = run("sleep 1000\nadd2i r1 -1\ncmpi r1 255\nend: jump end", WordSize::W64, |_| {});
+        assert_eq!(cpu.r[1], u64::MAX);
+        assert!(!cpu.zflag && cpu.nflag);
     }
 }
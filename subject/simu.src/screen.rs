@@ -1,74 +1,105 @@
-extern crate sdl2;
-
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 128;
 pub const MEM_SCREEN_BEGIN: usize = 0x10000;
+// Keyboard register: one 64-bit word right after the 16-bit-per-pixel VRAM
+pub const MEM_KEYBOARD: usize = MEM_SCREEN_BEGIN + WIDTH * HEIGHT * 16;
+
+use crate::display::{Display, ScreenEvent};
+use crate::memory::Memory;
 
-pub struct Memory {
-    pub m: Vec<u64>, 
+// SDL scancodes of the keys the keyboard register has
+const SCANCODE_A: u32 = 4;
+const SCANCODE_Z: u32 = 29;
+const SCANCODE_1: u32 = 30;
+const SCANCODE_0: u32 = 39;
+const SCANCODE_RETURN: u32 = 40;
+const SCANCODE_ESCAPE: u32 = 41;
+const SCANCODE_SPACE: u32 = 44;
+const SCANCODE_RIGHT: u32 = 79;
+const SCANCODE_LEFT: u32 = 80;
+const SCANCODE_DOWN: u32 = 81;
+const SCANCODE_UP: u32 = 82;
+
+// Bit index of a key in the keyboard register, from its SDL scancode:
+// arrows, space and return first, then letters A-Z and digits 0-9
+pub fn key_index(scancode: u32) -> Option<usize> {
+    let key = match scancode {
+        SCANCODE_LEFT => 0,
+        SCANCODE_RIGHT => 1,
+        SCANCODE_UP => 2,
+        SCANCODE_DOWN => 3,
+        SCANCODE_SPACE => 4,
+        SCANCODE_RETURN => 5,
+        SCANCODE_A..=SCANCODE_Z => 6 + (scancode - SCANCODE_A) as usize,
+        // Scancodes go from 1 to 9, then 0
+        SCANCODE_0 => 32,
+        SCANCODE_1..SCANCODE_0 => 33 + (scancode - SCANCODE_1) as usize,
+        _ => return None,
+    };
+    Some(key)
 }
+// Color of pixel i: RGB555 pixels are packed four per word from
+// MEM_SCREEN_BEGIN, the first in the low bits
+fn pixel_rgb(mem: &Memory, i: usize) -> [u8; 3] {
+    let mword = mem.m.word(((MEM_SCREEN_BEGIN >> 6) + (i >> 2)) as u64);
+    let pixel = ((mword >> ((i & 3) << 4)) & 0xFFFF) as u32;
 
-impl Memory {
-    pub fn new(size: usize) -> Self {
-        Memory {
-            m: vec![0; size], 
-        }
-    }
+    let blue = pixel & ((1 << 5) - 1);
+    let green = (pixel >> 5) & ((1 << 5) - 1);
+    let red = (pixel >> 10) & ((1 << 5) - 1);
+    [(red << 3) as u8, (green << 3) as u8, (blue << 3) as u8]
 }
-pub fn simulate_screen(m: Arc<Mutex<Memory>>, refresh: Arc<Mutex<bool>>) {
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
 
-    let window = video_subsystem
-        .window("Asm", (WIDTH * 2) as u32, (HEIGHT * 2) as u32)
-        .position_centered()
-        .build()
-        .unwrap();
+// Save the screen as PNG or PPM (from the file extension), without a window
+pub fn save_screen(mem: &Memory, filename: &str) -> std::io::Result<()> {
+    let pixels: Vec<[u8; 3]> = (0..WIDTH * HEIGHT).map(|i| pixel_rgb(mem, i)).collect();
+    minimisa_core::image::save(filename, WIDTH, HEIGHT, &pixels)
+}
 
-    let mut canvas = window.into_canvas().build().unwrap();
-    let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::ARGB8888, WIDTH as u32, HEIGHT as u32)
-        .unwrap();
+// Runs until the screen is closed (which raises quit) or until someone else
+// raises quit, e.g. the processor halting
+pub fn simulate_screen(m: Arc<Mutex<Memory>>, quit: Arc<AtomicBool>, display: Display) {
+    let screen = display.open(2, None).and_then(|mut screen| screen.init(WIDTH, HEIGHT).map(|()| screen));
+    let mut screen = match screen {
+        Ok(screen) => screen,
+        Err(e) => {
+            eprintln!("Can't open the screen: {}", e);
+            quit.store(true, Ordering::SeqCst);
+            return;
+        }
+    };
 
-    let mut event_pump = sdl_context.event_pump().unwrap();
     let mut last_time = Instant::now();
-    let mut tempscreen = vec![0u32; WIDTH * HEIGHT];
+    let mut tempscreen = vec![[0u8; 3]; WIDTH * HEIGHT];
 
     let mut escape = false;
 
-    while !escape {
-        for event in event_pump.poll_iter() {
+    while !escape && !quit.load(Ordering::SeqCst) {
+        for event in screen.poll_events() {
             match event {
-                Event::Quit { .. } => escape = true,
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => escape = true,
-                _ => {}
+                ScreenEvent::Quit | ScreenEvent::Key(SCANCODE_ESCAPE, true) => escape = true,
+                ScreenEvent::Key(scancode, pressed) => {
+                    if let Some(key) = key_index(scancode) {
+                        m.lock().unwrap().set_key(key, pressed);
+                    }
+                }
             }
         }
-        {
+        // Only convert rows that were written since last frame
+        let dirty_rows = m.lock().unwrap().take_dirty_rows();
+        if dirty_rows != 0 {
             let mem = m.lock().unwrap();
-            for i in 0..(WIDTH * HEIGHT) {
-                let mword = mem.m[(MEM_SCREEN_BEGIN >> 6) + (i >> 2)];
-                let pixel = ((mword >> ((i & 3) << 4)) & 0xFFFF) as u32;
-
-                let blue = pixel & ((1 << 5) - 1);
-                let green = (pixel >> 5) & ((1 << 5) - 1);
-                let red = pixel >> 10;
-                tempscreen[i] = (red << (2 + 16)) + (green << (3 + 8)) + (blue << 3);
+            for row in (0..HEIGHT).filter(|r| (dirty_rows >> r) & 1 == 1) {
+                for (i, pixel) in tempscreen.iter_mut().enumerate().skip(row * WIDTH).take(WIDTH) {
+                    *pixel = pixel_rgb(&mem, i);
+                }
             }
         }
-        texture
-            .update(None, &tempscreen, WIDTH * 4)
-            .expect("Failed to update texture");
-        canvas.clear();
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+        screen.blit(&tempscreen);
         let frame_duration = Duration::from_secs_f32(1.0 / 60.0);
         let elapsed = last_time.elapsed();
         if elapsed < frame_duration {
@@ -76,7 +107,6 @@ pub fn simulate_screen(m: Arc<Mutex<Memory>>, refresh: Arc<Mutex<bool>>) {
         }
         last_time = Instant::now();
     }
-    drop(texture);
-    drop(canvas);
-    sdl_context.quit();
+    quit.store(true, Ordering::SeqCst);
+    screen.shutdown();
 }